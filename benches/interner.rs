@@ -0,0 +1,125 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use coap_lite::{
+    CoapOption, InternedPacket, Interner, MessageClass, Packet, RequestType,
+};
+use criterion::{criterion_group, Criterion};
+
+// Counts allocations and the bytes in use, to compare the memory taken up
+// by decoded messages
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static IN_USE: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        IN_USE.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        IN_USE.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const REQUESTS: usize = 100_000;
+const DEVICES: usize = 100;
+
+// Telemetry POSTs of a few devices that differ in their reading only
+fn telemetry_requests(count: usize) -> Vec<Vec<u8>> {
+    (0..count)
+        .map(|i| {
+            let mut packet = Packet::new();
+            packet.header.code = MessageClass::Request(RequestType::Post);
+            packet.header.message_id = i as u16;
+            packet.set_token((i as u32).to_be_bytes().to_vec());
+            packet.add_option(CoapOption::UriHost, b"gateway".to_vec());
+            packet.add_option(CoapOption::UriPath, b"v1".to_vec());
+            packet.add_option(CoapOption::UriPath, b"telemetry".to_vec());
+            let device = format!("device-{}", i % DEVICES);
+            packet.add_option(CoapOption::UriPath, device.into_bytes());
+            packet.payload = (i as u32).to_be_bytes().to_vec();
+            packet.to_bytes().unwrap()
+        })
+        .collect()
+}
+
+// Decodes all requests and keeps them, as a gateway queueing them would,
+// returning the allocations made and the bytes they take up
+fn measure<T>(
+    requests: &[Vec<u8>],
+    mut decode: impl FnMut(&[u8]) -> T,
+) -> (usize, usize) {
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let in_use = IN_USE.load(Ordering::Relaxed);
+    let decoded: Vec<T> = requests.iter().map(|bytes| decode(bytes)).collect();
+    let result = (
+        ALLOCATIONS.load(Ordering::Relaxed) - allocations,
+        IN_USE.load(Ordering::Relaxed) - in_use,
+    );
+    drop(black_box(decoded));
+    result
+}
+
+fn report_memory() {
+    let requests = telemetry_requests(REQUESTS);
+    let (allocations, bytes) =
+        measure(&requests, |bytes| Packet::from_bytes(bytes).unwrap());
+    println!(
+        "Packet::from_bytes: {} allocations, {} bytes for {} requests",
+        allocations, bytes, REQUESTS
+    );
+
+    let mut interner = Interner::new(32, 64 * 1024);
+    let (allocations, bytes) = measure(&requests, |bytes| {
+        InternedPacket::from_bytes_with_interner(bytes, &mut interner).unwrap()
+    });
+    println!(
+        "InternedPacket::from_bytes_with_interner: {} allocations, {} bytes \
+         for {} requests",
+        allocations, bytes, REQUESTS
+    );
+}
+
+fn decode(c: &mut Criterion) {
+    let requests = telemetry_requests(1000);
+    let mut group = c.benchmark_group("decode_telemetry");
+    group.bench_function("from_bytes", |b| {
+        b.iter(|| {
+            for bytes in requests.iter() {
+                black_box(Packet::from_bytes(bytes).unwrap());
+            }
+        })
+    });
+    let mut interner = Interner::new(32, 64 * 1024);
+    group.bench_function("from_bytes_with_interner", |b| {
+        b.iter(|| {
+            for bytes in requests.iter() {
+                black_box(
+                    InternedPacket::from_bytes_with_interner(
+                        bytes,
+                        &mut interner,
+                    )
+                    .unwrap(),
+                );
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, decode);
+
+fn main() {
+    report_memory();
+    benches();
+    Criterion::default().configure_from_args().final_summary();
+}
@@ -0,0 +1,80 @@
+use std::hint::black_box;
+
+use coap_lite::{
+    CoapOption, ContentFormat, LazyPacket, MessageClass, Packet, RequestType,
+};
+use criterion::{criterion_group, criterion_main, Criterion};
+
+fn telemetry_options(packet: &mut Packet) {
+    packet.add_option(CoapOption::UriHost, b"collector.example".to_vec());
+    packet.add_option(CoapOption::UriPath, b"telemetry".to_vec());
+    packet.add_option(CoapOption::UriPath, b"v1".to_vec());
+    packet.add_option(CoapOption::UriQuery, b"device=4711".to_vec());
+    packet.set_content_format(ContentFormat::ApplicationCBOR);
+}
+
+fn telemetry_post(message_id: u16, template: Option<&Packet>) -> Packet {
+    let mut packet = Packet::new();
+    packet.header.code = MessageClass::Request(RequestType::Post);
+    packet.header.message_id = message_id;
+    packet.set_token(message_id.to_be_bytes().to_vec());
+    match template {
+        Some(template) => packet.merge_options_from(template, |_| true),
+        None => telemetry_options(&mut packet),
+    }
+    packet.payload = vec![0xA1, 0x01, 0x18, 0x2A];
+    packet
+}
+
+fn template_stamping(c: &mut Criterion) {
+    let mut template = Packet::new();
+    telemetry_options(&mut template);
+    assert_eq!(
+        telemetry_post(1, None).to_bytes().unwrap(),
+        telemetry_post(1, Some(&template)).to_bytes().unwrap()
+    );
+
+    let mut group = c.benchmark_group("telemetry_post");
+    group.bench_function("rebuild", |b| {
+        b.iter(|| telemetry_post(black_box(1), None).to_bytes().unwrap())
+    });
+    group.bench_function("stamp_template", |b| {
+        b.iter(|| {
+            telemetry_post(black_box(1), Some(&template))
+                .to_bytes()
+                .unwrap()
+        })
+    });
+    group.finish();
+}
+
+fn first_uri_path(c: &mut Criterion) {
+    let mut packet = telemetry_post(1, None);
+    packet.add_option(CoapOption::Observe, vec![1]);
+    packet.add_option(CoapOption::Accept, vec![60]);
+    packet.add_option(CoapOption::Size1, vec![4]);
+    let bytes = packet.to_bytes().unwrap();
+
+    let mut group = c.benchmark_group("first_uri_path");
+    group.bench_function("from_bytes", |b| {
+        b.iter(|| {
+            let packet = Packet::from_bytes(black_box(&bytes)).unwrap();
+            packet
+                .get_first_option(CoapOption::UriPath)
+                .map(|v| v.len())
+        })
+    });
+    group.bench_function("lazy", |b| {
+        b.iter(|| {
+            let packet = LazyPacket::new(black_box(&bytes)).unwrap();
+            packet
+                .first_option(CoapOption::UriPath)
+                .unwrap()
+                .map(|v| v.len())
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, template_stamping, first_uri_path);
+criterion_main!(benches);
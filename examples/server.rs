@@ -19,7 +19,7 @@ fn main() {
     let mut response = request.response.unwrap();
     response.message.payload = b"OK".to_vec();
 
-    let packet = response.message.to_bytes().unwrap();
+    let packet = response.to_bytes_for(&request.message).unwrap();
     socket
         .send_to(&packet[..], &src)
         .expect("Could not send the data");
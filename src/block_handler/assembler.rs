@@ -0,0 +1,582 @@
+//! Reassembly of request bodies sent block-wise with Block1, for servers
+//! that handle the blocks themselves rather than through `BlockHandler`.
+
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use super::BlockValue;
+use crate::error::MessageError;
+use crate::transmission::EXCHANGE_LIFETIME;
+use crate::{CoapOption, Packet, ResponseType};
+
+/// Returns the Block1 option to echo in the response to the block carried by
+/// `request` (RFC 7959 section 2.3).
+///
+/// A 2.31 (Continue) response, with `final_response` false, echoes the block
+/// with the M bit set, asking for more blocks, and fails for the last block.
+/// The final response, e.g. 2.04 (Changed), echoes it with the M bit clear;
+/// when answering a block that isn't the last one, this tells the client
+/// that the server acted on the blocks so far rather than the whole body.
+pub fn ack_block1(
+    request: &Packet,
+    final_response: bool,
+) -> Result<BlockValue, MessageError> {
+    let block = request
+        .get_first_option_as::<BlockValue>(CoapOption::Block1)
+        .and_then(|block| block.ok())
+        .ok_or(MessageError::InvalidBlockOption)?;
+    if !final_response && !block.more {
+        return Err(MessageError::InvalidBlockOption);
+    }
+    Ok(echo(&block, !final_response))
+}
+
+fn echo(block: &BlockValue, more: bool) -> BlockValue {
+    BlockValue {
+        more,
+        ..block.clone()
+    }
+}
+
+/// Storage for a body being reassembled, see [`Block1Assembler`].
+pub trait Block1Buffer {
+    /// Returns the maximum length of a body.
+    fn max_len(&self) -> usize;
+
+    /// Returns the body received so far.
+    fn as_slice(&self) -> &[u8];
+
+    /// Appends to the body, which has room for `data`.
+    fn append(&mut self, data: &[u8]);
+
+    /// Drops the body.
+    fn clear(&mut self);
+}
+
+/// A `Vec` that holds bodies of up to a maximum length, which keeps peers
+/// from growing it without bounds.
+#[derive(Debug, Clone)]
+pub struct BoundedVec {
+    data: Vec<u8>,
+    max_len: usize,
+}
+
+impl BoundedVec {
+    /// Creates a buffer for bodies of up to `max_len` bytes.
+    pub fn new(max_len: usize) -> Self {
+        BoundedVec {
+            data: Vec::new(),
+            max_len,
+        }
+    }
+}
+
+impl Block1Buffer for BoundedVec {
+    fn max_len(&self) -> usize {
+        self.max_len
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn append(&mut self, data: &[u8]) {
+        self.data.extend_from_slice(data);
+    }
+
+    fn clear(&mut self) {
+        self.data.clear();
+    }
+}
+
+/// A buffer of `N` bytes that doesn't allocate.
+#[derive(Debug, Clone)]
+pub struct FixedBuffer<const N: usize> {
+    data: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> Default for FixedBuffer<N> {
+    fn default() -> Self {
+        FixedBuffer {
+            data: [0; N],
+            len: 0,
+        }
+    }
+}
+
+impl<const N: usize> Block1Buffer for FixedBuffer<N> {
+    fn max_len(&self) -> usize {
+        N
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    fn append(&mut self, data: &[u8]) {
+        self.data[self.len..self.len + data.len()].copy_from_slice(data);
+        self.len += data.len();
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+/// The outcome of passing a block to [`Block1Assembler::push`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Block1Outcome {
+    /// More blocks are expected, the block is to be answered with 2.31
+    /// (Continue) carrying the Block1 option `block1`.
+    Continue { block1: BlockValue },
+    /// The body is complete and can be taken from the assembler.  The final
+    /// response is to carry the Block1 option `block1`.
+    Complete { block1: BlockValue },
+    /// The body exceeds the capacity, to be answered with 4.13 (Request
+    /// Entity Too Large).  The transfer is abandoned.
+    TooLarge,
+    /// The block doesn't continue the body, e.g. because a block was
+    /// skipped, to be answered with 4.08 (Request Entity Incomplete).  The
+    /// transfer is abandoned.
+    Incomplete,
+}
+
+impl Block1Outcome {
+    /// Returns the code to respond to the block with, or `None` if the
+    /// complete request is to be handled.
+    pub fn response_type(&self) -> Option<ResponseType> {
+        match self {
+            Block1Outcome::Continue { .. } => Some(ResponseType::Continue),
+            Block1Outcome::Complete { .. } => None,
+            Block1Outcome::TooLarge => {
+                Some(ResponseType::RequestEntityTooLarge)
+            }
+            Block1Outcome::Incomplete => {
+                Some(ResponseType::RequestEntityIncomplete)
+            }
+        }
+    }
+}
+
+/// Reassembles the body of a single Block1 transfer from its blocks, which
+/// have to arrive in order.
+///
+/// The body is stored in a [`Block1Buffer`] with a maximum length: a
+/// [`BoundedVec`] for [`Block1Assembler::new`], or a fixed array for targets
+/// without a heap, see [`Block1AssemblerFixed`].
+///
+/// Like [`BlockHandler`](super::BlockHandler), a transfer the client never
+/// finishes can be dropped with [`Block1Assembler::cancel`], or after a
+/// period of inactivity with [`Block1Assembler::purge_expired`].
+#[derive(Debug, Clone)]
+pub struct Block1Assembler<B = BoundedVec> {
+    buffer: B,
+    in_progress: bool,
+    expiry: Duration,
+    last_activity: Option<Duration>,
+}
+
+impl<B: Block1Buffer + Default> Default for Block1Assembler<B> {
+    fn default() -> Self {
+        Self::with_buffer(B::default())
+    }
+}
+
+/// A [`Block1Assembler`] that stores bodies of up to `N` bytes in an array.
+pub type Block1AssemblerFixed<const N: usize> =
+    Block1Assembler<FixedBuffer<N>>;
+
+impl Block1Assembler {
+    /// Creates an assembler that stores bodies of up to `max_body_size`
+    /// bytes in a `Vec`.
+    pub fn new(max_body_size: usize) -> Self {
+        Self::with_buffer(BoundedVec::new(max_body_size))
+    }
+}
+
+impl<const N: usize> Block1Assembler<FixedBuffer<N>> {
+    /// Creates an assembler that stores bodies of up to `N` bytes.
+    pub fn new_fixed() -> Self {
+        Self::with_buffer(FixedBuffer::default())
+    }
+}
+
+impl<B: Block1Buffer> Block1Assembler<B> {
+    /// Creates an assembler storing the body in `buffer`, which is cleared.
+    pub fn with_buffer(mut buffer: B) -> Self {
+        buffer.clear();
+        Block1Assembler {
+            buffer,
+            in_progress: false,
+            expiry: EXCHANGE_LIFETIME,
+            last_activity: None,
+        }
+    }
+
+    /// Sets how long a transfer may go without a block before
+    /// [`Block1Assembler::purge_expired`] drops it, [`EXCHANGE_LIFETIME`] by
+    /// default.
+    pub fn with_expiry(mut self, expiry: Duration) -> Self {
+        self.expiry = expiry;
+        self
+    }
+
+    /// Adds the block with the Block1 option `block` and payload `payload`
+    /// to the body.  `size1` is the value of the Size1 option, which
+    /// announces the size of the whole body, if present.
+    ///
+    /// Block 0 starts a new transfer.  The block size may shrink during the
+    /// transfer, as long as each block continues where the previous one
+    /// ended.  The block that was added last may be sent again by a client
+    /// that didn't get the response to it; it gets the same outcome without
+    /// being added twice, which for the last block means that the body is
+    /// reported complete again.
+    pub fn push(
+        &mut self,
+        block: &BlockValue,
+        payload: &[u8],
+        size1: Option<u32>,
+    ) -> Block1Outcome {
+        if block.num == 0 {
+            self.buffer.clear();
+            self.in_progress = true;
+        } else if self.is_retransmission(block, payload) {
+            return self.answer(block);
+        }
+        if !self.in_progress
+            || block.byte_offset() != self.buffer.as_slice().len()
+            || (block.more && payload.len() != block.size())
+            || payload.len() > block.size()
+        {
+            return self.abandon(Block1Outcome::Incomplete);
+        }
+
+        let max_len = self.buffer.max_len();
+        let announced_too_large = size1.is_some_and(|size| {
+            usize::try_from(size).map_or(true, |size| size > max_len)
+        });
+        if announced_too_large || block.byte_offset() + payload.len() > max_len
+        {
+            return self.abandon(Block1Outcome::TooLarge);
+        }
+
+        self.buffer.append(payload);
+        self.answer(block)
+    }
+
+    /// Like [`Block1Assembler::push`], but also records `now` as the time
+    /// of the last activity of the transfer, which is what
+    /// [`Block1Assembler::purge_expired`] goes by.
+    pub fn push_at(
+        &mut self,
+        block: &BlockValue,
+        payload: &[u8],
+        size1: Option<u32>,
+        now: Duration,
+    ) -> Block1Outcome {
+        self.last_activity = Some(now);
+        self.push(block, payload, size1)
+    }
+
+    /// Returns the body received so far, which is the whole body after
+    /// [`Block1Outcome::Complete`].
+    pub fn body(&self) -> &[u8] {
+        self.buffer.as_slice()
+    }
+
+    /// Abandons the transfer in progress.
+    pub fn reset(&mut self) {
+        self.buffer.clear();
+        self.in_progress = false;
+        self.last_activity = None;
+    }
+
+    /// Abandons the transfer in progress, returning whether there was one.
+    pub fn cancel(&mut self) -> bool {
+        let in_progress = self.in_progress;
+        self.reset();
+        in_progress
+    }
+
+    /// Drops the body if its last activity recorded with
+    /// [`Block1Assembler::push_at`] is at least the expiry ago, returning
+    /// whether it was dropped.
+    ///
+    /// This is meant to be called periodically by the host.  Transfers
+    /// pushed without a timestamp never expire.
+    pub fn purge_expired(&mut self, now: Duration) -> bool {
+        let expired = matches!(
+            self.last_activity,
+            Some(last_activity)
+                if now.saturating_sub(last_activity) >= self.expiry
+        );
+        if expired {
+            self.reset();
+        }
+        expired
+    }
+
+    // Whether the block ends the body received so far with the same data,
+    // i.e. it was already added
+    fn is_retransmission(&self, block: &BlockValue, payload: &[u8]) -> bool {
+        let body = self.buffer.as_slice();
+        !payload.is_empty()
+            && block.byte_offset() + payload.len() == body.len()
+            && body[block.byte_offset()..] == *payload
+            && (!block.more || payload.len() == block.size())
+    }
+
+    fn answer(&mut self, block: &BlockValue) -> Block1Outcome {
+        if block.more {
+            Block1Outcome::Continue {
+                block1: echo(block, true),
+            }
+        } else {
+            self.in_progress = false;
+            Block1Outcome::Complete {
+                block1: echo(block, false),
+            }
+        }
+    }
+
+    fn abandon(&mut self, outcome: Block1Outcome) -> Block1Outcome {
+        self.reset();
+        outcome
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn block(num: usize, more: bool, size: usize) -> BlockValue {
+        BlockValue::new(num, more, size).unwrap()
+    }
+
+    #[test]
+    fn vec_transfer() {
+        let body: Vec<u8> = (0..40).collect();
+        let mut assembler = Block1Assembler::new(1024);
+        let mut chunks = body.chunks(16).enumerate().peekable();
+        while let Some((num, chunk)) = chunks.next() {
+            let more = chunks.peek().is_some();
+            let outcome = assembler.push(&block(num, more, 16), chunk, None);
+            let block1 = block(num, more, 16);
+            let expected = if more {
+                Block1Outcome::Continue { block1 }
+            } else {
+                Block1Outcome::Complete { block1 }
+            };
+            assert_eq!(expected, outcome);
+        }
+        assert_eq!(body, assembler.body());
+    }
+
+    #[test]
+    fn shrinking_block_size() {
+        let mut assembler = Block1Assembler::new(1024);
+        assert_eq!(
+            Block1Outcome::Continue {
+                block1: block(0, true, 32)
+            },
+            assembler.push(&block(0, true, 32), &[1; 32], Some(56))
+        );
+        // Block 2 of size 16 continues at byte 32
+        assert_eq!(
+            Block1Outcome::Continue {
+                block1: block(2, true, 16)
+            },
+            assembler.push(&block(2, true, 16), &[2; 16], None)
+        );
+        assert_eq!(
+            Block1Outcome::Complete {
+                block1: block(3, false, 16)
+            },
+            assembler.push(&block(3, false, 16), &[3; 8], None)
+        );
+        assert_eq!(56, assembler.body().len());
+    }
+
+    #[test]
+    fn retransmitted_block() {
+        let mut assembler = Block1Assembler::new(1024);
+        let continued = Block1Outcome::Continue {
+            block1: block(1, true, 16),
+        };
+        assembler.push(&block(0, true, 16), &[0; 16], None);
+        assert_eq!(
+            continued,
+            assembler.push(&block(1, true, 16), &[1; 16], None)
+        );
+        // The 2.31 response got lost and the client sends block 1 again
+        assert_eq!(
+            continued,
+            assembler.push(&block(1, true, 16), &[1; 16], None)
+        );
+        assert_eq!(32, assembler.body().len());
+
+        let complete = Block1Outcome::Complete {
+            block1: block(2, false, 16),
+        };
+        assert_eq!(
+            complete,
+            assembler.push(&block(2, false, 16), &[2; 4], None)
+        );
+        assert_eq!(
+            complete,
+            assembler.push(&block(2, false, 16), &[2; 4], None)
+        );
+        assert_eq!(36, assembler.body().len());
+
+        // Other data at the same position doesn't continue the body
+        assert_eq!(
+            Block1Outcome::Incomplete,
+            assembler.push(&block(2, false, 16), &[3; 4], None)
+        );
+        assert!(assembler.body().is_empty());
+    }
+
+    #[test]
+    fn out_of_sequence() {
+        let mut assembler = Block1Assembler::new(1024);
+        assert_eq!(
+            Block1Outcome::Incomplete,
+            assembler.push(&block(1, true, 16), &[0; 16], None)
+        );
+        assembler.push(&block(0, true, 16), &[0; 16], None);
+        assert_eq!(
+            Block1Outcome::Incomplete,
+            assembler.push(&block(2, false, 16), &[0; 16], None)
+        );
+        assert!(assembler.body().is_empty());
+
+        // A short block that isn't the last one
+        assert_eq!(
+            Block1Outcome::Incomplete,
+            assembler.push(&block(0, true, 16), &[0; 10], None)
+        );
+        assert_eq!(
+            Some(ResponseType::RequestEntityIncomplete),
+            Block1Outcome::Incomplete.response_type()
+        );
+    }
+
+    #[test]
+    fn vec_too_large() {
+        let mut assembler = Block1Assembler::new(32);
+        assert_eq!(
+            Block1Outcome::TooLarge,
+            assembler.push(&block(0, true, 16), &[0; 16], Some(33))
+        );
+
+        // Without Size1 the body is rejected once it grows too large
+        assembler.push(&block(0, true, 16), &[0; 16], None);
+        assembler.push(&block(1, true, 16), &[1; 16], None);
+        assert_eq!(
+            Block1Outcome::TooLarge,
+            assembler.push(&block(2, true, 16), &[2; 16], None)
+        );
+        assert!(assembler.body().is_empty());
+        assert_eq!(
+            Some(ResponseType::RequestEntityTooLarge),
+            Block1Outcome::TooLarge.response_type()
+        );
+    }
+
+    #[test]
+    fn fixed_announced_too_large() {
+        let mut assembler = Block1AssemblerFixed::<64>::new_fixed();
+        assert_eq!(
+            Block1Outcome::TooLarge,
+            assembler.push(&block(0, true, 16), &[0; 16], Some(65))
+        );
+        assert_eq!(
+            Block1Outcome::Continue {
+                block1: block(0, true, 16)
+            },
+            assembler.push(&block(0, true, 16), &[0; 16], Some(64))
+        );
+    }
+
+    #[test]
+    fn purge_expired() {
+        let start = Duration::from_secs(1000);
+        let mut assembler = Block1AssemblerFixed::<64>::new_fixed();
+        assembler.push_at(&block(0, true, 16), &[0; 16], None, start);
+        assert!(!assembler.purge_expired(start + Duration::from_secs(60)));
+        assert_eq!(16, assembler.body().len());
+        assert!(assembler.purge_expired(start + EXCHANGE_LIFETIME));
+        assert!(assembler.body().is_empty());
+        assert!(!assembler.purge_expired(start + EXCHANGE_LIFETIME));
+
+        // The transfer has to start over
+        assert_eq!(
+            Block1Outcome::Incomplete,
+            assembler.push(&block(1, true, 16), &[1; 16], None)
+        );
+
+        // Activity keeps the transfer alive
+        let mut assembler =
+            Block1Assembler::new(1024).with_expiry(Duration::from_secs(10));
+        assembler.push_at(&block(0, true, 16), &[0; 16], None, start);
+        let later = start + Duration::from_secs(8);
+        assembler.push_at(&block(1, true, 16), &[1; 16], None, later);
+        assert!(!assembler.purge_expired(start + Duration::from_secs(10)));
+        assert!(assembler.purge_expired(later + Duration::from_secs(10)));
+
+        // Transfers without a timestamp aren't expired
+        assembler.push(&block(0, true, 16), &[0; 16], None);
+        assert!(!assembler.purge_expired(Duration::MAX));
+    }
+
+    #[test]
+    fn cancel() {
+        let mut assembler = Block1Assembler::new(1024);
+        assert!(!assembler.cancel());
+        assembler.push(&block(0, true, 16), &[0; 16], None);
+        assert!(assembler.cancel());
+        assert!(assembler.body().is_empty());
+        assert!(!assembler.cancel());
+    }
+
+    fn put(block1: Option<BlockValue>) -> Packet {
+        let mut request = Packet::new();
+        if let Some(block1) = block1 {
+            request.add_option_as(CoapOption::Block1, block1);
+        }
+        request
+    }
+
+    #[test]
+    fn ack_rfc7959_figure_6() {
+        // CON PUT 1:0/1/128 -> ACK 2.31 1:0/1/128
+        // CON PUT 1:1/1/128 -> ACK 2.31 1:1/1/128
+        // CON PUT 1:2/0/128 -> ACK 2.04 1:2/0/128
+        for num in 0..2 {
+            let request = put(Some(block(num, true, 128)));
+            assert_eq!(Ok(block(num, true, 128)), ack_block1(&request, false));
+        }
+        let request = put(Some(block(2, false, 128)));
+        assert_eq!(Ok(block(2, false, 128)), ack_block1(&request, true));
+        assert_eq!(Vec::from(ack_block1(&request, true).unwrap()), vec![0x23]);
+    }
+
+    #[test]
+    fn ack_invalid() {
+        // The last block can't be answered with 2.31
+        let request = put(Some(block(2, false, 128)));
+        assert_eq!(
+            Err(MessageError::InvalidBlockOption),
+            ack_block1(&request, false)
+        );
+        assert_eq!(
+            Err(MessageError::InvalidBlockOption),
+            ack_block1(&put(None), true)
+        );
+
+        // A non-atomic server answering a block that isn't the last one
+        let request = put(Some(block(1, true, 64)));
+        assert_eq!(Ok(block(1, false, 64)), ack_block1(&request, true));
+    }
+}
@@ -1,18 +1,26 @@
 use alloc::vec::Vec;
 use core::convert::TryFrom;
 
-use crate::error::{IncompatibleOptionValueFormat, InvalidBlockValue};
-use crate::option_value::{OptionValueType, OptionValueU16};
+use crate::error::{
+    IncompatibleOptionValueFormat, InvalidBlockValue, MessageError,
+};
+use crate::option_value::{OptionValueType, OptionValueU32};
 
 /// The block option value.
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct BlockValue {
-    pub num: u16,
+    /// The number of the block, of at most 20 bits.
+    pub num: u32,
     pub more: bool,
+    /// The block size is `2 ** (size_exponent + 4)`, the exponent 7 is
+    /// reserved.
     pub size_exponent: u8,
 }
 
 impl BlockValue {
+    /// The largest block number that can be encoded.
+    pub const MAX_NUM: u32 = 0xF_FFFF;
+
     pub fn new(
         num: usize,
         more: bool,
@@ -23,11 +31,14 @@ impl BlockValue {
 
         let size_exponent = u8::try_from(true_size_exponent.saturating_sub(4))
             .map_err(InvalidBlockValue::TypeBoundsError)?;
-        if size_exponent > 0x7 {
+        if size_exponent > 0x6 {
             return Err(InvalidBlockValue::SizeExponentEncodingError(size));
         }
         let num =
-            u16::try_from(num).map_err(InvalidBlockValue::TypeBoundsError)?;
+            u32::try_from(num).map_err(InvalidBlockValue::TypeBoundsError)?;
+        if num > Self::MAX_NUM {
+            return Err(InvalidBlockValue::NumTooLarge(num));
+        }
         Ok(Self {
             num,
             more,
@@ -50,17 +61,61 @@ impl BlockValue {
         }
     }
 
+    /// Decodes the value of a Block1 or Block2 option, which is an unsigned
+    /// integer of up to 3 bytes (RFC 7959 section 2.2).  The reserved size
+    /// exponent 7 is rejected.
+    pub fn from_bytes(value: &[u8]) -> Result<BlockValue, MessageError> {
+        if value.len() > 3 {
+            return Err(MessageError::InvalidBlockOption);
+        }
+        let scalar = value
+            .iter()
+            .fold(0, |scalar, &byte| scalar << 8 | u32::from(byte));
+        let size_exponent = (scalar & 0x7) as u8;
+        if size_exponent == 0x7 {
+            return Err(MessageError::InvalidBlockOption);
+        }
+        Ok(Self {
+            num: scalar >> 4,
+            more: scalar >> 3 & 0x1 == 0x1,
+            size_exponent,
+        })
+    }
+
+    /// Encodes the value of a Block1 or Block2 option in as few bytes as
+    /// possible.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let scalar = (self.num & Self::MAX_NUM) << 4
+            | u32::from(self.more) << 3
+            | u32::from(self.size_exponent & 0x7);
+        Vec::from(OptionValueU32(scalar))
+    }
+
+    /// Returns the block size, `2 ** (size_exponent + 4)`.
     pub fn size(&self) -> usize {
         1 << (self.size_exponent + 4)
     }
+
+    /// Returns the offset of the block within the body.
+    pub fn byte_offset(&self) -> usize {
+        self.num as usize * self.size()
+    }
+
+    /// Returns the fraction of the body that was transferred before this
+    /// block, `byte_offset / total_size`, given the size of the body as
+    /// announced in the Size1 or Size2 option.
+    ///
+    /// The announced size is only an estimate, so the fraction is capped at
+    /// 1.  Returns `None` if the size isn't known or is 0.
+    pub fn progress(&self, total_size: Option<u32>) -> Option<f32> {
+        let total_size = total_size.filter(|&size| size > 0)? as f32;
+        Some((self.byte_offset() as f32 / total_size).min(1.0))
+    }
 }
 
 impl From<BlockValue> for Vec<u8> {
     fn from(block_value: BlockValue) -> Vec<u8> {
-        let scalar = block_value.num << 4
-            | u16::from(block_value.more) << 3
-            | u16::from(block_value.size_exponent & 0x7);
-        Vec::from(OptionValueU16(scalar))
+        block_value.to_bytes()
     }
 }
 
@@ -68,15 +123,8 @@ impl TryFrom<Vec<u8>> for BlockValue {
     type Error = IncompatibleOptionValueFormat;
 
     fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
-        let scalar = OptionValueU16::try_from(value)?.0;
-
-        let num: u16 = scalar >> 4;
-        let more = scalar >> 3 & 0x1 == 0x1;
-        let size_exponent: u8 = (scalar & 0x7) as u8;
-        Ok(Self {
-            num,
-            more,
-            size_exponent,
+        Self::from_bytes(&value).map_err(|_| IncompatibleOptionValueFormat {
+            message: format!("invalid block value {:02x?}", value),
         })
     }
 }
@@ -119,4 +167,59 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn test_progress() {
+        // Nothing has been transferred before block 0
+        let first = BlockValue::new(0, true, 64).unwrap();
+        assert_eq!(0, first.byte_offset());
+        assert_eq!(None, first.progress(None));
+        assert_eq!(None, first.progress(Some(0)));
+        assert_eq!(Some(0.0), first.progress(Some(64)));
+        assert_eq!(Some(0.0), first.progress(Some(256)));
+
+        let third = BlockValue::new(2, true, 64).unwrap();
+        assert_eq!(128, third.byte_offset());
+        assert_eq!(Some(0.5), third.progress(Some(256)));
+
+        // The announced size is only an estimate
+        assert_eq!(Some(1.0), third.progress(Some(100)));
+        let last = BlockValue::new(3, false, 64).unwrap();
+        assert_eq!(Some(0.25), last.progress(Some(768)));
+        assert_eq!(Some(1.0), last.progress(Some(150)));
+    }
+
+    #[test]
+    fn test_encoding() {
+        // The block number takes 4, 12 or 20 bits
+        let cases: [(u32, bool, u8, &[u8]); 5] = [
+            (0, false, 0, &[]),
+            (1, true, 6, &[0x1E]),
+            (16, false, 2, &[0x01, 0x02]),
+            (4096, true, 4, &[0x01, 0x00, 0x0C]),
+            (BlockValue::MAX_NUM, false, 6, &[0xFF, 0xFF, 0xF6]),
+        ];
+        for (num, more, size_exponent, bytes) in cases {
+            let value = BlockValue {
+                num,
+                more,
+                size_exponent,
+            };
+            assert_eq!(bytes, &value.to_bytes()[..]);
+            assert_eq!(Ok(value), BlockValue::from_bytes(bytes));
+        }
+        assert_eq!(1024, BlockValue::from_bytes(&[0x1E]).unwrap().size());
+
+        assert_eq!(
+            Err(MessageError::InvalidBlockOption),
+            BlockValue::from_bytes(&[0x00, 0x00, 0x00, 0x06])
+        );
+        assert_eq!(
+            Err(MessageError::InvalidBlockOption),
+            BlockValue::from_bytes(&[0x07])
+        );
+        assert!(BlockValue::try_from(vec![0x0F]).is_err());
+        assert!(BlockValue::new(0, false, 2048).is_err());
+        assert!(BlockValue::new(0x10_0000, false, 16).is_err());
+    }
 }
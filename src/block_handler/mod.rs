@@ -15,15 +15,24 @@ use core::time::Duration;
 
 use lru_time_cache::LruCache;
 
+mod assembler;
 mod block_value;
 
 use crate::error::HandlingError;
-use crate::{CoapOption, CoapRequest, MessageClass, Packet, ResponseType};
+use crate::transmission::EXCHANGE_LIFETIME;
+use crate::{
+    CoapOption, CoapRequest, MessageClass, Packet, ProtocolLimits,
+    RequestType, ResponseType,
+};
+pub use assembler::{
+    ack_block1, Block1Assembler, Block1AssemblerFixed, Block1Buffer,
+    Block1Outcome, BoundedVec, FixedBuffer,
+};
 pub use block_value::BlockValue;
 
-/// The maximum amount adding a block1 & block2 option to the message could add
-/// to the total size.
-const BLOCK_OPTIONS_MAX_LENGTH: usize = 12;
+/// The maximum amount adding a block1, block2 & size2 option to the message
+/// could add to the total size.
+const BLOCK_OPTIONS_MAX_LENGTH: usize = 17;
 
 /// Maximum amount we're willing to extend a client cached payload without the
 /// client committing to having to send us the bytes.  This prevents a common
@@ -44,6 +53,10 @@ const DEFAULT_MAX_TOTAL_MESSAGE_SIZE: usize = 1152;
 pub struct BlockHandler<Endpoint: Ord + Clone> {
     config: BlockHandlerConfig,
 
+    /// The range of block sizes to offer, which is further narrowed down by
+    /// `max_total_message_size` and the limit's own `max_message_size`.
+    limits: ProtocolLimits,
+
     /// Maintains a block1 and 2 cache for requests that we expect a client to
     /// soon follow-up and ask about.  If this recency requirement is not
     /// meant, the system will still work however consistency of results will
@@ -66,7 +79,8 @@ pub struct BlockHandlerConfig {
     pub max_total_message_size: usize,
 
     /// Length of time without interaction for cached responses to live (bumped
-    /// each time the client requests some portion of the response).
+    /// each time the client requests some portion of the response).  The
+    /// same applies to partially received requests.
     pub cache_expiry_duration: Duration,
 }
 
@@ -74,7 +88,7 @@ impl Default for BlockHandlerConfig {
     fn default() -> Self {
         Self {
             max_total_message_size: DEFAULT_MAX_TOTAL_MESSAGE_SIZE,
-            cache_expiry_duration: Duration::from_secs(120),
+            cache_expiry_duration: EXCHANGE_LIFETIME,
         }
     }
 }
@@ -88,9 +102,22 @@ impl<Endpoint: Ord + Clone> BlockHandler<Endpoint> {
                 config.cache_expiry_duration,
             ),
             config,
+            limits: ProtocolLimits::RFC,
         }
     }
 
+    /// Sets the limits the block sizes offered to the peer are kept within,
+    /// [`ProtocolLimits::RFC`] by default.
+    ///
+    /// Blocks smaller than `min_block_size` that the peer asks for are
+    /// answered with 4.00 (Bad Request).  Blocks are never larger than what
+    /// fits into a message though, even when that is less than
+    /// `min_block_size`.
+    pub fn with_limits(mut self, limits: ProtocolLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
     /// Intercepts request before application processing has occurred.
     ///
     /// Returns true if the request requires Block1/2 handling and no further
@@ -100,14 +127,37 @@ impl<Endpoint: Ord + Clone> BlockHandler<Endpoint> {
     pub fn intercept_request(
         &mut self,
         request: &mut CoapRequest<Endpoint>,
+    ) -> Result<bool, HandlingError> {
+        self.intercept_request_internal(request, None)
+    }
+
+    /// Like [`BlockHandler::intercept_request`], but also records `now` as
+    /// the time of the last activity of the transfer, which is what
+    /// [`BlockHandler::purge_expired`] goes by.
+    pub fn intercept_request_at(
+        &mut self,
+        request: &mut CoapRequest<Endpoint>,
+        now: Duration,
+    ) -> Result<bool, HandlingError> {
+        self.intercept_request_internal(request, Some(now))
+    }
+
+    fn intercept_request_internal(
+        &mut self,
+        request: &mut CoapRequest<Endpoint>,
+        now: Option<Duration>,
     ) -> Result<bool, HandlingError> {
         let state = self
             .states
             .entry(request.deref().into())
             .or_insert(BlockState::default());
+        if now.is_some() {
+            state.last_activity = now;
+        }
         let block1_handled = Self::maybe_handle_request_block1(
             request,
             self.config.max_total_message_size,
+            &self.limits,
             state,
         )?;
         if block1_handled {
@@ -126,6 +176,7 @@ impl<Endpoint: Ord + Clone> BlockHandler<Endpoint> {
     fn maybe_handle_request_block1(
         request: &mut CoapRequest<Endpoint>,
         max_total_message_size: usize,
+        limits: &ProtocolLimits,
         state: &mut BlockState,
     ) -> Result<bool, HandlingError> {
         let request_block1 = request
@@ -137,10 +188,30 @@ impl<Endpoint: Ord + Clone> BlockHandler<Endpoint> {
             Self::compute_message_size_hack(&mut request.message),
             request.message.payload.len(),
             max_total_message_size,
+            limits,
         )?;
 
         match (request_block1, maybe_response_block1) {
-            (Some(request_block1), Some(response_block1)) => {
+            (Some(request_block1), Some(_))
+                if request_block1.num > 0
+                    && state.cached_request_payload.is_none() =>
+            {
+                // The earlier blocks are gone, most likely because the
+                // transfer expired, so the client has to start over
+                let response = request
+                    .response
+                    .as_mut()
+                    .ok_or_else(HandlingError::not_handled)?;
+                response.message.header.code = MessageClass::Response(
+                    ResponseType::RequestEntityIncomplete,
+                );
+                response.message.payload.clear();
+                Ok(true)
+            }
+            (Some(request_block1), Some(mut response_block1)) => {
+                // The M bit of the echo asks for more blocks, rather than
+                // telling whether the response has more
+                response_block1.more = request_block1.more;
                 if state.cached_request_payload.is_none() {
                     state.cached_request_payload = Some(Vec::new());
                 }
@@ -148,7 +219,7 @@ impl<Endpoint: Ord + Clone> BlockHandler<Endpoint> {
                     state.cached_request_payload.as_mut().unwrap();
 
                 let payload_offset =
-                    usize::from(request_block1.num) * request_block1.size();
+                    request_block1.num as usize * request_block1.size();
                 extending_splice(
                     cached_payload,
                     payload_offset..payload_offset + request_block1.size(),
@@ -246,7 +317,7 @@ impl<Endpoint: Ord + Clone> BlockHandler<Endpoint> {
         let request_block_size = request_block2.size();
         let mut chunks = cached_payload
             .chunks(request_block_size)
-            .skip(usize::from(request_block2.num));
+            .skip(request_block2.num as usize);
 
         let cached_payload_chunk = chunks.next().ok_or_else(|| {
             HandlingError::bad_request(format!(
@@ -271,6 +342,14 @@ impl<Endpoint: Ord + Clone> BlockHandler<Endpoint> {
             [response_block2].into(),
         );
 
+        // Only the first block needs to announce the total size
+        match u32::try_from(cached_payload.len()) {
+            Ok(total) if request_block2.num == 0 => {
+                response.message.advertise_response_size(total)
+            }
+            _ => response.message.clear_option(CoapOption::Size2),
+        }
+
         Ok(has_more_chunks)
     }
 
@@ -297,11 +376,32 @@ impl<Endpoint: Ord + Clone> BlockHandler<Endpoint> {
     pub fn intercept_response(
         &mut self,
         request: &mut CoapRequest<Endpoint>,
+    ) -> Result<bool, HandlingError> {
+        self.intercept_response_internal(request, None)
+    }
+
+    /// Like [`BlockHandler::intercept_response`], but also records `now` as
+    /// the time of the last activity of the transfer.
+    pub fn intercept_response_at(
+        &mut self,
+        request: &mut CoapRequest<Endpoint>,
+        now: Duration,
+    ) -> Result<bool, HandlingError> {
+        self.intercept_response_internal(request, Some(now))
+    }
+
+    fn intercept_response_internal(
+        &mut self,
+        request: &mut CoapRequest<Endpoint>,
+        now: Option<Duration>,
     ) -> Result<bool, HandlingError> {
         let state = self
             .states
             .entry(request.deref().into())
             .or_insert(BlockState::default());
+        if now.is_some() {
+            state.last_activity = now;
+        }
         if let Some(ref mut response) = request.response {
             // Don't do anything if the caller appears to be trying to
             // implement this manually.
@@ -312,6 +412,7 @@ impl<Endpoint: Ord + Clone> BlockHandler<Endpoint> {
                         Self::compute_message_size_hack(&mut response.message),
                         response.message.payload.len(),
                         self.config.max_total_message_size,
+                        &self.limits,
                     )?
                 {
                     let cached_response = response.message.clone();
@@ -331,6 +432,42 @@ impl<Endpoint: Ord + Clone> BlockHandler<Endpoint> {
         Ok(false)
     }
 
+    /// Abandons any block transfer in progress for the request, returning
+    /// whether there was one.
+    pub fn cancel(&mut self, request: &CoapRequest<Endpoint>) -> bool {
+        self.states
+            .remove(&RequestCacheKey::from(request))
+            .is_some()
+    }
+
+    /// Drops the state of all transfers whose last activity recorded with
+    /// [`BlockHandler::intercept_request_at`] or
+    /// [`BlockHandler::intercept_response_at`] is at least
+    /// `cache_expiry_duration` ago, returning how many were dropped.
+    ///
+    /// This is meant to be called periodically by the host.  Transfers
+    /// handled without a timestamp are only expired by the cache itself.
+    pub fn purge_expired(&mut self, now: Duration) -> usize {
+        let expiry = self.config.cache_expiry_duration;
+        let expired: Vec<_> = self
+            .states
+            .peek_iter()
+            .filter(|(_, state)| {
+                matches!(
+                    state.last_activity,
+                    Some(last_activity)
+                        if now.saturating_sub(last_activity) >= expiry
+                )
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in expired.iter() {
+            self.states.remove(key);
+        }
+        expired.len()
+    }
+
     /// Hack to work around the lack of an API to compute the size of a message
     /// before producing it.
     fn compute_message_size_hack(packet: &mut Packet) -> usize {
@@ -349,9 +486,12 @@ impl<Endpoint: Ord + Clone> BlockHandler<Endpoint> {
         message_size: usize,
         total_payload_size: usize,
         max_total_message_size: usize,
+        limits: &ProtocolLimits,
     ) -> Result<Option<BlockValue>, HandlingError> {
         let max_non_payload_size =
             (message_size + BLOCK_OPTIONS_MAX_LENGTH) - total_payload_size;
+        let max_total_message_size =
+            max_total_message_size.min(limits.max_message_size);
         let max_block_size = max_total_message_size
             .checked_sub(max_non_payload_size)
             .ok_or_else(|| {
@@ -359,9 +499,21 @@ impl<Endpoint: Ord + Clone> BlockHandler<Endpoint> {
             "Message too large to encode at any block size: {} exceeds {}",
             max_total_message_size,
             max_non_payload_size))
-            })?;
+            })?
+            // The configured minimum yields to what fits into a message
+            .min(limits.max_block_size.max(limits.min_block_size));
 
         let maybe_response_block = match request_block {
+            Some(request_block)
+                if request_block.size()
+                    < limits.min_block_size.min(max_block_size) =>
+            {
+                return Err(HandlingError::bad_request(format!(
+                    "Block size {} is below the minimum of {}",
+                    request_block.size(),
+                    limits.min_block_size
+                )));
+            }
             Some(request_block) => {
                 // Client requested block encoding so let's give them that, but
                 // not larger than our max block size.
@@ -369,7 +521,7 @@ impl<Endpoint: Ord + Clone> BlockHandler<Endpoint> {
                     min(request_block.size(), max_block_size);
 
                 let reply_start_offset =
-                    usize::from(request_block.num) * request_block.size();
+                    request_block.num as usize * request_block.size();
                 let reply_end_offset =
                     reply_start_offset + negotiated_block_size;
 
@@ -444,6 +596,10 @@ pub struct RequestCacheKey<Endpoint: Ord + Clone> {
     /// Request type as an integer to make it easy to derive Ord.
     request_type_ord: u8,
     path: Vec<String>,
+    /// The body of a FETCH request, which selects what is being fetched (RFC
+    /// 8132 section 2).  Left empty while the body itself is transferred in
+    /// blocks, since each block carries a different part of it.
+    fetch_payload: Vec<u8>,
     requester: Option<Endpoint>,
 }
 
@@ -451,11 +607,21 @@ impl<Endpoint: Ord + Clone> From<&CoapRequest<Endpoint>>
     for RequestCacheKey<Endpoint>
 {
     fn from(request: &CoapRequest<Endpoint>) -> Self {
+        let body_in_blocks = matches!(
+            request.message.get_option(CoapOption::Block1),
+            Some(values) if !values.is_empty()
+        );
         Self {
             request_type_ord: u8::from(MessageClass::Request(
                 *request.get_method(),
             )),
             path: request.get_path_as_vec().unwrap_or_default(),
+            fetch_payload: match request.get_method() {
+                RequestType::Fetch if !body_in_blocks => {
+                    request.message.payload.clone()
+                }
+                _ => Vec::new(),
+            },
             requester: request.source.clone(),
         }
     }
@@ -481,6 +647,10 @@ pub struct BlockState {
     /// packet is the one containing the interesting options we will need to
     /// handle the request and that we simply need to copy the payload into it.
     cached_request_payload: Option<Vec<u8>>,
+
+    /// Time of the last request or response of the transfer, if it is
+    /// handled with timestamps.
+    last_activity: Option<Duration>,
 }
 
 #[cfg(test)]
@@ -531,12 +701,19 @@ mod tests {
             let block_size = received_block.size();
             let block_num = received_block.num;
 
+            let size2 = received_response.message.get_size2();
+            if block_num == 0 {
+                assert_eq!(Some(Ok(expected_payload.len() as u32)), size2);
+            } else {
+                assert_eq!(None, size2);
+            }
+
             if !received_block.more {
                 break block_num;
             }
 
             let sent_block = BlockValue::new(
-                usize::from(block_num + 1),
+                block_num as usize + 1,
                 false, /* more */
                 block_size,
             )
@@ -616,6 +793,85 @@ mod tests {
         assert!(received_block.more);
     }
 
+    #[test]
+    fn test_block_size_limits() {
+        let mut harness = TestServerHarness {
+            handler: BlockHandler::new(BlockHandlerConfig::default())
+                .with_limits(ProtocolLimits {
+                    max_block_size: 16,
+                    ..ProtocolLimits::RFC
+                }),
+        };
+
+        let payload = [0u8; 64];
+        let mut sent_request = create_put_request("test", 1, &payload, None);
+        let received_response = harness
+            .exchange_messages_using_cache(&mut sent_request)
+            .unwrap();
+        assert_eq!(
+            received_response.message.header.code,
+            MessageClass::Response(ResponseType::RequestEntityTooLarge)
+        );
+        let received_block = received_response
+            .message
+            .get_first_option_as::<BlockValue>(CoapOption::Block1)
+            .unwrap()
+            .unwrap();
+        assert_eq!(16, received_block.size());
+    }
+
+    #[test]
+    fn test_min_block_size_exceeds_max_message_size() {
+        let mut harness = TestServerHarness {
+            handler: BlockHandler::new(BlockHandlerConfig::default())
+                .with_limits(ProtocolLimits {
+                    max_message_size: 64,
+                    min_block_size: 256,
+                    ..ProtocolLimits::RFC
+                }),
+        };
+
+        let payload = [0u8; 512];
+        let mut sent_request = create_put_request("test", 1, &payload, None);
+        let received_response = harness
+            .exchange_messages_using_cache(&mut sent_request)
+            .unwrap();
+        let received_block = received_response
+            .message
+            .get_first_option_as::<BlockValue>(CoapOption::Block1)
+            .unwrap()
+            .unwrap();
+        assert_eq!(32, received_block.size());
+
+        let block_request = create_put_request(
+            "test",
+            2,
+            &payload[..received_block.size()],
+            Some(BlockValue::new(0, true, received_block.size()).unwrap()),
+        );
+        assert!(block_request.message.to_bytes().unwrap().len() <= 64);
+    }
+
+    #[test]
+    fn test_block_size_below_min() {
+        let mut handler = BlockHandler::new(BlockHandlerConfig::default())
+            .with_limits(ProtocolLimits {
+                min_block_size: 64,
+                ..ProtocolLimits::RFC
+            });
+
+        let block = BlockValue::new(0, true, 16).unwrap();
+        let mut sent_request =
+            create_put_request("test", 1, &[0u8; 16], Some(block));
+        let error = handler.intercept_request(&mut sent_request).unwrap_err();
+        assert_eq!(Some(ResponseType::BadRequest), error.code);
+
+        let block = BlockValue::new(0, true, 64).unwrap();
+        let mut sent_request =
+            create_put_request("test", 2, &[0u8; 64], Some(block));
+        assert!(handler.intercept_request(&mut sent_request).unwrap());
+    }
+
     #[test]
     fn test_cached_request_happy_path() {
         let block = "0123456789\n";
@@ -680,9 +936,80 @@ mod tests {
 
             // This test isn't expecting renegotiation...
             assert_eq!(received_block.size(), block_size);
+            assert_eq!(received_block.more, has_more_chunks);
         }
     }
 
+    #[test]
+    fn test_expired_request_transfer() {
+        let mut handler = BlockHandler::new(BlockHandlerConfig {
+            max_total_message_size: 32,
+            ..Default::default()
+        });
+        let chunk = [0u8; 16];
+        let put = |handler: &mut BlockHandler<_>, num, now| {
+            let block = BlockValue::new(num, true, chunk.len()).unwrap();
+            let mut request =
+                create_put_request("test", 1, &chunk, Some(block));
+            assert!(handler.intercept_request_at(&mut request, now).unwrap());
+            request.response.unwrap().message.header.code
+        };
+        let start = Duration::from_secs(1000);
+
+        assert_eq!(
+            MessageClass::Response(ResponseType::Continue),
+            put(&mut handler, 0, start)
+        );
+        assert_eq!(0, handler.purge_expired(start + Duration::from_secs(60)));
+        assert_eq!(1, handler.purge_expired(start + EXCHANGE_LIFETIME));
+        assert_eq!(0, handler.purge_expired(start + EXCHANGE_LIFETIME));
+
+        // Resuming isn't possible anymore, but starting over is
+        let now = start + EXCHANGE_LIFETIME;
+        assert_eq!(
+            MessageClass::Response(ResponseType::RequestEntityIncomplete),
+            put(&mut handler, 1, now)
+        );
+        assert_eq!(
+            MessageClass::Response(ResponseType::Continue),
+            put(&mut handler, 0, now)
+        );
+        assert_eq!(
+            MessageClass::Response(ResponseType::Continue),
+            put(&mut handler, 1, now)
+        );
+
+        let request = create_put_request("test", 1, &chunk, None);
+        assert!(handler.cancel(&request));
+        assert!(!handler.cancel(&request));
+        assert_eq!(
+            MessageClass::Response(ResponseType::RequestEntityIncomplete),
+            put(&mut handler, 2, now)
+        );
+    }
+
+    #[test]
+    fn test_fetch_cache_key_includes_body() {
+        let fetch = |payload: &[u8], block1| {
+            let mut request =
+                create_request(RequestType::Fetch, "test", 1, block1, None);
+            request.message.payload.extend(payload);
+            RequestCacheKey::from(&request)
+        };
+
+        assert!(fetch(b"a", None) == fetch(b"a", None));
+        assert!(fetch(b"a", None) != fetch(b"b", None));
+
+        // The body only arrives in parts while it is sent in blocks
+        let block1 = || Some(BlockValue::new(0, true, 16).unwrap());
+        assert!(fetch(b"a", block1()) == fetch(b"b", block1()));
+
+        let mut get = create_get_request("test", 1, None);
+        let key = RequestCacheKey::from(&get);
+        get.message.payload.extend(b"ignored");
+        assert!(key == RequestCacheKey::from(&get));
+    }
+
     struct TestServerHarness {
         handler: BlockHandler<TestEndpoint>,
     }
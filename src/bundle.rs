@@ -0,0 +1,175 @@
+//! Bundling of several messages into a single datagram, for high-rate
+//! telemetry to a cooperating collector.
+//!
+//! **This is not a CoAP standard.**  Both ends have to agree to use it, a
+//! regular CoAP endpoint can't decode a bundle.  Each message is preceded by
+//! its length as 2 bytes in network byte order, and messages follow each
+//! other without padding:
+//!
+//! ```text
+//! +--------+-----------+--------+-----------+----
+//! | length | message 0 | length | message 1 | ...
+//! +--------+-----------+--------+-----------+----
+//! ```
+//!
+//! This is only available with the `bundle` feature.
+
+use alloc::vec::Vec;
+
+use crate::{error::BundleError, Packet};
+
+/// The size of the length prefix of each message.
+const PREFIX_LENGTH: usize = 2;
+
+/// Encodes the packets into as few datagrams of at most `limit` bytes as
+/// possible, keeping their order.
+///
+/// Fails if a packet can't be encoded or doesn't fit into a datagram on its
+/// own.
+pub fn encode(
+    packets: &[Packet],
+    limit: usize,
+) -> Result<Vec<Vec<u8>>, BundleError> {
+    let mut datagrams: Vec<Vec<u8>> = Vec::new();
+    for (index, packet) in packets.iter().enumerate() {
+        let bytes = packet
+            .to_bytes()
+            .map_err(|error| BundleError::Message { index, error })?;
+        let length = u16::try_from(bytes.len())
+            .ok()
+            .filter(|_| PREFIX_LENGTH + bytes.len() <= limit)
+            .ok_or(BundleError::MessageTooLarge { index })?;
+
+        let datagram = match datagrams.last_mut() {
+            Some(datagram)
+                if datagram.len() + PREFIX_LENGTH + bytes.len() <= limit =>
+            {
+                datagram
+            }
+            _ => {
+                datagrams.push(Vec::new());
+                datagrams.last_mut().unwrap()
+            }
+        };
+        datagram.extend_from_slice(&length.to_be_bytes());
+        datagram.extend_from_slice(&bytes);
+    }
+    Ok(datagrams)
+}
+
+/// Decodes the packets of a datagram created by [`encode`].
+pub fn decode(buf: &[u8]) -> Result<Vec<Packet>, BundleError> {
+    let mut packets = Vec::new();
+    let mut rest = buf;
+    while !rest.is_empty() {
+        let index = packets.len();
+        let invalid = BundleError::InvalidLengthPrefix { index };
+        let (prefix, tail) = match rest {
+            [a, b, tail @ ..] => ([*a, *b], tail),
+            _ => return Err(invalid),
+        };
+        let length = usize::from(u16::from_be_bytes(prefix));
+        if length > tail.len() {
+            return Err(invalid);
+        }
+
+        let (message, tail) = tail.split_at(length);
+        let packet = Packet::from_bytes(message)
+            .map_err(|error| BundleError::Message { index, error })?;
+        packets.push(packet);
+        rest = tail;
+    }
+    Ok(packets)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{error::MessageError, MessageType, RequestType};
+
+    fn telemetry(value: u8) -> Packet {
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::NonConfirmable);
+        packet.header.code = crate::MessageClass::Request(RequestType::Post);
+        packet.header.message_id = u16::from(value);
+        packet.payload = vec![value; 6];
+        packet
+    }
+
+    #[test]
+    fn exact_fit() {
+        let packets: Vec<Packet> = (0..5).map(telemetry).collect();
+        // Each message takes 4 + 1 + 6 bytes plus the prefix
+        let datagrams = encode(&packets, 2 * 13).unwrap();
+        assert_eq!(
+            vec![26, 26, 13],
+            datagrams.iter().map(Vec::len).collect::<Vec<_>>()
+        );
+
+        let decoded: Vec<Packet> = datagrams
+            .iter()
+            .flat_map(|datagram| decode(datagram).unwrap())
+            .collect();
+        assert_eq!(packets, decoded);
+
+        assert_eq!(1, encode(&packets, 5 * 13).unwrap().len());
+        let datagrams = encode(&packets, usize::MAX).unwrap();
+        assert_eq!(
+            vec![5 * 13],
+            datagrams.iter().map(Vec::len).collect::<Vec<_>>()
+        );
+        assert_eq!(Ok(vec![]), decode(&[]));
+    }
+
+    #[test]
+    fn oversized_message() {
+        let mut packets: Vec<Packet> = (0..3).map(telemetry).collect();
+        packets[1].payload = vec![0; 20];
+        assert_eq!(
+            Err(BundleError::MessageTooLarge { index: 1 }),
+            encode(&packets, 26)
+        );
+
+        packets[1].payload = vec![0; 1300];
+        assert_eq!(
+            Err(BundleError::Message {
+                index: 1,
+                error: MessageError::InvalidPacketLength
+            }),
+            encode(&packets, 2000)
+        );
+    }
+
+    #[test]
+    fn corrupt_length_prefix() {
+        let packets: Vec<Packet> = (0..3).map(telemetry).collect();
+        let mut datagram = encode(&packets, 100).unwrap().remove(0);
+        assert_eq!(3 * 13, datagram.len());
+
+        // Claiming more bytes than there are
+        datagram[13] = 0x01;
+        assert_eq!(
+            Err(BundleError::InvalidLengthPrefix { index: 1 }),
+            decode(&datagram)
+        );
+
+        // Claiming fewer bytes makes the rest a malformed message
+        datagram[13] = 0x00;
+        datagram[14] = 0x03;
+        assert_eq!(
+            Err(BundleError::Message {
+                index: 1,
+                error: MessageError::InvalidHeader
+            }),
+            decode(&datagram)
+        );
+
+        // Truncated prefix
+        let mut datagram = encode(&packets[..1], 100).unwrap().remove(0);
+        datagram.push(0x00);
+        assert_eq!(
+            Err(BundleError::InvalidLengthPrefix { index: 1 }),
+            decode(&datagram)
+        );
+    }
+}
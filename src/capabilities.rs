@@ -0,0 +1,215 @@
+//! Reporting which optional features and protocol extensions a build of
+//! this crate supports.
+
+use core::fmt;
+
+/// The optional features and protocol extensions a build of this crate
+/// supports, see [`capabilities`].
+///
+/// Works like a set of flags: the associated constants can be combined with
+/// [`Capabilities::union`] and tested with [`Capabilities::contains`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities {
+    bits: u32,
+}
+
+impl Capabilities {
+    /// The `std` feature: `std::error::Error` implementations and the
+    /// helpers for IP addresses.
+    pub const STD: Capabilities = Capabilities::from_bits(1 << 0);
+    /// The `log` feature: diagnostics through the `log` crate.
+    pub const LOG: Capabilities = Capabilities::from_bits(1 << 1);
+    /// The `json` feature: typed JSON payloads.
+    pub const JSON: Capabilities = Capabilities::from_bits(1 << 2);
+    /// The `cbor` feature: typed CBOR payloads.
+    pub const CBOR: Capabilities = Capabilities::from_bits(1 << 3);
+    /// The `with-coap-message` feature: the `coap-message` traits.
+    pub const COAP_MESSAGE: Capabilities = Capabilities::from_bits(1 << 4);
+    /// The `bundle` feature: several messages in one datagram.
+    pub const BUNDLE: Capabilities = Capabilities::from_bits(1 << 5);
+    /// The `strict-invariants` feature: broken invariants are errors in
+    /// release builds too.
+    pub const STRICT_INVARIANTS: Capabilities =
+        Capabilities::from_bits(1 << 6);
+    /// The `testing` feature: builders for malformed messages.
+    pub const TESTING: Capabilities = Capabilities::from_bits(1 << 7);
+    /// Message framing for reliable transports (RFC 8323), always
+    /// available.
+    pub const TCP_FRAMING: Capabilities = Capabilities::from_bits(1 << 16);
+    /// Block-wise transfers (RFC 7959), always available.
+    pub const BLOCK_WISE: Capabilities = Capabilities::from_bits(1 << 17);
+    /// Observing resources (RFC 7641), always available.
+    pub const OBSERVE: Capabilities = Capabilities::from_bits(1 << 18);
+    /// Tokens longer than 8 bytes (RFC 8974), always available once
+    /// `ProtocolLimits::max_token_length` is raised.
+    pub const EXTENDED_TOKENS: Capabilities = Capabilities::from_bits(1 << 19);
+
+    const NAMES: [(Capabilities, &'static str); 12] = [
+        (Capabilities::STD, "std"),
+        (Capabilities::LOG, "log"),
+        (Capabilities::JSON, "json"),
+        (Capabilities::CBOR, "cbor"),
+        (Capabilities::COAP_MESSAGE, "with-coap-message"),
+        (Capabilities::BUNDLE, "bundle"),
+        (Capabilities::STRICT_INVARIANTS, "strict-invariants"),
+        (Capabilities::TESTING, "testing"),
+        (Capabilities::TCP_FRAMING, "tcp-framing"),
+        (Capabilities::BLOCK_WISE, "block-wise"),
+        (Capabilities::OBSERVE, "observe"),
+        (Capabilities::EXTENDED_TOKENS, "extended-tokens"),
+    ];
+
+    const fn from_bits(bits: u32) -> Capabilities {
+        Capabilities { bits }
+    }
+
+    /// Returns the capabilities as bits, one per associated constant.
+    pub const fn bits(&self) -> u32 {
+        self.bits
+    }
+
+    /// Returns whether all of `other` are supported.
+    pub const fn contains(&self, other: Capabilities) -> bool {
+        self.bits & other.bits == other.bits
+    }
+
+    /// Returns the capabilities of both.
+    pub const fn union(self, other: Capabilities) -> Capabilities {
+        Capabilities::from_bits(self.bits | other.bits)
+    }
+
+    const fn with_if(
+        self,
+        other: Capabilities,
+        enabled: bool,
+    ) -> Capabilities {
+        if enabled {
+            self.union(other)
+        } else {
+            self
+        }
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut names = Capabilities::NAMES
+            .iter()
+            .filter(|(capability, _)| self.contains(*capability))
+            .map(|(_, name)| name);
+        if let Some(first) = names.next() {
+            write!(f, "{}", first)?;
+        }
+        for name in names {
+            write!(f, ", {}", name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns what this build of the crate supports, for frameworks that adapt
+/// to it at runtime rather than mirroring the cargo features.
+///
+/// # Examples
+///
+/// ```
+/// use coap_lite::{capabilities, Capabilities};
+///
+/// // A server framework only offers typed payloads the build supports
+/// let caps = capabilities();
+/// let mut formats = vec!["text/plain"];
+/// if caps.contains(Capabilities::JSON) {
+///     formats.push("application/json");
+/// }
+/// if caps.contains(Capabilities::CBOR) {
+///     formats.push("application/cbor");
+/// }
+/// println!("coap-lite capabilities: {}", caps);
+/// ```
+pub const fn capabilities() -> Capabilities {
+    Capabilities::TCP_FRAMING
+        .union(Capabilities::BLOCK_WISE)
+        .union(Capabilities::OBSERVE)
+        .union(Capabilities::EXTENDED_TOKENS)
+        .with_if(Capabilities::STD, cfg!(feature = "std"))
+        .with_if(Capabilities::LOG, cfg!(feature = "log"))
+        .with_if(Capabilities::JSON, cfg!(feature = "json"))
+        .with_if(Capabilities::CBOR, cfg!(feature = "cbor"))
+        .with_if(
+            Capabilities::COAP_MESSAGE,
+            cfg!(feature = "with-coap-message"),
+        )
+        .with_if(Capabilities::BUNDLE, cfg!(feature = "bundle"))
+        .with_if(
+            Capabilities::STRICT_INVARIANTS,
+            cfg!(feature = "strict-invariants"),
+        )
+        .with_if(Capabilities::TESTING, cfg!(feature = "testing"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn features() {
+        let caps = capabilities();
+        #[cfg(feature = "std")]
+        assert!(caps.contains(Capabilities::STD));
+        #[cfg(not(feature = "std"))]
+        assert!(!caps.contains(Capabilities::STD));
+        #[cfg(feature = "log")]
+        assert!(caps.contains(Capabilities::LOG));
+        #[cfg(not(feature = "log"))]
+        assert!(!caps.contains(Capabilities::LOG));
+        #[cfg(feature = "json")]
+        assert!(caps.contains(Capabilities::JSON));
+        #[cfg(not(feature = "json"))]
+        assert!(!caps.contains(Capabilities::JSON));
+        #[cfg(feature = "cbor")]
+        assert!(caps.contains(Capabilities::CBOR));
+        #[cfg(not(feature = "cbor"))]
+        assert!(!caps.contains(Capabilities::CBOR));
+        #[cfg(feature = "with-coap-message")]
+        assert!(caps.contains(Capabilities::COAP_MESSAGE));
+        #[cfg(not(feature = "with-coap-message"))]
+        assert!(!caps.contains(Capabilities::COAP_MESSAGE));
+        #[cfg(feature = "bundle")]
+        assert!(caps.contains(Capabilities::BUNDLE));
+        #[cfg(not(feature = "bundle"))]
+        assert!(!caps.contains(Capabilities::BUNDLE));
+        #[cfg(feature = "strict-invariants")]
+        assert!(caps.contains(Capabilities::STRICT_INVARIANTS));
+        #[cfg(not(feature = "strict-invariants"))]
+        assert!(!caps.contains(Capabilities::STRICT_INVARIANTS));
+        #[cfg(feature = "testing")]
+        assert!(caps.contains(Capabilities::TESTING));
+        #[cfg(not(feature = "testing"))]
+        assert!(!caps.contains(Capabilities::TESTING));
+        assert!(caps.contains(
+            Capabilities::TCP_FRAMING
+                .union(Capabilities::BLOCK_WISE)
+                .union(Capabilities::OBSERVE)
+                .union(Capabilities::EXTENDED_TOKENS)
+        ));
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!("", Capabilities::default().to_string());
+        assert_eq!(
+            "json, block-wise",
+            Capabilities::BLOCK_WISE
+                .union(Capabilities::JSON)
+                .to_string()
+        );
+        let all = Capabilities::NAMES
+            .iter()
+            .fold(Capabilities::default(), |all, (capability, _)| {
+                all.union(*capability)
+            });
+        assert_eq!(12, all.to_string().split(", ").count());
+        assert_eq!(0xF_00FF, all.bits());
+    }
+}
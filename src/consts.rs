@@ -0,0 +1,56 @@
+//! Well-known numbers of CoAP deployments (RFC 7252 sections 6.1, 6.2 and
+//! 12.8).
+
+#[cfg(feature = "std")]
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// The default port of the `coap` scheme.
+pub const DEFAULT_PORT: u16 = 5683;
+
+/// The default port of the `coaps` scheme.
+pub const DEFAULT_PORT_SECURE: u16 = 5684;
+
+/// The IPv4 "All CoAP Nodes" multicast address.
+#[cfg(feature = "std")]
+pub const ALL_COAP_NODES_V4: Ipv4Addr = Ipv4Addr::new(224, 0, 1, 187);
+
+/// The link-local IPv6 "All CoAP Nodes" multicast address.
+#[cfg(feature = "std")]
+pub const ALL_COAP_NODES_V6_LINK_LOCAL: Ipv6Addr =
+    Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfd);
+
+/// The site-local IPv6 "All CoAP Nodes" multicast address.
+#[cfg(feature = "std")]
+pub const ALL_COAP_NODES_V6_SITE_LOCAL: Ipv6Addr =
+    Ipv6Addr::new(0xff05, 0, 0, 0, 0, 0, 0, 0xfd);
+
+/// Returns whether `addr` is one of the "All CoAP Nodes" multicast addresses,
+/// e.g. to tell whether a request was received by multicast.
+#[cfg(feature = "std")]
+pub fn is_coap_multicast(addr: &IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(addr) => *addr == ALL_COAP_NODES_V4,
+        IpAddr::V6(addr) => {
+            *addr == ALL_COAP_NODES_V6_LINK_LOCAL
+                || *addr == ALL_COAP_NODES_V6_SITE_LOCAL
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn coap_multicast() {
+        for group in
+            ["224.0.1.187", "ff02::fd", "ff05::fd", "::ffff:224.0.1.187"]
+        {
+            let addr: IpAddr = group.parse().unwrap();
+            assert_eq!(!group.starts_with("::"), is_coap_multicast(&addr));
+        }
+        for other in ["192.0.2.1", "224.0.0.251", "ff02::1", "ff08::fd"] {
+            assert!(!is_coap_multicast(&other.parse().unwrap()));
+        }
+    }
+}
@@ -0,0 +1,587 @@
+//! Media type information about content formats, extensible with formats
+//! that are defined by the application.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::{option_value::OptionValueU16, CoapOption, ContentFormat, Packet};
+
+/// The serialization a content format is based on, which decides the payload
+/// helpers that can be used with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadEncoding {
+    Json,
+    Cbor,
+    Other,
+}
+
+/// How to handle requests with several Accept options, which isn't
+/// repeatable.  Some stacks following pre-standard drafts send one for each
+/// format they support.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MultiAcceptPolicy {
+    /// Treat the repeats like unrecognized options, as RFC 7252 requires,
+    /// so no format is acceptable.
+    #[default]
+    Reject,
+    /// Choose the first of the accepted formats that is available.
+    FirstSupported,
+    /// Only consider the last Accept option.
+    LastWins,
+}
+
+/// The description of a content format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentFormatEntry {
+    /// The content format number, as used in the Content-Format and Accept
+    /// options.
+    pub number: u16,
+    /// The media type including any parameters, e.g. `application/json`.
+    pub media_type: &'static str,
+    pub encoding: PayloadEncoding,
+}
+
+impl ContentFormatEntry {
+    /// Creates a new entry, usable in constant tables.
+    pub const fn new(
+        number: u16,
+        media_type: &'static str,
+        encoding: PayloadEncoding,
+    ) -> ContentFormatEntry {
+        ContentFormatEntry {
+            number,
+            media_type,
+            encoding,
+        }
+    }
+}
+
+/// The content formats known to this crate, matching [`ContentFormat`].
+pub const BUILTIN_CONTENT_FORMATS: &[ContentFormatEntry] = &[
+    ContentFormatEntry::new(
+        0,
+        "text/plain;charset=utf-8",
+        PayloadEncoding::Other,
+    ),
+    ContentFormatEntry::new(
+        16,
+        "application/cose; cose-type=\"cose-encrypt0\"",
+        PayloadEncoding::Cbor,
+    ),
+    ContentFormatEntry::new(
+        17,
+        "application/cose; cose-type=\"cose-mac0\"",
+        PayloadEncoding::Cbor,
+    ),
+    ContentFormatEntry::new(
+        18,
+        "application/cose; cose-type=\"cose-sign1\"",
+        PayloadEncoding::Cbor,
+    ),
+    ContentFormatEntry::new(19, "application/ace+cbor", PayloadEncoding::Cbor),
+    ContentFormatEntry::new(
+        40,
+        "application/link-format",
+        PayloadEncoding::Other,
+    ),
+    ContentFormatEntry::new(41, "application/xml", PayloadEncoding::Other),
+    ContentFormatEntry::new(
+        42,
+        "application/octet-stream",
+        PayloadEncoding::Other,
+    ),
+    ContentFormatEntry::new(47, "application/exi", PayloadEncoding::Other),
+    ContentFormatEntry::new(50, "application/json", PayloadEncoding::Json),
+    ContentFormatEntry::new(
+        51,
+        "application/json-patch+json",
+        PayloadEncoding::Json,
+    ),
+    ContentFormatEntry::new(
+        52,
+        "application/merge-patch+json",
+        PayloadEncoding::Json,
+    ),
+    ContentFormatEntry::new(60, "application/cbor", PayloadEncoding::Cbor),
+    ContentFormatEntry::new(61, "application/cwt", PayloadEncoding::Cbor),
+    ContentFormatEntry::new(
+        62,
+        "application/multipart-core",
+        PayloadEncoding::Cbor,
+    ),
+    ContentFormatEntry::new(
+        63,
+        "application/cbor-seq",
+        PayloadEncoding::Other,
+    ),
+    ContentFormatEntry::new(
+        96,
+        "application/cose; cose-type=\"cose-encrypt\"",
+        PayloadEncoding::Cbor,
+    ),
+    ContentFormatEntry::new(
+        97,
+        "application/cose; cose-type=\"cose-mac\"",
+        PayloadEncoding::Cbor,
+    ),
+    ContentFormatEntry::new(
+        98,
+        "application/cose; cose-type=\"cose-sign\"",
+        PayloadEncoding::Cbor,
+    ),
+    ContentFormatEntry::new(
+        101,
+        "application/cose-key",
+        PayloadEncoding::Cbor,
+    ),
+    ContentFormatEntry::new(
+        102,
+        "application/cose-key-set",
+        PayloadEncoding::Cbor,
+    ),
+    ContentFormatEntry::new(
+        110,
+        "application/senml+json",
+        PayloadEncoding::Json,
+    ),
+    ContentFormatEntry::new(
+        111,
+        "application/sensml+json",
+        PayloadEncoding::Json,
+    ),
+    ContentFormatEntry::new(
+        112,
+        "application/senml+cbor",
+        PayloadEncoding::Cbor,
+    ),
+    ContentFormatEntry::new(
+        113,
+        "application/sensml+cbor",
+        PayloadEncoding::Cbor,
+    ),
+    ContentFormatEntry::new(
+        114,
+        "application/senml-exi",
+        PayloadEncoding::Other,
+    ),
+    ContentFormatEntry::new(
+        115,
+        "application/sensml-exi",
+        PayloadEncoding::Other,
+    ),
+    ContentFormatEntry::new(
+        256,
+        "application/coap-group+json",
+        PayloadEncoding::Json,
+    ),
+    ContentFormatEntry::new(
+        310,
+        "application/senml+xml",
+        PayloadEncoding::Other,
+    ),
+    ContentFormatEntry::new(
+        311,
+        "application/sensml+xml",
+        PayloadEncoding::Other,
+    ),
+];
+
+/// A table of content formats, consisting of the built-in ones and those
+/// registered by the application, e.g. in the experimental range 65000-65535.
+///
+/// Registrations take precedence over built-in formats with the same number.
+/// Since the registrations are a borrowed slice, a registry can be a
+/// `static`:
+///
+/// ```
+/// use coap_lite::content_format::{
+///     ContentFormatEntry, ContentFormatRegistry, PayloadEncoding,
+/// };
+///
+/// static REGISTRY: ContentFormatRegistry = ContentFormatRegistry::new(&[
+///     ContentFormatEntry::new(
+///         65001,
+///         "application/vnd.example+cbor",
+///         PayloadEncoding::Cbor,
+///     ),
+/// ]);
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct ContentFormatRegistry<'a> {
+    registered: &'a [ContentFormatEntry],
+}
+
+impl Default for ContentFormatRegistry<'_> {
+    fn default() -> Self {
+        ContentFormatRegistry::new(&[])
+    }
+}
+
+impl<'a> ContentFormatRegistry<'a> {
+    /// Creates a registry with the given formats in addition to the built-in
+    /// ones.
+    pub const fn new(registered: &'a [ContentFormatEntry]) -> Self {
+        ContentFormatRegistry { registered }
+    }
+
+    /// Returns the entry of a content format number.
+    pub fn get(&self, number: u16) -> Option<&'a ContentFormatEntry> {
+        self.entries().find(|entry| entry.number == number)
+    }
+
+    /// Returns the media type of a content format number.
+    pub fn media_type(&self, number: u16) -> Option<&'static str> {
+        self.get(number).map(|entry| entry.media_type)
+    }
+
+    /// Returns the entry of a media type, ignoring ASCII case, whitespace
+    /// and quotes, so that e.g. the Content-Type header `text/plain;
+    /// charset=UTF-8` of HTTP matches as well.
+    pub fn from_media_type(
+        &self,
+        media_type: &str,
+    ) -> Option<&'a ContentFormatEntry> {
+        self.entries()
+            .find(|entry| media_types_match(entry.media_type, media_type))
+    }
+
+    /// Returns a value displaying a content format number as its media type,
+    /// or as the number if it isn't known.
+    pub fn display(&self, number: u16) -> ContentFormatDisplay<'a> {
+        ContentFormatDisplay {
+            registry: *self,
+            number,
+        }
+    }
+
+    /// Chooses the content format of the response to `request` out of the
+    /// formats in `available`, in order of preference.
+    ///
+    /// Formats that aren't known to the registry are never chosen.  If the
+    /// request has an Accept option, only that format can be chosen.  If
+    /// there is no acceptable format the server should respond with 4.06
+    /// (Not Acceptable).  Several Accept options are rejected, see
+    /// [`ContentFormatRegistry::negotiate_with_policy`] to accept them.
+    ///
+    /// The Accept option of a FETCH request applies to the response just
+    /// like that of a GET request, independent of the content format of the
+    /// request body (RFC 8132 section 2.3.1).
+    pub fn negotiate(
+        &self,
+        request: &Packet,
+        available: &[u16],
+    ) -> Option<&'a ContentFormatEntry> {
+        self.negotiate_with_policy(
+            request,
+            available,
+            MultiAcceptPolicy::Reject,
+        )
+    }
+
+    /// Like [`ContentFormatRegistry::negotiate`], but handles several
+    /// Accept options as described by `multi_accept`.
+    pub fn negotiate_with_policy(
+        &self,
+        request: &Packet,
+        available: &[u16],
+        multi_accept: MultiAcceptPolicy,
+    ) -> Option<&'a ContentFormatEntry> {
+        let accepted = request
+            .get_options_as::<OptionValueU16>(CoapOption::Accept)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|accept| accept.map(|accept| accept.0))
+            .collect::<Result<Vec<_>, _>>()
+            .ok()?;
+        let supported = |number: u16| {
+            available
+                .contains(&number)
+                .then(|| self.get(number))
+                .flatten()
+        };
+        match (accepted.as_slice(), multi_accept) {
+            ([], _) => available.iter().find_map(|&number| self.get(number)),
+            ([accept], _) => supported(*accept),
+            (_, MultiAcceptPolicy::Reject) => None,
+            (_, MultiAcceptPolicy::FirstSupported) => {
+                accepted.iter().find_map(|&accept| supported(accept))
+            }
+            (_, MultiAcceptPolicy::LastWins) => {
+                accepted.last().and_then(|&accept| supported(accept))
+            }
+        }
+    }
+
+    fn entries(&self) -> impl Iterator<Item = &'a ContentFormatEntry> {
+        self.registered.iter().chain(BUILTIN_CONTENT_FORMATS.iter())
+    }
+}
+
+/// Displays a content format number, see
+/// [`ContentFormatRegistry::display`].
+#[derive(Debug, Clone, Copy)]
+pub struct ContentFormatDisplay<'a> {
+    registry: ContentFormatRegistry<'a>,
+    number: u16,
+}
+
+impl fmt::Display for ContentFormatDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.registry.media_type(self.number) {
+            Some(media_type) => f.write_str(media_type),
+            None => write!(f, "{}", self.number),
+        }
+    }
+}
+
+// Compares media types ignoring ASCII case, whitespace and quotes.  A bare
+// text/plain, the most common Content-Type of HTTP, is taken as UTF-8 like
+// CoAP's text/plain, as its default charset US-ASCII is a subset.
+fn media_types_match(a: &str, b: &str) -> bool {
+    let significant = |media_type: &'_ str| {
+        let media_type = media_type
+            .bytes()
+            .filter(|byte| !byte.is_ascii_whitespace() && *byte != b'"')
+            .map(|byte| byte.to_ascii_lowercase())
+            .collect::<Vec<u8>>();
+        if media_type == b"text/plain" {
+            b"text/plain;charset=utf-8".to_vec()
+        } else {
+            media_type
+        }
+    };
+    significant(a) == significant(b)
+}
+
+impl ContentFormat {
+    /// Returns the content format of a MIME type, which is matched like in
+    /// [`ContentFormatRegistry::from_media_type`], e.g. to translate the
+    /// Content-Type header in an HTTP-CoAP proxy.
+    ///
+    /// ```
+    /// use coap_lite::ContentFormat;
+    ///
+    /// assert_eq!(
+    ///     Some(ContentFormat::ApplicationCoseSign1),
+    ///     ContentFormat::from_mime(
+    ///         "application/cose; cose-type=\"cose-sign1\""
+    ///     )
+    /// );
+    /// assert_eq!(None, ContentFormat::from_mime("text/html"));
+    /// ```
+    pub fn from_mime(mime: &str) -> Option<ContentFormat> {
+        ContentFormatRegistry::default()
+            .from_media_type(mime)
+            .and_then(|entry| ContentFormat::from_u16(entry.number))
+    }
+
+    /// Returns the MIME type of the content format, e.g.
+    /// `application/json`.
+    pub fn to_mime(&self) -> &'static str {
+        ContentFormatRegistry::default()
+            .media_type(self.as_u16())
+            .unwrap()
+    }
+
+    /// Returns the serialization the content format is based on.
+    pub fn encoding(&self) -> PayloadEncoding {
+        ContentFormatRegistry::default()
+            .get(self.as_u16())
+            .unwrap()
+            .encoding
+    }
+}
+
+impl fmt::Display for ContentFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.to_mime())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{MessageClass, RequestType};
+
+    static REGISTRY: ContentFormatRegistry =
+        ContentFormatRegistry::new(&[ContentFormatEntry::new(
+            65001,
+            "application/vnd.example+cbor",
+            PayloadEncoding::Cbor,
+        )]);
+
+    #[test]
+    fn builtin_formats() {
+        assert_eq!(
+            "application/json",
+            ContentFormat::ApplicationJSON.to_mime()
+        );
+        assert_eq!(
+            "application/senml+cbor",
+            format!("{}", ContentFormat::ApplicationSenmlCBOR)
+        );
+        for entry in BUILTIN_CONTENT_FORMATS {
+            let format =
+                ContentFormat::try_from(usize::from(entry.number)).unwrap();
+            assert_eq!(entry.media_type, format.to_mime());
+            assert_eq!(
+                Some(format),
+                ContentFormat::from_mime(entry.media_type)
+            );
+        }
+        assert_eq!(
+            ContentFormat::known_variants().len(),
+            BUILTIN_CONTENT_FORMATS.len()
+        );
+
+        // The numbers of the IANA CoAP Content-Formats registry
+        for (number, media_type) in [
+            (16, "application/cose; cose-type=\"cose-encrypt0\""),
+            (17, "application/cose; cose-type=\"cose-mac0\""),
+            (18, "application/cose; cose-type=\"cose-sign1\""),
+            (19, "application/ace+cbor"),
+            (51, "application/json-patch+json"),
+            (52, "application/merge-patch+json"),
+            (61, "application/cwt"),
+            (62, "application/multipart-core"),
+            (63, "application/cbor-seq"),
+            (96, "application/cose; cose-type=\"cose-encrypt\""),
+            (97, "application/cose; cose-type=\"cose-mac\""),
+            (98, "application/cose; cose-type=\"cose-sign\""),
+            (101, "application/cose-key"),
+            (102, "application/cose-key-set"),
+            (256, "application/coap-group+json"),
+        ] {
+            let format = ContentFormat::from_u16(number).unwrap();
+            assert_eq!(media_type, format.to_mime());
+        }
+
+        // HTTP spellings of the media types
+        assert_eq!(
+            Some(ContentFormat::TextPlain),
+            ContentFormat::from_mime("Text/Plain; charset=\"utf-8\"")
+        );
+        assert_eq!(
+            Some(ContentFormat::ApplicationCoseMac0),
+            ContentFormat::from_mime("application/cose;cose-type=cose-mac0")
+        );
+        assert_eq!(
+            Some(ContentFormat::TextPlain),
+            ContentFormat::from_mime("text/plain")
+        );
+        assert_eq!(
+            Some(ContentFormat::TextPlain),
+            ContentFormat::from_mime("Text/Plain ")
+        );
+        assert_eq!(
+            None,
+            ContentFormat::from_mime("text/plain;charset=latin1")
+        );
+        assert_eq!(
+            PayloadEncoding::Cbor,
+            ContentFormat::ApplicationSenmlCBOR.encoding()
+        );
+        assert_eq!(
+            PayloadEncoding::Other,
+            ContentFormat::ApplicationLinkFormat.encoding()
+        );
+    }
+
+    #[test]
+    fn registered_formats() {
+        let entry = REGISTRY.get(65001).unwrap();
+        assert_eq!(PayloadEncoding::Cbor, entry.encoding);
+        assert_eq!(
+            Some(entry),
+            REGISTRY.from_media_type("Application/Vnd.Example+CBOR")
+        );
+        assert_eq!(Some("application/cbor"), REGISTRY.media_type(60));
+        assert_eq!(None, ContentFormatRegistry::default().get(65001));
+
+        assert_eq!(
+            "application/vnd.example+cbor",
+            format!("{}", REGISTRY.display(65001))
+        );
+        assert_eq!("65002", format!("{}", REGISTRY.display(65002)));
+    }
+
+    #[test]
+    fn negotiate() {
+        let mut request = Packet::new();
+        let available = [65002, 65001, 50];
+        assert_eq!(
+            Some(65001),
+            REGISTRY
+                .negotiate(&request, &available)
+                .map(|entry| entry.number)
+        );
+        assert_eq!(
+            Some(50),
+            ContentFormatRegistry::default()
+                .negotiate(&request, &available)
+                .map(|entry| entry.number)
+        );
+
+        request.add_option_as(CoapOption::Accept, OptionValueU16(50));
+        assert_eq!(
+            Some(50),
+            REGISTRY
+                .negotiate(&request, &available)
+                .map(|entry| entry.number)
+        );
+
+        request.clear_option(CoapOption::Accept);
+        request.add_option_as(CoapOption::Accept, OptionValueU16(65002));
+        assert_eq!(None, REGISTRY.negotiate(&request, &available));
+
+        request.clear_option(CoapOption::Accept);
+        request.add_option_as(CoapOption::Accept, OptionValueU16(60));
+        assert_eq!(None, REGISTRY.negotiate(&request, &available));
+    }
+
+    #[test]
+    fn negotiate_fetch() {
+        let mut fetch = Packet::new();
+        fetch.header.code = MessageClass::Request(RequestType::Fetch);
+        fetch.set_content_format(ContentFormat::ApplicationCBOR);
+        fetch.set_accept(ContentFormat::ApplicationJSON);
+        fetch.payload = vec![0xA1, 0x61, 0x6B, 0x01];
+
+        let registry = ContentFormatRegistry::default();
+        assert_eq!(
+            Some(50),
+            registry
+                .negotiate(&fetch, &[60, 50])
+                .map(|entry| entry.number)
+        );
+        assert_eq!(None, registry.negotiate(&fetch, &[60]));
+    }
+
+    #[test]
+    fn negotiate_multi_accept() {
+        let available = [50, 60];
+        let mut request = Packet::new();
+        request.add_option_as(CoapOption::Accept, OptionValueU16(65002));
+        request.add_option_as(CoapOption::Accept, OptionValueU16(60));
+        let negotiate = |request: &Packet, policy| {
+            REGISTRY
+                .negotiate_with_policy(request, &available, policy)
+                .map(|entry| entry.number)
+        };
+
+        assert_eq!(None, REGISTRY.negotiate(&request, &available));
+        assert_eq!(None, negotiate(&request, MultiAcceptPolicy::Reject));
+        assert_eq!(
+            Some(60),
+            negotiate(&request, MultiAcceptPolicy::FirstSupported)
+        );
+        assert_eq!(Some(60), negotiate(&request, MultiAcceptPolicy::LastWins));
+
+        request.add_option_as(CoapOption::Accept, OptionValueU16(65001));
+        assert_eq!(
+            Some(60),
+            negotiate(&request, MultiAcceptPolicy::FirstSupported)
+        );
+        assert_eq!(None, negotiate(&request, MultiAcceptPolicy::LastWins));
+    }
+}
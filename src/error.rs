@@ -8,7 +8,7 @@ use core::{fmt, num::TryFromIntError};
 #[cfg(feature = "std")]
 use std::error;
 
-use crate::ResponseType;
+use crate::{CoapOption, MessageType, RequestType, ResponseType};
 
 /// The errors that can occur when encoding/decoding packets.
 #[derive(Debug, PartialEq)]
@@ -16,8 +16,46 @@ pub enum MessageError {
     InvalidHeader,
     InvalidPacketLength,
     InvalidTokenLength,
-    InvalidOptionDelta,
-    InvalidOptionLength,
+    /// The header announces a token length in the reserved range 9-15.
+    ReservedTokenLength(u8),
+    /// The message ended before a field it announced, `expected` is the
+    /// number of bytes needed to decode that field and `actual` the length
+    /// of the message.
+    TruncatedMessage {
+        expected: usize,
+        actual: usize,
+    },
+    /// The option at the given byte offset has an invalid delta.
+    InvalidOptionDelta {
+        offset: usize,
+    },
+    /// The option at the given byte offset has an invalid length.
+    InvalidOptionLength {
+        offset: usize,
+    },
+    /// The payload marker is not followed by any payload.
+    EmptyPayload,
+    /// The payload is `size` bytes long, more than the `max` bytes allowed
+    /// by [`DecodeConfig::max_payload_size`](crate::DecodeConfig).  A
+    /// server should respond with 4.13 (Request Entity Too Large).
+    PayloadTooLarge {
+        size: usize,
+        max: usize,
+    },
+    /// A Block1 or Block2 option is missing, malformed or doesn't fit the
+    /// exchange, e.g. a 2.31 (Continue) answering the last block.
+    InvalidBlockOption,
+    /// A value of the option is `length` bytes long, which the format of
+    /// the option doesn't allow, see
+    /// [`Packet::validate_options`](crate::Packet::validate_options).
+    InvalidOptionValue {
+        option: CoapOption,
+        length: usize,
+    },
+    /// The type or message ID of a response doesn't fit the request it
+    /// answers, see
+    /// [`CoapResponse::to_bytes_for`](crate::CoapResponse::to_bytes_for).
+    InvalidResponse(ValidationError),
 }
 
 impl fmt::Display for MessageError {
@@ -32,12 +70,52 @@ impl fmt::Display for MessageError {
             MessageError::InvalidTokenLength => {
                 write!(f, "CoAP error: invalid token length")
             }
-            MessageError::InvalidOptionDelta => {
-                write!(f, "CoAP error: invalid option delta")
+            MessageError::ReservedTokenLength(length) => {
+                write!(f, "CoAP error: reserved token length {}", length)
             }
-            MessageError::InvalidOptionLength => {
-                write!(f, "CoAP error: invalid option length")
+            MessageError::TruncatedMessage { expected, actual } => {
+                write!(
+                    f,
+                    "CoAP error: truncated message, expected {} bytes, got {}",
+                    expected, actual
+                )
             }
+            MessageError::InvalidOptionDelta { offset } => {
+                write!(
+                    f,
+                    "CoAP error: invalid option delta at byte {}",
+                    offset
+                )
+            }
+            MessageError::InvalidOptionLength { offset } => {
+                write!(
+                    f,
+                    "CoAP error: invalid option length at byte {}",
+                    offset
+                )
+            }
+            MessageError::EmptyPayload => {
+                write!(f, "CoAP error: payload marker without payload")
+            }
+            MessageError::PayloadTooLarge { size, max } => {
+                write!(
+                    f,
+                    "CoAP error: payload of {} bytes exceeds the maximum of {}",
+                    size, max
+                )
+            }
+            MessageError::InvalidBlockOption => {
+                write!(f, "CoAP error: invalid block option")
+            }
+            MessageError::InvalidOptionValue { option, length } => {
+                write!(
+                    f,
+                    "CoAP error: invalid length {} of {} option",
+                    length,
+                    option.name()
+                )
+            }
+            MessageError::InvalidResponse(error) => error.fmt(f),
         }
     }
 }
@@ -45,6 +123,100 @@ impl fmt::Display for MessageError {
 #[cfg(feature = "std")]
 impl error::Error for MessageError {}
 
+/// The errors that can occur when checking whether a packet uses the protocol
+/// correctly, beyond being well-formed.
+#[derive(Debug, PartialEq)]
+pub enum ValidationError {
+    /// The Observe option is present on a request with a method other than
+    /// GET or FETCH, which can't be observed.
+    ObserveOnUnobservableMethod(RequestType),
+    /// An option number in the experimental range 65000-65535 is present,
+    /// which must not be used outside of closed environments.
+    ExperimentalOption(u16),
+    /// A reserved option number is present.
+    ReservedOption(u16),
+    /// A value of the option with the given number is longer than allowed.
+    OptionTooLong(u16),
+    /// The type of a response doesn't fit the type of its request, e.g. an
+    /// acknowledgement answering a non-confirmable request.
+    ResponseTypeMismatch {
+        expected: MessageType,
+        actual: MessageType,
+    },
+    /// A piggybacked response doesn't echo the message ID of its request.
+    MessageIdMismatch { expected: u16, actual: u16 },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidationError::ObserveOnUnobservableMethod(method) => {
+                write!(
+                    f,
+                    "CoAP error: Observe option on {:?} request, only GET \
+                     and FETCH requests can be observed",
+                    method
+                )
+            }
+            ValidationError::ExperimentalOption(number) => {
+                write!(f, "CoAP error: experimental option {}", number)
+            }
+            ValidationError::ReservedOption(number) => {
+                write!(f, "CoAP error: reserved option {}", number)
+            }
+            ValidationError::OptionTooLong(number) => {
+                write!(f, "CoAP error: value of option {} too long", number)
+            }
+            ValidationError::ResponseTypeMismatch { expected, actual } => {
+                write!(
+                    f,
+                    "CoAP error: response of type {:?}, expected {:?}",
+                    actual, expected
+                )
+            }
+            ValidationError::MessageIdMismatch { expected, actual } => {
+                write!(
+                    f,
+                    "CoAP error: response with message ID {}, expected {}",
+                    actual, expected
+                )
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for ValidationError {}
+
+/// The errors that can occur when reading the payload as text.
+#[derive(Debug, PartialEq)]
+pub enum PayloadTextError {
+    /// The Content-Format option is not text/plain;charset=utf-8, or absent
+    /// when it was required.
+    WrongContentFormat(Option<u16>),
+    /// The payload is not valid UTF-8, starting at the given byte offset.
+    InvalidUtf8 { offset: usize },
+}
+
+impl fmt::Display for PayloadTextError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PayloadTextError::WrongContentFormat(Some(number)) => {
+                write!(f, "CoAP error: content-format {} is not text", number)
+            }
+            PayloadTextError::WrongContentFormat(None) => {
+                write!(f, "CoAP error: content-format is missing")
+            }
+            PayloadTextError::InvalidUtf8 { offset } => {
+                write!(f, "CoAP error: invalid UTF-8 at byte {}", offset)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for PayloadTextError {}
+
 /// The error that can occur when parsing a content-format.
 #[derive(Debug, PartialEq)]
 pub struct InvalidContentFormat;
@@ -71,6 +243,48 @@ impl fmt::Display for InvalidObserve {
 #[cfg(feature = "std")]
 impl error::Error for InvalidObserve {}
 
+/// The error that can occur when restoring the state of a subject from a
+/// snapshot that is malformed or of an unknown version.
+#[derive(Debug, PartialEq)]
+pub struct InvalidSnapshot;
+
+impl fmt::Display for InvalidSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CoAP error: invalid observe snapshot")
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for InvalidSnapshot {}
+
+/// The error that can occur when parsing a URI that isn't a valid CoAP URI.
+#[derive(Debug, PartialEq)]
+pub struct InvalidUri;
+
+impl fmt::Display for InvalidUri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CoAP error: invalid URI")
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for InvalidUri {}
+
+/// The error that can occur when setting a transmission parameter to a value
+/// RFC 7252 doesn't allow, see
+/// [`TransmissionParameters`](crate::transmission::TransmissionParameters).
+#[derive(Debug, PartialEq)]
+pub struct InvalidTransmissionParameter;
+
+impl fmt::Display for InvalidTransmissionParameter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CoAP error: invalid transmission parameter")
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for InvalidTransmissionParameter {}
+
 /// The error that can occur when parsing an option value.
 #[derive(Debug, PartialEq)]
 pub struct IncompatibleOptionValueFormat {
@@ -86,11 +300,65 @@ impl fmt::Display for IncompatibleOptionValueFormat {
 #[cfg(feature = "std")]
 impl error::Error for IncompatibleOptionValueFormat {}
 
+/// The error that can occur when serializing a value into a payload.
+#[derive(Debug, PartialEq)]
+pub struct PayloadEncodingError {
+    pub message: String,
+}
+
+impl fmt::Display for PayloadEncodingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Payload encoding error: {}", self.message)
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for PayloadEncodingError {}
+
+/// The errors that can occur when encoding or decoding a bundle of messages,
+/// see the `bundle` module.  Each refers to the index of the message at
+/// fault.
+#[derive(Debug, PartialEq)]
+pub enum BundleError {
+    /// The message can't be encoded or decoded.
+    Message { index: usize, error: MessageError },
+    /// The encoded message doesn't fit into a datagram on its own.
+    MessageTooLarge { index: usize },
+    /// The length prefix is truncated or exceeds the rest of the datagram.
+    InvalidLengthPrefix { index: usize },
+}
+
+impl fmt::Display for BundleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BundleError::Message { index, error } => {
+                write!(f, "{} (message {} of the bundle)", error, index)
+            }
+            BundleError::MessageTooLarge { index } => {
+                write!(f, "CoAP error: message {} exceeds the bundle", index)
+            }
+            BundleError::InvalidLengthPrefix { index } => {
+                write!(
+                    f,
+                    "CoAP error: invalid length prefix of message {} in the \
+                     bundle",
+                    index
+                )
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for BundleError {}
+
 /// The errors that can occur when constructing a new block value.
 #[derive(Debug, PartialEq)]
 pub enum InvalidBlockValue {
     SizeExponentEncodingError(usize),
     TypeBoundsError(TryFromIntError),
+    /// The block number doesn't fit into 20 bits.
+    NumTooLarge(u32),
 }
 
 impl fmt::Display for InvalidBlockValue {
@@ -102,6 +370,9 @@ impl fmt::Display for InvalidBlockValue {
             InvalidBlockValue::TypeBoundsError(err) => {
                 write!(f, "size provided is outside type bounds: {}", err)
             }
+            InvalidBlockValue::NumTooLarge(num) => {
+                write!(f, "block number {} exceeds 20 bits", num)
+            }
         }
     }
 }
@@ -146,6 +417,10 @@ impl HandlingError {
         Self::with_code(ResponseType::BadRequest, e)
     }
 
+    pub fn bad_option<T: ToString>(e: T) -> Self {
+        Self::with_code(ResponseType::BadOption, e)
+    }
+
     pub fn internal<T: ToString>(e: T) -> Self {
         Self::with_code(ResponseType::InternalServerError, e)
     }
@@ -0,0 +1,148 @@
+//! Out-of-band data attached to requests and responses.
+
+use alloc::{boxed::Box, collections::BTreeMap};
+use core::{
+    any::{Any, TypeId},
+    fmt,
+};
+
+// An `Any` that can be cloned, so that requests and responses stay `Clone`
+trait CloneAny: Any + Send + Sync {
+    fn clone_box(&self) -> Box<dyn CloneAny>;
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+}
+
+impl<T: Any + Clone + Send + Sync> CloneAny for T {
+    fn clone_box(&self) -> Box<dyn CloneAny> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+impl Clone for Box<dyn CloneAny> {
+    fn clone(&self) -> Self {
+        (**self).clone_box()
+    }
+}
+
+/// A map holding one value of each type, which transports and middleware use
+/// to pass along data that isn't part of the message, like the receive
+/// timestamp or the security identity of the peer.
+///
+/// The extensions are never encoded and are not part of the message, so they
+/// don't affect its equality either.
+#[derive(Clone, Default)]
+pub struct Extensions {
+    map: BTreeMap<TypeId, Box<dyn CloneAny>>,
+}
+
+impl Extensions {
+    /// Creates an empty map.
+    pub fn new() -> Extensions {
+        Default::default()
+    }
+
+    /// Inserts a value, returning the previous value of the same type.
+    pub fn insert<T: Any + Clone + Send + Sync>(
+        &mut self,
+        value: T,
+    ) -> Option<T> {
+        self.map
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|previous| previous.into_any().downcast().ok())
+            .map(|previous| *previous)
+    }
+
+    /// Returns the value of the given type.
+    pub fn get<T: Any>(&self) -> Option<&T> {
+        self.map
+            .get(&TypeId::of::<T>())
+            .and_then(|value| (**value).as_any().downcast_ref())
+    }
+
+    /// Returns the value of the given type for modification.
+    pub fn get_mut<T: Any>(&mut self) -> Option<&mut T> {
+        self.map
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|value| (**value).as_any_mut().downcast_mut())
+    }
+
+    /// Removes and returns the value of the given type.
+    pub fn remove<T: Any>(&mut self) -> Option<T> {
+        self.map
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.into_any().downcast().ok())
+            .map(|value| *value)
+    }
+
+    /// Returns the number of values.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Returns whether there are no values.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Removes all values.
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct ReceivedAt(u64);
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct ConnectionId(Vec<u8>);
+
+    #[test]
+    fn insert_get_remove() {
+        let mut extensions = Extensions::new();
+        assert!(extensions.is_empty());
+
+        assert_eq!(None, extensions.insert(ReceivedAt(1)));
+        assert_eq!(None, extensions.insert(ConnectionId(vec![0x42])));
+        assert_eq!(2, extensions.len());
+        assert_eq!(Some(&ReceivedAt(1)), extensions.get());
+        assert_eq!(Some(&ConnectionId(vec![0x42])), extensions.get());
+        assert_eq!(None, extensions.get::<u32>());
+
+        assert_eq!(Some(ReceivedAt(1)), extensions.insert(ReceivedAt(2)));
+        extensions.get_mut::<ReceivedAt>().unwrap().0 += 1;
+
+        let copy = extensions.clone();
+        assert_eq!(Some(ReceivedAt(3)), extensions.remove());
+        assert_eq!(None, extensions.get::<ReceivedAt>());
+        assert_eq!(Some(&ReceivedAt(3)), copy.get());
+        assert_eq!(2, copy.len());
+    }
+}
@@ -0,0 +1,187 @@
+//! Checking whether a message fits into a datagram before sending it, and
+//! which block size to fall back to if it doesn't (RFC 7959).
+
+use crate::{block_handler::BlockValue, CoapOption, EncodeConfig, Packet};
+
+/// The outcome of [`check`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitResult {
+    /// The message fits as it is.
+    Fits,
+    /// The payload has to be transferred block-wise, in `block_count`
+    /// blocks of the size with the exponent `suggested_szx`, which is the
+    /// largest that fits.  This is a Block1 transfer for requests and a
+    /// Block2 transfer for responses.
+    NeedsBlock { suggested_szx: u8, block_count: u32 },
+    /// The message doesn't even fit with blocks of 16 bytes, it needs a
+    /// datagram of at least `min_required` bytes.
+    CannotFit { min_required: usize },
+}
+
+/// Checks whether `packet` fits into a datagram of `mtu` bytes, of which
+/// `overhead` bytes are taken by the transport, e.g. 28 for IPv4 and UDP
+/// headers or the record overhead of DTLS.
+///
+/// The block sizes are checked including the Block option that would be
+/// added, for the last block, which has the longest one.
+pub fn check(packet: &Packet, mtu: usize, overhead: usize) -> FitResult {
+    check_with_config(packet, mtu, overhead, &EncodeConfig::default())
+}
+
+/// Checks whether `packet` fits like [`check`], for a packet encoded as
+/// described by `config`, see [`Packet::to_bytes_with_config`].
+pub fn check_with_config(
+    packet: &Packet,
+    mtu: usize,
+    overhead: usize,
+    config: &EncodeConfig,
+) -> FitResult {
+    let required = packet.encoded_len_with_config(config) + overhead;
+    if required <= mtu {
+        return FitResult::Fits;
+    }
+
+    let payload_len = packet.payload.len();
+    if payload_len == 0 {
+        return FitResult::CannotFit {
+            min_required: required,
+        };
+    }
+
+    let option = if packet.expects_response() {
+        CoapOption::Block1
+    } else {
+        CoapOption::Block2
+    };
+    let mut envelope = packet.clone();
+    envelope.payload.clear();
+
+    let mut min_required = usize::MAX;
+    for szx in (0..=6u8).rev() {
+        let block_size = 16usize << szx;
+        let block_count = payload_len.div_ceil(block_size);
+        let Ok(last) = BlockValue::new(block_count - 1, true, block_size)
+        else {
+            // The block numbers are out of range for smaller sizes as well
+            break;
+        };
+        envelope.set_options_as(option, [last].into());
+        let required = envelope.encoded_len_with_config(config)
+            + 1
+            + block_size.min(payload_len)
+            + overhead;
+        if required <= mtu {
+            return FitResult::NeedsBlock {
+                suggested_szx: szx,
+                block_count: block_count as u32,
+            };
+        }
+        min_required = required;
+    }
+    FitResult::CannotFit { min_required }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{MessageClass, ResponseType};
+
+    fn response(payload_len: usize) -> Packet {
+        let mut packet = Packet::new();
+        packet.header.code = MessageClass::Response(ResponseType::Content);
+        packet.set_token(vec![1, 2, 3, 4]);
+        packet.add_option(CoapOption::Size2, vec![100]);
+        packet.payload = vec![0; payload_len];
+        packet
+    }
+
+    #[test]
+    fn fits() {
+        let packet = response(100);
+        let len = packet.encoded_len();
+        assert_eq!(FitResult::Fits, check(&packet, len + 10, 10));
+        assert_ne!(FitResult::Fits, check(&packet, len + 9, 10));
+    }
+
+    #[test]
+    fn minimized_uints() {
+        let mut packet = response(100);
+        packet.add_option(CoapOption::MaxAge, vec![0x00, 0x00, 0x3C]);
+        let minimize = EncodeConfig {
+            minimize_uints: true,
+            ..Default::default()
+        };
+        let len = packet.encoded_len_with_config(&minimize);
+        assert_eq!(len + 2, packet.encoded_len());
+        assert_eq!(
+            FitResult::Fits,
+            check_with_config(&packet, len, 0, &minimize)
+        );
+        assert_ne!(FitResult::Fits, check(&packet, len, 0));
+    }
+
+    #[test]
+    fn block_option_boundary() {
+        let packet = response(100);
+        // The envelope without the payload and its marker
+        let envelope_len = packet.encoded_len() - 101;
+
+        // A block of 64 bytes would fit if it weren't for the Block2 option,
+        // which takes 2 bytes
+        let mtu = envelope_len + 1 + 64;
+        assert_eq!(
+            FitResult::NeedsBlock {
+                suggested_szx: 1,
+                block_count: 4,
+            },
+            check(&packet, mtu, 0)
+        );
+        assert_eq!(
+            FitResult::NeedsBlock {
+                suggested_szx: 2,
+                block_count: 2,
+            },
+            check(&packet, mtu + 2, 0)
+        );
+        assert_eq!(
+            FitResult::NeedsBlock {
+                suggested_szx: 2,
+                block_count: 2,
+            },
+            check(&packet, mtu + 10, 8)
+        );
+    }
+
+    #[test]
+    fn block1_for_requests() {
+        let mut packet = Packet::new();
+        packet.add_option(CoapOption::UriPath, b"firmware".to_vec());
+        packet.payload = vec![0; 5000];
+        // The last of 313 blocks of 16 bytes needs a 2 byte Block1 value,
+        // and the delta from Uri-Path an extended byte
+        let envelope_len = packet.encoded_len() - 5001;
+        let mtu = envelope_len + 4 + 1 + 16;
+        assert_eq!(
+            FitResult::NeedsBlock {
+                suggested_szx: 0,
+                block_count: 313,
+            },
+            check(&packet, mtu, 0)
+        );
+        assert_eq!(
+            FitResult::CannotFit { min_required: mtu },
+            check(&packet, mtu - 1, 0)
+        );
+    }
+
+    #[test]
+    fn cannot_fit_without_payload() {
+        let mut packet = response(0);
+        packet.add_option(CoapOption::ETag, vec![0; 8]);
+        let len = packet.encoded_len();
+        assert_eq!(
+            FitResult::CannotFit { min_required: len },
+            check(&packet, len - 1, 0)
+        );
+    }
+}
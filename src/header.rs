@@ -7,7 +7,7 @@ use core::{convert::TryFrom, fmt};
 use crate::error::MessageError;
 
 /// The raw byte header representation, useful for encoding/decoding directly.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct HeaderRaw {
     ver_type_tkl: u8,
     code: u8,
@@ -15,22 +15,20 @@ pub struct HeaderRaw {
 }
 
 impl HeaderRaw {
-    /// Writes the header into the given buffer, which must have a capacity of
-    /// at least 4.
-    pub fn serialize_into(
-        &self,
-        buf: &mut Vec<u8>,
-    ) -> Result<(), MessageError> {
-        if buf.capacity() < 4 {
-            return Err(MessageError::InvalidPacketLength);
-        }
-
-        buf.push(self.ver_type_tkl);
-        buf.push(self.code);
-        let id_bytes = self.message_id.to_be_bytes();
-        buf.extend(&id_bytes);
+    /// Returns the four bytes of the header.
+    pub fn to_bytes(&self) -> [u8; 4] {
+        let [id_high, id_low] = self.message_id.to_be_bytes();
+        [self.ver_type_tkl, self.code, id_high, id_low]
+    }
 
-        Ok(())
+    /// Reads the header from its four bytes.  Any bytes make a header, it's
+    /// up to [`Header`] to interpret them.
+    pub fn from_bytes(bytes: [u8; 4]) -> HeaderRaw {
+        HeaderRaw {
+            ver_type_tkl: bytes[0],
+            code: bytes[1],
+            message_id: u16::from_be_bytes([bytes[2], bytes[3]]),
+        }
     }
 }
 
@@ -48,18 +46,10 @@ impl TryFrom<&[u8]> for HeaderRaw {
     type Error = MessageError;
 
     fn try_from(buf: &[u8]) -> Result<HeaderRaw, MessageError> {
-        if buf.len() < 4 {
-            return Err(MessageError::InvalidPacketLength);
+        match buf {
+            [a, b, c, d, ..] => Ok(HeaderRaw::from_bytes([*a, *b, *c, *d])),
+            _ => Err(MessageError::InvalidPacketLength),
         }
-
-        let mut id_bytes = [0; 2];
-        id_bytes.copy_from_slice(&buf[2..4]);
-
-        Ok(HeaderRaw {
-            ver_type_tkl: buf[0],
-            code: buf[1],
-            message_id: u16::from_be_bytes(id_bytes),
-        })
     }
 }
 
@@ -69,7 +59,31 @@ pub enum MessageClass {
     Empty,
     Request(RequestType),
     Response(ResponseType),
-    Reserved,
+    /// A code that isn't known to this crate, which is kept as it is.
+    Reserved(u8),
+}
+
+impl MessageClass {
+    /// Classifies a code byte, e.g. to inspect it without decoding a whole
+    /// message.  This is lossless, codes that aren't known become
+    /// [`MessageClass::Reserved`].
+    ///
+    /// ```
+    /// use coap_lite::{MessageClass, ResponseType};
+    ///
+    /// let class = MessageClass::from_byte(0x45);
+    /// assert_eq!(MessageClass::Response(ResponseType::Content), class);
+    /// assert_eq!("2.05", class.to_string());
+    /// assert_eq!(0x45, class.to_byte());
+    /// ```
+    pub fn from_byte(code: u8) -> MessageClass {
+        MessageClass::from(code)
+    }
+
+    /// Returns the code byte of the class.
+    pub fn to_byte(&self) -> u8 {
+        u8::from(*self)
+    }
 }
 
 impl From<u8> for MessageClass {
@@ -120,7 +134,7 @@ impl From<u8> for MessageClass {
             0xA4 => MessageClass::Response(ResponseType::GatewayTimeout),
             0xA5 => MessageClass::Response(ResponseType::ProxyingNotSupported),
             0xA8 => MessageClass::Response(ResponseType::HopLimitReached),
-            _ => MessageClass::Reserved,
+            _ => MessageClass::Reserved(number),
         }
     }
 }
@@ -174,6 +188,7 @@ impl From<MessageClass> for u8 {
             MessageClass::Response(ResponseType::ProxyingNotSupported) => 0xA5,
             MessageClass::Response(ResponseType::HopLimitReached) => 0xA8,
 
+            MessageClass::Reserved(code) => code,
             _ => 0xFF,
         }
     }
@@ -189,7 +204,12 @@ impl fmt::Display for MessageClass {
 }
 
 /// The request codes.
+///
+/// More methods may be added in minor releases, so matches need a wildcard
+/// arm.  See [`RequestType::known_variants`] and the predicates like
+/// [`RequestType::is_safe`] for alternatives.
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
 pub enum RequestType {
     Get,
     Post,
@@ -202,7 +222,12 @@ pub enum RequestType {
 }
 
 /// The response codes.
+///
+/// More codes may be added in minor releases, so matches need a wildcard
+/// arm.  See [`ResponseType::known_variants`] and the predicates like
+/// [`ResponseType::is_success`] for alternatives.
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
 pub enum ResponseType {
     // 200 Codes
     Created,
@@ -240,6 +265,116 @@ pub enum ResponseType {
     UnKnown,
 }
 
+/// The categories of response codes, by their class.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseCategory {
+    /// Class 2, the request was successfully received, understood and
+    /// accepted.
+    Success,
+    /// Class 4, the request was erroneous.
+    ClientError,
+    /// Class 5, the server failed to fulfill a valid request.
+    ServerError,
+    /// A code this crate doesn't know.  This includes the signaling codes of
+    /// class 7 (RFC 8323), which are not responses.
+    Unknown,
+}
+
+impl RequestType {
+    /// Returns all methods known to this crate, i.e. all but `UnKnown`, in
+    /// the order of their codes.
+    pub const fn known_variants() -> &'static [RequestType] {
+        &[
+            RequestType::Get,
+            RequestType::Post,
+            RequestType::Put,
+            RequestType::Delete,
+            RequestType::Fetch,
+            RequestType::Patch,
+            RequestType::IPatch,
+        ]
+    }
+
+    /// Returns whether the method is safe, i.e. only retrieves data (RFC
+    /// 7252 section 5.1 and RFC 8132 section 2).
+    pub fn is_safe(&self) -> bool {
+        matches!(self, RequestType::Get | RequestType::Fetch)
+    }
+
+    /// Returns whether the method is idempotent, i.e. may be repeated with
+    /// the same effect (RFC 7252 section 5.1 and RFC 8132 section 3).
+    pub fn is_idempotent(&self) -> bool {
+        matches!(
+            self,
+            RequestType::Get
+                | RequestType::Put
+                | RequestType::Delete
+                | RequestType::Fetch
+                | RequestType::IPatch
+        )
+    }
+}
+
+impl ResponseType {
+    /// Returns all response codes known to this crate, i.e. all but
+    /// `UnKnown`, in the order of their codes.
+    pub const fn known_variants() -> &'static [ResponseType] {
+        &[
+            ResponseType::Created,
+            ResponseType::Deleted,
+            ResponseType::Valid,
+            ResponseType::Changed,
+            ResponseType::Content,
+            ResponseType::Continue,
+            ResponseType::BadRequest,
+            ResponseType::Unauthorized,
+            ResponseType::BadOption,
+            ResponseType::Forbidden,
+            ResponseType::NotFound,
+            ResponseType::MethodNotAllowed,
+            ResponseType::NotAcceptable,
+            ResponseType::RequestEntityIncomplete,
+            ResponseType::Conflict,
+            ResponseType::PreconditionFailed,
+            ResponseType::RequestEntityTooLarge,
+            ResponseType::UnsupportedContentFormat,
+            ResponseType::UnprocessableEntity,
+            ResponseType::TooManyRequests,
+            ResponseType::InternalServerError,
+            ResponseType::NotImplemented,
+            ResponseType::BadGateway,
+            ResponseType::ServiceUnavailable,
+            ResponseType::GatewayTimeout,
+            ResponseType::ProxyingNotSupported,
+            ResponseType::HopLimitReached,
+        ]
+    }
+
+    /// Returns the category of the response code.
+    pub fn category(&self) -> ResponseCategory {
+        match u8::from(MessageClass::Response(*self)) >> 5 {
+            2 => ResponseCategory::Success,
+            4 => ResponseCategory::ClientError,
+            5 => ResponseCategory::ServerError,
+            _ => ResponseCategory::Unknown,
+        }
+    }
+
+    /// Returns whether the response code is a success (class 2).
+    pub fn is_success(&self) -> bool {
+        self.category() == ResponseCategory::Success
+    }
+
+    /// Returns whether the response code is a client or server error (class
+    /// 4 or 5).
+    pub fn is_error(&self) -> bool {
+        matches!(
+            self.category(),
+            ResponseCategory::ClientError | ResponseCategory::ServerError
+        )
+    }
+}
+
 /// The message types.
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MessageType {
@@ -250,7 +385,7 @@ pub enum MessageType {
 }
 
 /// The message header.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Header {
     ver_type_tkl: u8,
     pub code: MessageClass,
@@ -327,7 +462,11 @@ impl Header {
         }
     }
 
-    /// Sets the token length.
+    /// Sets the token length, which is the token length indicator of RFC
+    /// 8974 for tokens longer than 12 bytes.  Use [`Packet::set_token`]
+    /// rather than setting it separately.
+    ///
+    /// [`Packet::set_token`]: crate::Packet::set_token
     #[inline]
     pub fn set_token_length(&mut self, tkl: u8) {
         assert_eq!(0xF0 & tkl, 0);
@@ -336,7 +475,8 @@ impl Header {
         self.ver_type_tkl = tkl | ver_type;
     }
 
-    /// Returns the token length.
+    /// Returns the token length, or the token length indicator of RFC 8974
+    /// (13 or 14) for tokens longer than 12 bytes.
     #[inline]
     pub fn get_token_length(&self) -> u8 {
         0x0F & self.ver_type_tkl
@@ -367,31 +507,144 @@ mod test {
 
     #[test]
     fn test_header_codes() {
-        for code in 0..255 {
+        for code in 0..=255 {
             let class: MessageClass = code.into();
             let code_str = class.to_string();
 
             let mut header = Header::new();
             header.set_code(&code_str);
 
-            // Reserved class could technically be many codes, so only check
-            // valid items
-            if class != MessageClass::Reserved {
-                assert_eq!(u8::from(class), code);
-                assert_eq!(class, header.code);
-                assert_eq!(code_str, header.get_code());
-            }
+            assert_eq!(u8::from(class), code);
+            assert_eq!(class, MessageClass::from_byte(class.to_byte()));
+            assert_eq!(class, header.code);
+            assert_eq!(code_str, header.get_code());
+        }
+        assert_eq!(MessageClass::Reserved(0xE1), MessageClass::from(0xE1));
+    }
+
+    #[test]
+    fn test_from_byte() {
+        let class = MessageClass::from_byte(0x45);
+        assert_eq!(MessageClass::Response(ResponseType::Content), class);
+        assert_eq!("2.05", class.to_string());
+        assert_eq!(0x45, class.to_byte());
+    }
+
+    #[test]
+    fn test_request_methods() {
+        let methods = [
+            (RequestType::Get, 0x01, "0.01"),
+            (RequestType::Post, 0x02, "0.02"),
+            (RequestType::Put, 0x03, "0.03"),
+            (RequestType::Delete, 0x04, "0.04"),
+            (RequestType::Fetch, 0x05, "0.05"),
+            (RequestType::Patch, 0x06, "0.06"),
+            (RequestType::IPatch, 0x07, "0.07"),
+        ];
+        for (method, code, code_str) in methods {
+            let mut header = Header::new();
+            header.code = MessageClass::Request(method);
+            let raw = header.to_raw();
+            assert_eq!(code, raw.code);
+            assert_eq!(code_str, header.get_code());
+
+            let decoded =
+                Header::from_raw(&HeaderRaw::from_bytes(raw.to_bytes()));
+            assert_eq!(MessageClass::Request(method), decoded.code);
         }
     }
 
     #[test]
-    fn serialize_raw_fail() {
-        let h = HeaderRaw::default();
-        let mut buf = Vec::with_capacity(3);
+    fn known_variants() {
+        let codes: Vec<u8> = RequestType::known_variants()
+            .iter()
+            .map(|&method| MessageClass::Request(method).into())
+            .collect();
+        assert_eq!((1..=7).collect::<Vec<u8>>(), codes);
+
+        // Every code that decodes to a known response is listed
+        let known = ResponseType::known_variants();
+        let decoded: Vec<ResponseType> = (0..=u8::MAX)
+            .filter_map(|code| match MessageClass::from(code) {
+                MessageClass::Response(ResponseType::UnKnown) => None,
+                MessageClass::Response(response) => Some(response),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(known, &decoded[..]);
+
+        let safe: Vec<_> = RequestType::known_variants()
+            .iter()
+            .filter(|method| method.is_safe())
+            .collect();
+        assert_eq!(vec![&RequestType::Get, &RequestType::Fetch], safe);
+        assert!(RequestType::IPatch.is_idempotent());
+        assert!(!RequestType::Patch.is_idempotent());
+        assert!(!RequestType::Post.is_idempotent());
+    }
+
+    #[test]
+    fn test_response_categories() {
+        for code in 0..=255u8 {
+            if let MessageClass::Response(response) = MessageClass::from(code)
+            {
+                let expected = match code >> 5 {
+                    2 => ResponseCategory::Success,
+                    4 => ResponseCategory::ClientError,
+                    5 => ResponseCategory::ServerError,
+                    _ => unreachable!(),
+                };
+                assert_eq!(expected, response.category());
+                assert_eq!(code >> 5 == 2, response.is_success());
+                assert_eq!(code >> 5 != 2, response.is_error());
+            }
+        }
+
+        assert!(ResponseType::Continue.is_success());
         assert_eq!(
-            MessageError::InvalidPacketLength,
-            h.serialize_into(&mut buf).unwrap_err()
+            ResponseCategory::ClientError,
+            ResponseType::TooManyRequests.category()
+        );
+        assert_eq!(
+            ResponseCategory::ServerError,
+            ResponseType::HopLimitReached.category()
         );
+        assert_eq!(
+            ResponseCategory::Unknown,
+            ResponseType::UnKnown.category()
+        );
+        assert!(!ResponseType::UnKnown.is_success());
+        assert!(!ResponseType::UnKnown.is_error());
+    }
+
+    #[test]
+    fn raw_round_trip() {
+        let types = [
+            MessageType::Confirmable,
+            MessageType::NonConfirmable,
+            MessageType::Acknowledgement,
+            MessageType::Reset,
+        ];
+        for (type_bits, message_type) in types.into_iter().enumerate() {
+            for code in 0..=255u8 {
+                let mut header = Header::new();
+                header.set_type(message_type);
+                header.set_token_length(8);
+                header.code = MessageClass::from_byte(code);
+                header.message_id = 0xA55A ^ u16::from(code);
+
+                let bytes = header.to_raw().to_bytes();
+                let [id_high, id_low] = header.message_id.to_be_bytes();
+                assert_eq!(
+                    [0x48 | (type_bits as u8) << 4, code, id_high, id_low],
+                    bytes
+                );
+                assert_eq!(
+                    header,
+                    Header::from_raw(&HeaderRaw::from_bytes(bytes))
+                );
+            }
+        }
     }
 
     #[test]
@@ -0,0 +1,309 @@
+//! Sharing the storage of option values that occur in many messages.
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::cell::Cell;
+
+use crate::{
+    error::MessageError, header::Header, packet::CoapOption, Packet, PacketRef,
+};
+
+/// Deduplicates short option values, e.g. Uri-Path segments or Uri-Host,
+/// into shared storage, for servers that decode the same values over and
+/// over.  See [`InternedPacket`].
+///
+/// Values of up to `max_value_len` bytes are kept until they take up more
+/// than `byte_budget` bytes.  The least recently used ones are dropped
+/// then, down to three quarters of the budget so that this happens rarely.
+/// Longer values are never kept.
+#[derive(Debug, Clone)]
+pub struct Interner {
+    max_value_len: usize,
+    byte_budget: usize,
+    bytes: usize,
+    // The values with the time of their last use
+    values: BTreeMap<Arc<[u8]>, Cell<u64>>,
+    clock: u64,
+}
+
+impl Interner {
+    /// Creates an interner for values of up to `max_value_len` bytes, which
+    /// keeps at most `byte_budget` bytes of them.
+    pub fn new(max_value_len: usize, byte_budget: usize) -> Self {
+        Interner {
+            max_value_len,
+            byte_budget,
+            bytes: 0,
+            values: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// Returns the shared storage of `value`, keeping it for later calls if
+    /// it is short enough.
+    pub fn intern(&mut self, value: &[u8]) -> Arc<[u8]> {
+        if value.len() > self.max_value_len || value.len() > self.byte_budget {
+            return Arc::from(value);
+        }
+
+        self.clock += 1;
+        if let Some((value, last_use)) = self.values.get_key_value(value) {
+            last_use.set(self.clock);
+            return value.clone();
+        }
+
+        if self.bytes + value.len() > self.byte_budget {
+            self.evict((self.byte_budget / 4 * 3).saturating_sub(value.len()));
+        }
+        let value: Arc<[u8]> = Arc::from(value);
+        self.bytes += value.len();
+        self.values.insert(value.clone(), Cell::new(self.clock));
+        value
+    }
+
+    /// Returns the number of values kept.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Returns whether no values are kept.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Returns the number of bytes taken up by the values kept.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    // Drops the least recently used values until at most `target` bytes are
+    // kept
+    fn evict(&mut self, target: usize) {
+        let mut by_last_use: Vec<(u64, usize)> = self
+            .values
+            .iter()
+            .map(|(value, last_use)| (last_use.get(), value.len()))
+            .collect();
+        by_last_use.sort_unstable();
+        let mut bytes = self.bytes;
+        let mut cutoff = 0;
+        for (last_use, len) in by_last_use {
+            if bytes <= target {
+                break;
+            }
+            bytes -= len;
+            cutoff = last_use;
+        }
+        self.values.retain(|_, last_use| last_use.get() > cutoff);
+        self.bytes = bytes;
+    }
+}
+
+/// A message whose option values are shared through an [`Interner`], so
+/// that decoding a value seen before doesn't allocate.
+///
+/// This is an alternative to [`Packet`] for decoding only, e.g. in a gateway
+/// that sees the same Uri-Path segments in every request.  It converts into
+/// a [`Packet`] to be modified or encoded.
+///
+/// # Examples
+///
+/// ```
+/// use coap_lite::{CoapOption, InternedPacket, Interner, Packet};
+///
+/// let mut packet = Packet::new();
+/// packet.add_option(CoapOption::UriPath, b"telemetry".to_vec());
+/// let bytes = packet.to_bytes().unwrap();
+///
+/// let mut interner = Interner::new(32, 4096);
+/// let first =
+///     InternedPacket::from_bytes_with_interner(&bytes, &mut interner)
+///         .unwrap();
+/// let second =
+///     InternedPacket::from_bytes_with_interner(&bytes, &mut interner)
+///         .unwrap();
+/// assert_eq!(first, second);
+/// assert_eq!(1, interner.len());
+/// assert_eq!(packet, second.to_packet());
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct InternedPacket {
+    pub header: Header,
+    token: Vec<u8>,
+    options: BTreeMap<u16, Vec<Arc<[u8]>>>,
+    pub payload: Vec<u8>,
+}
+
+impl InternedPacket {
+    /// Decodes a message like [`Packet::from_bytes`] does, sharing the
+    /// option values through `interner`.
+    pub fn from_bytes_with_interner(
+        buf: &[u8],
+        interner: &mut Interner,
+    ) -> Result<InternedPacket, MessageError> {
+        Ok(PacketRef::parse(buf)?.to_packet_with(interner))
+    }
+
+    /// Returns the token.
+    pub fn get_token(&self) -> &[u8] {
+        &self.token
+    }
+
+    /// Returns the values of an option, in the order they're encoded in.
+    pub fn get_option(&self, option: CoapOption) -> Option<&[Arc<[u8]>]> {
+        self.options
+            .get(&u16::from(option))
+            .map(|values| &values[..])
+    }
+
+    /// Returns an iterator over the option numbers and values, in the order
+    /// they're encoded in.
+    pub fn options(&self) -> impl Iterator<Item = (u16, &[u8])> {
+        self.options.iter().flat_map(|(&number, values)| {
+            values.iter().map(move |value| (number, &value[..]))
+        })
+    }
+
+    /// Copies the message into a [`Packet`].
+    pub fn to_packet(&self) -> Packet {
+        let mut packet = Packet::new();
+        packet.header = self.header.clone();
+        packet.set_token(self.token.clone());
+        for (number, value) in self.options() {
+            packet.add_option_raw(number, value.to_vec());
+        }
+        packet.payload = self.payload.clone();
+        packet
+    }
+}
+
+impl PacketRef<'_> {
+    /// Copies the message into an [`InternedPacket`], sharing the option
+    /// values through `interner`.
+    pub fn to_packet_with(&self, interner: &mut Interner) -> InternedPacket {
+        let mut options: BTreeMap<u16, Vec<Arc<[u8]>>> = BTreeMap::new();
+        for (number, value) in self.options() {
+            options
+                .entry(number)
+                .or_default()
+                .push(interner.intern(value));
+        }
+        InternedPacket {
+            header: self.header().clone(),
+            token: self.token().to_vec(),
+            options,
+            payload: self.payload().to_vec(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{MessageClass, RequestType};
+
+    fn telemetry_post(device: &str, reading: u8) -> Vec<u8> {
+        let mut packet = Packet::new();
+        packet.header.code = MessageClass::Request(RequestType::Post);
+        packet.header.message_id = u16::from(reading);
+        packet.set_token(vec![reading]);
+        packet.add_option(CoapOption::UriHost, b"gateway".to_vec());
+        packet.add_option(CoapOption::UriPath, b"v1".to_vec());
+        packet.add_option(CoapOption::UriPath, b"telemetry".to_vec());
+        packet.add_option(CoapOption::UriPath, device.as_bytes().to_vec());
+        packet.add_option(CoapOption::Unknown(2048), vec![reading; 100]);
+        packet.payload = vec![reading];
+        packet.to_bytes().unwrap()
+    }
+
+    #[test]
+    fn agrees_with_packet() {
+        let mut interner = Interner::new(16, 1024);
+        let mut uninterned = Interner::new(0, 0);
+        for reading in 0..10 {
+            let bytes = telemetry_post("device-1", reading);
+            let packet = Packet::from_bytes(&bytes).unwrap();
+            let interned = InternedPacket::from_bytes_with_interner(
+                &bytes,
+                &mut interner,
+            )
+            .unwrap();
+            assert_eq!(packet, interned.to_packet());
+            assert_eq!(
+                InternedPacket::from_bytes_with_interner(
+                    &bytes,
+                    &mut uninterned
+                )
+                .unwrap(),
+                interned
+            );
+        }
+        assert!(uninterned.is_empty());
+
+        let bytes = telemetry_post("device-1", 0);
+        assert_eq!(
+            Packet::from_bytes(&bytes[..4]).unwrap_err(),
+            InternedPacket::from_bytes_with_interner(
+                &bytes[..4],
+                &mut interner
+            )
+            .unwrap_err()
+        );
+    }
+
+    #[test]
+    fn shares_values() {
+        let mut interner = Interner::new(16, 1024);
+        let first = InternedPacket::from_bytes_with_interner(
+            &telemetry_post("device-1", 1),
+            &mut interner,
+        )
+        .unwrap();
+        let second = InternedPacket::from_bytes_with_interner(
+            &telemetry_post("device-1", 2),
+            &mut interner,
+        )
+        .unwrap();
+
+        let path = first.get_option(CoapOption::UriPath).unwrap();
+        assert_eq!(3, path.len());
+        for (a, b) in path
+            .iter()
+            .zip(second.get_option(CoapOption::UriPath).unwrap())
+        {
+            assert!(Arc::ptr_eq(a, b));
+        }
+        // Values longer than the threshold aren't kept
+        let long = CoapOption::Unknown(2048);
+        assert!(!Arc::ptr_eq(
+            &first.get_option(long).unwrap()[0],
+            &second.get_option(long).unwrap()[0]
+        ));
+        assert_eq!(4, interner.len());
+        assert_eq!(7 + 2 + 9 + 8, interner.bytes());
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut interner = Interner::new(8, 16);
+        let a = interner.intern(b"aaaa");
+        let b = interner.intern(b"bbbb");
+        interner.intern(b"cccc");
+        let d = interner.intern(b"dddd");
+        // Using "aaaa" again makes "bbbb" the least recently used
+        assert!(Arc::ptr_eq(&a, &interner.intern(b"aaaa")));
+        assert_eq!(16, interner.bytes());
+
+        // Values are dropped down to three quarters of the budget
+        interner.intern(b"eeee");
+        assert_eq!(3, interner.len());
+        assert_eq!(12, interner.bytes());
+        assert!(Arc::ptr_eq(&a, &interner.intern(b"aaaa")));
+        assert!(Arc::ptr_eq(&d, &interner.intern(b"dddd")));
+        assert!(!Arc::ptr_eq(&b, &interner.intern(b"bbbb")));
+
+        // Values longer than the budget aren't kept
+        let mut interner = Interner::new(8, 4);
+        interner.intern(b"aaaaa");
+        assert!(interner.is_empty());
+    }
+}
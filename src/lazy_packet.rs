@@ -0,0 +1,219 @@
+//! A view of an encoded message that decodes options only when asked for.
+
+use alloc::vec::Vec;
+use core::cell::Cell;
+
+use crate::{
+    error::MessageError,
+    header::Header,
+    packet::{
+        decode_header, CoapOption, OptionWalker, Packet, ProtocolLimits,
+    },
+};
+
+/// A message whose header and token are decoded eagerly, but whose options
+/// are only decoded when they are looked up, borrowing their values from
+/// the buffer.
+///
+/// This suits dispatchers that route on a few options, e.g. the first
+/// Uri-Path segment, and would otherwise decode every option into a map.
+/// Lookups of increasing option numbers continue where the previous one
+/// stopped, so looking up options in order scans the message only once.
+///
+/// # Examples
+///
+/// ```
+/// use coap_lite::{CoapOption, LazyPacket, Packet};
+///
+/// let mut packet = Packet::new();
+/// packet.add_option(CoapOption::UriPath, b"sensors".to_vec());
+/// packet.add_option(CoapOption::UriPath, b"temp".to_vec());
+/// let bytes = packet.to_bytes().unwrap();
+///
+/// let lazy = LazyPacket::new(&bytes).unwrap();
+/// assert_eq!(
+///     Some(&b"sensors"[..]),
+///     lazy.first_option(CoapOption::UriPath).unwrap()
+/// );
+/// assert_eq!(packet, lazy.into_packet().unwrap());
+/// ```
+#[derive(Debug, Clone)]
+pub struct LazyPacket<'a> {
+    buf: &'a [u8],
+    header: Header,
+    token: &'a [u8],
+    options_start: usize,
+    /// The offset of the next option to scan and the number of the option
+    /// before it, so that all options up to that number have been scanned.
+    cursor: Cell<(usize, u16)>,
+}
+
+impl<'a> LazyPacket<'a> {
+    /// Decodes the header and token of a message.  Errors in the options or
+    /// payload are only reported when they are reached.
+    pub fn new(buf: &'a [u8]) -> Result<LazyPacket<'a>, MessageError> {
+        let (header, token) =
+            decode_header(buf, ProtocolLimits::RFC.max_token_length)?;
+        let options_start = token.end;
+        Ok(LazyPacket {
+            buf,
+            header,
+            token: &buf[token],
+            options_start,
+            cursor: Cell::new((options_start, 0)),
+        })
+    }
+
+    /// Returns the header.
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Returns the token.
+    pub fn token(&self) -> &'a [u8] {
+        self.token
+    }
+
+    /// Returns the values of an option, in order, or an error if a
+    /// malformed option precedes or directly follows them.
+    pub fn options(
+        &self,
+        option: CoapOption,
+    ) -> Result<Vec<&'a [u8]>, MessageError> {
+        let mut values = Vec::new();
+        self.scan(u16::from(option), |value| {
+            values.push(value);
+            true
+        })?;
+        Ok(values)
+    }
+
+    /// Returns the first value of an option, without looking further.
+    pub fn first_option(
+        &self,
+        option: CoapOption,
+    ) -> Result<Option<&'a [u8]>, MessageError> {
+        let mut first = None;
+        self.scan(u16::from(option), |value| {
+            first = Some(value);
+            false
+        })?;
+        Ok(first)
+    }
+
+    /// Passes the values of the option with the given number to `visit`
+    /// until it returns false.
+    fn scan(
+        &self,
+        number: u16,
+        mut visit: impl FnMut(&'a [u8]) -> bool,
+    ) -> Result<(), MessageError> {
+        let (offset, previous) = match self.cursor.get() {
+            (offset, previous) if previous < number => (offset, previous),
+            _ => (self.options_start, 0),
+        };
+        for option in OptionWalker::new(self.buf, offset, previous) {
+            let option = option?;
+            if option.number > number {
+                break;
+            }
+            if option.number < number {
+                self.cursor.set((option.end, option.number));
+            } else if !visit(option.value) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Decodes the whole message.
+    pub fn into_packet(self) -> Result<Packet, MessageError> {
+        Packet::from_bytes(self.buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+
+    use crate::testing::RawPacketBuilder;
+    use crate::{ContentFormat, MessageClass, MessageType, RequestType};
+
+    fn fixtures() -> Vec<Vec<u8>> {
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::NonConfirmable);
+        packet.header.code = MessageClass::Request(RequestType::Post);
+        packet.set_token(vec![1, 2, 3, 4]);
+        packet.add_option(CoapOption::UriHost, b"example.org".to_vec());
+        packet.add_option(CoapOption::UriPath, b"a".to_vec());
+        packet.add_option(CoapOption::UriPath, b"b".to_vec());
+        packet.set_content_format(ContentFormat::ApplicationJSON);
+        packet.add_option(CoapOption::UriQuery, b"x=1".to_vec());
+        packet.add_option(CoapOption::Unknown(2048), vec![0; 300]);
+        packet.payload = b"{}".to_vec();
+
+        vec![
+            Packet::new().to_bytes().unwrap(),
+            packet.to_bytes().unwrap(),
+            // Malformed options after Uri-Path
+            vec![0x40, 0x01, 0x00, 0x00, 0xB1, b'a', 0xF0],
+            vec![0x40, 0x01, 0x00, 0x00, 0xB1, b'a', 0x35],
+            // Payload marker without payload
+            vec![0x40, 0x01, 0x00, 0x00, 0xB1, b'a', 0xFF],
+        ]
+    }
+
+    #[test]
+    fn agrees_with_packet() {
+        let numbers = [1, 3, 11, 12, 15, 60, 2048, 4000];
+        for bytes in fixtures() {
+            let lazy = LazyPacket::new(&bytes).unwrap();
+            let expected = Packet::from_bytes(&bytes);
+            if let Ok(packet) = &expected {
+                assert_eq!(packet.header, *lazy.header());
+                assert_eq!(packet.get_token(), lazy.token());
+                // Look up out of order too, to exercise the cursor reset
+                for &number in numbers.iter().chain(numbers.iter().rev()) {
+                    let option = CoapOption::from(number);
+                    let values: Vec<&[u8]> = packet
+                        .get_option(option)
+                        .into_iter()
+                        .flatten()
+                        .map(|value| &value[..])
+                        .collect();
+                    assert_eq!(values, lazy.options(option).unwrap());
+                }
+            }
+            assert_eq!(expected, lazy.into_packet());
+        }
+    }
+
+    #[test]
+    fn errors_only_when_reached() {
+        let bytes = RawPacketBuilder::new()
+            .message_id(0)
+            .option(11, b"a")
+            .option_header(15, 0)
+            .build();
+        let lazy = LazyPacket::new(&bytes).unwrap();
+        assert_eq!(
+            Some(&b"a"[..]),
+            lazy.first_option(CoapOption::UriPath).unwrap()
+        );
+        assert_eq!(
+            Err(MessageError::InvalidOptionDelta { offset: 6 }),
+            lazy.first_option(CoapOption::UriQuery)
+        );
+
+        assert_eq!(
+            Err(MessageError::ReservedTokenLength(9)),
+            LazyPacket::new(&RawPacketBuilder::new().token_length(9).build())
+                .map(|_| ())
+        );
+        assert_eq!(
+            Err(MessageError::InvalidHeader),
+            LazyPacket::new(&[0x40, 0x01]).map(|_| ())
+        );
+    }
+}
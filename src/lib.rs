@@ -16,6 +16,10 @@
 //! - CoAP Observe Option [RFC 7641](https://tools.ietf.org/html/rfc7641)
 //! - Too Many Requests Response Code [RFC 8516](https://tools.ietf.org/html/rfc8516)
 //! - Block-Wise Transfers [RFC 7959](https://tools.ietf.org/html/rfc7959)
+//! - FETCH, PATCH and iPATCH Methods
+//!   [RFC 8132](https://tools.ietf.org/html/rfc8132)
+//! - CoAP over TCP message framing
+//!   [RFC 8323](https://tools.ietf.org/html/rfc8323)
 //! - Constrained RESTful Environments (CoRE) Link Format
 //!   [RFC6690](https://tools.ietf.org/html/rfc6690#:~:text=well-known%2Fcore)
 //!
@@ -76,7 +80,7 @@
 //!     let mut response = request.response.unwrap();
 //!     response.message.payload = b"OK".to_vec();
 //!
-//!     let packet = response.message.to_bytes().unwrap();
+//!     let packet = response.to_bytes_for(&request.message).unwrap();
 //!     socket.send_to(&packet[..], &src).expect("Could not send the data");
 //! }
 //! ```
@@ -118,6 +122,34 @@
 //! assert_eq!(b"Hello World!", &response.payload[..]);
 //! ```
 //!
+//! ## Matching on codes and options
+//!
+//! `CoapOption`, `ContentFormat`, `RequestType` and `ResponseType` are
+//! `#[non_exhaustive]`, so new options and codes can be added without a
+//! breaking release. A `match` on them needs a wildcard arm. Code that used to
+//! list every variant can use the helpers instead:
+//!
+//! - `known_variants()` returns all variants the crate knows, e.g. to build
+//!   lookup tables;
+//! - `CoapOption::as_u16()` and `ContentFormat::as_u16()` return the
+//!   registered numbers;
+//! - predicates like `CoapOption::is_critical()`, `RequestType::is_safe()`,
+//!   `ResponseType::is_success()` and `ContentFormat::encoding()` replace
+//!   matches that only sort variants into groups.
+//!
+//! ## Constructing requests and responses
+//!
+//! `CoapRequest` and `CoapResponse` carry private state next to their public
+//! fields: the extensions attached by the transport or middleware, and for
+//! requests the security context they were received with. They can no longer
+//! be built with a struct literal. Use a constructor and set the public fields
+//! afterwards instead:
+//!
+//! - `CoapRequest::new()` or `CoapRequest::from_packet(packet, source)`,
+//!   followed by `set_security()` if the transport protected the request;
+//! - `CoapResponse::new(&request.message)` to answer a request, or
+//!   `CoapResponse::from_packet(packet)` to wrap a received response.
+//!
 //! [coap]: https://github.com/covertness/coap-rs
 //! [rust-async-coap]: https://github.com/google/rust-async-coap
 
@@ -132,24 +164,62 @@ extern crate alloc;
 pub mod error;
 
 pub mod block_handler;
+#[cfg(feature = "bundle")]
+pub mod bundle;
+mod capabilities;
+pub mod consts;
+pub mod content_format;
+mod extensions;
+pub mod fit;
 mod header;
+mod interner;
+mod lazy_packet;
 pub mod link_format;
 #[macro_use]
 mod log;
+pub mod notification;
 mod observe;
+pub mod option_header;
+mod option_set;
 pub mod option_value;
 mod packet;
+mod packet_ref;
+pub mod rate_limit;
 mod request;
 mod response;
+mod tcp;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod transmission;
+pub mod uri;
 
 #[cfg(feature = "with-coap-message")]
 mod impl_coap_message;
 
 pub use block_handler::{BlockHandler, BlockHandlerConfig};
+pub use capabilities::{capabilities, Capabilities};
+pub use content_format::{ContentFormatRegistry, MultiAcceptPolicy};
+pub use extensions::Extensions;
 pub use header::{
-    Header, HeaderRaw, MessageClass, MessageType, RequestType, ResponseType,
+    Header, HeaderRaw, MessageClass, MessageType, RequestType,
+    ResponseCategory, ResponseType,
 };
+pub use interner::{InternedPacket, Interner};
+pub use lazy_packet::LazyPacket;
 pub use observe::{create_notification, Subject};
-pub use packet::{CoapOption, ContentFormat, ObserveOption, Packet};
-pub use request::CoapRequest;
+pub use option_set::OptionSet;
+pub use packet::{
+    option_overhead, CacheKey, CoapOption, ContentFormat, DecodeConfig,
+    EncodeConfig, EnvelopeInfo, Observe, ObserveOption, OptionSink, Packet,
+    PacketStats, ProtocolLimits, ProxyAudit, StreamingHeader, ValidationMode,
+    ValueOrder,
+};
+pub use packet_ref::{PacketRef, PacketRefOptions};
+pub use rate_limit::{OverloadGuard, RateLimiter, RateLimiterConfig};
+pub use request::{CoapRequest, SecurityContext};
 pub use response::CoapResponse;
+pub use transmission::{
+    ClientTransaction, DedupCache, RetransmissionState, RttEstimator,
+    TransmissionParameters,
+};
+pub use uri::CoapUri;
@@ -1,5 +1,6 @@
 #[cfg(feature = "log")]
 macro_rules! coap_log {
+    (warn, $($arg:expr),*) => { log::warn!($($arg),*); };
     (info, $($arg:expr),*) => { log::info!($($arg),*); };
     (debug, $($arg:expr),*) => { log::debug!($($arg),*); };
 }
@@ -9,6 +10,10 @@ macro_rules! coap_log {
     ($level:ident, $($arg:expr),*) => { $( let _ = $arg; )* }
 }
 
+macro_rules! coap_warn {
+    ($($arg:expr),*) => (coap_log!(warn, $($arg),*));
+}
+
 macro_rules! coap_info {
     ($($arg:expr),*) => (coap_log!(info, $($arg),*));
 }
@@ -0,0 +1,366 @@
+//! The client side of Observe (RFC 7641 section 3): deciding what to do with
+//! the notifications received for observations.
+//!
+//! Like the transmission helpers, nothing in here performs any I/O or reads
+//! a clock.
+
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use crate::{
+    header::{MessageClass, MessageType},
+    packet::{Observe, Packet},
+    response::CoapResponse,
+    transmission::DedupCache,
+};
+
+/// How long a notification is considered newer than the previous one
+/// regardless of the sequence numbers (RFC 7641 section 3.4).
+pub const FRESHNESS_WINDOW: Duration = Duration::from_secs(128);
+
+/// Returns whether the Observe sequence number `sequence` is newer than
+/// `latest`, comparing the lower 24 bits in serial number arithmetic so that
+/// the numbers can wrap around (RFC 7641 section 3.4).
+///
+/// This ignores the time the notifications were received at, see
+/// [`ObserveFreshness`] for that.
+///
+/// ```
+/// use coap_lite::notification::is_newer_sequence;
+///
+/// assert!(is_newer_sequence(11, 10));
+/// assert!(!is_newer_sequence(10, 11));
+/// // Across the wrap boundary
+/// assert!(is_newer_sequence(2, 0xFF_FFFE));
+/// ```
+pub fn is_newer_sequence(sequence: u32, latest: u32) -> bool {
+    let (v1, v2) = (latest & 0xFF_FFFF, sequence & 0xFF_FFFF);
+    (v1 < v2 && v2 - v1 < 1 << 23) || (v1 > v2 && v1 - v2 > 1 << 23)
+}
+
+/// Tracks the Observe sequence numbers of one observation, to tell newer
+/// notifications from reordered older ones (RFC 7641 section 3.4).
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ObserveFreshness {
+    latest: Option<(u32, Duration)>,
+}
+
+impl ObserveFreshness {
+    /// Creates a tracker that hasn't seen any notification yet.
+    pub fn new() -> ObserveFreshness {
+        Default::default()
+    }
+
+    /// Returns whether a notification with the given sequence number,
+    /// received at `now`, is newer than the latest one.
+    pub fn is_fresh(&self, sequence: u32, now: Duration) -> bool {
+        let (latest, received_at) = match self.latest {
+            Some(latest) => latest,
+            None => return true,
+        };
+        is_newer_sequence(sequence, latest)
+            || now > received_at + FRESHNESS_WINDOW
+    }
+
+    /// Records the notification if it's fresh, returning whether it was.
+    pub fn update(&mut self, sequence: u32, now: Duration) -> bool {
+        let fresh = self.is_fresh(sequence, now);
+        if fresh {
+            self.latest = Some((sequence, now));
+        }
+        fresh
+    }
+
+    /// Returns the sequence number of the latest notification.
+    pub fn latest(&self) -> Option<u32> {
+        self.latest.map(|(sequence, _)| sequence)
+    }
+}
+
+/// What to do with a message passed to [`NotificationReceiver::handle`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotificationAction {
+    /// Process the notification and acknowledge it if it's confirmable.
+    ProcessAndAck,
+    /// Only acknowledge the notification, which is a retransmission or
+    /// older than the latest one.
+    AckOnly,
+    /// Reject the notification, which doesn't belong to an observation.
+    Rst,
+    /// Drop the message.
+    Ignore,
+}
+
+impl NotificationAction {
+    /// Returns the empty acknowledgement or reset to send in reply to
+    /// `notification`, if any.
+    pub fn reply(&self, notification: &Packet) -> Option<Packet> {
+        match self {
+            NotificationAction::ProcessAndAck
+            | NotificationAction::AckOnly => {
+                CoapResponse::new_empty_ack(notification)
+            }
+            NotificationAction::Rst => {
+                let mut reset = Packet::new();
+                reset.header.set_type(MessageType::Reset);
+                reset.header.code = MessageClass::Empty;
+                reset.header.message_id = notification.header.message_id;
+                Some(reset)
+            }
+            NotificationAction::Ignore => None,
+        }
+    }
+}
+
+// An observation the client registered for.
+#[derive(Debug, Clone)]
+struct Observation<Endpoint> {
+    endpoint: Endpoint,
+    token: Vec<u8>,
+    freshness: ObserveFreshness,
+}
+
+/// Decides what to do with the notifications of the observations a client
+/// registered for, combining the detection of retransmissions by message ID
+/// with the freshness of the Observe sequence numbers.
+///
+/// A retransmitted notification, or one older than the latest, is
+/// acknowledged if confirmable but not processed again.  A notification
+/// without the Observe option ends the observation.
+#[derive(Debug, Clone)]
+pub struct NotificationReceiver<Endpoint> {
+    dedup: DedupCache<Endpoint>,
+    observations: Vec<Observation<Endpoint>>,
+}
+
+impl<Endpoint: Clone + PartialEq> NotificationReceiver<Endpoint> {
+    /// Creates a receiver without any observations.
+    pub fn new() -> NotificationReceiver<Endpoint> {
+        NotificationReceiver {
+            dedup: DedupCache::new(),
+            observations: Vec::new(),
+        }
+    }
+
+    /// Starts accepting notifications with `token` from `endpoint`.
+    pub fn observe(&mut self, endpoint: Endpoint, token: Vec<u8>) {
+        self.cancel(&endpoint, &token);
+        self.observations.push(Observation {
+            endpoint,
+            token,
+            freshness: ObserveFreshness::new(),
+        });
+    }
+
+    /// Stops accepting notifications with `token` from `endpoint`,
+    /// returning whether there was such an observation.
+    pub fn cancel(&mut self, endpoint: &Endpoint, token: &[u8]) -> bool {
+        let before = self.observations.len();
+        self.observations.retain(|observation| {
+            observation.endpoint != *endpoint || observation.token != token
+        });
+        before != self.observations.len()
+    }
+
+    /// Decides what to do with `packet` received from `endpoint`.
+    pub fn handle(
+        &mut self,
+        packet: &Packet,
+        endpoint: &Endpoint,
+        now: Duration,
+    ) -> NotificationAction {
+        if !matches!(packet.header.code, MessageClass::Response(_)) {
+            return NotificationAction::Ignore;
+        }
+        let message_type = packet.header.get_type();
+        let confirmable = message_type == MessageType::Confirmable;
+        let not_processed = if confirmable {
+            NotificationAction::AckOnly
+        } else {
+            NotificationAction::Ignore
+        };
+
+        let piggybacked = message_type == MessageType::Acknowledgement;
+        let message_id = packet.header.message_id;
+        if !piggybacked && self.dedup.contains(endpoint, message_id, now) {
+            return not_processed;
+        }
+
+        let position = self.observations.iter().position(|observation| {
+            observation.endpoint == *endpoint
+                && observation.token == packet.get_token()
+        });
+        let position = match position {
+            Some(position) => position,
+            None if piggybacked => return NotificationAction::Ignore,
+            None => return NotificationAction::Rst,
+        };
+        // Only the message IDs of matched notifications are remembered, so
+        // that the retransmission of a rejected one is rejected again
+        if !piggybacked {
+            self.dedup.remember(endpoint, message_id, now);
+        }
+
+        match packet.get_observe() {
+            Some(Ok(Observe::Sequence(sequence))) => {
+                let observation = &mut self.observations[position];
+                if observation.freshness.update(sequence, now) {
+                    NotificationAction::ProcessAndAck
+                } else {
+                    not_processed
+                }
+            }
+            _ => {
+                self.observations.remove(position);
+                NotificationAction::ProcessAndAck
+            }
+        }
+    }
+}
+
+impl<Endpoint: Clone + PartialEq> Default for NotificationReceiver<Endpoint> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::header::ResponseType;
+
+    fn notification(
+        message_type: MessageType,
+        message_id: u16,
+        sequence: Option<u32>,
+    ) -> Packet {
+        let mut packet = Packet::new();
+        packet.header.set_type(message_type);
+        packet.header.code = MessageClass::Response(ResponseType::Content);
+        packet.header.message_id = message_id;
+        packet.set_token(vec![0x7A]);
+        if let Some(sequence) = sequence {
+            packet.set_observe(Observe::Sequence(sequence));
+        }
+        packet
+    }
+
+    #[test]
+    fn freshness() {
+        let now = Duration::from_secs(1);
+        let mut freshness = ObserveFreshness::new();
+        assert!(freshness.update(10, now));
+        assert!(!freshness.update(9, now));
+        assert!(!freshness.update(10, now));
+        assert!(freshness.update(11, now));
+
+        // Wrapping around the 24 bit sequence space
+        let mut freshness = ObserveFreshness::new();
+        freshness.update(0xFF_FFFE, now);
+        assert!(freshness.is_fresh(1, now));
+        assert!(!freshness.is_fresh(0x7F_FFFE, now));
+
+        // Older sequence numbers are fresh again after 128 seconds
+        assert!(!freshness.is_fresh(0xFF_FFF0, now + FRESHNESS_WINDOW));
+        let later = now + FRESHNESS_WINDOW + Duration::from_secs(1);
+        assert!(freshness.is_fresh(0xFF_FFF0, later));
+    }
+
+    #[test]
+    fn newer_sequence() {
+        assert!(is_newer_sequence(1, 0));
+        assert!(!is_newer_sequence(0, 0));
+        assert!(!is_newer_sequence(0, 1));
+        // Half of the sequence space ahead is newer, the other half older
+        assert!(is_newer_sequence((1 << 23) - 1, 0));
+        assert!(!is_newer_sequence(1 << 23, 0));
+        assert!(!is_newer_sequence((1 << 23) + 1, 0));
+        assert!(is_newer_sequence(0, 0xFF_FFFF));
+        assert!(is_newer_sequence(0x7F_FFFE, 0xFF_FFFF));
+        assert!(!is_newer_sequence(0xFF_FFFF, 0));
+        // Only the lower 24 bits count
+        assert!(is_newer_sequence(0x100_0001, 0));
+    }
+
+    #[test]
+    fn retransmitted_confirmable() {
+        let mut receiver = NotificationReceiver::new();
+        receiver.observe("server", vec![0x7A]);
+        let now = Duration::from_secs(1);
+
+        let first = notification(MessageType::Confirmable, 100, Some(5));
+        assert_eq!(
+            NotificationAction::ProcessAndAck,
+            receiver.handle(&first, &"server", now)
+        );
+        // The acknowledgement was lost and the server retransmits
+        let later = now + Duration::from_secs(2);
+        let action = receiver.handle(&first, &"server", later);
+        assert_eq!(NotificationAction::AckOnly, action);
+        let ack = action.reply(&first).unwrap();
+        assert_eq!(MessageType::Acknowledgement, ack.header.get_type());
+        assert_eq!(100, ack.header.message_id);
+    }
+
+    #[test]
+    fn older_sequence_on_new_message_id() {
+        let mut receiver = NotificationReceiver::new();
+        receiver.observe("server", vec![0x7A]);
+        let now = Duration::from_secs(1);
+
+        let latest = notification(MessageType::NonConfirmable, 100, Some(5));
+        assert_eq!(
+            NotificationAction::ProcessAndAck,
+            receiver.handle(&latest, &"server", now)
+        );
+        assert_eq!(None, NotificationAction::ProcessAndAck.reply(&latest));
+
+        let older = notification(MessageType::NonConfirmable, 101, Some(4));
+        assert_eq!(
+            NotificationAction::Ignore,
+            receiver.handle(&older, &"server", now)
+        );
+        let older = notification(MessageType::Confirmable, 102, Some(3));
+        assert_eq!(
+            NotificationAction::AckOnly,
+            receiver.handle(&older, &"server", now)
+        );
+        let newer = notification(MessageType::Confirmable, 103, Some(6));
+        assert_eq!(
+            NotificationAction::ProcessAndAck,
+            receiver.handle(&newer, &"server", now)
+        );
+    }
+
+    #[test]
+    fn unknown_and_ended_observations() {
+        let mut receiver = NotificationReceiver::new();
+        let now = Duration::from_secs(1);
+
+        let stray = notification(MessageType::Confirmable, 100, Some(5));
+        let action = receiver.handle(&stray, &"server", now);
+        assert_eq!(NotificationAction::Rst, action);
+        let reset = action.reply(&stray).unwrap();
+        assert_eq!(MessageType::Reset, reset.header.get_type());
+        // Its retransmission is rejected again rather than acknowledged
+        let later = now + Duration::from_secs(2);
+        assert_eq!(
+            NotificationAction::Rst,
+            receiver.handle(&stray, &"server", later)
+        );
+
+        // A response without the Observe option ends the observation
+        receiver.observe("server", vec![0x7A]);
+        let last = notification(MessageType::Confirmable, 101, None);
+        assert_eq!(
+            NotificationAction::ProcessAndAck,
+            receiver.handle(&last, &"server", now)
+        );
+        let after = notification(MessageType::Confirmable, 102, Some(6));
+        assert_eq!(
+            NotificationAction::Rst,
+            receiver.handle(&after, &"server", now)
+        );
+        assert!(!receiver.cancel(&"server", &[0x7A]));
+    }
+}
@@ -5,10 +5,23 @@ use alloc::{
 };
 use core::{fmt::Display, marker::PhantomData};
 
-use crate::{request::CoapRequest, MessageClass, MessageType, Packet};
+use crate::{
+    error::InvalidSnapshot, request::CoapRequest,
+    transmission::MAX_RETRANSMIT, MessageClass, MessageType, Observe, Packet,
+};
 
 const DEFAULT_UNACKNOWLEDGED_LIMIT: u8 = 10;
 
+/// The version of the format written by [`Subject::snapshot`].
+const SNAPSHOT_VERSION: u8 = 1;
+
+/// Observe sequence numbers are 24-bit values (RFC 7641 §4.4).
+const SEQUENCE_MASK: u32 = 0xFF_FFFF;
+
+/// The largest jump that still makes the sequence numbers look fresher to
+/// clients, which take any difference of 2^23 or more as going backwards.
+const MAX_SEQUENCE_JUMP: u32 = (1 << 23) - 1;
+
 type ResourcePath = String;
 
 /// An observer client.
@@ -18,6 +31,10 @@ pub struct Observer<Endpoint: Display> {
     unacknowledged_messages: u8,
     // The message id of the last update to be acknowledged
     message_id: Option<u16>,
+    // The message id of the confirmable notification in flight
+    con_message_id: Option<u16>,
+    // The number of confirmable notifications in a row that failed
+    failed_notifications: u8,
 }
 
 /// An observed resource.
@@ -30,6 +47,12 @@ pub struct Resource<Endpoint: Display> {
 pub struct Subject<Endpoint: Display + PartialEq> {
     resources: BTreeMap<ResourcePath, Resource<Endpoint>>,
     unacknowledged_limit: u8,
+    con_failure_limit: u8,
+    // Observers removed after failed confirmable notifications, until
+    // drained
+    removed: Vec<(ResourcePath, Endpoint)>,
+    // The sequence number resources start out with
+    initial_sequence: u32,
     // The Endpoint generic is needed internally for CoapRequest, but not as an
     // actual field for this struct
     phantom: PhantomData<Endpoint>,
@@ -47,6 +70,8 @@ impl<Endpoint: Display + PartialEq + Clone> Subject<Endpoint> {
             token: token.to_vec(),
             unacknowledged_messages: 0,
             message_id: None,
+            con_message_id: None,
+            failed_notifications: 0,
         };
 
         coap_info!(
@@ -58,7 +83,7 @@ impl<Endpoint: Display + PartialEq + Clone> Subject<Endpoint> {
         let resource =
             self.resources.entry(resource_path).or_insert(Resource {
                 observers: Vec::new(),
-                sequence: 0,
+                sequence: self.initial_sequence,
             });
 
         if let Some(position) = resource
@@ -103,37 +128,55 @@ impl<Endpoint: Display + PartialEq + Clone> Subject<Endpoint> {
         let unacknowledged_limit = self.unacknowledged_limit;
         coap_debug!("Resource changed");
 
-        self.resources
-            .entry(resource.to_string())
-            .and_modify(|resource| {
-                resource.sequence += 1;
-
-                resource.observers.iter_mut().for_each(|observer| {
-                    observer.unacknowledged_messages += 1;
-                    observer.message_id = Some(message_id);
-                });
-
-                resource.observers.retain(|observer| {
-                    observer.unacknowledged_messages <= unacknowledged_limit
-                });
+        if let Some(entry) = self.resources.get_mut(resource) {
+            entry.sequence = entry.sequence.wrapping_add(1) & SEQUENCE_MASK;
+
+            entry.observers.iter_mut().for_each(|observer| {
+                observer.unacknowledged_messages += 1;
+                observer.message_id = Some(message_id);
+            });
+
+            entry.observers.retain(|observer| {
+                observer.unacknowledged_messages <= unacknowledged_limit
             });
+        }
     }
 
     /// Resets the counter of unacknowledged updates for a resource observer.
     pub fn acknowledge(&mut self, request: &CoapRequest<Endpoint>) {
         let observer_endpoint = request.source.as_ref().unwrap();
-        let message_id = request.message.header.message_id;
+        self.on_ack(observer_endpoint, request.message.header.message_id);
+    }
+
+    /// Records that a confirmable notification with the given message ID
+    /// was sent to the observer of `resource` at `endpoint`.
+    ///
+    /// There should be no more than one of them in flight per observer (RFC
+    /// 7641 section 4.5.1), see [`Subject::can_send_con`].
+    pub fn on_con_sent(
+        &mut self,
+        resource: &str,
+        endpoint: &Endpoint,
+        message_id: u16,
+    ) {
+        if let Some(observer) = self.find_observer(resource, endpoint) {
+            observer.con_message_id = Some(message_id);
+        }
+    }
 
+    /// Handles the acknowledgement with the given message ID from
+    /// `endpoint`, which resets the counters of unacknowledged updates and
+    /// failed notifications of the observer it belongs to.  Returns whether
+    /// it belonged to an observer.
+    pub fn on_ack(&mut self, endpoint: &Endpoint, message_id: u16) -> bool {
+        let mut found = false;
         for (resource_path, resource) in self.resources.iter_mut() {
+            // Acknowledgements don't officially require the token to be
+            // passed so it's not checked
             let observer = resource.observers.iter_mut().find(|x| {
-                if let Some(observe_msg_id) = x.message_id {
-                    // Unacknowledgement doesn't officially require the token
-                    // to be passed in the ACK so it's not checked
-                    return x.endpoint == *observer_endpoint
-                        && observe_msg_id == message_id;
-                }
-
-                false
+                x.endpoint == *endpoint
+                    && (x.message_id == Some(message_id)
+                        || x.con_message_id == Some(message_id))
             });
 
             if let Some(observer) = observer {
@@ -141,8 +184,80 @@ impl<Endpoint: Display + PartialEq + Clone> Subject<Endpoint> {
 
                 observer.unacknowledged_messages = 0;
                 observer.message_id = None;
+                observer.con_message_id = None;
+                observer.failed_notifications = 0;
+                found = true;
             }
         }
+        found
+    }
+
+    /// Records that the confirmable notification in flight to the observer
+    /// of `resource` at `endpoint` failed, i.e. it wasn't acknowledged
+    /// despite retransmissions.  After [`Subject::set_con_failure_limit`]
+    /// failures in a row the observer is removed, and reported by
+    /// [`Subject::removed`].
+    pub fn on_con_failed(&mut self, resource: &str, endpoint: &Endpoint) {
+        let limit = self.con_failure_limit;
+        let Some(entry) = self.resources.get_mut(resource) else {
+            return;
+        };
+        for observer in entry.observers.iter_mut() {
+            if observer.endpoint == *endpoint {
+                observer.con_message_id = None;
+                observer.failed_notifications =
+                    observer.failed_notifications.saturating_add(1);
+            }
+        }
+        let removed = &mut self.removed;
+        entry.observers.retain(|observer| {
+            if observer.failed_notifications < limit {
+                return true;
+            }
+            coap_info!(
+                "Removing unresponsive observer {} of resource {}",
+                observer.endpoint,
+                resource
+            );
+            removed.push((resource.to_string(), observer.endpoint.clone()));
+            false
+        });
+    }
+
+    /// Returns whether a confirmable notification may be sent to the
+    /// observer of `resource` at `endpoint`, which is the case unless one is
+    /// still in flight.  Meanwhile, the server should send non-confirmable
+    /// notifications or skip intermediate states.
+    pub fn can_send_con(&self, resource: &str, endpoint: &Endpoint) -> bool {
+        self.resources
+            .get(resource)
+            .and_then(|resource| {
+                resource.observers.iter().find(|x| x.endpoint == *endpoint)
+            })
+            .is_some_and(|observer| observer.con_message_id.is_none())
+    }
+
+    /// Returns the resources and endpoints of the observers that were
+    /// removed by [`Subject::on_con_failed`] since the last call.  They are
+    /// kept until then, so a server using `on_con_failed` has to call this
+    /// regularly.
+    ///
+    /// Observers removed by [`Subject::resource_changed`] for exceeding the
+    /// limit of unacknowledged updates aren't reported.
+    pub fn removed(&mut self) -> Vec<(String, Endpoint)> {
+        core::mem::take(&mut self.removed)
+    }
+
+    fn find_observer(
+        &mut self,
+        resource: &str,
+        endpoint: &Endpoint,
+    ) -> Option<&mut Observer<Endpoint>> {
+        self.resources
+            .get_mut(resource)?
+            .observers
+            .iter_mut()
+            .find(|x| x.endpoint == *endpoint)
     }
 
     /// Gets the tracked resources.
@@ -164,6 +279,110 @@ impl<Endpoint: Display + PartialEq + Clone> Subject<Endpoint> {
     pub fn set_unacknowledged_limit(&mut self, limit: u8) {
         self.unacknowledged_limit = limit;
     }
+
+    /// Sets the number of failed confirmable notifications in a row before
+    /// removing an observer, which is `MAX_RETRANSMIT` by default.
+    pub fn set_con_failure_limit(&mut self, limit: u8) {
+        self.con_failure_limit = limit;
+    }
+
+    /// Serializes the sequence numbers of the resources, so that they can be
+    /// stored across a reboot and passed to [`Subject::restore`].  Otherwise
+    /// the sequence numbers start over, and observers would discard the
+    /// notifications as stale until they catch up with the old ones.
+    ///
+    /// The observers are not part of the snapshot, as they have to register
+    /// again after a reboot anyway.  The format starts with a version byte,
+    /// followed by the path length (2 bytes), path and sequence number (4
+    /// bytes) of each resource, in network byte order.
+    ///
+    /// Fails if a path is longer than 65535 bytes, as it couldn't be
+    /// restored.
+    pub fn snapshot(&self) -> Result<Vec<u8>, InvalidSnapshot> {
+        let mut snapshot = vec![SNAPSHOT_VERSION];
+        for (path, resource) in self.resources.iter() {
+            let length =
+                u16::try_from(path.len()).map_err(|_| InvalidSnapshot)?;
+            snapshot.extend_from_slice(&length.to_be_bytes());
+            snapshot.extend_from_slice(path.as_bytes());
+            snapshot.extend_from_slice(&resource.sequence.to_be_bytes());
+        }
+        Ok(snapshot)
+    }
+
+    /// Restores the sequence numbers of the resources from a snapshot taken
+    /// by [`Subject::snapshot`], keeping any registered observers.
+    ///
+    /// Nothing is restored if the snapshot is invalid, which includes
+    /// sequence numbers that don't fit into 24 bits.
+    pub fn restore(&mut self, snapshot: &[u8]) -> Result<(), InvalidSnapshot> {
+        let mut sequences = Vec::new();
+        let mut rest = match snapshot.split_first() {
+            Some((&SNAPSHOT_VERSION, rest)) => rest,
+            _ => return Err(InvalidSnapshot),
+        };
+        while !rest.is_empty() {
+            let (length, tail) = split_array::<2>(rest)?;
+            let length = usize::from(u16::from_be_bytes(length));
+            if tail.len() < length {
+                return Err(InvalidSnapshot);
+            }
+            let (path, tail) = tail.split_at(length);
+            let path =
+                core::str::from_utf8(path).map_err(|_| InvalidSnapshot)?;
+            let (sequence, tail) = split_array::<4>(tail)?;
+            let sequence = u32::from_be_bytes(sequence);
+            if sequence > SEQUENCE_MASK {
+                return Err(InvalidSnapshot);
+            }
+            sequences.push((path.to_string(), sequence));
+            rest = tail;
+        }
+
+        for (path, sequence) in sequences {
+            self.resources
+                .entry(path)
+                .or_insert(Resource {
+                    observers: Vec::new(),
+                    sequence,
+                })
+                .sequence = sequence;
+        }
+        Ok(())
+    }
+
+    /// Advances the sequence numbers of all resources by `gap`, including
+    /// those of resources that are registered later on.
+    ///
+    /// This is meant to be called after a reboot, either after restoring a
+    /// snapshot that may not reflect the last notifications, or instead of
+    /// it if none was stored.  The gap should exceed the number of
+    /// notifications that can have been sent since the snapshot was taken,
+    /// or without one since the subject was created.
+    ///
+    /// Sequence numbers wrap at 2^24, and clients take a jump of 2^23 or
+    /// more as going backwards (RFC 7641 §3.4), so `gap` is clamped to
+    /// 2^23 - 1.
+    pub fn resume_with_jump(&mut self, gap: u32) {
+        let gap = gap.min(MAX_SEQUENCE_JUMP);
+        self.initial_sequence =
+            self.initial_sequence.wrapping_add(gap) & SEQUENCE_MASK;
+        for resource in self.resources.values_mut() {
+            resource.sequence =
+                resource.sequence.wrapping_add(gap) & SEQUENCE_MASK;
+        }
+    }
+}
+
+/// Splits the first `N` bytes off a snapshot.
+fn split_array<const N: usize>(
+    buf: &[u8],
+) -> Result<([u8; N], &[u8]), InvalidSnapshot> {
+    if buf.len() < N {
+        return Err(InvalidSnapshot);
+    }
+    let (head, tail) = buf.split_at(N);
+    Ok((head.try_into().unwrap(), tail))
 }
 
 /// Creates a notification response for notifying observers about an update.
@@ -181,7 +400,7 @@ pub fn create_notification(
     packet.header.message_id = message_id;
     packet.set_token(token);
     packet.payload = payload;
-    packet.set_observe_value(sequence);
+    packet.set_observe(Observe::Sequence(sequence));
 
     packet
 }
@@ -191,6 +410,9 @@ impl<Endpoint: Display + PartialEq + Clone> Default for Subject<Endpoint> {
         Subject {
             resources: BTreeMap::new(),
             unacknowledged_limit: DEFAULT_UNACKNOWLEDGED_LIMIT,
+            con_failure_limit: MAX_RETRANSMIT,
+            removed: Vec::new(),
+            initial_sequence: 0,
             phantom: PhantomData,
         }
     }
@@ -201,11 +423,21 @@ mod test {
     use super::*;
     use crate::{
         header::{MessageType, RequestType as Method},
+        notification::is_newer_sequence,
         packet::ObserveOption,
     };
 
     type Endpoint = String;
 
+    fn register_request(path: &str) -> CoapRequest<Endpoint> {
+        let mut request = CoapRequest::new();
+        request.source = Some(String::from("0.0.0.0"));
+        request.set_method(Method::Get);
+        request.set_path(path);
+        request.set_observe_flag(ObserveOption::Register);
+        request
+    }
+
     #[test]
     fn register() {
         let resource_path = "temp";
@@ -335,5 +567,184 @@ mod test {
             .unwrap();
 
         assert_eq!(observers.len(), 0);
+        assert!(subject.removed().is_empty());
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let mut request = register_request("temp");
+
+        let mut subject: Subject<Endpoint> = Subject::default();
+        subject.register(&request);
+        request.set_path("hum");
+        subject.register(&request);
+        for message_id in 0..3 {
+            subject.resource_changed("temp", message_id);
+        }
+        subject.resource_changed("hum", 3);
+
+        let snapshot = subject.snapshot().unwrap();
+        assert_eq!(1, snapshot[0]);
+
+        let mut rebooted: Subject<Endpoint> = Subject::default();
+        rebooted.restore(&snapshot).unwrap();
+        assert_eq!(snapshot, rebooted.snapshot().unwrap());
+        assert_eq!(3, rebooted.get_resource("temp").unwrap().sequence);
+        assert_eq!(1, rebooted.get_resource("hum").unwrap().sequence);
+        assert!(rebooted.get_resource("temp").unwrap().observers.is_empty());
+
+        // Registering again keeps the restored sequence number
+        rebooted.register(&request);
+        assert_eq!(1, rebooted.get_resource("hum").unwrap().sequence);
+
+        let mut invalid: Subject<Endpoint> = Subject::default();
+        assert_eq!(Err(InvalidSnapshot), invalid.restore(&[]));
+        assert_eq!(Err(InvalidSnapshot), invalid.restore(&[2]));
+        assert_eq!(
+            Err(InvalidSnapshot),
+            invalid.restore(&snapshot[..snapshot.len() - 1])
+        );
+        assert!(invalid.get_resource("temp").is_none());
+        assert_eq!(
+            Err(InvalidSnapshot),
+            invalid.restore(&[1, 0, 1, b'a', 0x01, 0x00, 0x00, 0x00])
+        );
+        assert!(invalid.get_resource("a").is_none());
+
+        // A path that doesn't fit the length field can't be snapshotted
+        request.set_path(&"a".repeat(65536));
+        subject.register(&request);
+        assert_eq!(Err(InvalidSnapshot), subject.snapshot());
+    }
+
+    #[test]
+    fn resume_with_jump() {
+        let request = register_request("temp");
+
+        let mut subject: Subject<Endpoint> = Subject::default();
+        subject.register(&request);
+        subject.resource_changed("temp", 0);
+        let snapshot = subject.snapshot().unwrap();
+
+        // Notifications sent after the snapshot was taken
+        for message_id in 1..10 {
+            subject.resource_changed("temp", message_id);
+        }
+        let last_sequence = subject.get_resource("temp").unwrap().sequence;
+
+        let mut rebooted: Subject<Endpoint> = Subject::default();
+        rebooted.restore(&snapshot).unwrap();
+        rebooted.resume_with_jump(100);
+        assert!(
+            rebooted.get_resource("temp").unwrap().sequence > last_sequence
+        );
+
+        // Without a snapshot the jump applies to resources registered later
+        let mut rebooted: Subject<Endpoint> = Subject::default();
+        rebooted.resume_with_jump(100);
+        rebooted.register(&request);
+        assert!(
+            rebooted.get_resource("temp").unwrap().sequence > last_sequence
+        );
+    }
+
+    #[test]
+    fn resume_with_large_jump_near_wrap() {
+        let request = register_request("temp");
+
+        let mut subject: Subject<Endpoint> = Subject::default();
+        subject.register(&request);
+        subject.resume_with_jump(0xFF_FFF0);
+        let snapshot = subject.snapshot().unwrap();
+        for message_id in 0..0x20 {
+            subject.resource_changed("temp", message_id);
+        }
+        let last_sequence = subject.get_resource("temp").unwrap().sequence;
+        assert!(last_sequence <= 0xFF_FFFF);
+
+        for gap in [1 << 23, 1 << 24, u32::MAX] {
+            let mut rebooted: Subject<Endpoint> = Subject::default();
+            rebooted.restore(&snapshot).unwrap();
+            rebooted.resume_with_jump(gap);
+            let sequence = rebooted.get_resource("temp").unwrap().sequence;
+            assert!(sequence <= 0xFF_FFFF);
+            assert!(is_newer_sequence(sequence, last_sequence));
+        }
+    }
+
+    #[test]
+    fn resource_changed_wraps_at_24_bits() {
+        let request = register_request("temp");
+
+        let mut subject: Subject<Endpoint> = Subject::default();
+        subject
+            .restore(&[
+                1, 0, 4, b't', b'e', b'm', b'p', 0x00, 0xFF, 0xFF, 0xFF,
+            ])
+            .unwrap();
+        subject.register(&request);
+        subject.resource_changed("temp", 0);
+        assert_eq!(0, subject.get_resource("temp").unwrap().sequence);
+    }
+
+    #[test]
+    fn con_backpressure() {
+        let resource_path = "temp";
+        let endpoint = String::from("0.0.0.0");
+        let request = register_request(resource_path);
+
+        let mut subject: Subject<Endpoint> = Subject::default();
+        subject.set_con_failure_limit(3);
+        subject.register(&request);
+        assert!(subject.can_send_con(resource_path, &endpoint));
+        assert!(!subject.can_send_con("other", &endpoint));
+
+        subject.on_con_sent(resource_path, &endpoint, 1);
+        assert!(!subject.can_send_con(resource_path, &endpoint));
+        assert!(!subject.on_ack(&endpoint, 2));
+        assert!(subject.on_ack(&endpoint, 1));
+        assert!(subject.can_send_con(resource_path, &endpoint));
+
+        // The observer stops acknowledging
+        for message_id in 2..5 {
+            assert!(subject.removed().is_empty());
+            subject.on_con_sent(resource_path, &endpoint, message_id);
+            subject.on_con_failed(resource_path, &endpoint);
+        }
+        assert_eq!(
+            vec![(resource_path.to_string(), endpoint.clone())],
+            subject.removed()
+        );
+        assert!(subject.removed().is_empty());
+        assert!(subject
+            .get_resource_observers(resource_path)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn con_failures_reset_by_ack() {
+        let resource_path = "temp";
+        let endpoint = String::from("0.0.0.0");
+
+        let mut request = CoapRequest::new();
+        request.source = Some(endpoint.clone());
+        request.set_path(resource_path);
+
+        let mut subject: Subject<Endpoint> = Subject::default();
+        subject.register(&request);
+        for message_id in 0..u16::from(MAX_RETRANSMIT) * 2 {
+            subject.on_con_sent(resource_path, &endpoint, message_id);
+            if message_id % 2 == 0 {
+                subject.on_con_failed(resource_path, &endpoint);
+            } else {
+                subject.on_ack(&endpoint, message_id);
+            }
+        }
+        assert!(subject.removed().is_empty());
+        assert_eq!(
+            1,
+            subject.get_resource_observers(resource_path).unwrap().len()
+        );
     }
 }
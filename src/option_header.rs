@@ -0,0 +1,217 @@
+//! The codec of option headers (RFC 7252 section 3.1): the byte holding the
+//! delta and length nibbles, followed by their extended values if any.
+//!
+//! This is what the packet codec uses for each option; it's exposed for
+//! tools that frame or dissect options themselves.
+
+use crate::error::MessageError;
+
+/// The maximum length of an option header.
+pub const MAX_LEN: usize = 5;
+
+/// The largest value a delta or length can be encoded with.
+const MAX_EXTENDED: usize = 0xFFFF + 269;
+
+/// Writes the header of an option with the given delta to the number of the
+/// previous option and value length into `out`, returning the number of
+/// bytes written, which is between 1 and [`MAX_LEN`].  The number needed is
+/// also returned by [`crate::option_overhead`].
+///
+/// Returns `None` without writing anything if `len` exceeds 65804, the
+/// largest length that can be encoded.
+///
+/// # Panics
+///
+/// Panics if `out` is too short for the header.
+pub fn encode(delta: u16, len: usize, out: &mut [u8]) -> Option<usize> {
+    if len > MAX_EXTENDED {
+        return None;
+    }
+    let (delta_nibble, delta_extended) = nibble(usize::from(delta));
+    let (length_nibble, length_extended) = nibble(len);
+
+    out[0] = delta_nibble << 4 | length_nibble;
+    let mut written = 1;
+    for extended in [delta_extended, length_extended] {
+        out[written..written + extended.len()]
+            .copy_from_slice(extended.as_slice());
+        written += extended.len();
+    }
+    Some(written)
+}
+
+/// Reads the header of an option from the front of `buf`, returning the
+/// delta, the length of the value and the number of bytes read.  The value
+/// itself isn't checked to be present.
+///
+/// The reserved nibble 15 and deltas that don't fit into an option number
+/// are errors, as is an empty `buf`.  Offsets in errors are relative to
+/// `buf`.
+pub fn decode(buf: &[u8]) -> Result<(u16, usize, usize), MessageError> {
+    let first = *buf.first().ok_or(MessageError::TruncatedMessage {
+        expected: 1,
+        actual: 0,
+    })?;
+    let delta_nibble = first >> 4;
+    let length_nibble = first & 0x0F;
+
+    let mut consumed = 1;
+    let delta = extended(buf, &mut consumed, delta_nibble)?
+        .ok_or(MessageError::InvalidOptionDelta { offset: 0 })?;
+    let len = extended(buf, &mut consumed, length_nibble)?
+        .ok_or(MessageError::InvalidOptionLength { offset: 0 })?;
+    let delta = u16::try_from(delta)
+        .map_err(|_| MessageError::InvalidOptionDelta { offset: 0 })?;
+    Ok((delta, len, consumed))
+}
+
+/// An extended value of up to two bytes.
+struct Extended {
+    bytes: [u8; 2],
+    len: usize,
+}
+
+impl Extended {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+/// Returns the nibble and extended bytes a delta or length is encoded with.
+fn nibble(value: usize) -> (u8, Extended) {
+    match value {
+        0..=12 => (
+            value as u8,
+            Extended {
+                bytes: [0; 2],
+                len: 0,
+            },
+        ),
+        13..=268 => (
+            13,
+            Extended {
+                bytes: [(value - 13) as u8, 0],
+                len: 1,
+            },
+        ),
+        _ => (
+            14,
+            Extended {
+                bytes: ((value - 269) as u16).to_be_bytes(),
+                len: 2,
+            },
+        ),
+    }
+}
+
+/// Reads the value announced by a nibble, advancing `consumed` past its
+/// extended bytes.  Returns `None` for the reserved nibble.
+fn extended(
+    buf: &[u8],
+    consumed: &mut usize,
+    nibble: u8,
+) -> Result<Option<usize>, MessageError> {
+    let extension_len = match nibble {
+        0..=12 => return Ok(Some(usize::from(nibble))),
+        13 => 1,
+        14 => 2,
+        _ => return Ok(None),
+    };
+    let end = *consumed + extension_len;
+    let bytes =
+        buf.get(*consumed..end)
+            .ok_or(MessageError::TruncatedMessage {
+                expected: end,
+                actual: buf.len(),
+            })?;
+    *consumed = end;
+    Ok(Some(match bytes {
+        [byte] => usize::from(*byte) + 13,
+        _ => usize::from(u16::from_be_bytes([bytes[0], bytes[1]])) + 269,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::option_overhead;
+
+    const BOUNDARIES: [usize; 8] = [0, 1, 12, 13, 268, 269, 270, 0xFFFF];
+
+    #[test]
+    fn round_trip() {
+        let lengths = BOUNDARIES.iter().copied().chain([MAX_EXTENDED]);
+        for len in lengths {
+            for &delta in BOUNDARIES.iter() {
+                let delta = delta as u16;
+                let mut out = [0; MAX_LEN];
+                let written = encode(delta, len, &mut out).unwrap();
+                assert_eq!(option_overhead(usize::from(delta), len), written);
+                assert_eq!(Ok((delta, len, written)), decode(&out[..written]));
+
+                // Missing extended bytes
+                if written > 1 {
+                    assert_eq!(
+                        Err(MessageError::TruncatedMessage {
+                            expected: written,
+                            actual: written - 1,
+                        }),
+                        decode(&out[..written - 1])
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn encoding() {
+        let mut out = [0; MAX_LEN];
+        assert_eq!(Some(1), encode(12, 12, &mut out));
+        assert_eq!([0xCC], out[..1]);
+        assert_eq!(Some(3), encode(13, 268, &mut out));
+        assert_eq!([0xDD, 0x00, 0xFF], out[..3]);
+        assert_eq!(Some(5), encode(269, 0xFFFF, &mut out));
+        assert_eq!([0xEE, 0x00, 0x00, 0xFE, 0xF2], out);
+
+        // Too long to be encoded
+        assert_eq!(None, encode(0, MAX_EXTENDED + 1, &mut out));
+        assert_eq!([0xEE, 0x00, 0x00, 0xFE, 0xF2], out);
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(
+            Err(MessageError::TruncatedMessage {
+                expected: 1,
+                actual: 0,
+            }),
+            decode(&[])
+        );
+    }
+
+    #[test]
+    fn reserved_and_overflow() {
+        for byte in 0xF0..=0xFF {
+            assert_eq!(
+                Err(MessageError::InvalidOptionDelta { offset: 0 }),
+                decode(&[byte, 0, 0, 0, 0])
+            );
+        }
+        for nibble in 0..=14 {
+            assert_eq!(
+                Err(MessageError::InvalidOptionLength { offset: 0 }),
+                decode(&[nibble << 4 | 0x0F, 0, 0, 0, 0])
+            );
+        }
+        // The largest delta that fits into an option number, and beyond
+        assert_eq!(Ok((0xFFFF, 0, 3)), decode(&[0xE0, 0xFE, 0xF2]));
+        assert_eq!(
+            Err(MessageError::InvalidOptionDelta { offset: 0 }),
+            decode(&[0xE0, 0xFE, 0xF3])
+        );
+    }
+}
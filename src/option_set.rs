@@ -0,0 +1,238 @@
+use alloc::{
+    collections::{BTreeMap, LinkedList},
+    vec::Vec,
+};
+
+use crate::{
+    error::IncompatibleOptionValueFormat,
+    option_value::OptionValueType,
+    packet::{Options, Packet},
+    CoapOption,
+};
+
+/// A set of options that is composed independently of a packet, e.g. once
+/// per route, and then applied to any number of packets.
+///
+/// Sets compare equal when they contain the same values for the same options,
+/// which together with `Hash` allows them to be used as cache keys.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct OptionSet {
+    options: OptionMap,
+}
+
+impl OptionSet {
+    /// Creates a new, empty option set.
+    pub fn new() -> OptionSet {
+        Default::default()
+    }
+
+    /// Extracts the options of a packet.
+    pub fn from_packet(packet: &Packet) -> OptionSet {
+        let options = packet
+            .options()
+            .filter(|(_, values)| !values.is_empty())
+            .map(|(&number, values)| (number, values.clone()))
+            .collect();
+        OptionSet { options }
+    }
+
+    /// Sets the options of this set on a packet, replacing any values the
+    /// packet already has for them.
+    pub fn apply_to(&self, packet: &mut Packet) {
+        for (&number, values) in self.options.iter() {
+            packet.set_option(CoapOption::from(number), values.clone());
+        }
+    }
+
+    /// Returns an iterator over the options of the set.
+    pub fn options(&self) -> Options<'_> {
+        self.options.iter()
+    }
+
+    /// Returns whether the set contains no options.
+    pub fn is_empty(&self) -> bool {
+        self.options.is_empty()
+    }
+
+    /// Sets an option's values.
+    pub fn set_option(&mut self, tp: CoapOption, value: LinkedList<Vec<u8>>) {
+        if value.is_empty() {
+            self.clear_option(tp);
+        } else {
+            self.options.insert(tp.into(), value);
+        }
+    }
+
+    /// Sets an option's values using a structured option value format.
+    pub fn set_options_as<T: OptionValueType>(
+        &mut self,
+        tp: CoapOption,
+        value: LinkedList<T>,
+    ) {
+        self.set_option(tp, raw_values(value));
+    }
+
+    /// Returns an option's values.
+    pub fn get_option(&self, tp: CoapOption) -> Option<&LinkedList<Vec<u8>>> {
+        get_option(&self.options, tp)
+    }
+
+    /// Returns an option's values all decoded using the specified structured
+    /// option value format.
+    pub fn get_options_as<T: OptionValueType>(
+        &self,
+        tp: CoapOption,
+    ) -> Option<LinkedList<Result<T, IncompatibleOptionValueFormat>>> {
+        get_options_as(&self.options, tp)
+    }
+
+    /// Returns an option's first value as a convenience when only one is
+    /// expected.
+    pub fn get_first_option(&self, tp: CoapOption) -> Option<&Vec<u8>> {
+        get_first_option(&self.options, tp)
+    }
+
+    /// Returns an option's first value as a convenience when only one is
+    /// expected.
+    pub fn get_first_option_as<T: OptionValueType>(
+        &self,
+        tp: CoapOption,
+    ) -> Option<Result<T, IncompatibleOptionValueFormat>> {
+        get_first_option_as(&self.options, tp)
+    }
+
+    /// Adds an option value.
+    pub fn add_option(&mut self, tp: CoapOption, value: Vec<u8>) {
+        push_option_value(&mut self.options, tp.into(), value);
+    }
+
+    /// Adds an option value using a structured option value format.
+    pub fn add_option_as<T: OptionValueType>(
+        &mut self,
+        tp: CoapOption,
+        value: T,
+    ) {
+        self.add_option(tp, value.into());
+    }
+
+    /// Removes an option.
+    pub fn clear_option(&mut self, tp: CoapOption) {
+        self.options.remove(&tp.into());
+    }
+}
+
+/// The options of a [`Packet`] or an [`OptionSet`], mapping option numbers
+/// to their values.
+pub(crate) type OptionMap = BTreeMap<u16, LinkedList<Vec<u8>>>;
+
+/// Returns the values of an option.
+pub(crate) fn get_option(
+    options: &OptionMap,
+    tp: CoapOption,
+) -> Option<&LinkedList<Vec<u8>>> {
+    options.get(&tp.into())
+}
+
+/// Returns the values of an option all decoded using the specified
+/// structured option value format.
+pub(crate) fn get_options_as<T: OptionValueType>(
+    options: &OptionMap,
+    tp: CoapOption,
+) -> Option<LinkedList<Result<T, IncompatibleOptionValueFormat>>> {
+    get_option(options, tp).map(|values| {
+        values
+            .iter()
+            .map(|raw_value| T::try_from(raw_value.clone()))
+            .collect()
+    })
+}
+
+/// Returns the first value of an option.
+pub(crate) fn get_first_option(
+    options: &OptionMap,
+    tp: CoapOption,
+) -> Option<&Vec<u8>> {
+    get_option(options, tp).and_then(|values| values.front())
+}
+
+/// Returns the first value of an option decoded using the specified
+/// structured option value format.
+pub(crate) fn get_first_option_as<T: OptionValueType>(
+    options: &OptionMap,
+    tp: CoapOption,
+) -> Option<Result<T, IncompatibleOptionValueFormat>> {
+    get_first_option(options, tp).map(|value| T::try_from(value.clone()))
+}
+
+/// Encodes option values given in a structured option value format.
+pub(crate) fn raw_values<T: OptionValueType>(
+    values: LinkedList<T>,
+) -> LinkedList<Vec<u8>> {
+    values.into_iter().map(|x| x.into()).collect()
+}
+
+/// Appends a value to the list of values of an option, creating the list if
+/// the option isn't present yet.
+pub(crate) fn push_option_value(
+    options: &mut OptionMap,
+    number: u16,
+    value: Vec<u8>,
+) {
+    options.entry(number).or_default().push_back(value);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{option_value::OptionValueU16, ContentFormat};
+
+    #[test]
+    fn apply_to_packets() {
+        let mut set = OptionSet::new();
+        set.add_option(CoapOption::UriPath, b"sensors".to_vec());
+        set.add_option(CoapOption::UriPath, b"temp".to_vec());
+        set.add_option(CoapOption::UriQuery, b"unit=C".to_vec());
+        set.add_option_as(CoapOption::Accept, OptionValueU16(60));
+        set.add_option(CoapOption::Unknown(65000), b"route".to_vec());
+
+        let mut first = Packet::new();
+        first.header.message_id = 1;
+        first.add_option(CoapOption::UriPath, b"other".to_vec());
+        set.apply_to(&mut first);
+
+        let mut second = Packet::new();
+        second.header.message_id = 1;
+        second.payload = b"ignored".to_vec();
+        set.apply_to(&mut second);
+        second.payload.clear();
+
+        assert_eq!(first.to_bytes().unwrap(), second.to_bytes().unwrap());
+        assert_eq!(
+            Some(Ok(OptionValueU16(60))),
+            second.get_first_option_as::<OptionValueU16>(CoapOption::Accept)
+        );
+    }
+
+    #[test]
+    fn from_packet() {
+        let mut set = OptionSet::new();
+        set.add_option(CoapOption::UriPath, b"temp".to_vec());
+        set.add_option_as(
+            CoapOption::ContentFormat,
+            OptionValueU16(usize::from(ContentFormat::ApplicationJSON) as u16),
+        );
+
+        let mut packet = Packet::new();
+        set.apply_to(&mut packet);
+        packet.add_option(CoapOption::Observe, vec![]);
+        packet.clear_option(CoapOption::Observe);
+        let packet = Packet::from_bytes(&packet.to_bytes().unwrap()).unwrap();
+
+        let extracted = OptionSet::from_packet(&packet);
+        assert_eq!(set, extracted);
+
+        set.clear_option(CoapOption::UriPath);
+        assert_ne!(set, extracted);
+        assert!(set.get_option(CoapOption::UriPath).is_none());
+    }
+}
@@ -21,7 +21,7 @@ macro_rules! option_value_uint_impl {
 
         impl From<$struct_name> for Vec<u8> {
             fn from(value: $struct_name) -> Self {
-                option_from_uint(value.0.into(), $bytes)
+                encode_uint(value.0.into())
             }
         }
 
@@ -40,31 +40,26 @@ macro_rules! option_value_uint_impl {
     };
 }
 
-fn option_from_uint(value_as_u64: u64, value_size: usize) -> Vec<u8> {
+/// Encodes an unsigned integer option value in as few bytes as possible,
+/// without leading zeros (RFC 7252 section 3.2), so zero is empty.
+pub(crate) fn encode_uint(value_as_u64: u64) -> Vec<u8> {
     // Optimize common paths
     if value_as_u64 == 0 {
         vec![]
     } else if value_as_u64 < 256 {
         vec![value_as_u64 as u8]
     } else {
-        let mut draining_value = value_as_u64;
-
-        let mut output = Vec::with_capacity(value_size);
-        while draining_value > 0 {
-            let next_lowest_byte = (draining_value & 0xff) as u8;
-            assert!(output.len() < value_size);
-            output.push(next_lowest_byte);
-            draining_value >>= 8;
-        }
-
-        // Output is in little endian, flip it to big endian (network order) as
-        // required
-        output.reverse();
-
-        output
+        let zeros = value_as_u64.leading_zeros() as usize / 8;
+        value_as_u64.to_be_bytes()[zeros..].to_vec()
     }
 }
 
+/// Decodes an unsigned integer option value, accepting leading zeros.  Only
+/// the last 8 bytes are significant, callers check the length.
+pub(crate) fn decode_uint(encoded: &[u8]) -> u64 {
+    encoded.iter().fold(0, |acc, &b| acc << 8 | u64::from(b))
+}
+
 fn option_to_uint(
     encoded: &[u8],
     value_size: usize,
@@ -78,7 +73,7 @@ fn option_to_uint(
             ),
         })
     } else {
-        Ok(encoded.iter().fold(0, |acc, &b| (acc << 8) + b as u64))
+        Ok(decode_uint(encoded))
     }
 }
 
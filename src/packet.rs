@@ -1,28 +1,42 @@
 use alloc::{
     collections::{BTreeMap, LinkedList},
+    string::String,
     vec::Vec,
 };
-use core::convert::TryFrom;
+use core::{
+    cmp::Ordering,
+    convert::TryFrom,
+    fmt::Write,
+    iter::FusedIterator,
+    ops::{Range, RangeInclusive},
+};
 
 use crate::{
+    block_handler::BlockValue,
+    content_format::MultiAcceptPolicy,
     error::{
         IncompatibleOptionValueFormat, InvalidContentFormat, InvalidObserve,
-        MessageError,
+        MessageError, PayloadTextError, ValidationError,
+    },
+    header::{
+        Header, HeaderRaw, MessageClass, MessageType, RequestType,
+        ResponseCategory, ResponseType,
+    },
+    option_header,
+    option_set::{self, push_option_value, OptionMap},
+    option_value::{
+        decode_uint, encode_uint, OptionValueType, OptionValueU16,
+        OptionValueU32, OptionValueU8,
     },
-    header::{Header, HeaderRaw, MessageClass},
-    option_value::{OptionValueType, OptionValueU16, OptionValueU32},
 };
 
-macro_rules! u8_to_unsigned_be {
-    ($src:ident, $start:expr, $end:expr, $t:ty) => ({
-        (0..=$end - $start).rev().fold(
-            0, |acc, i| acc | $src[$start+i] as $t << i * 8
-        )
-    })
-}
-
 /// The CoAP options.
+///
+/// More options may be added in minor releases, so matches need a wildcard
+/// arm.  See [`CoapOption::known_variants`] and the predicates like
+/// [`CoapOption::is_critical`] for alternatives.
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
 pub enum CoapOption {
     IfMatch,
     UriHost,
@@ -45,6 +59,8 @@ pub enum CoapOption {
     Size1,
     Size2,
     NoResponse,
+    /// An option this crate doesn't know, e.g. a vendor specific or
+    /// experimental one, which is kept and encoded like any other.
     Unknown(u16),
 }
 
@@ -106,8 +122,178 @@ impl From<CoapOption> for u16 {
     }
 }
 
+impl CoapOption {
+    /// Returns all options known to this crate, i.e. all but `Unknown`, in
+    /// the order of their numbers.
+    pub const fn known_variants() -> &'static [CoapOption] {
+        &[
+            CoapOption::IfMatch,
+            CoapOption::UriHost,
+            CoapOption::ETag,
+            CoapOption::IfNoneMatch,
+            CoapOption::Observe,
+            CoapOption::UriPort,
+            CoapOption::LocationPath,
+            CoapOption::Oscore,
+            CoapOption::UriPath,
+            CoapOption::ContentFormat,
+            CoapOption::MaxAge,
+            CoapOption::UriQuery,
+            CoapOption::Accept,
+            CoapOption::LocationQuery,
+            CoapOption::Block2,
+            CoapOption::Block1,
+            CoapOption::Size2,
+            CoapOption::ProxyUri,
+            CoapOption::ProxyScheme,
+            CoapOption::Size1,
+            CoapOption::NoResponse,
+        ]
+    }
+
+    /// Returns the option number.
+    pub fn as_u16(&self) -> u16 {
+        u16::from(*self)
+    }
+
+    /// Returns whether the option is critical, meaning that a recipient that
+    /// doesn't recognize it must not just ignore it (RFC 7252 section 5.4.1).
+    pub fn is_critical(&self) -> bool {
+        u16::from(*self) & 0x01 != 0
+    }
+
+    /// Returns whether the option is unsafe to forward, meaning that a proxy
+    /// that doesn't recognize it must not forward it (RFC 7252 section
+    /// 5.4.2).
+    pub fn is_unsafe_to_forward(&self) -> bool {
+        u16::from(*self) & 0x02 != 0
+    }
+
+    /// Returns whether the option is excluded from the cache key of a
+    /// request (RFC 7252 section 5.4.2).
+    pub fn is_no_cache_key(&self) -> bool {
+        u16::from(*self) & 0x1e == 0x1c
+    }
+
+    /// Returns whether the option may occur more than once in a message (RFC
+    /// 7252 section 5.10).  Options unknown to this crate are assumed to be
+    /// repeatable, as that can't be told from their number.
+    pub fn is_repeatable(&self) -> bool {
+        matches!(
+            self,
+            CoapOption::IfMatch
+                | CoapOption::ETag
+                | CoapOption::LocationPath
+                | CoapOption::UriPath
+                | CoapOption::UriQuery
+                | CoapOption::LocationQuery
+                | CoapOption::Unknown(_)
+        )
+    }
+
+    /// Returns whether the value of the option is an unsigned integer (RFC
+    /// 7252 section 3.2), which is encoded in as few bytes as possible.
+    pub fn is_uint(&self) -> bool {
+        matches!(
+            self,
+            CoapOption::Observe
+                | CoapOption::UriPort
+                | CoapOption::ContentFormat
+                | CoapOption::MaxAge
+                | CoapOption::Accept
+                | CoapOption::Block2
+                | CoapOption::Block1
+                | CoapOption::Size2
+                | CoapOption::Size1
+                | CoapOption::NoResponse
+        )
+    }
+
+    /// Returns the lengths a value of the option may have according to its
+    /// registered format (RFC 7252 section 5.10, RFC 7641, RFC 7959, RFC
+    /// 7967 and RFC 8613), or `None` for unknown options.
+    pub fn value_length_range(&self) -> Option<RangeInclusive<usize>> {
+        let range = match self {
+            CoapOption::IfMatch => 0..=8,
+            CoapOption::UriHost => 1..=255,
+            CoapOption::ETag => 1..=8,
+            CoapOption::IfNoneMatch => 0..=0,
+            CoapOption::Observe => 0..=3,
+            CoapOption::UriPort => 0..=2,
+            CoapOption::LocationPath => 0..=255,
+            CoapOption::Oscore => 0..=255,
+            CoapOption::UriPath => 0..=255,
+            CoapOption::ContentFormat => 0..=2,
+            CoapOption::MaxAge => 0..=4,
+            CoapOption::UriQuery => 0..=255,
+            CoapOption::Accept => 0..=2,
+            CoapOption::LocationQuery => 0..=255,
+            CoapOption::Block2 => 0..=3,
+            CoapOption::Block1 => 0..=3,
+            CoapOption::Size2 => 0..=4,
+            CoapOption::ProxyUri => 1..=1034,
+            CoapOption::ProxyScheme => 1..=255,
+            CoapOption::Size1 => 0..=4,
+            CoapOption::NoResponse => 0..=1,
+            CoapOption::Unknown(_) => return None,
+        };
+        Some(range)
+    }
+
+    /// Returns whether the option number is in the range 65000-65535, which
+    /// is meant for experiments and must not be used outside of closed
+    /// environments (RFC 7252 section 12.2).
+    pub fn is_experimental(&self) -> bool {
+        u16::from(*self) >= 65000
+    }
+
+    /// Returns whether an option number is reserved and must not be used
+    /// (RFC 7252 section 12.2).
+    pub fn is_reserved(number: u16) -> bool {
+        matches!(number, 0 | 128 | 132 | 136 | 140)
+    }
+
+    /// Returns the name of the option, or a label for the range an unknown
+    /// option number is in.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            CoapOption::IfMatch => "If-Match",
+            CoapOption::UriHost => "Uri-Host",
+            CoapOption::ETag => "ETag",
+            CoapOption::IfNoneMatch => "If-None-Match",
+            CoapOption::Observe => "Observe",
+            CoapOption::UriPort => "Uri-Port",
+            CoapOption::LocationPath => "Location-Path",
+            CoapOption::Oscore => "OSCORE",
+            CoapOption::UriPath => "Uri-Path",
+            CoapOption::ContentFormat => "Content-Format",
+            CoapOption::MaxAge => "Max-Age",
+            CoapOption::UriQuery => "Uri-Query",
+            CoapOption::Accept => "Accept",
+            CoapOption::LocationQuery => "Location-Query",
+            CoapOption::Block2 => "Block2",
+            CoapOption::Block1 => "Block1",
+            CoapOption::ProxyUri => "Proxy-Uri",
+            CoapOption::ProxyScheme => "Proxy-Scheme",
+            CoapOption::Size1 => "Size1",
+            CoapOption::Size2 => "Size2",
+            CoapOption::NoResponse => "No-Response",
+            CoapOption::Unknown(number) if CoapOption::is_reserved(number) => {
+                "Reserved"
+            }
+            CoapOption::Unknown(number) if number >= 65000 => "Experimental",
+            CoapOption::Unknown(_) => "Unknown",
+        }
+    }
+}
+
 /// The content formats.
+///
+/// More formats may be added in minor releases, so matches need a wildcard
+/// arm.  See [`ContentFormat::known_variants`] and
+/// [`ContentFormat::encoding`] for alternatives.
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
 pub enum ContentFormat {
     TextPlain,
     ApplicationLinkFormat,
@@ -124,6 +310,71 @@ pub enum ContentFormat {
     ApplicationSensmlExi,
     ApplicationSenmlXML,
     ApplicationSensmlXML,
+    ApplicationCoseEncrypt0,
+    ApplicationCoseMac0,
+    ApplicationCoseSign1,
+    ApplicationAceCBOR,
+    ApplicationJsonPatchJSON,
+    ApplicationMergePatchJSON,
+    ApplicationCWT,
+    ApplicationMultipartCore,
+    ApplicationCborSeq,
+    ApplicationCoseEncrypt,
+    ApplicationCoseMac,
+    ApplicationCoseSign,
+    ApplicationCoseKey,
+    ApplicationCoseKeySet,
+    ApplicationCoapGroupJSON,
+}
+
+impl ContentFormat {
+    /// Returns all content formats, in the order of their numbers.
+    pub const fn known_variants() -> &'static [ContentFormat] {
+        &[
+            ContentFormat::TextPlain,
+            ContentFormat::ApplicationCoseEncrypt0,
+            ContentFormat::ApplicationCoseMac0,
+            ContentFormat::ApplicationCoseSign1,
+            ContentFormat::ApplicationAceCBOR,
+            ContentFormat::ApplicationLinkFormat,
+            ContentFormat::ApplicationXML,
+            ContentFormat::ApplicationOctetStream,
+            ContentFormat::ApplicationEXI,
+            ContentFormat::ApplicationJSON,
+            ContentFormat::ApplicationJsonPatchJSON,
+            ContentFormat::ApplicationMergePatchJSON,
+            ContentFormat::ApplicationCBOR,
+            ContentFormat::ApplicationCWT,
+            ContentFormat::ApplicationMultipartCore,
+            ContentFormat::ApplicationCborSeq,
+            ContentFormat::ApplicationCoseEncrypt,
+            ContentFormat::ApplicationCoseMac,
+            ContentFormat::ApplicationCoseSign,
+            ContentFormat::ApplicationCoseKey,
+            ContentFormat::ApplicationCoseKeySet,
+            ContentFormat::ApplicationSenmlJSON,
+            ContentFormat::ApplicationSensmlJSON,
+            ContentFormat::ApplicationSenmlCBOR,
+            ContentFormat::ApplicationSensmlCBOR,
+            ContentFormat::ApplicationSenmlExi,
+            ContentFormat::ApplicationSensmlExi,
+            ContentFormat::ApplicationCoapGroupJSON,
+            ContentFormat::ApplicationSenmlXML,
+            ContentFormat::ApplicationSensmlXML,
+        ]
+    }
+
+    /// Returns the content format number.
+    pub fn as_u16(&self) -> u16 {
+        // All numbers are below 65536
+        usize::from(*self) as u16
+    }
+
+    /// Returns the content-format with the given number, or `None` if it's
+    /// unknown to this crate.
+    pub fn from_u16(number: u16) -> Option<ContentFormat> {
+        ContentFormat::try_from(usize::from(number)).ok()
+    }
 }
 
 impl TryFrom<usize> for ContentFormat {
@@ -132,18 +383,33 @@ impl TryFrom<usize> for ContentFormat {
     fn try_from(number: usize) -> Result<ContentFormat, InvalidContentFormat> {
         match number {
             0 => Ok(ContentFormat::TextPlain),
+            16 => Ok(ContentFormat::ApplicationCoseEncrypt0),
+            17 => Ok(ContentFormat::ApplicationCoseMac0),
+            18 => Ok(ContentFormat::ApplicationCoseSign1),
+            19 => Ok(ContentFormat::ApplicationAceCBOR),
             40 => Ok(ContentFormat::ApplicationLinkFormat),
             41 => Ok(ContentFormat::ApplicationXML),
             42 => Ok(ContentFormat::ApplicationOctetStream),
             47 => Ok(ContentFormat::ApplicationEXI),
             50 => Ok(ContentFormat::ApplicationJSON),
+            51 => Ok(ContentFormat::ApplicationJsonPatchJSON),
+            52 => Ok(ContentFormat::ApplicationMergePatchJSON),
             60 => Ok(ContentFormat::ApplicationCBOR),
+            61 => Ok(ContentFormat::ApplicationCWT),
+            62 => Ok(ContentFormat::ApplicationMultipartCore),
+            63 => Ok(ContentFormat::ApplicationCborSeq),
+            96 => Ok(ContentFormat::ApplicationCoseEncrypt),
+            97 => Ok(ContentFormat::ApplicationCoseMac),
+            98 => Ok(ContentFormat::ApplicationCoseSign),
+            101 => Ok(ContentFormat::ApplicationCoseKey),
+            102 => Ok(ContentFormat::ApplicationCoseKeySet),
             110 => Ok(ContentFormat::ApplicationSenmlJSON),
             111 => Ok(ContentFormat::ApplicationSensmlJSON),
             112 => Ok(ContentFormat::ApplicationSenmlCBOR),
             113 => Ok(ContentFormat::ApplicationSensmlCBOR),
             114 => Ok(ContentFormat::ApplicationSenmlExi),
             115 => Ok(ContentFormat::ApplicationSensmlExi),
+            256 => Ok(ContentFormat::ApplicationCoapGroupJSON),
             310 => Ok(ContentFormat::ApplicationSenmlXML),
             311 => Ok(ContentFormat::ApplicationSensmlXML),
             _ => Err(InvalidContentFormat),
@@ -155,18 +421,33 @@ impl From<ContentFormat> for usize {
     fn from(format: ContentFormat) -> usize {
         match format {
             ContentFormat::TextPlain => 0,
+            ContentFormat::ApplicationCoseEncrypt0 => 16,
+            ContentFormat::ApplicationCoseMac0 => 17,
+            ContentFormat::ApplicationCoseSign1 => 18,
+            ContentFormat::ApplicationAceCBOR => 19,
             ContentFormat::ApplicationLinkFormat => 40,
             ContentFormat::ApplicationXML => 41,
             ContentFormat::ApplicationOctetStream => 42,
             ContentFormat::ApplicationEXI => 47,
             ContentFormat::ApplicationJSON => 50,
+            ContentFormat::ApplicationJsonPatchJSON => 51,
+            ContentFormat::ApplicationMergePatchJSON => 52,
             ContentFormat::ApplicationCBOR => 60,
+            ContentFormat::ApplicationCWT => 61,
+            ContentFormat::ApplicationMultipartCore => 62,
+            ContentFormat::ApplicationCborSeq => 63,
+            ContentFormat::ApplicationCoseEncrypt => 96,
+            ContentFormat::ApplicationCoseMac => 97,
+            ContentFormat::ApplicationCoseSign => 98,
+            ContentFormat::ApplicationCoseKey => 101,
+            ContentFormat::ApplicationCoseKeySet => 102,
             ContentFormat::ApplicationSenmlJSON => 110,
             ContentFormat::ApplicationSensmlJSON => 111,
             ContentFormat::ApplicationSenmlCBOR => 112,
             ContentFormat::ApplicationSensmlCBOR => 113,
             ContentFormat::ApplicationSenmlExi => 114,
             ContentFormat::ApplicationSensmlExi => 115,
+            ContentFormat::ApplicationCoapGroupJSON => 256,
             ContentFormat::ApplicationSenmlXML => 310,
             ContentFormat::ApplicationSensmlXML => 311,
         }
@@ -201,13 +482,320 @@ impl From<ObserveOption> for usize {
     }
 }
 
+/// The meaning of the Observe option, which depends on whether it is part of
+/// a request or of a response (RFC 7641).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Observe {
+    /// The request registers for notifications.
+    Register,
+    /// The request cancels the registration.
+    Deregister,
+    /// The response is a notification with the given sequence number.
+    /// Sequence numbers are 24 bits long, higher bits are not encoded.
+    Sequence(u32),
+}
+
+impl Observe {
+    /// Decodes the value of the Observe option of a request, or of a
+    /// response if `is_request` is not set.
+    pub fn decode(
+        value: &[u8],
+        is_request: bool,
+    ) -> Result<Observe, InvalidObserve> {
+        if value.len() > 3 {
+            return Err(InvalidObserve);
+        }
+        // At most 3 bytes, so the number fits
+        let number = decode_uint(value) as u32;
+        match (is_request, number) {
+            (true, 0) => Ok(Observe::Register),
+            (true, 1) => Ok(Observe::Deregister),
+            (true, _) => Err(InvalidObserve),
+            (false, _) => Ok(Observe::Sequence(number)),
+        }
+    }
+
+    /// Encodes the value of the Observe option, where registering is encoded
+    /// as an empty value.
+    pub fn encode(&self) -> Vec<u8> {
+        let number = match *self {
+            Observe::Register => 0,
+            Observe::Deregister => 1,
+            Observe::Sequence(sequence) => {
+                sequence & ProtocolLimits::RFC.max_observe_sequence
+            }
+        };
+        encode_uint(u64::from(number))
+    }
+}
+
+/// The cache key of a request, see [`Packet::cache_key`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CacheKey {
+    code: u8,
+    options: Vec<(u16, Vec<u8>)>,
+    payload: Vec<u8>,
+}
+
+/// The options of a request a proxy is about to forward, partitioned by how
+/// the proxy has to handle them, see [`Packet::proxy_audit`].
+///
+/// Each list holds the option numbers in ascending order along with the
+/// number of values of the option.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProxyAudit {
+    /// Options that are safe to forward and can be forwarded as they are,
+    /// whether they are recognized or not.
+    pub forward: Vec<(u16, usize)>,
+    /// Recognized options that are critical or unsafe to forward, which the
+    /// proxy has to process itself.
+    pub process: Vec<(u16, usize)>,
+    /// Unrecognized options that are critical, because of which the request
+    /// must be rejected, e.g. with 5.02 (Bad Gateway).
+    pub reject: Vec<(u16, usize)>,
+    /// Unrecognized options that are elective but unsafe to forward, which
+    /// the proxy silently drops (RFC 7252 sections 5.4.1 and 5.7.1).
+    pub drop: Vec<(u16, usize)>,
+}
+
+/// How strictly [`Packet::validate`] treats options that are allowed by the
+/// message format but are not meant to be used in production.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationMode {
+    /// Such options are reported as warnings.
+    Lenient,
+    /// Such options are errors.
+    Strict,
+}
+
+/// Describes the envelope written by [`Packet::serialize_envelope_into`] and
+/// [`Packet::serialize_envelope_into_with_config`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnvelopeInfo {
+    /// The number of bytes written, the payload goes right after them.
+    pub len: usize,
+    /// Whether the envelope ends with the payload marker, in which case the
+    /// payload has to follow.
+    pub payload_marker: bool,
+}
+
+/// Describes a message decoded by [`Packet::decode_streaming`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamingHeader<'a> {
+    pub header: Header,
+    pub token: &'a [u8],
+    /// The range of the payload in the buffer, which is empty if there is
+    /// none.
+    pub payload: Range<usize>,
+}
+
+/// Statistics about an encoded message, gathered by [`Packet::inspect`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PacketStats {
+    pub code: MessageClass,
+    pub message_type: MessageType,
+    pub message_id: u16,
+    pub token_length: u8,
+    /// The number of option values.
+    pub option_count: usize,
+    /// The number of bytes taken by the options, headers included.
+    pub option_bytes: usize,
+    /// Whether any option header has an extended delta.
+    pub extended_delta: bool,
+    /// Whether any option header has an extended length.
+    pub extended_length: bool,
+    pub payload_length: usize,
+    pub total_length: usize,
+}
+
+/// The limits of the protocol that encoding, decoding and validation adhere
+/// to.
+///
+/// [`ProtocolLimits::RFC`] holds the limits of the RFCs, which is also the
+/// default.  They can be overridden e.g. for networks with a larger MTU, or
+/// for the longer tokens of RFC 8974.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolLimits {
+    /// The maximum size of an encoded message, 1280 bytes by default so that
+    /// it fits into an IPv6 packet without fragmentation (RFC 7252 section
+    /// 4.6).
+    ///
+    /// This limit is a concern of the transport rather than of the protocol:
+    /// reliable transports (RFC 8323) don't have it, and networks with a
+    /// larger MTU can raise it, see [`Packet::to_bytes_with_limit`].
+    pub max_message_size: usize,
+    /// The maximum length of a token, 8 bytes by default (RFC 7252 section
+    /// 3).  RFC 8974 allows longer tokens of up to 65804 bytes, with the
+    /// lengths beyond 12 bytes encoded in extension bytes following the
+    /// header.
+    pub max_token_length: usize,
+    /// The maximum length of the Uri-Host option, 255 bytes.
+    pub max_uri_host_length: usize,
+    /// The maximum length of the Proxy-Uri option, 1034 bytes.
+    pub max_proxy_uri_length: usize,
+    /// The largest sequence number of a notification, 2^24 - 1 as the
+    /// Observe option is at most 3 bytes long (RFC 7641 section 3.4).
+    pub max_observe_sequence: u32,
+    /// The smallest block size of a block-wise transfer, 16 bytes (RFC 7959
+    /// section 2.2).  [`BlockHandler`](crate::BlockHandler) rejects peers
+    /// asking for smaller blocks.
+    pub min_block_size: usize,
+    /// The largest block size of a block-wise transfer, 1024 bytes.
+    pub max_block_size: usize,
+}
+
+impl ProtocolLimits {
+    /// The limits as specified by the RFCs.
+    pub const RFC: ProtocolLimits = ProtocolLimits {
+        max_message_size: 1280,
+        max_token_length: 8,
+        max_uri_host_length: 255,
+        max_proxy_uri_length: 1034,
+        max_observe_sequence: 0xFF_FFFF,
+        min_block_size: 16,
+        max_block_size: 1024,
+    };
+
+    /// The longest token length the extended token length of RFC 8974 can
+    /// express.
+    const MAX_EXTENDED_TOKEN_LENGTH: usize = 65804;
+}
+
+impl Default for ProtocolLimits {
+    fn default() -> Self {
+        ProtocolLimits::RFC
+    }
+}
+
+/// Compares two values of the option with the given number.
+pub type ValueOrder = fn(u16, &[u8], &[u8]) -> Ordering;
+
+/// The configuration of [`Packet::to_bytes_with_config`], for
+/// compatibility with peers that depend on the order in which repeated
+/// option values are encoded.
+///
+/// The default is the canonical encoding of [`Packet::to_bytes`], with
+/// repeated values in the order they were added.  Options are always encoded
+/// in order of their number, as the encoding of RFC 7252 requires, so e.g.
+/// Uri-Query can't precede Uri-Path.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EncodeConfig<'a> {
+    /// Orders the repeated values of the option with the given number.  The
+    /// sort is stable, so values comparing equal keep the order they were
+    /// added in.
+    pub value_order: Option<ValueOrder>,
+
+    /// The numbers of the options whose values keep the order they were
+    /// added in regardless of `value_order`, like Uri-Path whose meaning
+    /// depends on it.
+    pub pinned: &'a [u16],
+
+    /// Whether to encode the `trailing_bytes` of the packet after the
+    /// options, see [`DecodeConfig::lenient`].
+    pub lenient: bool,
+
+    /// The limits on the message size and token length.
+    pub limits: ProtocolLimits,
+
+    /// Whether to strip the leading zero bytes of the values of options that
+    /// are unsigned integers (see [`CoapOption::is_uint`]), e.g. ones added
+    /// through [`Packet::add_option`] with padding, so zero becomes an empty
+    /// value.
+    ///
+    /// This yields the minimal encoding strict peers and OSCORE expect.  It
+    /// only changes the cache key for peers that wrongly include padding.
+    pub minimize_uints: bool,
+}
+
+/// The configuration of [`Packet::from_bytes_with_config`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DecodeConfig {
+    /// Whether to keep the bytes following the last option that could be
+    /// decoded in the `trailing_bytes` of the packet, instead of failing.
+    ///
+    /// This is not conformant to RFC 7252, it is meant for gateways that
+    /// forward messages of non-conformant peers as they are, which requires
+    /// encoding with [`EncodeConfig::lenient`] as well.  Errors in the
+    /// header or token are not tolerated.
+    pub lenient: bool,
+
+    /// The limits on the token length.
+    pub limits: ProtocolLimits,
+
+    /// The maximum size of the payload, beyond which decoding fails with
+    /// [`MessageError::PayloadTooLarge`] without copying it.  There is no
+    /// limit by default.
+    ///
+    /// This limits the body a server is willing to buffer, independent of
+    /// the message size.  A server rejecting a request because of it should
+    /// include the limit in the Size1 option of its 4.13 (Request Entity Too
+    /// Large) response, see [`crate::CoapResponse::set_status`] and
+    /// [`CoapOption::Size1`].  Clients can announce the size of a body in
+    /// the Size1 option of their request before transferring it block-wise,
+    /// which is not checked here.
+    pub max_payload_size: Option<usize>,
+}
+
+/// A destination for the bytes of an encoded packet, see
+/// [`Packet::write_to`].
+pub trait OptionSink {
+    /// Writes all of `buf`, failing with
+    /// [`MessageError::InvalidPacketLength`] if it doesn't fit.
+    fn write(&mut self, buf: &[u8]) -> Result<(), MessageError>;
+}
+
+impl OptionSink for Vec<u8> {
+    fn write(&mut self, buf: &[u8]) -> Result<(), MessageError> {
+        self.extend_from_slice(buf);
+        Ok(())
+    }
+}
+
+// Writes to the front of a slice
+struct SliceSink<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl OptionSink for SliceSink<'_> {
+    fn write(&mut self, buf: &[u8]) -> Result<(), MessageError> {
+        let end = self.len + buf.len();
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(MessageError::InvalidPacketLength)?
+            .copy_from_slice(buf);
+        self.len = end;
+        Ok(())
+    }
+}
+
 /// The CoAP packet.
 #[derive(Debug, Clone, Default)]
 pub struct Packet {
     pub header: Header,
     token: Vec<u8>,
-    pub(crate) options: BTreeMap<u16, LinkedList<Vec<u8>>>,
+    pub(crate) options: OptionMap,
     pub payload: Vec<u8>,
+    /// The bytes following the last option that could be decoded, when
+    /// decoding in lenient mode, see [`DecodeConfig::lenient`].
+    ///
+    /// The option deltas in these bytes are relative to the last decoded
+    /// option, so they are cleared as soon as options are added or removed
+    /// through the methods of the packet, unless they start with the
+    /// payload marker.
+    pub trailing_bytes: Vec<u8>,
+}
+
+// Options without any values are not encoded, so they are indistinguishable
+// from absent ones
+impl PartialEq for Packet {
+    fn eq(&self, other: &Packet) -> bool {
+        self.header == other.header
+            && self.token == other.token
+            && self.payload == other.payload
+            && self.trailing_bytes == other.trailing_bytes
+            && self.present_options().eq(other.present_options())
+    }
 }
 
 /// An iterator over the options of a packet.
@@ -225,12 +813,73 @@ impl Packet {
         self.options.iter()
     }
 
+    /// Returns an iterator over the options of the packet like
+    /// [`Packet::options`], with the numbers converted to [`CoapOption`].
+    ///
+    /// Numbers the crate doesn't know become [`CoapOption::Unknown`], so
+    /// the conversion is the inverse of `u16::from(CoapOption)`.
+    ///
+    /// ```
+    /// use coap_lite::{CoapOption, Packet};
+    ///
+    /// let mut packet = Packet::new();
+    /// packet.add_option(CoapOption::UriPath, b"temp".to_vec());
+    /// packet.add_option(CoapOption::Unknown(2049), vec![1]);
+    ///
+    /// for (option, values) in packet.typed_options() {
+    ///     match option {
+    ///         CoapOption::UriPath => assert_eq!(1, values.len()),
+    ///         CoapOption::Unknown(number) => assert_eq!(2049, number),
+    ///         _ => unreachable!(),
+    ///     }
+    /// }
+    /// ```
+    pub fn typed_options(
+        &self,
+    ) -> impl Iterator<Item = (CoapOption, &LinkedList<Vec<u8>>)> {
+        self.options
+            .iter()
+            .map(|(&number, values)| (CoapOption::from(number), values))
+    }
+
     /// Sets the token.
+    ///
+    /// The token length in the header is set as well, to the token length
+    /// indicator of RFC 8974 for tokens longer than 12 bytes.  Tokens longer
+    /// than 8 bytes are only encoded if the
+    /// [`ProtocolLimits::max_token_length`] allows it.
     pub fn set_token(&mut self, token: Vec<u8>) {
-        self.header.set_token_length(token.len() as u8);
+        self.header
+            .set_token_length(encode_token_length(token.len()).0);
         self.token = token;
     }
 
+    /// Sets a token of `len` bytes obtained from `alloc`, e.g. the token
+    /// registry of a client that keeps tokens unique across requests.
+    ///
+    /// Fails without calling `alloc` if `len` is longer than the 8 bytes a
+    /// token can have (see [`ProtocolLimits::max_token_length`]), and without
+    /// changing the token if `alloc` returns a longer one.
+    pub fn with_token_from<F>(
+        &mut self,
+        len: usize,
+        mut alloc: F,
+    ) -> Result<(), MessageError>
+    where
+        F: FnMut(usize) -> Vec<u8>,
+    {
+        let max_token_length = ProtocolLimits::RFC.max_token_length;
+        if len > max_token_length {
+            return Err(MessageError::InvalidTokenLength);
+        }
+        let token = alloc(len);
+        if token.len() > max_token_length {
+            return Err(MessageError::InvalidTokenLength);
+        }
+        self.set_token(token);
+        Ok(())
+    }
+
     /// Returns the token.
     pub fn get_token(&self) -> &[u8] {
         &self.token
@@ -239,6 +888,7 @@ impl Packet {
     /// Sets an option's values.
     pub fn set_option(&mut self, tp: CoapOption, value: LinkedList<Vec<u8>>) {
         self.options.insert(tp.into(), value);
+        self.options_changed();
     }
 
     /// Sets an option's values using a structured option value format.
@@ -247,13 +897,12 @@ impl Packet {
         tp: CoapOption,
         value: LinkedList<T>,
     ) {
-        let raw_value = value.into_iter().map(|x| x.into()).collect();
-        self.set_option(tp, raw_value);
+        self.set_option(tp, option_set::raw_values(value));
     }
 
     /// Returns an option's values.
     pub fn get_option(&self, tp: CoapOption) -> Option<&LinkedList<Vec<u8>>> {
-        self.options.get(&tp.into())
+        option_set::get_option(&self.options, tp)
     }
 
     /// Returns an option's values all decoded using the specified structured
@@ -262,20 +911,25 @@ impl Packet {
         &self,
         tp: CoapOption,
     ) -> Option<LinkedList<Result<T, IncompatibleOptionValueFormat>>> {
-        self.get_option(tp).map(|options| {
-            options
-                .iter()
-                .map(|raw_value| T::try_from(raw_value.clone()))
-                .collect()
-        })
+        option_set::get_options_as(&self.options, tp)
     }
 
     /// Returns an option's first value as a convenience when only one is
-    /// expected.
+    /// expected.  This is `None` as well for an option set to an empty list
+    /// of values with [`Packet::set_option`].
     pub fn get_first_option(&self, tp: CoapOption) -> Option<&Vec<u8>> {
+        option_set::get_first_option(&self.options, tp)
+    }
+
+    /// Returns a mutable reference to an option's first value, like
+    /// [`Packet::get_first_option`].
+    pub fn get_first_option_mut(
+        &mut self,
+        tp: CoapOption,
+    ) -> Option<&mut Vec<u8>> {
         self.options
-            .get(&tp.into())
-            .and_then(|options| options.front())
+            .get_mut(&tp.into())
+            .and_then(|options| options.front_mut())
     }
 
     /// Returns an option's first value as a convenience when only one is
@@ -284,21 +938,13 @@ impl Packet {
         &self,
         tp: CoapOption,
     ) -> Option<Result<T, IncompatibleOptionValueFormat>> {
-        self.get_first_option(tp)
-            .map(|value| T::try_from(value.clone()))
+        option_set::get_first_option_as(&self.options, tp)
     }
 
     /// Adds an option value.
     pub fn add_option(&mut self, tp: CoapOption, value: Vec<u8>) {
-        let num = tp.into();
-        if let Some(list) = self.options.get_mut(&num) {
-            list.push_back(value);
-            return;
-        }
-
-        let mut list = LinkedList::new();
-        list.push_back(value);
-        self.options.insert(num, list);
+        push_option_value(&mut self.options, tp.into(), value);
+        self.options_changed();
     }
 
     /// Adds an option value using a structured option value format.
@@ -310,459 +956,4100 @@ impl Packet {
         self.add_option(tp, value.into());
     }
 
+    /// Adds an unsigned integer option value, e.g. of Max-Age or Size2, in
+    /// the minimal encoding of RFC 7252 section 3.2: no bytes for zero, one
+    /// byte below 256 and so on.
+    pub fn add_option_as_u32(&mut self, tp: CoapOption, value: u32) {
+        self.add_option_as(tp, OptionValueU32(value));
+    }
+
+    /// Returns an option's first value as an unsigned integer, or `None` if
+    /// it's absent or longer than 4 bytes.  Shorter values, including
+    /// padded ones, are accepted.
+    pub fn get_first_option_as_u32(&self, tp: CoapOption) -> Option<u32> {
+        self.get_first_option_as::<OptionValueU32>(tp)?
+            .ok()
+            .map(|value| value.0)
+    }
+
+    /// Adds a value of the option with the given number, which needn't be
+    /// known to this crate, e.g. an option defined after it or a vendor
+    /// specific one.  The same as adding to [`CoapOption::Unknown`].
+    pub fn add_option_raw(&mut self, number: u16, value: Vec<u8>) {
+        push_option_value(&mut self.options, number, value);
+        self.options_changed();
+    }
+
+    /// Returns the values of the option with the given number, whether it's
+    /// known to this crate or not.
+    pub fn get_option_raw(&self, number: u16) -> Option<&LinkedList<Vec<u8>>> {
+        self.options.get(&number)
+    }
+
     /// Removes an option.
     pub fn clear_option(&mut self, tp: CoapOption) {
-        if let Some(list) = self.options.get_mut(&tp.into()) {
-            list.clear()
+        self.remove_option(tp);
+    }
+
+    /// Removes an option, returning its values if it was present, e.g. for
+    /// proxies moving hop-by-hop options out of a message.
+    pub fn remove_option(
+        &mut self,
+        tp: CoapOption,
+    ) -> Option<LinkedList<Vec<u8>>> {
+        let removed = self.options.remove(&tp.into());
+        if removed.is_some() {
+            self.options_changed();
+        }
+        removed
+    }
+
+    /// Copies the options of `template` for which `filter` returns true into
+    /// this packet, replacing any values it already has for those options.
+    ///
+    /// The header, token and payload are left untouched, which allows
+    /// stamping out many similar requests from a single prepared packet.
+    pub fn merge_options_from<F>(&mut self, template: &Packet, filter: F)
+    where
+        F: Fn(CoapOption) -> bool,
+    {
+        for (&number, values) in template.options.iter() {
+            if !values.is_empty() && filter(CoapOption::from(number)) {
+                self.options.insert(number, values.clone());
+                self.options_changed();
+            }
+        }
+    }
+
+    // The option deltas of the trailing bytes are relative to the last
+    // decoded option, which may have changed now.  A lone payload marker
+    // doesn't depend on the options.
+    fn options_changed(&mut self) {
+        if self.trailing_bytes.first() != Some(&0xFF) {
+            self.trailing_bytes.clear();
+        }
+    }
+
+    /// Returns a copy of the packet that only keeps the options for which
+    /// `filter` returns true, e.g. to build a sanitized request for
+    /// forwarding while leaving the original untouched.
+    pub fn filter_options<F>(&self, filter: F) -> Packet
+    where
+        F: Fn(CoapOption) -> bool,
+    {
+        let options = self
+            .options
+            .iter()
+            .filter(|(&number, _)| filter(CoapOption::from(number)))
+            .map(|(&number, values)| (number, values.clone()))
+            .collect();
+
+        let mut packet = Packet {
+            header: self.header.clone(),
+            token: self.token.clone(),
+            options,
+            payload: self.payload.clone(),
+            trailing_bytes: self.trailing_bytes.clone(),
+        };
+        if packet.options.len() != self.options.len() {
+            packet.options_changed();
         }
+        packet
     }
 
     /// Sets the content-format.
+    ///
+    /// This adds the option without removing an existing value, so use
+    /// [`Packet::set_content_format_raw`] or clear the option first to
+    /// replace it.
     pub fn set_content_format(&mut self, cf: ContentFormat) {
-        let content_format: u16 = u16::try_from(usize::from(cf)).unwrap();
-        self.add_option_as(
+        self.add_option(
             CoapOption::ContentFormat,
-            OptionValueU16(content_format),
+            encode_uint(cf.as_u16().into()),
         );
     }
 
-    /// Returns the content-format.
+    /// Returns the content-format, or `None` if it's absent, longer than 2
+    /// bytes or unknown to this crate, see
+    /// [`Packet::get_content_format_raw`].
     pub fn get_content_format(&self) -> Option<ContentFormat> {
-        self.get_first_option_as::<OptionValueU16>(CoapOption::ContentFormat)
-            .and_then(|option| option.ok())
-            .map(|value| usize::try_from(value.0).unwrap())
-            .and_then(|value| ContentFormat::try_from(value).ok())
+        self.get_content_format_raw()
+            .and_then(ContentFormat::from_u16)
     }
 
-    /// Sets the value of the observe option.
-    pub fn set_observe_value(&mut self, value: u32) {
-        self.clear_option(CoapOption::Observe);
-        self.add_option_as(CoapOption::Observe, OptionValueU32(value));
+    /// Sets the content-format by its number, which needn't be known to this
+    /// crate, e.g. one registered after it or from the experimental range
+    /// 65000-65535.  Replaces any existing value.
+    pub fn set_content_format_raw(&mut self, number: u16) {
+        self.clear_option(CoapOption::ContentFormat);
+        self.add_option(CoapOption::ContentFormat, encode_uint(number.into()));
     }
 
-    /// Returns the value of the observe option.
-    pub fn get_observe_value(
-        &self,
-    ) -> Option<Result<u32, IncompatibleOptionValueFormat>> {
-        self.get_first_option_as::<OptionValueU32>(CoapOption::Observe)
-            .map(|option| option.map(|value| value.0))
+    /// Returns the number of the content-format, whether it's known to this
+    /// crate or not, or `None` if it's absent or longer than 2 bytes.
+    pub fn get_content_format_raw(&self) -> Option<u16> {
+        self.get_first_option_as_u16(CoapOption::ContentFormat)
     }
 
-    /// Decodes a byte slice and constructs the equivalent packet.
-    pub fn from_bytes(buf: &[u8]) -> Result<Packet, MessageError> {
-        let header_result = HeaderRaw::try_from(buf);
-        match header_result {
-            Ok(raw_header) => {
-                let header = Header::from_raw(&raw_header);
-                let token_length = header.get_token_length();
-                let options_start: usize = 4 + token_length as usize;
-
-                if token_length > 8 {
-                    return Err(MessageError::InvalidTokenLength);
-                }
-
-                if options_start > buf.len() {
-                    return Err(MessageError::InvalidTokenLength);
-                }
-
-                let token = buf[4..options_start].to_vec();
-
-                let mut idx = options_start;
-                let mut options_number = 0;
-                let mut options: BTreeMap<u16, LinkedList<Vec<u8>>> =
-                    BTreeMap::new();
-                while idx < buf.len() {
-                    let byte = buf[idx];
-
-                    if byte == 255 || idx > buf.len() {
-                        break;
-                    }
-
-                    let mut delta = (byte >> 4) as u16;
-                    let mut length = (byte & 0xF) as usize;
-
-                    idx += 1;
-
-                    // Check for special delta characters
-                    match delta {
-                        13 => {
-                            if idx >= buf.len() {
-                                return Err(MessageError::InvalidOptionLength);
-                            }
-                            delta = (buf[idx] + 13).into();
-                            idx += 1;
-                        }
-                        14 => {
-                            if idx + 1 >= buf.len() {
-                                return Err(MessageError::InvalidOptionLength);
-                            }
-
-                            delta = u16::from_be(u8_to_unsigned_be!(
-                                buf,
-                                idx,
-                                idx + 1,
-                                u16
-                            )) + 269;
-                            idx += 2;
-                        }
-                        15 => {
-                            return Err(MessageError::InvalidOptionDelta);
-                        }
-                        _ => {}
-                    };
-
-                    // Check for special length characters
-                    match length {
-                        13 => {
-                            if idx >= buf.len() {
-                                return Err(MessageError::InvalidOptionLength);
-                            }
-
-                            length = buf[idx] as usize + 13;
-                            idx += 1;
-                        }
-                        14 => {
-                            if idx + 1 >= buf.len() {
-                                return Err(MessageError::InvalidOptionLength);
-                            }
-
-                            length = (u16::from_be(u8_to_unsigned_be!(
-                                buf,
-                                idx,
-                                idx + 1,
-                                u16
-                            )) + 269)
-                                as usize;
-                            idx += 2;
-                        }
-                        15 => {
-                            return Err(MessageError::InvalidOptionLength);
-                        }
-                        _ => {}
-                    };
-
-                    options_number += delta;
-
-                    let end = idx + length;
-                    if end > buf.len() {
-                        return Err(MessageError::InvalidOptionLength);
-                    }
-                    let options_value = buf[idx..end].to_vec();
+    /// Sets the Accept option, the content-format a client wants in the
+    /// response, replacing any existing value.
+    pub fn set_accept(&mut self, cf: ContentFormat) {
+        self.clear_option(CoapOption::Accept);
+        self.add_option(CoapOption::Accept, encode_uint(cf.as_u16().into()));
+    }
 
-                    options
-                        .entry(options_number)
-                        .or_insert_with(LinkedList::new)
-                        .push_back(options_value);
+    /// Returns the first Accept option.  Like for
+    /// [`Packet::get_content_format`], this is `None` for content-formats
+    /// unknown to this crate and for values longer than 2 bytes.
+    pub fn get_accept(&self) -> Option<ContentFormat> {
+        self.get_first_option_as_u16(CoapOption::Accept)
+            .and_then(ContentFormat::from_u16)
+    }
 
-                    idx += length;
-                }
+    // Decodes the first value as a uint of up to 2 bytes, like the number of
+    // a content-format, which is empty for text/plain
+    fn get_first_option_as_u16(&self, tp: CoapOption) -> Option<u16> {
+        self.get_first_option_as::<OptionValueU16>(tp)
+            .and_then(|option| option.ok())
+            .map(|value| value.0)
+    }
 
-                let payload = if idx < buf.len() {
-                    buf[(idx + 1)..buf.len()].to_vec()
-                } else {
-                    Vec::new()
-                };
+    /// Returns the payload as text, which requires the content-format to be
+    /// text/plain;charset=utf-8 or absent, and the payload to be valid UTF-8.
+    pub fn payload_text(&self) -> Result<&str, PayloadTextError> {
+        self.payload_text_with(true)
+    }
 
-                Ok(Packet {
-                    header,
-                    token,
-                    options,
-                    payload,
-                })
+    /// Returns the payload as text like [`Packet::payload_text`], treating a
+    /// missing content-format as an error unless `allow_absent` is set.
+    pub fn payload_text_with(
+        &self,
+        allow_absent: bool,
+    ) -> Result<&str, PayloadTextError> {
+        let format = self
+            .get_first_option_as::<OptionValueU16>(CoapOption::ContentFormat)
+            .map(|format| format.map(|value| value.0));
+        match format {
+            Some(Ok(0)) => {}
+            None if allow_absent => {}
+            Some(Ok(number)) => {
+                return Err(PayloadTextError::WrongContentFormat(Some(number)))
+            }
+            Some(Err(_)) | None => {
+                return Err(PayloadTextError::WrongContentFormat(None))
             }
-            Err(_) => Err(MessageError::InvalidHeader),
         }
+        core::str::from_utf8(&self.payload).map_err(|e| {
+            PayloadTextError::InvalidUtf8 {
+                offset: e.valid_up_to(),
+            }
+        })
     }
 
-    /// Returns a vector of bytes representing the Packet.
-    pub fn to_bytes(&self) -> Result<Vec<u8>, MessageError> {
-        let mut options_delta_length = 0;
-        let mut options_bytes: Vec<u8> = Vec::new();
-        for (number, value_list) in self.options.iter() {
-            for value in value_list.iter() {
-                let mut header: Vec<u8> = Vec::with_capacity(1 + 2 + 2);
-                let delta = number - options_delta_length;
-
-                let mut byte: u8 = 0;
-                if delta <= 12 {
-                    byte |= (delta << 4) as u8;
-                } else if delta < 269 {
-                    byte |= 13 << 4;
-                } else {
-                    byte |= 14 << 4;
-                }
-                if value.len() <= 12 {
-                    byte |= value.len() as u8;
-                } else if value.len() < 269 {
-                    byte |= 13;
-                } else {
-                    byte |= 14;
-                }
-                header.push(byte);
-
-                if delta > 12 && delta < 269 {
-                    header.push((delta - 13) as u8);
-                } else if delta >= 269 {
-                    let fix = (delta - 269) as u16;
-                    header.push((fix >> 8) as u8);
-                    header.push((fix & 0xFF) as u8);
-                }
+    /// Sets the payload to `text` and the content-format to
+    /// text/plain;charset=utf-8.
+    pub fn set_payload_text(&mut self, text: &str) {
+        self.set_content_format_raw(ContentFormat::TextPlain.as_u16());
+        self.payload = text.as_bytes().to_vec();
+    }
 
-                if value.len() > 12 && value.len() < 269 {
-                    header.push((value.len() - 13) as u8);
-                } else if value.len() >= 269 {
-                    let fix = (value.len() - 269) as u16;
-                    header.push((fix >> 8) as u8);
-                    header.push((fix & 0xFF) as u8);
-                }
+    /// The maximum length in bytes of a diagnostic payload, see
+    /// [`Packet::set_diagnostic_payload`].
+    pub const MAX_DIAGNOSTIC_LENGTH: usize = 128;
 
-                options_delta_length += delta;
-
-                options_bytes.reserve(header.len() + value.len());
-                unsafe {
-                    use core::ptr;
-                    let buf_len = options_bytes.len();
-                    ptr::copy(
-                        header.as_ptr(),
-                        options_bytes.as_mut_ptr().add(buf_len),
-                        header.len(),
-                    );
-                    ptr::copy(
-                        value.as_ptr(),
-                        options_bytes.as_mut_ptr().add(buf_len + header.len()),
-                        value.len(),
-                    );
-                    options_bytes
-                        .set_len(buf_len + header.len() + value.len());
+    /// Sets the payload to the diagnostic message `msg`, which explains an
+    /// error response to a human (RFC 7252 section 5.5.2), and the
+    /// content-format to text/plain;charset=utf-8.
+    ///
+    /// The message is truncated to [`Packet::MAX_DIAGNOSTIC_LENGTH`] bytes
+    /// at a character boundary, as diagnostics are meant to be brief and
+    /// must not make the response too large to send.
+    pub fn set_diagnostic_payload(&mut self, msg: &str) {
+        let mut end = msg.len().min(Self::MAX_DIAGNOSTIC_LENGTH);
+        while !msg.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.set_payload_text(&msg[..end]);
+    }
+
+    /// Returns the path of the request as a single byte string, suitable as
+    /// the key of a router.
+    ///
+    /// The Uri-Path options are normalized as follows:
+    ///
+    /// - Every segment is preceded by a `/`, without any Uri-Path options
+    ///   the path is just `/`.
+    /// - Empty segments are kept, so `/a/` and `/a` differ.
+    /// - The values of the options are already decoded, so they aren't
+    ///   percent-decoded again.  Instead, `%` and `/` within a segment are
+    ///   percent-encoded as `%25` and `%2F`, so that the segment `a/b` is
+    ///   distinct from the two segments `a` and `b`, and the segment `%2F`
+    ///   from `/`.  Other bytes are kept as they are, without any character
+    ///   set conversion.
+    ///
+    /// Unlike with [`crate::CoapRequest::get_path`], segments that are not
+    /// valid UTF-8 are kept.
+    pub fn uri_path_normalized(&self) -> Vec<u8> {
+        let mut path = Vec::new();
+        if let Some(segments) = self.get_option(CoapOption::UriPath) {
+            for segment in segments.iter() {
+                path.push(b'/');
+                for &byte in segment.iter() {
+                    match byte {
+                        b'%' => path.extend_from_slice(b"%25"),
+                        b'/' => path.extend_from_slice(b"%2F"),
+                        _ => path.push(byte),
+                    }
                 }
             }
         }
+        if path.is_empty() {
+            path.push(b'/');
+        }
+        path
+    }
 
-        let mut buf_length = 4 + self.payload.len() + self.token.len();
-        if self.header.code != MessageClass::Empty && !self.payload.is_empty()
-        {
-            buf_length += 1;
+    /// Returns the Uri-Path options joined with `/`, each segment preceded
+    /// by one, so without any Uri-Path options the path is just `/`.
+    ///
+    /// Nothing is percent-decoded or encoded, see
+    /// [`Packet::uri_path_normalized`] for a path in which every segment is
+    /// unambiguous.  Segments that are not valid UTF-8 are converted
+    /// lossily, with invalid bytes replaced by U+FFFD.
+    ///
+    /// This differs from [`crate::CoapRequest::get_path`], which returns the
+    /// path without the leading `/` and skips segments that are not valid
+    /// UTF-8.
+    pub fn get_uri_path(&self) -> String {
+        let mut path = String::new();
+        if let Some(segments) = self.get_option(CoapOption::UriPath) {
+            for segment in segments.iter() {
+                path.push('/');
+                path.push_str(&String::from_utf8_lossy(segment));
+            }
+        }
+        if path.is_empty() {
+            path.push('/');
         }
-        buf_length += options_bytes.len();
+        path
+    }
 
-        if buf_length > 1280 {
-            return Err(MessageError::InvalidPacketLength);
+    /// Sets the Uri-Path options to the segments of `path` split at `/`,
+    /// replacing any existing ones.  Empty segments are dropped, so `/a//b/`
+    /// becomes the two segments `a` and `b`, and `/` none at all.
+    ///
+    /// Nothing is percent-decoded, so `a%2Fb` is a single segment.  Fails
+    /// with [`MessageError::InvalidOptionValue`] without changing the packet
+    /// if a segment is longer than the 255 bytes Uri-Path allows.
+    ///
+    /// This differs from [`crate::CoapRequest::set_path`], which keeps empty
+    /// segments other than a leading one and doesn't check their length.
+    pub fn set_uri_path(&mut self, path: &str) -> Result<(), MessageError> {
+        let segments = path.split('/').filter(|s| !s.is_empty());
+        if let Some(segment) = segments.clone().find(|s| s.len() > 255) {
+            return Err(MessageError::InvalidOptionValue {
+                option: CoapOption::UriPath,
+                length: segment.len(),
+            });
         }
+        self.clear_option(CoapOption::UriPath);
+        for segment in segments {
+            self.add_option(CoapOption::UriPath, segment.as_bytes().to_vec());
+        }
+        Ok(())
+    }
 
-        let mut buf: Vec<u8> = Vec::with_capacity(buf_length);
-        let header_result = self.header.to_raw().serialize_into(&mut buf);
-
-        match header_result {
-            Ok(_) => {
-                buf.reserve(self.token.len() + options_bytes.len());
-                unsafe {
-                    use core::ptr;
-                    let buf_len = buf.len();
-                    ptr::copy(
-                        self.token.as_ptr(),
-                        buf.as_mut_ptr().add(buf_len),
-                        self.token.len(),
-                    );
-                    ptr::copy(
-                        options_bytes.as_ptr(),
-                        buf.as_mut_ptr().add(buf_len + self.token.len()),
-                        options_bytes.len(),
-                    );
-                    buf.set_len(
-                        buf_len + self.token.len() + options_bytes.len(),
-                    );
-                }
+    /// Returns the Uri-Query options as strings, or `None` if there are
+    /// none.
+    ///
+    /// Values that are not valid UTF-8 are converted lossily, with invalid
+    /// bytes replaced by U+FFFD.  See
+    /// [`crate::CoapRequest::get_uri_query_pairs`] for splitting them into
+    /// names and values.
+    pub fn get_queries(&self) -> Option<Vec<String>> {
+        self.get_option(CoapOption::UriQuery).map(|queries| {
+            queries
+                .iter()
+                .map(|query| String::from_utf8_lossy(query).into_owned())
+                .collect()
+        })
+    }
 
-                if self.header.code != MessageClass::Empty
-                    && !self.payload.is_empty()
-                {
-                    buf.push(0xFF);
-                    buf.reserve(self.payload.len());
-                    unsafe {
-                        use core::ptr;
-                        let buf_len = buf.len();
-                        ptr::copy(
-                            self.payload.as_ptr(),
-                            buf.as_mut_ptr().add(buf.len()),
-                            self.payload.len(),
-                        );
-                        buf.set_len(buf_len + self.payload.len());
-                    }
-                }
-                Ok(buf)
-            }
-            Err(_) => Err(MessageError::InvalidHeader),
-        }
+    /// Sets the ETag option of a response, replacing any existing ones.
+    /// Fails with [`MessageError::InvalidOptionValue`] unless the tag is 1
+    /// to 8 bytes long.
+    pub fn set_etag(&mut self, etag: &[u8]) -> Result<(), MessageError> {
+        check_etag(etag)?;
+        self.clear_option(CoapOption::ETag);
+        self.add_option(CoapOption::ETag, etag.to_vec());
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::{header, option_value::OptionValueString};
-    use alloc::borrow::ToOwned;
+    /// Adds an ETag option, of which a request can carry several to have
+    /// any of the representations validated (RFC 7252 section 5.10.6.2).
+    /// Fails like [`Packet::set_etag`].
+    pub fn add_etag(&mut self, etag: &[u8]) -> Result<(), MessageError> {
+        check_etag(etag)?;
+        self.add_option(CoapOption::ETag, etag.to_vec());
+        Ok(())
+    }
 
-    #[test]
-    fn test_decode_packet_with_options() {
-        let buf = [
-            0x44, 0x01, 0x84, 0x9e, 0x51, 0x55, 0x77, 0xe8, 0xb2, 0x48, 0x69,
-            0x04, 0x54, 0x65, 0x73, 0x74, 0x43, 0x61, 0x3d, 0x31,
-        ];
-        let packet = Packet::from_bytes(&buf);
-        assert!(packet.is_ok());
-        let packet = packet.unwrap();
-        assert_eq!(packet.header.get_version(), 1);
-        assert_eq!(packet.header.get_type(), header::MessageType::Confirmable);
-        assert_eq!(packet.header.get_token_length(), 4);
-        assert_eq!(
-            packet.header.code,
-            header::MessageClass::Request(header::RequestType::Get)
-        );
-        assert_eq!(packet.header.message_id, 33950);
-        assert_eq!(*packet.get_token(), vec![0x51, 0x55, 0x77, 0xE8]);
-        assert_eq!(packet.options.len(), 2);
+    /// Returns the first ETag option.
+    pub fn get_etag(&self) -> Option<&[u8]> {
+        self.get_first_option(CoapOption::ETag)
+            .map(|etag| &etag[..])
+    }
 
-        let uri_path = packet.get_option(CoapOption::UriPath);
-        assert!(uri_path.is_some());
-        let uri_path = uri_path.unwrap();
-        let mut expected_uri_path = LinkedList::new();
-        expected_uri_path.push_back("Hi".as_bytes().to_vec());
-        expected_uri_path.push_back("Test".as_bytes().to_vec());
-        assert_eq!(*uri_path, expected_uri_path);
+    /// Returns all ETag options, in the order they were added.
+    pub fn get_etags(&self) -> Vec<&[u8]> {
+        self.get_option(CoapOption::ETag)
+            .into_iter()
+            .flatten()
+            .map(|etag| &etag[..])
+            .collect()
+    }
 
-        let uri_query = packet.get_option(CoapOption::UriQuery);
-        assert!(uri_query.is_some());
-        let uri_query = uri_query.unwrap();
-        let mut expected_uri_query = LinkedList::new();
-        expected_uri_query.push_back("a=1".as_bytes().to_vec());
-        assert_eq!(*uri_query, expected_uri_query);
+    /// Returns whether any ETag option of a request matches `etag`, the tag
+    /// of the current representation, in which case a server can respond
+    /// with 2.03 (Valid) instead of sending the representation again.
+    ///
+    /// ```
+    /// use coap_lite::{Packet, ResponseType};
+    ///
+    /// let mut request = Packet::new();
+    /// request.add_etag(b"v1").unwrap();
+    /// request.add_etag(b"v2").unwrap();
+    ///
+    /// let status = if request.etag_matches(b"v2") {
+    ///     ResponseType::Valid
+    /// } else {
+    ///     ResponseType::Content
+    /// };
+    /// assert_eq!(ResponseType::Valid, status);
+    /// ```
+    pub fn etag_matches(&self, etag: &[u8]) -> bool {
+        self.get_etags().contains(&etag)
+    }
+
+    /// Sets the Uri-Port option, replacing any existing value.
+    pub fn set_uri_port(&mut self, port: u16) {
+        self.clear_option(CoapOption::UriPort);
+        self.add_option_as(CoapOption::UriPort, OptionValueU16(port));
+    }
+
+    /// Returns the value of the Uri-Port option, failing if it is longer
+    /// than the two bytes needed for a port number.
+    pub fn get_uri_port(
+        &self,
+    ) -> Option<Result<u16, IncompatibleOptionValueFormat>> {
+        self.get_first_option_as::<OptionValueU16>(CoapOption::UriPort)
+            .map(|option| option.map(|value| value.0))
+    }
+
+    /// Announces the total size of the response body in the Size2 option
+    /// (RFC 7959 section 4), replacing any existing value.  This belongs in
+    /// the first block of a Block2 transfer, so that the client can
+    /// preallocate the body and report progress.
+    pub fn advertise_response_size(&mut self, total: u32) {
+        self.clear_option(CoapOption::Size2);
+        self.add_option_as(CoapOption::Size2, OptionValueU32(total));
+    }
+
+    /// Returns the size of the response body announced in the Size2 option,
+    /// see [`BlockValue::progress`].
+    pub fn get_size2(
+        &self,
+    ) -> Option<Result<u32, IncompatibleOptionValueFormat>> {
+        self.get_first_option_as::<OptionValueU32>(CoapOption::Size2)
+            .map(|option| option.map(|value| value.0))
+    }
+
+    /// Sets the Max-Age option, the number of seconds a response may be
+    /// cached for, replacing any existing value.  Zero is encoded as an
+    /// empty value.
+    pub fn set_max_age(&mut self, seconds: u32) {
+        self.clear_option(CoapOption::MaxAge);
+        self.add_option(CoapOption::MaxAge, encode_uint(seconds.into()));
+    }
+
+    /// Returns the value of the Max-Age option, or `None` if it's absent or
+    /// longer than 4 bytes.
+    pub fn get_max_age(&self) -> Option<u32> {
+        self.get_first_option_as_u32(CoapOption::MaxAge)
+    }
+
+    /// Returns the value of the Max-Age option, or its default of 60
+    /// seconds if it's absent (RFC 7252 section 5.10.5).
+    ///
+    /// A value longer than 4 bytes is ignored like an unrecognized elective
+    /// option (RFC 7252 section 5.4.1), so it yields the default as well.
+    /// Use [`Packet::validate_options`] to reject such a message instead.
+    pub fn max_age_or_default(&self) -> u32 {
+        self.get_max_age().unwrap_or(60)
+    }
+
+    /// Sets the Block1 option, replacing any existing value.
+    pub fn set_block1(&mut self, block: BlockValue) {
+        self.clear_option(CoapOption::Block1);
+        self.add_option(CoapOption::Block1, block.to_bytes());
+    }
+
+    /// Returns the Block1 option, or `None` if it's absent or invalid.
+    pub fn get_block1(&self) -> Option<BlockValue> {
+        self.get_first_option(CoapOption::Block1)
+            .and_then(|value| BlockValue::from_bytes(value).ok())
+    }
+
+    /// Sets the Block2 option, replacing any existing value.
+    pub fn set_block2(&mut self, block: BlockValue) {
+        self.clear_option(CoapOption::Block2);
+        self.add_option(CoapOption::Block2, block.to_bytes());
+    }
+
+    /// Returns the Block2 option, or `None` if it's absent or invalid.
+    pub fn get_block2(&self) -> Option<BlockValue> {
+        self.get_first_option(CoapOption::Block2)
+            .and_then(|value| BlockValue::from_bytes(value).ok())
+    }
+
+    /// Serializes `value` as JSON into the payload and sets the matching
+    /// content-format.
+    #[cfg(feature = "json")]
+    pub fn set_json_payload<T: serde::Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+    ) -> Result<(), crate::error::PayloadEncodingError> {
+        self.set_json_payload_as(
+            value,
+            50,
+            &crate::content_format::ContentFormatRegistry::default(),
+        )
+    }
+
+    /// Serializes `value` as JSON into the payload and sets the
+    /// content-format `number`, which has to be a JSON based format in the
+    /// registry.
+    #[cfg(feature = "json")]
+    pub fn set_json_payload_as<T: serde::Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+        number: u16,
+        registry: &crate::content_format::ContentFormatRegistry,
+    ) -> Result<(), crate::error::PayloadEncodingError> {
+        check_payload_encoding(
+            registry,
+            number,
+            crate::content_format::PayloadEncoding::Json,
+        )?;
+        let payload = serde_json::to_vec(value).map_err(|e| {
+            crate::error::PayloadEncodingError {
+                message: format!("{}", e),
+            }
+        })?;
+        self.set_payload_with_format(payload, number);
+        Ok(())
+    }
+
+    /// Serializes `value` as CBOR into the payload and sets the matching
+    /// content-format.
+    #[cfg(feature = "cbor")]
+    pub fn set_cbor_payload<T: serde::Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+    ) -> Result<(), crate::error::PayloadEncodingError> {
+        self.set_cbor_payload_as(
+            value,
+            60,
+            &crate::content_format::ContentFormatRegistry::default(),
+        )
+    }
+
+    /// Serializes `value` as CBOR into the payload and sets the
+    /// content-format `number`, which has to be a CBOR based format in the
+    /// registry.
+    #[cfg(feature = "cbor")]
+    pub fn set_cbor_payload_as<T: serde::Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+        number: u16,
+        registry: &crate::content_format::ContentFormatRegistry,
+    ) -> Result<(), crate::error::PayloadEncodingError> {
+        check_payload_encoding(
+            registry,
+            number,
+            crate::content_format::PayloadEncoding::Cbor,
+        )?;
+        let mut payload = Vec::new();
+        ciborium::ser::into_writer(value, &mut payload).map_err(|e| {
+            crate::error::PayloadEncodingError {
+                message: format!("{:?}", e),
+            }
+        })?;
+        self.set_payload_with_format(payload, number);
+        Ok(())
+    }
+
+    #[cfg(any(feature = "json", feature = "cbor"))]
+    fn set_payload_with_format(&mut self, payload: Vec<u8>, number: u16) {
+        self.set_content_format_raw(number);
+        self.payload = payload;
+    }
+
+    /// Sets the Observe option, replacing any existing value.
+    pub fn set_observe(&mut self, observe: Observe) {
+        self.clear_option(CoapOption::Observe);
+        self.add_option(CoapOption::Observe, observe.encode());
+    }
+
+    /// Returns the Observe option, interpreted according to whether the
+    /// packet is a request.
+    pub fn get_observe(&self) -> Option<Result<Observe, InvalidObserve>> {
+        let is_request = matches!(self.header.code, MessageClass::Request(_));
+        self.get_first_option(CoapOption::Observe)
+            .map(|value| Observe::decode(value, is_request))
+    }
+
+    /// Returns whether the packet is a well-formed notification, i.e. a 2.05
+    /// (Content) response carrying a sequence number of at most 3 bytes in
+    /// the Observe option.
+    ///
+    /// Whether the notification belongs to a registration is up to the
+    /// caller to check by its token.
+    pub fn is_observe_notification(&self) -> bool {
+        self.header.code == MessageClass::Response(ResponseType::Content)
+            && matches!(self.get_observe(), Some(Ok(Observe::Sequence(_))))
+    }
+
+    /// Sets the value of the observe option, of which the lower 24 bits are
+    /// used.
+    #[deprecated(note = "use `Packet::set_observe` instead")]
+    pub fn set_observe_value(&mut self, value: u32) {
+        self.set_observe(Observe::Sequence(value));
+    }
+
+    /// Returns the value of the observe option.
+    #[deprecated(note = "use `Packet::get_observe` instead")]
+    pub fn get_observe_value(
+        &self,
+    ) -> Option<Result<u32, IncompatibleOptionValueFormat>> {
+        self.get_first_option_as::<OptionValueU32>(CoapOption::Observe)
+            .map(|option| option.map(|value| value.0))
+    }
+
+    /// Sets the Observe option to register for notifications, failing if
+    /// the packet is a request with a method that can't be observed.
+    pub fn set_observe_register(&mut self) -> Result<(), ValidationError> {
+        if let MessageClass::Request(method) = self.header.code {
+            check_observable(method)?;
+        }
+        self.set_observe(Observe::Register);
+        Ok(())
+    }
+
+    /// Sets the Observe option to register for notifications if `register`
+    /// is true, and to deregister otherwise, replacing any existing value.
+    pub fn set_observe_registration(&mut self, register: bool) {
+        self.set_observe(if register {
+            Observe::Register
+        } else {
+            Observe::Deregister
+        });
+    }
+
+    /// Checks that the packet uses the protocol correctly beyond being
+    /// well-formed, which catches mistakes when building packets.
+    ///
+    /// Currently this checks that requests only carry the Observe option if
+    /// their method is GET or FETCH.  Responses are exempt, as notifications
+    /// carry the sequence number in it.
+    pub fn semantic_validate(&self) -> Result<(), ValidationError> {
+        self.validate(ValidationMode::Lenient).map(|_| ())
+    }
+
+    /// Checks the packet like [`Packet::semantic_validate`] and additionally
+    /// looks for experimental and reserved option numbers.  Depending on
+    /// `mode` these are errors, or warnings which are logged and returned.
+    ///
+    /// The lengths of the Uri-Host and Proxy-Uri options are checked against
+    /// the limits of the RFCs as well.
+    pub fn validate(
+        &self,
+        mode: ValidationMode,
+    ) -> Result<Vec<ValidationError>, ValidationError> {
+        self.validate_with_limits(mode, &ProtocolLimits::RFC)
+    }
+
+    /// Checks the packet like [`Packet::validate`], with the option lengths
+    /// checked against `limits`.
+    pub fn validate_with_limits(
+        &self,
+        mode: ValidationMode,
+        limits: &ProtocolLimits,
+    ) -> Result<Vec<ValidationError>, ValidationError> {
+        if let MessageClass::Request(method) = self.header.code {
+            if self.get_first_option(CoapOption::Observe).is_some() {
+                check_observable(method)?;
+            }
+        }
+
+        for (option, max_length) in [
+            (CoapOption::UriHost, limits.max_uri_host_length),
+            (CoapOption::ProxyUri, limits.max_proxy_uri_length),
+        ] {
+            let too_long = self
+                .get_option(option)
+                .into_iter()
+                .flatten()
+                .any(|value| value.len() > max_length);
+            if too_long {
+                return Err(ValidationError::OptionTooLong(option.into()));
+            }
+        }
+
+        let mut warnings = Vec::new();
+        for (&number, _) in self.present_options() {
+            let issue = if CoapOption::is_reserved(number) {
+                ValidationError::ReservedOption(number)
+            } else if CoapOption::from(number).is_experimental() {
+                ValidationError::ExperimentalOption(number)
+            } else {
+                continue;
+            };
+            if mode == ValidationMode::Strict {
+                return Err(issue);
+            }
+            coap_warn!("{}", issue);
+            warnings.push(issue);
+        }
+        Ok(warnings)
+    }
+
+    /// Checks that the length of every value of the options known to this
+    /// crate fits the registered format of the option, see
+    /// [`CoapOption::value_length_range`], e.g. that Uri-Port is at most 2
+    /// bytes and ETag 1 to 8 bytes long.  Unknown options are not checked.
+    ///
+    /// Decoding doesn't check this, so servers should call this before
+    /// interpreting the options of a request.
+    pub fn validate_options(&self) -> Result<(), MessageError> {
+        for (&number, values) in self.options.iter() {
+            let option = CoapOption::from(number);
+            let range = match option.value_length_range() {
+                Some(range) => range,
+                None => continue,
+            };
+            if let Some(value) =
+                values.iter().find(|value| !range.contains(&value.len()))
+            {
+                return Err(MessageError::InvalidOptionValue {
+                    option,
+                    length: value.len(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the numbers of the options that occur more than once although
+    /// they aren't repeatable.  The repeats of such options are to be treated
+    /// like unrecognized options (RFC 7252 section 5.4.5), which for critical
+    /// ones means rejecting a request with 4.02 (Bad Option).
+    ///
+    /// Repeated Accept options are only reported with
+    /// [`MultiAcceptPolicy::Reject`], as the other policies pick one of them
+    /// in [`ContentFormatRegistry::negotiate_with_policy`].
+    ///
+    /// [`ContentFormatRegistry::negotiate_with_policy`]:
+    /// crate::ContentFormatRegistry::negotiate_with_policy
+    pub fn repeated_options(
+        &self,
+        multi_accept: MultiAcceptPolicy,
+    ) -> Vec<u16> {
+        self.present_options()
+            .filter(|(_, values)| values.len() > 1)
+            .map(|(&number, _)| number)
+            .filter(|&number| !CoapOption::from(number).is_repeatable())
+            .filter(|&number| {
+                multi_accept == MultiAcceptPolicy::Reject
+                    || CoapOption::from(number) != CoapOption::Accept
+            })
+            .collect()
+    }
+
+    /// Checks that the packet carries no critical options other than the
+    /// `known` ones, returning the numbers of the unrecognized critical
+    /// options otherwise.
+    ///
+    /// A server has to reject a confirmable request carrying any with 4.02
+    /// (Bad Option), and ignore a non-confirmable one (RFC 7252 section
+    /// 5.4.1).  Unrecognized elective options are silently ignored.
+    ///
+    /// ```
+    /// use coap_lite::{CoapOption, Packet};
+    ///
+    /// let known = [CoapOption::UriPath.as_u16()];
+    /// let mut request = Packet::new();
+    /// request.add_option(CoapOption::UriPath, b"temp".to_vec());
+    /// request.add_option(CoapOption::Unknown(2048), vec![]);
+    /// assert_eq!(Ok(()), request.check_critical_options(&known));
+    ///
+    /// request.add_option(CoapOption::Unknown(2049), vec![]);
+    /// assert_eq!(Err(vec![2049]), request.check_critical_options(&known));
+    /// ```
+    pub fn check_critical_options(
+        &self,
+        known: &[u16],
+    ) -> Result<(), Vec<u16>> {
+        let unrecognized: Vec<u16> = self
+            .present_options()
+            .map(|(&number, _)| number)
+            .filter(|number| {
+                CoapOption::from(*number).is_critical()
+                    && !known.contains(number)
+            })
+            .collect();
+        if unrecognized.is_empty() {
+            Ok(())
+        } else {
+            Err(unrecognized)
+        }
+    }
+
+    /// Partitions the options of the packet by how a proxy forwarding it has
+    /// to handle them (RFC 7252 section 5.7.1), given the option numbers
+    /// the proxy recognizes.
+    pub fn proxy_audit(&self, known: &dyn Fn(u16) -> bool) -> ProxyAudit {
+        let mut audit = ProxyAudit::default();
+        for (&number, values) in self.present_options() {
+            let option = CoapOption::from(number);
+            let list =
+                if !option.is_critical() && !option.is_unsafe_to_forward() {
+                    &mut audit.forward
+                } else if known(number) {
+                    &mut audit.process
+                } else if option.is_critical() {
+                    &mut audit.reject
+                } else {
+                    &mut audit.drop
+                };
+            list.push((number, values.len()));
+        }
+        audit
+    }
+
+    /// Returns whether the recipient has to acknowledge the packet, which is
+    /// the case for confirmable messages.  These are retransmitted until the
+    /// acknowledgement arrives.
+    pub fn requires_ack(&self) -> bool {
+        self.header.get_type() == MessageType::Confirmable
+    }
+
+    /// Returns a copy of the packet to retransmit, which encodes to exactly
+    /// the same bytes with any [`EncodeConfig`]: the message ID, token,
+    /// options with their values in order, and payload are all kept.
+    ///
+    /// This is what `clone` does as well, this method documents the
+    /// guarantee retransmission relies on.
+    pub fn retransmit_copy(&self) -> Packet {
+        self.clone()
+    }
+
+    /// Returns whether the packet is a request, for which a response is
+    /// expected (unless suppressed by the No-Response option).
+    pub fn expects_response(&self) -> bool {
+        matches!(self.header.code, MessageClass::Request(_))
+    }
+
+    /// Returns whether the No-Response option of this request suppresses
+    /// responses with the given status (RFC 7967 section 2.1).  A malformed
+    /// option suppresses nothing.
+    pub fn suppresses_response(&self, status: ResponseType) -> bool {
+        let mask = match status.category() {
+            ResponseCategory::Success => 0x02,
+            ResponseCategory::ClientError => 0x08,
+            ResponseCategory::ServerError => 0x10,
+            ResponseCategory::Unknown => return false,
+        };
+        matches!(
+            self.get_first_option_as::<OptionValueU8>(CoapOption::NoResponse),
+            Some(Ok(OptionValueU8(value))) if value & mask != 0
+        )
+    }
+
+    /// Returns whether the packet is a CoAP ping, i.e. an empty confirmable
+    /// message, which the peer answers with a reset to prove it is alive (RFC
+    /// 7252 section 4.3).
+    ///
+    /// This is the keepalive of the message layer over UDP.  Over reliable
+    /// transports (RFC 8323) there is no message type, instead a 7.02 Ping
+    /// signaling message is sent, which may carry a token to match it to its
+    /// 7.03 Pong.  Such signaling messages are not recognized here.
+    pub fn is_keepalive(&self) -> bool {
+        self.header.get_type() == MessageType::Confirmable
+            && self.header.code == MessageClass::Empty
+    }
+
+    /// Returns the cache key of this request (RFC 7252 section 5.4.2).
+    ///
+    /// It consists of the method and all options that aren't marked as
+    /// NoCacheKey, which covers the Uri-* options and Accept.  FETCH requests
+    /// are treated like GET requests with a body (RFC 8132 section 2), so
+    /// their payload is part of the key as well.
+    pub fn cache_key(&self) -> CacheKey {
+        let options = self
+            .cache_key_options()
+            .flat_map(|(&number, values)| {
+                values.iter().map(move |value| (number, value.clone()))
+            })
+            .collect();
+        let payload = match self.header.code {
+            MessageClass::Request(RequestType::Fetch) => self.payload.clone(),
+            _ => Vec::new(),
+        };
+
+        CacheKey {
+            code: self.header.code.into(),
+            options,
+            payload,
+        }
+    }
+
+    /// Returns whether this request and `other` can be satisfied by a single
+    /// request to the origin, as done by proxies aggregating requests.
+    ///
+    /// This is the case when both are GET or FETCH requests with the same
+    /// [`Packet::cache_key`].  The token and message ID are not considered.
+    ///
+    /// Requests carrying the Observe option are never coalescable since each
+    /// of them needs its own registration.
+    pub fn is_coalescable_with(&self, other: &Packet) -> bool {
+        let is_safe_request = |packet: &Packet| {
+            matches!(
+                packet.header.code,
+                MessageClass::Request(RequestType::Get)
+                    | MessageClass::Request(RequestType::Fetch)
+            )
+        };
+        if !is_safe_request(self) {
+            return false;
+        }
+
+        let observes = |packet: &Packet| {
+            matches!(
+                packet.get_option(CoapOption::Observe),
+                Some(values) if !values.is_empty()
+            )
+        };
+        if observes(self) || observes(other) {
+            return false;
+        }
+
+        self.cache_key() == other.cache_key()
+    }
+
+    /// Creates a minimal non-confirmable response to this request, carrying
+    /// the given response code and the request's token.
+    ///
+    /// Unlike a piggybacked response, which reuses the message ID of the
+    /// confirmable request it acknowledges, a non-confirmable response is a
+    /// new message and needs a fresh `message_id`.
+    pub fn new_non_response(
+        &self,
+        code: ResponseType,
+        message_id: u16,
+    ) -> Packet {
+        let mut packet = Packet::new();
+        packet.header.set_version(1);
+        packet.header.set_type(MessageType::NonConfirmable);
+        packet.header.code = MessageClass::Response(code);
+        packet.header.message_id = message_id;
+        packet.set_token(self.token.clone());
+        packet
+    }
+
+    fn present_options(
+        &self,
+    ) -> impl Iterator<Item = (&u16, &LinkedList<Vec<u8>>)> {
+        self.options.iter().filter(|(_, values)| !values.is_empty())
+    }
+
+    fn cache_key_options(
+        &self,
+    ) -> impl Iterator<Item = (&u16, &LinkedList<Vec<u8>>)> {
+        self.present_options()
+            .filter(|(&number, _)| !CoapOption::from(number).is_no_cache_key())
+    }
+
+    /// Returns a human readable description of the packet, which unlike its
+    /// `Debug` output has a stable format suitable for snapshot tests.
+    ///
+    /// There is one line per field: the type, the code, the message ID, the
+    /// token, one line for each option value in the order they are encoded,
+    /// the payload and, only if there are any, the trailing bytes.  Opaque
+    /// bytes are written in lowercase hex.  For example:
+    ///
+    /// ```text
+    /// type: Confirmable
+    /// code: 0.01
+    /// message_id: 23839
+    /// token: 00003974
+    /// option 3 Uri-Host: 6c6f63616c686f7374
+    /// option 11 Uri-Path: 747631
+    /// payload:
+    /// ```
+    ///
+    /// Changes to this format are noted in the release notes and only made
+    /// in minor releases.
+    pub fn to_debug_string(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "type: {:?}", self.header.get_type());
+        let _ = writeln!(out, "code: {}", self.header.code);
+        let _ = writeln!(out, "message_id: {}", self.header.message_id);
+        write_hex_line(&mut out, "token", &self.token);
+        for (&number, values) in self.present_options() {
+            let label = format!(
+                "option {} {}",
+                number,
+                CoapOption::from(number).name()
+            );
+            for value in values {
+                write_hex_line(&mut out, &label, value);
+            }
+        }
+        write_hex_line(&mut out, "payload", &self.payload);
+        if !self.trailing_bytes.is_empty() {
+            write_hex_line(&mut out, "trailing_bytes", &self.trailing_bytes);
+        }
+        out
+    }
+
+    /// Decodes a byte slice and constructs the equivalent packet.
+    ///
+    /// See [`Packet::to_bytes`] for how decoding and encoding relate.
+    pub fn from_bytes(buf: &[u8]) -> Result<Packet, MessageError> {
+        Packet::from_bytes_with_config(buf, &DecodeConfig::default())
+    }
+
+    /// Decodes a byte slice and constructs the equivalent packet as
+    /// described by `config`.
+    pub fn from_bytes_with_config(
+        buf: &[u8],
+        config: &DecodeConfig,
+    ) -> Result<Packet, MessageError> {
+        let (header, token) =
+            decode_header(buf, config.limits.max_token_length)?;
+        let options_start = token.end;
+        let token = buf[token].to_vec();
+        let body = decode_options_and_payload(buf, options_start, config)?;
+
+        Ok(Packet {
+            header,
+            token,
+            options: body.options,
+            payload: body.payload,
+            trailing_bytes: body.trailing_bytes,
+        })
+    }
+
+    /// Decodes a byte slice without building the options, passing each
+    /// option's number and value to `on_option` as it is reached instead.
+    /// The values are borrowed from the buffer, so nothing is allocated.
+    ///
+    /// This suits servers too constrained to hold all options of a message,
+    /// which can react to the options on the fly.  Returning an error from
+    /// `on_option` aborts decoding with that error.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use coap_lite::{CoapOption, Packet};
+    ///
+    /// let mut packet = Packet::new();
+    /// packet.add_option(CoapOption::UriPath, b"led".to_vec());
+    /// packet.payload = b"on".to_vec();
+    /// let bytes = packet.to_bytes().unwrap();
+    ///
+    /// let mut path_segments = 0;
+    /// let message = Packet::decode_streaming(&bytes, |number, _| {
+    ///     if CoapOption::from(number) == CoapOption::UriPath {
+    ///         path_segments += 1;
+    ///     }
+    ///     Ok(())
+    /// })
+    /// .unwrap();
+    /// assert_eq!(1, path_segments);
+    /// assert_eq!(b"on", &bytes[message.payload]);
+    /// ```
+    pub fn decode_streaming<'a>(
+        buf: &'a [u8],
+        mut on_option: impl FnMut(u16, &'a [u8]) -> Result<(), MessageError>,
+    ) -> Result<StreamingHeader<'a>, MessageError> {
+        let (header, token) =
+            decode_header(buf, ProtocolLimits::RFC.max_token_length)?;
+
+        let mut options = OptionWalker::new(buf, token.end, 0);
+        for option in options.by_ref() {
+            let option = option?;
+            on_option(option.number, option.value)?;
+        }
+        let payload = options.payload()?;
+
+        Ok(StreamingHeader {
+            header,
+            token: &buf[token],
+            payload,
+        })
+    }
+
+    /// Gathers statistics about a byte slice in a single pass, without
+    /// allocating, e.g. for metrics about messages that are then dropped.
+    ///
+    /// Fails like [`Packet::from_bytes`] on malformed messages.
+    pub fn inspect(buf: &[u8]) -> Result<PacketStats, MessageError> {
+        let (header, token) =
+            decode_header(buf, ProtocolLimits::RFC.max_token_length)?;
+        let options_start = token.end;
+        let mut stats = PacketStats {
+            code: header.code,
+            message_type: header.get_type(),
+            message_id: header.message_id,
+            token_length: header.get_token_length(),
+            option_count: 0,
+            option_bytes: 0,
+            extended_delta: false,
+            extended_length: false,
+            payload_length: 0,
+            total_length: buf.len(),
+        };
+
+        let mut options = OptionWalker::new(buf, options_start, 0);
+        for option in options.by_ref() {
+            let option = option?;
+            let header = buf[option.offset];
+            stats.option_count += 1;
+            stats.extended_delta |= header >> 4 >= 13;
+            stats.extended_length |= header & 0x0F >= 13;
+        }
+        stats.option_bytes = options.offset() - options_start;
+        stats.payload_length = options.payload()?.len();
+        Ok(stats)
+    }
+
+    /// Returns a vector of bytes representing the Packet.
+    ///
+    /// Encoding and decoding are symmetric: a packet that can be encoded
+    /// decodes to an equal packet, and a byte buffer that can be decoded
+    /// encodes to the same bytes again, apart from these normalizations:
+    ///
+    /// - Options set to an empty list of values with
+    ///   [`Packet::set_option`] are not encoded.
+    /// - The payload of an empty message (code 0.00) is not encoded.
+    /// - The `UnKnown` request and response types are encoded as 0xFF,
+    ///   which is decoded as [`MessageClass::Reserved`].  Other codes that
+    ///   aren't known to this crate are kept as they are.
+    /// - The `trailing_bytes` are not encoded.
+    ///
+    /// Fails with [`MessageError::InvalidTokenLength`] if the token length
+    /// in the header doesn't match the token, e.g. after changing it through
+    /// `header` rather than [`Packet::set_token`].
+    pub fn to_bytes(&self) -> Result<Vec<u8>, MessageError> {
+        self.to_bytes_with_config(&EncodeConfig::default())
+    }
+
+    /// Returns a vector of bytes representing the Packet, failing if it is
+    /// larger than `max` bytes, or with no limit on the size for `None`.
+    ///
+    /// [`Packet::to_bytes`] limits the size to
+    /// [`ProtocolLimits::RFC`]`.max_message_size`, i.e. 1280 bytes to avoid
+    /// IP fragmentation over UDP.  That is a concern of the transport, so
+    /// e.g. CoAP over TCP or WebSockets (RFC 8323) encodes without a limit.
+    ///
+    /// ```
+    /// use coap_lite::Packet;
+    ///
+    /// let mut packet = Packet::new();
+    /// packet.payload = vec![0x42; 2000];
+    /// assert!(packet.to_bytes().is_err());
+    /// assert!(packet.to_bytes_with_limit(Some(1500)).is_err());
+    /// assert!(packet.to_bytes_with_limit(None).is_ok());
+    /// ```
+    pub fn to_bytes_with_limit(
+        &self,
+        max: Option<usize>,
+    ) -> Result<Vec<u8>, MessageError> {
+        let config = EncodeConfig {
+            limits: ProtocolLimits {
+                max_message_size: max.unwrap_or(usize::MAX),
+                ..ProtocolLimits::RFC
+            },
+            ..Default::default()
+        };
+        self.to_bytes_with_config(&config)
+    }
+
+    /// Returns a vector of bytes representing the Packet, encoding the
+    /// options as described by `config`.
+    ///
+    /// The result decodes like the one of [`Packet::to_bytes`], except for
+    /// the order of repeated option values and any trailing bytes.  Fails if
+    /// the token or the message exceed the limits of the configuration.
+    pub fn to_bytes_with_config(
+        &self,
+        config: &EncodeConfig,
+    ) -> Result<Vec<u8>, MessageError> {
+        self.check_token_length(config.limits.max_token_length)?;
+
+        let buf_length = self.encoded_len_with_config(config);
+        if buf_length > config.limits.max_message_size {
+            return Err(MessageError::InvalidPacketLength);
+        }
+
+        let mut buf: Vec<u8> = Vec::with_capacity(buf_length);
+        self.write_with_config(config, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Writes the bytes representing the Packet to `sink`, returning their
+    /// number.
+    ///
+    /// This encodes the packet like [`Packet::to_bytes`], for sinks that
+    /// aren't a contiguous buffer such as ring buffers.  Fails without
+    /// writing anything if the packet can't be encoded; if the sink fails,
+    /// it may have received part of the packet.
+    pub fn write_to<W: OptionSink>(
+        &self,
+        sink: &mut W,
+    ) -> Result<usize, MessageError> {
+        let config = EncodeConfig::default();
+        self.check_token_length(config.limits.max_token_length)?;
+        if self.encoded_len() > config.limits.max_message_size {
+            return Err(MessageError::InvalidPacketLength);
+        }
+        self.write_with_config(&config, sink)
+    }
+
+    // Checks that the token fits the limit, and that the token length in the
+    // header, which can be modified directly, still matches the token
+    fn check_token_length(
+        &self,
+        max_token_length: usize,
+    ) -> Result<(), MessageError> {
+        let max_token_length =
+            max_token_length.min(ProtocolLimits::MAX_EXTENDED_TOKEN_LENGTH);
+        let (token_length, _, _) = encode_token_length(self.token.len());
+        if self.token.len() > max_token_length
+            || self.header.get_token_length() != token_length
+        {
+            return Err(MessageError::InvalidTokenLength);
+        }
+        Ok(())
+    }
+
+    // Writes the packet without checking the limits of `config`
+    fn write_with_config<W: OptionSink>(
+        &self,
+        config: &EncodeConfig,
+        sink: &mut W,
+    ) -> Result<usize, MessageError> {
+        let header = self.header.to_raw().to_bytes();
+        let (_, extension, extension_len) =
+            encode_token_length(self.token.len());
+
+        let options_start = header.len() + extension_len + self.token.len();
+        let mut options_bytes =
+            self.options_to_bytes(config, options_start)?;
+        if config.lenient {
+            options_bytes.extend_from_slice(&self.trailing_bytes);
+        }
+
+        sink.write(&header)?;
+        sink.write(&extension[..extension_len])?;
+        sink.write(&self.token)?;
+        sink.write(&options_bytes)?;
+        let mut len = header.len()
+            + extension_len
+            + self.token.len()
+            + options_bytes.len();
+        if self.header.code != MessageClass::Empty && !self.payload.is_empty()
+        {
+            sink.write(&[0xFF])?;
+            sink.write(&self.payload)?;
+            len += 1 + self.payload.len();
+        }
+        Ok(len)
+    }
+
+    /// Writes everything but the payload into the front of `buf`, so that a
+    /// payload of `payload_len` bytes can be placed right after it without
+    /// copying it into the packet first.  The `payload` field is ignored.
+    ///
+    /// The payload has to be exactly `payload_len` bytes long, as UDP has no
+    /// other way of delimiting it.  If the envelope ends with the payload
+    /// marker, which it doesn't for an empty payload or an empty message
+    /// (code 0.00), the payload has to follow it; otherwise nothing may.
+    /// Fails if `buf` is too small for the envelope or the message would be
+    /// too large, like [`Packet::to_bytes`].
+    pub fn serialize_envelope_into(
+        &self,
+        payload_len: usize,
+        buf: &mut [u8],
+    ) -> Result<EnvelopeInfo, MessageError> {
+        self.serialize_envelope_into_with_config(
+            payload_len,
+            buf,
+            &EncodeConfig::default(),
+        )
+    }
+
+    /// Writes everything but the payload into the front of `buf` like
+    /// [`Packet::serialize_envelope_into`], encoding the options as
+    /// described by `config` and checking its limits.
+    ///
+    /// The size is checked before anything is written, and nothing is
+    /// allocated unless `config` orders the values of an option.
+    pub fn serialize_envelope_into_with_config(
+        &self,
+        payload_len: usize,
+        buf: &mut [u8],
+        config: &EncodeConfig,
+    ) -> Result<EnvelopeInfo, MessageError> {
+        self.check_token_length(config.limits.max_token_length)?;
+        let header = self.header.to_raw().to_bytes();
+        let (_, extension, extension_len) =
+            encode_token_length(self.token.len());
+        let payload_marker =
+            self.header.code != MessageClass::Empty && payload_len > 0;
+
+        let options_start = header.len() + extension_len + self.token.len();
+        let mut len = options_start + self.options_len(config);
+        if config.lenient {
+            len += self.trailing_bytes.len();
+        }
+        len += usize::from(payload_marker);
+        let payload_len = if payload_marker { payload_len } else { 0 };
+        if len.saturating_add(payload_len) > config.limits.max_message_size
+            || len > buf.len()
+        {
+            return Err(MessageError::InvalidPacketLength);
+        }
+
+        let mut sink = SliceSink {
+            buf: &mut buf[..len],
+            len: 0,
+        };
+        sink.write(&header)?;
+        sink.write(&extension[..extension_len])?;
+        sink.write(&self.token)?;
+        self.write_options(config, options_start, &mut sink)?;
+        if config.lenient {
+            sink.write(&self.trailing_bytes)?;
+        }
+        if payload_marker {
+            sink.write(&[0xFF])?;
+        }
+
+        Ok(EnvelopeInfo {
+            len,
+            payload_marker,
+        })
+    }
+
+    /// Returns the number of bytes [`Packet::to_bytes`] encodes the packet
+    /// in, without encoding it.
+    pub fn encoded_len(&self) -> usize {
+        self.encoded_len_with_config(&EncodeConfig::default())
+    }
+
+    /// Returns the number of bytes [`Packet::to_bytes_with_config`] encodes
+    /// the packet in, e.g. fewer with [`EncodeConfig::minimize_uints`].
+    pub fn encoded_len_with_config(&self, config: &EncodeConfig) -> usize {
+        let (_, _, extension_len) = encode_token_length(self.token.len());
+        let mut len =
+            4 + extension_len + self.token.len() + self.options_len(config);
+        if config.lenient {
+            len += self.trailing_bytes.len();
+        }
+        if self.header.code != MessageClass::Empty && !self.payload.is_empty()
+        {
+            len += 1 + self.payload.len();
+        }
+        len
+    }
+
+    /// Encodes the options, the same for UDP and TCP framing.  Fails with
+    /// [`MessageError::InvalidOptionLength`] for a value that is too long to
+    /// be encoded, at its offset from `start`, where the options begin.
+    pub(crate) fn options_to_bytes(
+        &self,
+        config: &EncodeConfig,
+        start: usize,
+    ) -> Result<Vec<u8>, MessageError> {
+        let mut options_bytes = Vec::new();
+        self.write_options(config, start, &mut options_bytes)?;
+        Ok(options_bytes)
+    }
+
+    // Writes the options to `sink` like `options_to_bytes`
+    fn write_options<W: OptionSink>(
+        &self,
+        config: &EncodeConfig,
+        start: usize,
+        sink: &mut W,
+    ) -> Result<(), MessageError> {
+        let mut previous_number = 0;
+        let mut offset = start;
+        let mut write_value = |number: u16, value: &[u8]| {
+            let delta = number - previous_number;
+            let mut header = [0; option_header::MAX_LEN];
+            let header_len =
+                option_header::encode(delta, value.len(), &mut header)
+                    .ok_or(MessageError::InvalidOptionLength { offset })?;
+            sink.write(&header[..header_len])?;
+            sink.write(value)?;
+            previous_number = number;
+            offset += header_len + value.len();
+            Ok(())
+        };
+
+        for (&number, value_list) in self.options.iter() {
+            let minimize =
+                config.minimize_uints && CoapOption::from(number).is_uint();
+            let order = config
+                .value_order
+                .filter(|_| !config.pinned.contains(&number));
+            match order {
+                Some(order) => {
+                    let mut values: Vec<&[u8]> = value_list
+                        .iter()
+                        .map(|value| minimized(value, minimize))
+                        .collect();
+                    values.sort_by(|a, b| order(number, a, b));
+                    for value in values {
+                        write_value(number, value)?;
+                    }
+                }
+                None => {
+                    for value in value_list.iter() {
+                        write_value(number, minimized(value, minimize))?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // The number of bytes the options take when encoded as described by
+    // `config`, which doesn't depend on the order of the values
+    fn options_len(&self, config: &EncodeConfig) -> usize {
+        let mut len = 0;
+        let mut previous_number = 0;
+        for (&number, values) in self.options.iter() {
+            let minimize =
+                config.minimize_uints && CoapOption::from(number).is_uint();
+            for value in values.iter() {
+                let value = minimized(value, minimize);
+                let delta = usize::from(number - previous_number);
+                len += option_overhead(delta, value.len()) + value.len();
+                previous_number = number;
+            }
+        }
+        len
+    }
+}
+
+/// Strips the leading zero bytes of an unsigned integer option value if
+/// `minimize` is set, see [`EncodeConfig::minimize_uints`].
+fn minimized(value: &[u8], minimize: bool) -> &[u8] {
+    if !minimize {
+        return value;
+    }
+    let zeros = value.iter().take_while(|&&byte| byte == 0).count();
+    &value[zeros..]
+}
+
+/// Writes a line of [`Packet::to_debug_string`] with bytes in hex.
+fn write_hex_line(out: &mut String, label: &str, bytes: &[u8]) {
+    out.push_str(label);
+    out.push(':');
+    if !bytes.is_empty() {
+        out.push(' ');
+    }
+    for byte in bytes {
+        let _ = write!(out, "{:02x}", byte);
+    }
+    out.push('\n');
+}
+
+/// Returns the number of bytes the header of an option occupies, given the
+/// difference of its number to that of the previous option and the length of
+/// its value.  Both need 0, 1 or 2 extension bytes in addition to the first
+/// byte, depending on their size.
+pub fn option_overhead(delta: usize, value_len: usize) -> usize {
+    let extension_len = |value| match value {
+        0..=12 => 0,
+        13..=268 => 1,
+        _ => 2,
+    };
+    1 + extension_len(delta) + extension_len(value_len)
+}
+
+pub(crate) fn hex_value(digit: u8) -> Option<u8> {
+    char::from(digit).to_digit(16).map(|value| value as u8)
+}
+
+fn check_etag(etag: &[u8]) -> Result<(), MessageError> {
+    if !(1..=8).contains(&etag.len()) {
+        return Err(MessageError::InvalidOptionValue {
+            option: CoapOption::ETag,
+            length: etag.len(),
+        });
+    }
+    Ok(())
+}
+
+fn check_observable(method: RequestType) -> Result<(), ValidationError> {
+    match method {
+        RequestType::Get | RequestType::Fetch => Ok(()),
+        _ => Err(ValidationError::ObserveOnUnobservableMethod(method)),
+    }
+}
+
+#[cfg(any(feature = "json", feature = "cbor"))]
+fn check_payload_encoding(
+    registry: &crate::content_format::ContentFormatRegistry,
+    number: u16,
+    encoding: crate::content_format::PayloadEncoding,
+) -> Result<(), crate::error::PayloadEncodingError> {
+    match registry.get(number) {
+        Some(entry) if entry.encoding == encoding => Ok(()),
+        _ => Err(crate::error::PayloadEncodingError {
+            message: format!(
+                "content-format {} is not {:?} based",
+                registry.display(number),
+                encoding
+            ),
+        }),
+    }
+}
+
+/// Returns the token length indicator of the header for a token of `len`
+/// bytes, along with the extended token length following the header and its
+/// length (RFC 8974 section 2.1).  Tokens that are too long get the reserved
+/// indicator 15.
+pub(crate) fn encode_token_length(len: usize) -> (u8, [u8; 2], usize) {
+    match len {
+        0..=12 => (len as u8, [0; 2], 0),
+        13..=268 => (13, [(len - 13) as u8, 0], 1),
+        269..=65804 => (14, ((len - 269) as u16).to_be_bytes(), 2),
+        _ => (15, [0; 2], 0),
+    }
+}
+
+/// Decodes the header of a message and checks that its token is present,
+/// returning the header and the range of the token, which the options
+/// follow.
+///
+/// The extended token lengths of RFC 8974 are decoded if `max_token_length`
+/// allows tokens longer than 12 bytes.
+pub(crate) fn decode_header(
+    buf: &[u8],
+    max_token_length: usize,
+) -> Result<(Header, Range<usize>), MessageError> {
+    let raw_header =
+        HeaderRaw::try_from(buf).map_err(|_| MessageError::InvalidHeader)?;
+    let header = Header::from_raw(&raw_header);
+    let indicator = header.get_token_length();
+    let (token_start, min_length) = match indicator {
+        0..=12 => (4, usize::from(indicator)),
+        13 => (5, 13),
+        14 => (6, 269),
+        _ => return Err(MessageError::ReservedTokenLength(indicator)),
+    };
+    if min_length > max_token_length {
+        return Err(MessageError::ReservedTokenLength(indicator));
+    }
+    if token_start > buf.len() {
+        return Err(MessageError::TruncatedMessage {
+            expected: token_start,
+            actual: buf.len(),
+        });
+    }
+    let token_length = min_length
+        + buf[4..token_start]
+            .iter()
+            .fold(0, |length, &byte| length << 8 | usize::from(byte));
+    if token_length > max_token_length {
+        return Err(MessageError::InvalidTokenLength);
+    }
+
+    let options_start = token_start + token_length;
+    if options_start > buf.len() {
+        return Err(MessageError::TruncatedMessage {
+            expected: options_start,
+            actual: buf.len(),
+        });
+    }
+    Ok((header, token_start..options_start))
+}
+
+/// The options and payload of a message, and in lenient mode the bytes
+/// that couldn't be decoded.
+pub(crate) struct DecodedBody {
+    pub(crate) options: OptionMap,
+    pub(crate) payload: Vec<u8>,
+    pub(crate) trailing_bytes: Vec<u8>,
+}
+
+/// Decodes the options and payload that make up the rest of a message
+/// starting at `start`, the same for UDP and TCP framing.
+pub(crate) fn decode_options_and_payload(
+    buf: &[u8],
+    start: usize,
+    config: &DecodeConfig,
+) -> Result<DecodedBody, MessageError> {
+    let lenient = config.lenient;
+    let mut options: OptionMap = BTreeMap::new();
+    let mut walker = OptionWalker::new(buf, start, 0);
+    while let Some(option) = walker.next() {
+        match option {
+            Ok(option) => options
+                .entry(option.number)
+                .or_default()
+                .push_back(option.value.to_vec()),
+            Err(_) if lenient => {
+                return Ok(DecodedBody {
+                    options,
+                    payload: Vec::new(),
+                    trailing_bytes: buf[walker.offset()..].to_vec(),
+                });
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    let idx = walker.offset();
+
+    let mut trailing_bytes = Vec::new();
+    let payload = if idx < buf.len() {
+        if idx + 1 == buf.len() {
+            if !lenient {
+                return Err(MessageError::EmptyPayload);
+            }
+            trailing_bytes.push(0xFF);
+            Vec::new()
+        } else {
+            let size = buf.len() - (idx + 1);
+            match config.max_payload_size {
+                Some(max) if size > max => {
+                    return Err(MessageError::PayloadTooLarge { size, max })
+                }
+                _ => buf[(idx + 1)..buf.len()].to_vec(),
+            }
+        }
+    } else {
+        Vec::new()
+    };
+
+    Ok(DecodedBody {
+        options,
+        payload,
+        trailing_bytes,
+    })
+}
+
+/// Decodes the option at `offset` following the option with the number
+/// `previous_number`, returning its number and the range of its value.
+fn decode_option(
+    buf: &[u8],
+    offset: usize,
+    previous_number: u16,
+) -> Result<(u16, usize, usize), MessageError> {
+    let (delta, length, header_len) = option_header::decode(&buf[offset..])
+        .map_err(|e| match e {
+            MessageError::InvalidOptionDelta { .. } => {
+                MessageError::InvalidOptionDelta { offset }
+            }
+            MessageError::InvalidOptionLength { .. } => {
+                MessageError::InvalidOptionLength { offset }
+            }
+            MessageError::TruncatedMessage { expected, .. } => {
+                MessageError::TruncatedMessage {
+                    expected: offset + expected,
+                    actual: buf.len(),
+                }
+            }
+            e => e,
+        })?;
+    let idx = offset + header_len;
+
+    let number = previous_number
+        .checked_add(delta)
+        .ok_or(MessageError::InvalidOptionDelta { offset })?;
+
+    let end = idx + length;
+    if end > buf.len() {
+        return Err(MessageError::TruncatedMessage {
+            expected: end,
+            actual: buf.len(),
+        });
+    }
+    Ok((number, idx, end))
+}
+
+/// An option of an encoded message, see [`OptionWalker`].
+pub(crate) struct RawOption<'a> {
+    pub(crate) number: u16,
+    pub(crate) value: &'a [u8],
+    /// The offset of the option header.
+    pub(crate) offset: usize,
+    /// The offset after the value, where the next option starts.
+    pub(crate) end: usize,
+}
+
+/// Walks the options of an encoded message up to the payload marker or the
+/// end of the buffer, validating them like [`Packet::from_bytes`].  Stops
+/// after the first malformed option.
+#[derive(Debug, Clone)]
+pub(crate) struct OptionWalker<'a> {
+    buf: &'a [u8],
+    offset: usize,
+    previous_number: u16,
+    failed: bool,
+}
+
+impl<'a> OptionWalker<'a> {
+    /// Starts at the option at `offset` following the option with the
+    /// number `previous_number`.
+    pub(crate) fn new(
+        buf: &'a [u8],
+        offset: usize,
+        previous_number: u16,
+    ) -> Self {
+        OptionWalker {
+            buf,
+            offset,
+            previous_number,
+            failed: false,
+        }
+    }
+
+    /// Returns the offset of the next option, or of the malformed option
+    /// that stopped the walk.
+    pub(crate) fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Returns the range of the payload after the options, which is empty
+    /// if there is none.  Only meaningful once all options were walked.
+    pub(crate) fn payload(&self) -> Result<Range<usize>, MessageError> {
+        let len = self.buf.len();
+        if self.offset >= len {
+            Ok(len..len)
+        } else if self.offset + 1 == len {
+            Err(MessageError::EmptyPayload)
+        } else {
+            Ok(self.offset + 1..len)
+        }
+    }
+}
+
+impl<'a> Iterator for OptionWalker<'a> {
+    type Item = Result<RawOption<'a>, MessageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed
+            || self.offset >= self.buf.len()
+            || self.buf[self.offset] == 0xFF
+        {
+            return None;
+        }
+        let offset = self.offset;
+        match decode_option(self.buf, offset, self.previous_number) {
+            Ok((number, start, end)) => {
+                self.offset = end;
+                self.previous_number = number;
+                Some(Ok(RawOption {
+                    number,
+                    value: &self.buf[start..end],
+                    offset,
+                    end,
+                }))
+            }
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl FusedIterator for OptionWalker<'_> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::RawPacketBuilder;
+    use crate::{header, option_value::OptionValueString};
+    use alloc::{borrow::ToOwned, string::ToString};
+
+    #[test]
+    fn test_decode_packet_with_options() {
+        let buf = [
+            0x44, 0x01, 0x84, 0x9e, 0x51, 0x55, 0x77, 0xe8, 0xb2, 0x48, 0x69,
+            0x04, 0x54, 0x65, 0x73, 0x74, 0x43, 0x61, 0x3d, 0x31,
+        ];
+        let packet = Packet::from_bytes(&buf);
+        assert!(packet.is_ok());
+        let packet = packet.unwrap();
+        assert_eq!(packet.header.get_version(), 1);
+        assert_eq!(packet.header.get_type(), header::MessageType::Confirmable);
+        assert_eq!(packet.header.get_token_length(), 4);
+        assert_eq!(
+            packet.header.code,
+            header::MessageClass::Request(header::RequestType::Get)
+        );
+        assert_eq!(packet.header.message_id, 33950);
+        assert_eq!(*packet.get_token(), vec![0x51, 0x55, 0x77, 0xE8]);
+        assert_eq!(packet.options.len(), 2);
+
+        let uri_path = packet.get_option(CoapOption::UriPath);
+        assert!(uri_path.is_some());
+        let uri_path = uri_path.unwrap();
+        let mut expected_uri_path = LinkedList::new();
+        expected_uri_path.push_back("Hi".as_bytes().to_vec());
+        expected_uri_path.push_back("Test".as_bytes().to_vec());
+        assert_eq!(*uri_path, expected_uri_path);
+
+        let uri_query = packet.get_option(CoapOption::UriQuery);
+        assert!(uri_query.is_some());
+        let uri_query = uri_query.unwrap();
+        let mut expected_uri_query = LinkedList::new();
+        expected_uri_query.push_back("a=1".as_bytes().to_vec());
+        assert_eq!(*uri_query, expected_uri_query);
+    }
+
+    #[test]
+    fn test_decode_packet_with_payload() {
+        let buf = [
+            0x64, 0x45, 0x13, 0xFD, 0xD0, 0xE2, 0x4D, 0xAC, 0xFF, 0x48, 0x65,
+            0x6C, 0x6C, 0x6F,
+        ];
+        let packet = Packet::from_bytes(&buf);
+        assert!(packet.is_ok());
+        let packet = packet.unwrap();
+        assert_eq!(packet.header.get_version(), 1);
+        assert_eq!(
+            packet.header.get_type(),
+            header::MessageType::Acknowledgement
+        );
+        assert_eq!(packet.header.get_token_length(), 4);
+        assert_eq!(
+            packet.header.code,
+            header::MessageClass::Response(header::ResponseType::Content)
+        );
+        assert_eq!(packet.header.message_id, 5117);
+        assert_eq!(*packet.get_token(), vec![0xD0, 0xE2, 0x4D, 0xAC]);
+        assert_eq!(packet.payload, "Hello".as_bytes().to_vec());
+    }
+
+    #[test]
+    fn test_inspect() {
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::NonConfirmable);
+        packet.header.message_id = 42;
+        packet.set_token(vec![1, 2, 3]);
+        packet.add_option(CoapOption::UriPath, b"a".to_vec());
+        packet.add_option(CoapOption::UriPath, b"b".to_vec());
+        packet.add_option(CoapOption::ProxyUri, vec![b'x'; 20]);
+        packet.add_option(CoapOption::Unknown(2048), vec![0; 300]);
+        packet.payload = b"data".to_vec();
+        let with_options = packet.to_bytes().unwrap();
+        let plain = Packet::new().to_bytes().unwrap();
+
+        for bytes in [&with_options, &plain] {
+            let stats = Packet::inspect(bytes).unwrap();
+            let packet = Packet::from_bytes(bytes).unwrap();
+            let option_count: usize =
+                packet.options().map(|(_, values)| values.len()).sum();
+            let header_len = 4 + packet.get_token().len();
+            let marker_len = usize::from(!packet.payload.is_empty());
+            assert_eq!(packet.header.code, stats.code);
+            assert_eq!(packet.header.get_type(), stats.message_type);
+            assert_eq!(packet.header.message_id, stats.message_id);
+            assert_eq!(
+                packet.get_token().len(),
+                usize::from(stats.token_length)
+            );
+            assert_eq!(option_count, stats.option_count);
+            assert_eq!(
+                bytes.len() - header_len - marker_len - packet.payload.len(),
+                stats.option_bytes
+            );
+            assert_eq!(packet.payload.len(), stats.payload_length);
+            assert_eq!(bytes.len(), stats.total_length);
+        }
+
+        // Proxy-Uri needs an extended delta and length, the unknown option
+        // a two byte extended delta and length
+        let stats = Packet::inspect(&with_options).unwrap();
+        assert!(stats.extended_delta && stats.extended_length);
+        assert_eq!(4, stats.option_count);
+        assert_eq!(2 + 2 + 3 + 20 + 5 + 300, stats.option_bytes);
+        let stats =
+            Packet::inspect(&RawPacketBuilder::new().option(1, &[]).build())
+                .unwrap();
+        assert!(!stats.extended_delta && !stats.extended_length);
+
+        let truncated = RawPacketBuilder::new().option_header(13, 0).build();
+        assert_eq!(
+            Packet::from_bytes(&truncated).unwrap_err(),
+            Packet::inspect(&truncated).unwrap_err()
+        );
+        let empty_payload = RawPacketBuilder::new().payload(&[]).build();
+        assert_eq!(
+            Err(MessageError::EmptyPayload),
+            Packet::inspect(&empty_payload)
+        );
+    }
+
+    #[test]
+    fn test_minimize_uints() {
+        let mut packet = Packet::new();
+        packet.add_option(CoapOption::MaxAge, vec![0x00, 0x3C]);
+        packet.add_option(CoapOption::Observe, vec![0x00, 0x00]);
+        packet.add_option(CoapOption::ETag, vec![0x00, 0x01]);
+        let minimize = EncodeConfig {
+            minimize_uints: true,
+            ..Default::default()
+        };
+
+        let bytes = packet.to_bytes().unwrap();
+        let decoded = Packet::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            Some(&vec![0x00, 0x3C]),
+            decoded.get_first_option(CoapOption::MaxAge)
+        );
+
+        let bytes = packet.to_bytes_with_config(&minimize).unwrap();
+        assert_eq!(bytes.len(), packet.encoded_len_with_config(&minimize));
+        assert_eq!(packet.encoded_len() - 3, bytes.len());
+        let decoded = Packet::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            Some(&vec![0x3C]),
+            decoded.get_first_option(CoapOption::MaxAge)
+        );
+        assert_eq!(
+            Some(&vec![]),
+            decoded.get_first_option(CoapOption::Observe)
+        );
+        // ETag is opaque, its leading zeros are significant
+        assert_eq!(
+            Some(&vec![0x00, 0x01]),
+            decoded.get_first_option(CoapOption::ETag)
+        );
+        assert_eq!(
+            Some(Ok(OptionValueU32(60))),
+            decoded.get_first_option_as::<OptionValueU32>(CoapOption::MaxAge)
+        );
+    }
+
+    #[test]
+    fn test_raw_options() {
+        // Echo and Request-Tag (RFC 9175) are unknown to this crate
+        const ECHO: u16 = 252;
+        const REQUEST_TAG: u16 = 292;
+        let mut packet = Packet::new();
+        packet.add_option(CoapOption::UriPath, b"a".to_vec());
+        packet.add_option_raw(REQUEST_TAG, vec![0x01]);
+        packet.add_option_raw(ECHO, vec![0xAA; 8]);
+        packet.add_option_raw(REQUEST_TAG, vec![0x02]);
+        packet.add_option_raw(11, b"b".to_vec());
+
+        let bytes = packet.to_bytes().unwrap();
+        let decoded = Packet::from_bytes(&bytes).unwrap();
+        assert_eq!(packet, decoded);
+        assert_eq!(
+            Some(&LinkedList::from([vec![0xAA; 8]])),
+            decoded.get_option_raw(ECHO)
+        );
+        assert_eq!(
+            Some(&LinkedList::from([vec![0x01], vec![0x02]])),
+            decoded.get_option(CoapOption::Unknown(REQUEST_TAG))
+        );
+        assert_eq!(
+            decoded.get_option(CoapOption::UriPath),
+            decoded.get_option_raw(11)
+        );
+        assert_eq!(2, decoded.get_option_raw(11).unwrap().len());
+        assert_eq!(bytes, decoded.to_bytes().unwrap());
+        assert_eq!(None, decoded.get_option_raw(2048));
+    }
+
+    #[test]
+    fn test_unknown_options_round_trip() {
+        let mut packet = Packet::new();
+        packet.add_option(CoapOption::Unknown(2049), vec![0x01]);
+        packet.add_option(CoapOption::Unknown(65000), vec![0x02, 0x03]);
+        packet.add_option(CoapOption::Unknown(65000), vec![]);
+
+        // Both deltas need two extension bytes
+        let bytes = packet.to_bytes().unwrap();
+        assert_eq!(
+            [0xE1, 0x06, 0xF4, 0x01, 0xE2, 0xF4, 0xDA, 0x02, 0x03, 0x00],
+            bytes[4..]
+        );
+
+        let decoded = Packet::from_bytes(&bytes).unwrap();
+        assert_eq!(packet, decoded);
+        let options: Vec<(CoapOption, usize)> = decoded
+            .typed_options()
+            .map(|(option, values)| (option, values.len()))
+            .collect();
+        assert_eq!(
+            vec![
+                (CoapOption::Unknown(2049), 1),
+                (CoapOption::Unknown(65000), 2)
+            ],
+            options
+        );
+    }
+
+    #[test]
+    fn test_block_options() {
+        let mut packet = Packet::new();
+        assert_eq!(None, packet.get_block2());
+        let block = BlockValue::new(5000, true, 512).unwrap();
+        packet.set_block2(block.clone());
+        packet.set_block2(block.clone());
+        let packet = Packet::from_bytes(&packet.to_bytes().unwrap()).unwrap();
+        assert_eq!(Some(block), packet.get_block2());
+        assert_eq!(None, packet.get_block1());
+
+        let mut packet = Packet::new();
+        packet.set_block1(BlockValue::new(0, false, 16).unwrap());
+        assert_eq!(Some(&vec![]), packet.get_first_option(CoapOption::Block1));
+        packet.add_option(CoapOption::Block2, vec![0x07]);
+        assert_eq!(None, packet.get_block2());
+    }
+
+    #[test]
+    fn test_u32_options() {
+        let mut packet = Packet::new();
+        packet.add_option_as_u32(CoapOption::MaxAge, 0);
+        packet.add_option_as_u32(CoapOption::Size2, 255);
+        packet.add_option_as_u32(CoapOption::Size1, 256);
+        packet.add_option_as_u32(CoapOption::Observe, u32::MAX);
+        let encoded = [
+            (CoapOption::MaxAge, vec![]),
+            (CoapOption::Size2, vec![0xFF]),
+            (CoapOption::Size1, vec![0x01, 0x00]),
+            (CoapOption::Observe, vec![0xFF; 4]),
+        ];
+        for (option, value) in encoded {
+            assert_eq!(Some(&value), packet.get_first_option(option));
+        }
+        assert_eq!(
+            Some(0),
+            packet.get_first_option_as_u32(CoapOption::MaxAge)
+        );
+        assert_eq!(
+            Some(u32::MAX),
+            packet.get_first_option_as_u32(CoapOption::Observe)
+        );
+
+        packet.add_option(CoapOption::UriPort, vec![0x00, 0x16, 0x33]);
+        packet.add_option(CoapOption::Accept, vec![0x01; 5]);
+        assert_eq!(
+            Some(5683),
+            packet.get_first_option_as_u32(CoapOption::UriPort)
+        );
+        assert_eq!(None, packet.get_first_option_as_u32(CoapOption::Accept));
+        assert_eq!(
+            None,
+            packet.get_first_option_as_u32(CoapOption::ContentFormat)
+        );
+    }
+
+    #[test]
+    fn test_first_option() {
+        let mut packet = Packet::new();
+        assert_eq!(None, packet.get_first_option(CoapOption::ETag));
+        packet.add_option(CoapOption::ETag, vec![1]);
+        packet.add_option(CoapOption::ETag, vec![2]);
+        assert_eq!(Some(&vec![1]), packet.get_first_option(CoapOption::ETag));
+
+        packet.get_first_option_mut(CoapOption::ETag).unwrap()[0] = 3;
+        assert_eq!(Some(&vec![3]), packet.get_first_option(CoapOption::ETag));
+
+        // An option can be present with an empty list of values
+        packet.set_option(CoapOption::ETag, LinkedList::new());
+        assert!(packet.get_option(CoapOption::ETag).is_some());
+        assert_eq!(None, packet.get_first_option(CoapOption::ETag));
+        assert_eq!(None, packet.get_first_option_mut(CoapOption::ETag));
+    }
+
+    #[test]
+    fn test_encode_long_option_values() {
+        // Lengths around the one and two byte extended lengths
+        for len in [12, 13, 268, 269, 270, 500] {
+            let mut packet = Packet::new();
+            packet.set_token(vec![0xAB; 8]);
+            packet.add_option(CoapOption::ProxyUri, vec![b'u'; len]);
+            packet.add_option(CoapOption::Unknown(2048), vec![0x5A; len]);
+            packet.payload = vec![0x42; 3];
+
+            let bytes = packet.to_bytes().unwrap();
+            assert_eq!(packet.encoded_len(), bytes.len());
+            assert_eq!([0x42; 3], bytes[bytes.len() - 3..]);
+            assert_eq!(packet, Packet::from_bytes(&bytes).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_remove_option() {
+        let mut packet = Packet::new();
+        packet.add_option(CoapOption::UriPath, b"a".to_vec());
+        packet.add_option(CoapOption::Unknown(2049), vec![1]);
+        packet.add_option(CoapOption::Unknown(2049), vec![2]);
+        let with_option = packet.to_bytes().unwrap();
+        assert_eq!(2, packet.options().len());
+
+        assert_eq!(
+            Some(LinkedList::from([vec![1], vec![2]])),
+            packet.remove_option(CoapOption::Unknown(2049))
+        );
+        assert_eq!(None, packet.remove_option(CoapOption::Unknown(2049)));
+        assert_eq!(1, packet.options().len());
+        let without_option = packet.to_bytes().unwrap();
+        assert_eq!(with_option[..6], without_option[..]);
+
+        packet.clear_option(CoapOption::UriPath);
+        assert_eq!(0, packet.options().len());
+        assert_eq!(None, packet.get_option(CoapOption::UriPath));
+        assert_eq!(4, packet.to_bytes().unwrap().len());
+    }
+
+    #[test]
+    fn test_decode_error_offset() {
+        // Uri-Path "a", then an option with the reserved delta 15
+        let uri_path = RawPacketBuilder::new().token(&[0x01]).option(11, b"a");
+        let buf = uri_path.clone().option_header(15, 1).bytes(&[0]).build();
+        assert_eq!(
+            MessageError::InvalidOptionDelta { offset: 7 },
+            Packet::from_bytes(&buf).unwrap_err()
+        );
+
+        // Uri-Path "a", then an option with the reserved length 15
+        let buf = uri_path.option_header(1, 15).bytes(&[0]).build();
+        assert_eq!(
+            MessageError::InvalidOptionLength { offset: 7 },
+            Packet::from_bytes(&buf).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_decode_token_length() {
+        let buf = RawPacketBuilder::new().token(&[0; 9]).build();
+        assert_eq!(
+            MessageError::ReservedTokenLength(9),
+            Packet::from_bytes(&buf).unwrap_err()
+        );
+
+        let buf = RawPacketBuilder::new()
+            .token(&[0xAB, 0xCD])
+            .token_length(4)
+            .build();
+        assert_eq!(
+            MessageError::TruncatedMessage {
+                expected: 8,
+                actual: 6
+            },
+            Packet::from_bytes(&buf).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_decode_truncated_option() {
+        let raw = RawPacketBuilder::new;
+        let cases = [
+            // Extended delta byte missing
+            (raw().option_header(13, 0), 6),
+            // Second extended delta byte missing
+            (raw().option_header(14, 0).bytes(&[0x00]), 7),
+            // Extended length byte missing
+            (raw().option_header(11, 13), 6),
+            // Second extended length byte missing
+            (raw().option_header(11, 14).bytes(&[0x00]), 7),
+            // Value runs past the end of the message
+            (raw().option_header(11, 3).bytes(b"a"), 8),
+            // Extended length pointing past the end of the message
+            (raw().option_header(11, 13).bytes(&[0x00, 0x61]), 19),
+        ];
+
+        for (builder, expected) in cases {
+            let buf = &builder.build()[..];
+            assert_eq!(
+                MessageError::TruncatedMessage {
+                    expected,
+                    actual: buf.len()
+                },
+                Packet::from_bytes(buf).unwrap_err()
+            );
+        }
+    }
+
+    #[test]
+    fn test_decode_overflowing_option_number() {
+        // Extended deltas that take the option number past 65535
+        let largest = RawPacketBuilder::new().option(65535, &[]);
+        let buf = largest.clone().option(268, &[]).build();
+        assert_eq!(
+            MessageError::InvalidOptionDelta { offset: 7 },
+            Packet::from_bytes(&buf).unwrap_err()
+        );
+        let buf = RawPacketBuilder::new()
+            .option_header(14, 0)
+            .bytes(&[0xFF, 0xFF])
+            .build();
+        assert_eq!(
+            MessageError::InvalidOptionDelta { offset: 4 },
+            Packet::from_bytes(&buf).unwrap_err()
+        );
+
+        // The largest possible delta and length don't overflow either
+        let packet = Packet::from_bytes(&largest.build()).unwrap();
+        assert!(packet.get_option(CoapOption::Unknown(65535)).is_some());
+        let buf = RawPacketBuilder::new()
+            .option_header(1, 14)
+            .bytes(&[0xFF, 0xFF])
+            .build();
+        assert_eq!(
+            MessageError::TruncatedMessage {
+                expected: 65811,
+                actual: 7
+            },
+            Packet::from_bytes(&buf).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_decode_empty_payload() {
+        let buf = RawPacketBuilder::new().payload(&[]).build();
+        assert_eq!(
+            MessageError::EmptyPayload,
+            Packet::from_bytes(&buf).unwrap_err()
+        );
+    }
+
+    // Deterministic xorshift generator, so that the round trip tests are
+    // reproducible without any additional dependencies
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn bytes(&mut self, len: usize) -> Vec<u8> {
+            (0..len).map(|_| self.next() as u8).collect()
+        }
+    }
+
+    fn random_packet(rng: &mut Rng) -> Packet {
+        const CODES: [u8; 12] = [
+            0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x45, 0x5F, 0x84, 0x8D, 0xA0,
+            0xA8,
+        ];
+        const OPTIONS: [u16; 22] = [
+            1, 3, 4, 5, 6, 7, 8, 11, 12, 14, 15, 17, 20, 23, 27, 28, 35, 39,
+            60, 258, 2049, 65000,
+        ];
+        const LONG_LENGTHS: [usize; 5] = [13, 14, 268, 269, 270];
+
+        let mut packet = Packet::new();
+        packet.header.set_type(match rng.below(4) {
+            0 => MessageType::Confirmable,
+            1 => MessageType::NonConfirmable,
+            2 => MessageType::Acknowledgement,
+            _ => MessageType::Reset,
+        });
+        packet.header.code = CODES[rng.below(CODES.len())].into();
+        packet.header.message_id = rng.next() as u16;
+        let token_length = rng.below(9);
+        packet.set_token(rng.bytes(token_length));
+
+        for _ in 0..rng.below(8) {
+            let number = OPTIONS[rng.below(OPTIONS.len())];
+            let length = if rng.below(8) == 0 {
+                LONG_LENGTHS[rng.below(LONG_LENGTHS.len())]
+            } else {
+                rng.below(13)
+            };
+            let value = rng.bytes(length);
+            packet.add_option(CoapOption::from(number), value);
+        }
+
+        if packet.header.code != MessageClass::Empty && rng.below(2) == 0 {
+            let length = rng.below(64) + 1;
+            packet.payload = rng.bytes(length);
+        }
+
+        packet
+    }
+
+    #[test]
+    fn test_decode_malformed() {
+        // Inputs that must fail cleanly rather than read out of bounds
+        let mut inputs = vec![
+            vec![],
+            vec![0x40],
+            vec![0x40, 0x01, 0x00],
+            // Token shorter than announced
+            vec![0x48, 0x01, 0x00, 0x01, 1, 2, 3],
+            vec![0x44, 0x01, 0x00, 0x01],
+            // Reserved token lengths
+            vec![0x4F, 0x01, 0x00, 0x01, 1, 2, 3, 4, 5, 6, 7, 8, 9],
+            vec![0x49, 0x01, 0x00, 0x01, 1, 2, 3, 4, 5, 6, 7, 8, 9],
+            // Extended delta and length without their extension bytes
+            vec![0x40, 0x01, 0x00, 0x01, 0xD0],
+            vec![0x40, 0x01, 0x00, 0x01, 0xE0, 0x01],
+            vec![0x40, 0x01, 0x00, 0x01, 0x0D],
+            vec![0x40, 0x01, 0x00, 0x01, 0x0E, 0x01],
+            // Values longer than the message
+            vec![0x40, 0x01, 0x00, 0x01, 0xB5, b'a'],
+            vec![0x40, 0x01, 0x00, 0x01, 0x0E, 0xFF, 0xFF],
+            // Option number beyond 65535
+            vec![0x40, 0x01, 0x00, 0x01, 0xE0, 0xFF, 0xFF, 0xE0, 0xFF, 0xFF],
+        ];
+        let mut rng = Rng(0xD1B5_4A32_D192_ED03);
+        for _ in 0..5000 {
+            let length = rng.below(24);
+            let mut bytes = rng.bytes(length);
+            if let Some(first) = bytes.first_mut() {
+                // Mostly version 1, so that decoding gets past the header
+                *first = *first & 0x3F | 0x40;
+            }
+            inputs.push(bytes);
+        }
+
+        for bytes in inputs {
+            if let Ok(packet) = Packet::from_bytes(&bytes) {
+                assert_eq!(
+                    usize::from(packet.header.get_token_length()),
+                    packet.get_token().len()
+                );
+                assert!(packet.get_token().len() <= 8);
+                assert_eq!(
+                    &bytes[4..4 + packet.get_token().len()],
+                    packet.get_token()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_extended_token_length() {
+        let limits = ProtocolLimits {
+            max_token_length: 65804,
+            max_message_size: usize::MAX,
+            ..ProtocolLimits::RFC
+        };
+        let encode = EncodeConfig {
+            limits,
+            ..Default::default()
+        };
+        let decode = DecodeConfig {
+            limits,
+            ..Default::default()
+        };
+
+        for (length, indicator, extension) in [
+            (12, 12, &[][..]),
+            (13, 13, &[0x00]),
+            (268, 13, &[0xFF]),
+            (269, 14, &[0x00, 0x00]),
+            (1000, 14, &[0x02, 0xDB]),
+            (65804, 14, &[0xFF, 0xFF]),
+        ] {
+            let mut packet = Packet::new();
+            packet.set_token(vec![0xAB; length]);
+            packet.add_option(CoapOption::UriPath, b"a".to_vec());
+            assert_eq!(indicator, packet.header.get_token_length());
+
+            let bytes = packet.to_bytes_with_config(&encode).unwrap();
+            assert_eq!(packet.encoded_len(), bytes.len());
+            assert_eq!(indicator, bytes[0] & 0x0F);
+            assert_eq!(extension, &bytes[4..4 + extension.len()]);
+            let decoded = Packet::from_bytes_with_config(&bytes, &decode);
+            assert_eq!(packet, decoded.unwrap());
+
+            // Unchanged by default
+            if length > 12 {
+                assert_eq!(
+                    Err(MessageError::InvalidTokenLength),
+                    packet.to_bytes()
+                );
+                assert_eq!(
+                    Err(MessageError::ReservedTokenLength(indicator)),
+                    Packet::from_bytes(&bytes)
+                );
+            }
+        }
+
+        let mut packet = Packet::new();
+        packet.set_token(vec![0; 65805]);
+        assert_eq!(15, packet.header.get_token_length());
+        assert_eq!(
+            Err(MessageError::InvalidTokenLength),
+            packet.to_bytes_with_config(&encode)
+        );
+
+        // The extended length has to fit the limit and the message
+        let bytes = RawPacketBuilder::new()
+            .token_length(13)
+            .bytes(&[0x03])
+            .build();
+        assert_eq!(
+            Err(MessageError::TruncatedMessage {
+                expected: 5 + 16,
+                actual: 5
+            }),
+            Packet::from_bytes_with_config(&bytes, &decode)
+        );
+        let short = DecodeConfig {
+            limits: ProtocolLimits {
+                max_token_length: 15,
+                ..limits
+            },
+            ..Default::default()
+        };
+        assert_eq!(
+            Err(MessageError::InvalidTokenLength),
+            Packet::from_bytes_with_config(&bytes, &short)
+        );
+        let bytes = RawPacketBuilder::new()
+            .token_length(14)
+            .bytes(&[0x00])
+            .build();
+        assert_eq!(
+            Err(MessageError::TruncatedMessage {
+                expected: 6,
+                actual: 5
+            }),
+            Packet::from_bytes_with_config(&bytes, &decode)
+        );
+        let bytes = RawPacketBuilder::new().token_length(15).build();
+        assert_eq!(
+            Err(MessageError::ReservedTokenLength(15)),
+            Packet::from_bytes_with_config(&bytes, &decode)
+        );
+    }
+
+    #[test]
+    fn test_encode_token_length_mismatch() {
+        let mut packet = Packet::new();
+        packet.set_token(vec![1, 2]);
+        packet.header.set_token_length(3);
+        assert_eq!(Err(MessageError::InvalidTokenLength), packet.to_bytes());
+        assert_eq!(
+            Err(MessageError::InvalidTokenLength),
+            packet.write_to(&mut Vec::new())
+        );
+        assert_eq!(
+            Err(MessageError::InvalidTokenLength),
+            packet.serialize_envelope_into(0, &mut [0; 16])
+        );
+
+        packet.header = Header::new();
+        assert_eq!(Err(MessageError::InvalidTokenLength), packet.to_bytes());
+        packet.set_token(vec![1, 2]);
+        let bytes = packet.to_bytes().unwrap();
+        assert_eq!(packet, Packet::from_bytes(&bytes).unwrap());
+    }
+
+    #[test]
+    fn test_round_trip_packets() {
+        let mut rng = Rng(0x2545_F491_4F6C_DD1D);
+        let mut encoded = 0;
+        for _ in 0..2000 {
+            let packet = random_packet(&mut rng);
+            let bytes = match packet.to_bytes() {
+                Ok(bytes) => bytes,
+                Err(MessageError::InvalidPacketLength) => continue,
+                Err(e) => panic!("Unexpected error {:?}", e),
+            };
+            encoded += 1;
+
+            let decoded = Packet::from_bytes(&bytes).unwrap();
+            assert_eq!(packet, decoded);
+            assert_eq!(bytes, decoded.to_bytes().unwrap());
+        }
+        assert!(encoded > 1500);
+    }
+
+    #[test]
+    fn test_round_trip_bytes() {
+        let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+        let mut decoded = 0;
+        for _ in 0..2000 {
+            let mut bytes = match random_packet(&mut rng).to_bytes() {
+                Ok(bytes) => bytes,
+                Err(_) => continue,
+            };
+            for _ in 0..rng.below(3) + 1 {
+                let position = rng.below(bytes.len());
+                bytes[position] = rng.next() as u8;
+            }
+
+            let packet = match Packet::from_bytes(&bytes) {
+                Ok(packet) => packet,
+                Err(_) => continue,
+            };
+            decoded += 1;
+
+            let code_known = u8::from(packet.header.code) == bytes[1];
+            let payload_dropped = packet.header.code == MessageClass::Empty
+                && !packet.payload.is_empty();
+            let reencoded = packet.to_bytes().unwrap();
+            if code_known && !payload_dropped {
+                assert_eq!(bytes, reencoded);
+            } else {
+                let redecoded = Packet::from_bytes(&reencoded).unwrap();
+                assert_eq!(packet.header, redecoded.header);
+                assert_eq!(packet.options, redecoded.options);
+            }
+        }
+        assert!(decoded > 500);
+    }
+
+    #[test]
+    fn test_encode_packet_with_options() {
+        let mut packet = Packet::new();
+        packet.header.set_version(1);
+        packet.header.set_type(header::MessageType::Confirmable);
+        packet.header.code =
+            header::MessageClass::Request(header::RequestType::Get);
+        packet.header.message_id = 33950;
+        packet.set_token(vec![0x51, 0x55, 0x77, 0xE8]);
+        packet.add_option(CoapOption::UriPath, b"Hi".to_vec());
+        packet.add_option(CoapOption::UriPath, b"Test".to_vec());
+        packet.add_option(CoapOption::UriQuery, b"a=1".to_vec());
+        assert_eq!(
+            packet.to_bytes().unwrap(),
+            vec![
+                0x44, 0x01, 0x84, 0x9e, 0x51, 0x55, 0x77, 0xe8, 0xb2, 0x48,
+                0x69, 0x04, 0x54, 0x65, 0x73, 0x74, 0x43, 0x61, 0x3d, 0x31
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encode_with_config() {
+        let mut packet = Packet::new();
+        packet.header.message_id = 1;
+        packet.add_option(CoapOption::UriPath, b"b".to_vec());
+        packet.add_option(CoapOption::UriPath, b"a".to_vec());
+        packet.add_option(CoapOption::UriQuery, b"y=2".to_vec());
+        packet.add_option(CoapOption::UriQuery, b"x=1".to_vec());
+
+        assert_eq!(
+            packet.to_bytes().unwrap(),
+            packet
+                .to_bytes_with_config(&EncodeConfig::default())
+                .unwrap()
+        );
+
+        let sorted = EncodeConfig {
+            value_order: Some(|_, a, b| a.cmp(b)),
+            ..Default::default()
+        };
+        let bytes = packet.to_bytes_with_config(&sorted).unwrap();
+        assert_eq!(
+            vec![
+                0x40, 0x01, 0x00, 0x01, 0xB1, b'a', 0x01, b'b', 0x43, b'x',
+                b'=', b'1', 0x03, b'y', b'=', b'2'
+            ],
+            bytes
+        );
+        let decoded = Packet::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            Some(&LinkedList::from([b"a".to_vec(), b"b".to_vec()])),
+            decoded.get_option(CoapOption::UriPath)
+        );
+
+        let pinned = EncodeConfig {
+            pinned: &[11],
+            ..sorted
+        };
+        let bytes = packet.to_bytes_with_config(&pinned).unwrap();
+        let decoded = Packet::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            packet.get_option(CoapOption::UriPath),
+            decoded.get_option(CoapOption::UriPath)
+        );
+        assert_eq!(
+            Some(&LinkedList::from([b"x=1".to_vec(), b"y=2".to_vec()])),
+            decoded.get_option(CoapOption::UriQuery)
+        );
+    }
+
+    #[test]
+    fn protocol_limits() {
+        let mut packet = Packet::new();
+        packet.payload = vec![0x42; 1500];
+        assert_eq!(Err(MessageError::InvalidPacketLength), packet.to_bytes());
+
+        let large = ProtocolLimits {
+            max_message_size: 1500 + 5,
+            ..ProtocolLimits::RFC
+        };
+        let config = EncodeConfig {
+            limits: large,
+            ..Default::default()
+        };
+        assert_eq!(
+            1500 + 5,
+            packet.to_bytes_with_config(&config).unwrap().len()
+        );
+        assert_eq!(
+            packet.to_bytes(),
+            packet.to_bytes_with_limit(Some(
+                ProtocolLimits::RFC.max_message_size
+            ))
+        );
+        assert_eq!(
+            Err(MessageError::InvalidPacketLength),
+            packet.to_bytes_with_limit(Some(1500 + 4))
+        );
+        assert_eq!(
+            packet.to_bytes_with_config(&config),
+            packet.to_bytes_with_limit(Some(1500 + 5))
+        );
+        packet.payload = vec![0x42; 70000];
+        assert_eq!(70000 + 5, packet.to_bytes_with_limit(None).unwrap().len());
+
+        // RFC 8974 tokens
+        let mut packet = Packet::new();
+        packet.set_token(vec![0x01; 10]);
+        assert_eq!(Err(MessageError::InvalidTokenLength), packet.to_bytes());
+
+        let limits = ProtocolLimits {
+            max_token_length: 12,
+            ..ProtocolLimits::RFC
+        };
+        let bytes = packet
+            .to_bytes_with_config(&EncodeConfig {
+                limits,
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(0x4A, bytes[0]);
+        assert_eq!(
+            Err(MessageError::ReservedTokenLength(10)),
+            Packet::from_bytes(&bytes)
+        );
+        let config = DecodeConfig {
+            limits,
+            ..Default::default()
+        };
+        assert_eq!(
+            packet,
+            Packet::from_bytes_with_config(&bytes, &config).unwrap()
+        );
+
+        let mut packet = Packet::new();
+        packet.add_option(CoapOption::UriHost, vec![b'a'; 256]);
+        assert_eq!(
+            Err(ValidationError::OptionTooLong(3)),
+            packet.validate(ValidationMode::Lenient)
+        );
+        let limits = ProtocolLimits {
+            max_uri_host_length: 256,
+            ..ProtocolLimits::RFC
+        };
+        assert_eq!(
+            Ok(vec![]),
+            packet.validate_with_limits(ValidationMode::Strict, &limits)
+        );
+    }
+
+    #[test]
+    fn test_write_to_sink() {
+        // Accepts at most 4 bytes at a time, like a DMA descriptor chain
+        struct Chunks(Vec<Vec<u8>>);
+
+        impl OptionSink for Chunks {
+            fn write(&mut self, buf: &[u8]) -> Result<(), MessageError> {
+                if self.0.len() + buf.len().div_ceil(4) > 8 {
+                    return Err(MessageError::InvalidPacketLength);
+                }
+                self.0.extend(buf.chunks(4).map(|chunk| chunk.to_vec()));
+                Ok(())
+            }
+        }
+
+        let mut packet = Packet::new();
+        packet.header.message_id = 1;
+        packet.set_token(vec![0x01, 0x02]);
+        packet.add_option(CoapOption::UriPath, b"test".to_vec());
+        packet.payload = b"hello".to_vec();
+
+        let mut chunks = Chunks(Vec::new());
+        let len = packet.write_to(&mut chunks).unwrap();
+        let bytes = packet.to_bytes().unwrap();
+        assert_eq!(bytes.len(), len);
+        assert_eq!(bytes, chunks.0.concat());
+
+        let mut buf = Vec::new();
+        assert_eq!(Ok(len), packet.write_to(&mut buf));
+        assert_eq!(bytes, buf);
+
+        packet.payload = vec![0x42; 20];
+        let mut chunks = Chunks(Vec::new());
+        assert_eq!(
+            Err(MessageError::InvalidPacketLength),
+            packet.write_to(&mut chunks)
+        );
+
+        packet.payload = vec![0x42; 1280];
+        assert_eq!(
+            Err(MessageError::InvalidPacketLength),
+            packet.write_to(&mut Vec::new())
+        );
     }
 
     #[test]
-    fn test_decode_packet_with_payload() {
-        let buf = [
-            0x64, 0x45, 0x13, 0xFD, 0xD0, 0xE2, 0x4D, 0xAC, 0xFF, 0x48, 0x65,
-            0x6C, 0x6C, 0x6F,
-        ];
-        let packet = Packet::from_bytes(&buf);
-        assert!(packet.is_ok());
-        let packet = packet.unwrap();
-        assert_eq!(packet.header.get_version(), 1);
+    fn test_serialize_envelope() {
+        let mut packet = Packet::new();
+        packet.header.message_id = 42;
+        packet.header.code = MessageClass::Response(ResponseType::Content);
+        packet.set_token(vec![1, 2, 3]);
+        packet.add_option(CoapOption::ContentFormat, vec![42]);
+        packet.payload = b"sensor data".to_vec();
+
+        let mut buf = [0; 1280];
+        let info = packet
+            .serialize_envelope_into(packet.payload.len(), &mut buf)
+            .unwrap();
+        assert!(info.payload_marker);
+        let end = info.len + packet.payload.len();
+        buf[info.len..end].copy_from_slice(&packet.payload);
+        assert_eq!(packet.to_bytes().unwrap(), &buf[..end]);
+
+        packet.payload.clear();
+        let info = packet.serialize_envelope_into(0, &mut buf).unwrap();
+        assert!(!info.payload_marker);
+        assert_eq!(packet.to_bytes().unwrap(), &buf[..info.len]);
+
+        let mut empty = Packet::new();
+        empty.header.code = MessageClass::Empty;
+        let info = empty.serialize_envelope_into(10, &mut buf).unwrap();
+        assert_eq!(
+            EnvelopeInfo {
+                len: 4,
+                payload_marker: false
+            },
+            info
+        );
+
+        assert_eq!(
+            Err(MessageError::InvalidPacketLength),
+            packet.serialize_envelope_into(0, &mut buf[..8])
+        );
+        assert_eq!(
+            Err(MessageError::InvalidPacketLength),
+            packet.serialize_envelope_into(1280, &mut buf)
+        );
+
+        // Larger messages and minimal option values as configured
+        packet.clear_option(CoapOption::ContentFormat);
+        packet.add_option(CoapOption::ContentFormat, vec![0, 0, 42]);
+        packet.payload = vec![0x42; 1500];
+        let config = EncodeConfig {
+            limits: ProtocolLimits {
+                max_message_size: 2000,
+                ..ProtocolLimits::RFC
+            },
+            minimize_uints: true,
+            ..Default::default()
+        };
+        let bytes = packet.to_bytes_with_config(&config).unwrap();
+        let info = packet
+            .serialize_envelope_into_with_config(1500, &mut buf, &config)
+            .unwrap();
+        assert_eq!(bytes.len() - 1500, info.len);
+        assert_eq!(bytes[..info.len], buf[..info.len]);
+        assert_eq!(
+            Err(MessageError::InvalidPacketLength),
+            packet.serialize_envelope_into(1500, &mut buf)
+        );
+
+        // A value too long to be encoded
+        packet.add_option(CoapOption::UriQuery, vec![0; 70000]);
+        let config = EncodeConfig {
+            limits: ProtocolLimits {
+                max_message_size: usize::MAX,
+                ..ProtocolLimits::RFC
+            },
+            ..Default::default()
+        };
+        assert_eq!(
+            Err(MessageError::InvalidPacketLength),
+            packet.serialize_envelope_into_with_config(0, &mut buf, &config)
+        );
+        let mut large = vec![0; 80000];
+        assert_eq!(
+            Err(MessageError::InvalidOptionLength { offset: 11 }),
+            packet.serialize_envelope_into_with_config(0, &mut large, &config)
+        );
+    }
+
+    #[test]
+    fn test_encode_option_too_long() {
+        let mut packet = Packet::new();
+        packet.set_token(vec![1]);
+        packet.add_option(CoapOption::UriPath, b"a".to_vec());
+        packet.add_option(CoapOption::UriQuery, vec![0; 70000]);
+
+        let error = MessageError::InvalidOptionLength { offset: 7 };
+        assert_eq!(Err(error), packet.to_bytes_with_limit(None));
+        assert_eq!(Err(MessageError::InvalidPacketLength), packet.to_bytes());
+        let mut buf = Vec::new();
+        assert_eq!(
+            Err(MessageError::InvalidPacketLength),
+            packet.write_to(&mut buf)
+        );
+        assert!(buf.is_empty());
+
+        packet.clear_option(CoapOption::UriQuery);
+        packet.add_option(CoapOption::UriQuery, vec![0; 65804]);
+        assert!(packet.to_bytes_with_limit(None).is_ok());
+    }
+
+    #[test]
+    fn test_option_overhead() {
+        assert_eq!(1, option_overhead(0, 0));
+        assert_eq!(1, option_overhead(12, 12));
+        assert_eq!(2, option_overhead(13, 0));
+        assert_eq!(3, option_overhead(268, 13));
+        assert_eq!(3, option_overhead(269, 0));
+        assert_eq!(2, option_overhead(0, 268));
+        assert_eq!(3, option_overhead(0, 269));
+        assert_eq!(5, option_overhead(269, 269));
+
+        let mut packet = Packet::new();
+        packet.set_token(vec![1, 2]);
+        assert_eq!(packet.to_bytes().unwrap().len(), packet.encoded_len());
+        for (number, value_len) in
+            [(11, 3), (11, 20), (60, 0), (258, 300), (2049, 1)]
+        {
+            let before = packet.encoded_len();
+            let previous = packet.options.keys().last().copied().unwrap_or(0);
+            packet.add_option(CoapOption::from(number), vec![0; value_len]);
+            assert_eq!(
+                before
+                    + option_overhead(
+                        usize::from(number - previous),
+                        value_len
+                    )
+                    + value_len,
+                packet.encoded_len()
+            );
+            assert_eq!(packet.to_bytes().unwrap().len(), packet.encoded_len());
+        }
+        packet.payload = b"payload".to_vec();
+        assert_eq!(packet.to_bytes().unwrap().len(), packet.encoded_len());
+    }
+
+    #[test]
+    fn test_max_payload_size() {
+        let mut packet = Packet::new();
+        packet.header.code = MessageClass::Request(RequestType::Post);
+        packet.add_option(CoapOption::UriPath, b"upload".to_vec());
+        packet.payload = vec![0x42; 100];
+        let bytes = packet.to_bytes().unwrap();
+
+        let config = DecodeConfig {
+            max_payload_size: Some(100),
+            ..Default::default()
+        };
+        assert_eq!(
+            packet,
+            Packet::from_bytes_with_config(&bytes, &config).unwrap()
+        );
+
+        let config = DecodeConfig {
+            max_payload_size: Some(99),
+            ..Default::default()
+        };
+        assert_eq!(
+            Err(MessageError::PayloadTooLarge { size: 100, max: 99 }),
+            Packet::from_bytes_with_config(&bytes, &config)
+        );
+
+        // The rest of the message doesn't count
+        packet.payload.clear();
+        let bytes = packet.to_bytes().unwrap();
+        let config = DecodeConfig {
+            max_payload_size: Some(0),
+            ..Default::default()
+        };
+        assert!(Packet::from_bytes_with_config(&bytes, &config).is_ok());
+    }
+
+    #[test]
+    fn test_lenient_trailing_bytes() {
+        let lenient_decode = DecodeConfig {
+            lenient: true,
+            ..Default::default()
+        };
+        let lenient_encode = EncodeConfig {
+            lenient: true,
+            ..Default::default()
+        };
+
+        // Uri-Path followed by an option with the reserved delta 15
+        let buf = [0x40, 0x01, 0x00, 0x01, 0xB1, b'a', 0xF1, 0x02, 0x03];
+        assert_eq!(
+            Err(MessageError::InvalidOptionDelta { offset: 6 }),
+            Packet::from_bytes(&buf)
+        );
+        let packet =
+            Packet::from_bytes_with_config(&buf, &lenient_decode).unwrap();
+        assert_eq!(
+            Some(&b"a".to_vec()),
+            packet.get_first_option(CoapOption::UriPath)
+        );
+        assert_eq!([0xF1, 0x02, 0x03], packet.trailing_bytes[..]);
+        assert!(packet.payload.is_empty());
+        assert_eq!(
+            buf,
+            packet.to_bytes_with_config(&lenient_encode).unwrap()[..]
+        );
+        assert_eq!(buf[..6], packet.to_bytes().unwrap()[..]);
+
+        // Changing the options invalidates the deltas of the trailing bytes
+        let mut changed = packet.clone();
+        changed.add_option(CoapOption::Unknown(65001), vec![]);
+        assert!(changed.trailing_bytes.is_empty());
+        let mut changed = packet.clone();
+        changed.clear_option(CoapOption::ContentFormat);
+        assert_eq!(packet.trailing_bytes, changed.trailing_bytes);
+        changed.clear_option(CoapOption::UriPath);
+        assert!(changed.trailing_bytes.is_empty());
+        let filtered = packet.filter_options(|_| false);
+        assert!(filtered.trailing_bytes.is_empty());
+        assert_eq!(packet, packet.filter_options(|_| true));
+
+        // A payload marker without payload
+        let buf = [0x40, 0x01, 0x00, 0x01, 0xFF];
+        assert_eq!(Err(MessageError::EmptyPayload), Packet::from_bytes(&buf));
+        let packet =
+            Packet::from_bytes_with_config(&buf, &lenient_decode).unwrap();
+        assert_eq!([0xFF], packet.trailing_bytes[..]);
+        assert_eq!(
+            buf,
+            packet.to_bytes_with_config(&lenient_encode).unwrap()[..]
+        );
+        let mut changed = packet.clone();
+        changed.add_option(CoapOption::UriPath, b"a".to_vec());
+        assert_eq!([0xFF], changed.trailing_bytes[..]);
+
+        // Conformant messages decode the same in both modes
+        let buf = [0x40, 0x01, 0x00, 0x01, 0xB1, b'a', 0xFF, b'x'];
+        assert_eq!(
+            Packet::from_bytes(&buf).unwrap(),
+            Packet::from_bytes_with_config(&buf, &lenient_decode).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_encode_packet_with_payload() {
+        let mut packet = Packet::new();
+        packet.header.set_version(1);
+        packet.header.set_type(header::MessageType::Acknowledgement);
+        packet.header.code =
+            header::MessageClass::Response(header::ResponseType::Content);
+        packet.header.message_id = 5117;
+        packet.set_token(vec![0xD0, 0xE2, 0x4D, 0xAC]);
+        packet.payload = "Hello".as_bytes().to_vec();
+        assert_eq!(
+            packet.to_bytes().unwrap(),
+            vec![
+                0x64, 0x45, 0x13, 0xFD, 0xD0, 0xE2, 0x4D, 0xAC, 0xFF, 0x48,
+                0x65, 0x6C, 0x6C, 0x6F
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encode_decode_content_format() {
+        let mut packet = Packet::new();
+        packet.set_content_format(ContentFormat::TextPlain);
+        assert_eq!(
+            ContentFormat::TextPlain,
+            packet.get_content_format().unwrap()
+        )
+    }
+
+    #[test]
+    fn test_encode_decode_content_format_without_msb() {
+        let mut packet = Packet::new();
+        packet.set_content_format(ContentFormat::ApplicationJSON);
+        assert_eq!(
+            ContentFormat::ApplicationJSON,
+            packet.get_content_format().unwrap()
+        )
+    }
+
+    #[test]
+    fn test_encode_decode_content_format_with_msb() {
+        let mut packet = Packet::new();
+        packet.set_content_format(ContentFormat::ApplicationSensmlXML);
+        assert_eq!(
+            ContentFormat::ApplicationSensmlXML,
+            packet.get_content_format().unwrap()
+        )
+    }
+
+    #[test]
+    fn test_decode_empty_content_format() {
+        let packet = Packet::new();
+        assert!(packet.get_content_format().is_none());
+    }
+
+    #[test]
+    fn option() {
+        for i in 0..512 {
+            match CoapOption::try_from(i) {
+                Ok(o) => assert_eq!(i, u16::from(o)),
+                _ => (),
+            }
+        }
+    }
+
+    #[test]
+    fn critical_option() {
+        assert!(CoapOption::IfMatch.is_critical());
+        assert!(CoapOption::UriPath.is_critical());
+        assert!(!CoapOption::ETag.is_critical());
+        assert!(!CoapOption::Size2.is_critical());
+        assert!(CoapOption::Unknown(2049).is_critical());
+        assert!(!CoapOption::Unknown(2048).is_critical());
+
+        // Proxy-Uri is critical and unsafe, Size1 elective, safe and no
+        // cache key (RFC 7252 section 5.10)
+        assert!(CoapOption::ProxyUri.is_unsafe_to_forward());
+        assert!(CoapOption::UriHost.is_unsafe_to_forward());
+        assert!(!CoapOption::ETag.is_unsafe_to_forward());
+        assert!(!CoapOption::Size1.is_unsafe_to_forward());
+        assert!(CoapOption::Size1.is_no_cache_key());
+        assert!(!CoapOption::MaxAge.is_no_cache_key());
+
+        let mut packet = Packet::new();
+        packet.add_option(CoapOption::UriPath, b"a".to_vec());
+        packet.add_option(CoapOption::ETag, vec![1]);
+        packet.add_option(CoapOption::Unknown(2049), vec![]);
+        packet.add_option(CoapOption::Unknown(65001), vec![]);
+        assert_eq!(
+            Err(vec![11, 2049, 65001]),
+            packet.check_critical_options(&[])
+        );
+        assert_eq!(
+            Err(vec![65001]),
+            packet.check_critical_options(&[11, 2049])
+        );
+        assert_eq!(Ok(()), packet.check_critical_options(&[11, 2049, 65001]));
+
+        // Options set to an empty list aren't present
+        packet.set_option(CoapOption::Unknown(65001), LinkedList::new());
+        assert_eq!(Ok(()), packet.check_critical_options(&[11, 2049]));
+    }
+
+    #[test]
+    fn no_cache_key_option() {
+        assert!(CoapOption::Size1.is_no_cache_key());
+        assert!(CoapOption::Size2.is_no_cache_key());
+        assert!(!CoapOption::UriPath.is_no_cache_key());
+        assert!(!CoapOption::Accept.is_no_cache_key());
+        assert!(!CoapOption::ETag.is_no_cache_key());
+    }
+
+    #[test]
+    fn coalescable() {
+        let mut request = Packet::new();
+        request.header.code = MessageClass::Request(RequestType::Get);
+        request.header.message_id = 1;
+        request.set_token(vec![1]);
+        request.add_option(CoapOption::UriPath, b"temp".to_vec());
+        request.add_option_as(CoapOption::Accept, OptionValueU16(50));
+
+        let mut other = request.clone();
+        other.header.message_id = 2;
+        other.set_token(vec![2]);
+        other.add_option_as(CoapOption::Size2, OptionValueU16(0));
+        assert!(request.is_coalescable_with(&other));
+
+        let mut different = other.clone();
+        different.clear_option(CoapOption::Accept);
+        assert!(!request.is_coalescable_with(&different));
+
+        let mut different = other.clone();
+        different.add_option(CoapOption::UriQuery, b"unit=C".to_vec());
+        assert!(!request.is_coalescable_with(&different));
+
+        let mut different = other.clone();
+        different.header.code = MessageClass::Request(RequestType::Fetch);
+        assert!(!request.is_coalescable_with(&different));
+
+        let mut observing = other.clone();
+        observing.set_observe(Observe::Register);
+        assert!(!request.is_coalescable_with(&observing));
+        assert!(!observing.is_coalescable_with(&request));
+
+        let mut post = request.clone();
+        post.header.code = MessageClass::Request(RequestType::Post);
+        assert!(!post.is_coalescable_with(&post.clone()));
+    }
+
+    #[test]
+    fn merge_options() {
+        let mut template = Packet::new();
+        template.header.code = MessageClass::Request(RequestType::Post);
+        template.add_option(CoapOption::UriPath, b"telemetry".to_vec());
+        template.add_option(CoapOption::UriQuery, b"id=1".to_vec());
+        template.set_content_format(ContentFormat::ApplicationCBOR);
+
+        let mut stamped = Packet::new();
+        stamped.header.code = MessageClass::Request(RequestType::Post);
+        stamped.header.message_id = 7;
+        stamped.add_option(CoapOption::UriPath, b"old".to_vec());
+        stamped.merge_options_from(&template, |_| true);
+        stamped.payload = vec![0xA0];
+
+        let mut rebuilt = Packet::new();
+        rebuilt.header.code = MessageClass::Request(RequestType::Post);
+        rebuilt.header.message_id = 7;
+        rebuilt.add_option(CoapOption::UriPath, b"telemetry".to_vec());
+        rebuilt.add_option(CoapOption::UriQuery, b"id=1".to_vec());
+        rebuilt.set_content_format(ContentFormat::ApplicationCBOR);
+        rebuilt.payload = vec![0xA0];
+        assert_eq!(rebuilt.to_bytes().unwrap(), stamped.to_bytes().unwrap());
+
+        let mut filtered = Packet::new();
+        filtered.merge_options_from(&template, |option| {
+            option != CoapOption::UriQuery
+        });
+        assert!(filtered.get_option(CoapOption::UriQuery).is_none());
+        assert_eq!(
+            b"telemetry".to_vec(),
+            *filtered.get_first_option(CoapOption::UriPath).unwrap()
+        );
+        assert_eq!(
+            Some(ContentFormat::ApplicationCBOR),
+            filtered.get_content_format()
+        );
+    }
+
+    #[test]
+    fn advertise_response_size() {
+        let mut packet = Packet::new();
+        assert_eq!(None, packet.get_size2());
+        packet.advertise_response_size(300);
+        packet.advertise_response_size(1000);
+        assert_eq!(
+            Some(&[vec![0x03, 0xE8]].into()),
+            packet.get_option(CoapOption::Size2)
+        );
+        assert_eq!(Some(Ok(1000)), packet.get_size2());
+        packet.advertise_response_size(0);
+        assert_eq!(Some(Ok(0)), packet.get_size2());
+    }
+
+    #[test]
+    fn suppresses_response() {
+        let mut request = Packet::new();
+        assert!(!request.suppresses_response(ResponseType::Content));
+
+        // Not interested in 2.xx and 5.xx responses
+        request.add_option_as(CoapOption::NoResponse, OptionValueU8(0x12));
+        assert!(request.suppresses_response(ResponseType::Content));
+        assert!(!request.suppresses_response(ResponseType::NotFound));
+        assert!(request.suppresses_response(ResponseType::ServiceUnavailable));
+
+        request.set_option(CoapOption::NoResponse, [vec![0, 0]].into());
+        assert!(!request.suppresses_response(ResponseType::Content));
+    }
+
+    #[test]
+    fn decode_streaming() {
+        let mut packet = Packet::new();
+        packet.set_token(vec![0xAB, 0xCD]);
+        packet.add_option(CoapOption::UriPath, b"a".to_vec());
+        packet.add_option(CoapOption::UriPath, b"b".to_vec());
+        packet.add_option(CoapOption::Unknown(2048), vec![1; 20]);
+        packet.payload = b"data".to_vec();
+        let bytes = packet.to_bytes().unwrap();
+
+        let mut options = Vec::new();
+        let message = Packet::decode_streaming(&bytes, |number, value| {
+            options.push((number, value));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(packet.header, message.header);
+        assert_eq!([0xAB, 0xCD], message.token);
+        assert_eq!(b"data", &bytes[message.payload]);
+        assert_eq!(
+            vec![(11, &b"a"[..]), (11, &b"b"[..]), (2048, &[1; 20][..])],
+            options
+        );
+
+        // The callback aborts decoding
+        let mut calls = 0;
+        assert_eq!(
+            Err(MessageError::InvalidHeader),
+            Packet::decode_streaming(&bytes, |_, _| {
+                calls += 1;
+                Err(MessageError::InvalidHeader)
+            })
+        );
+        assert_eq!(1, calls);
+
+        let message =
+            Packet::decode_streaming(&[0x40, 0x01, 0x00, 0x00], |_, _| {
+                panic!("no options")
+            })
+            .unwrap();
+        assert!(message.payload.is_empty());
+        assert_eq!(
+            Err(MessageError::EmptyPayload),
+            Packet::decode_streaming(
+                &[0x40, 0x01, 0x00, 0x00, 0xFF],
+                |_, _| { Ok(()) }
+            )
+        );
+    }
+
+    #[test]
+    fn repeated_options() {
+        let mut packet = Packet::new();
+        packet.add_option(CoapOption::UriPath, b"a".to_vec());
+        packet.add_option(CoapOption::UriPath, b"b".to_vec());
+        packet.add_option(CoapOption::Unknown(2048), vec![]);
+        packet.add_option(CoapOption::Unknown(2048), vec![]);
+        packet.add_option(CoapOption::Accept, vec![50]);
+        assert!(packet
+            .repeated_options(MultiAcceptPolicy::Reject)
+            .is_empty());
+
+        packet.add_option(CoapOption::Accept, vec![60]);
+        packet.add_option(CoapOption::UriHost, b"a".to_vec());
+        packet.add_option(CoapOption::UriHost, b"b".to_vec());
+        assert_eq!(
+            vec![3, 17],
+            packet.repeated_options(MultiAcceptPolicy::Reject)
+        );
+        assert_eq!(
+            vec![3],
+            packet.repeated_options(MultiAcceptPolicy::FirstSupported)
+        );
+    }
+
+    #[test]
+    fn known_variants() {
+        // What used to be an exhaustive match on the option
+        let repeatable_numbers: Vec<u16> = CoapOption::known_variants()
+            .iter()
+            .filter(|option| option.is_repeatable())
+            .map(CoapOption::as_u16)
+            .collect();
+        assert_eq!(vec![1, 4, 8, 11, 15, 20], repeatable_numbers);
+
+        for option in CoapOption::known_variants() {
+            assert_eq!(*option, CoapOption::from(option.as_u16()));
+            assert!(!matches!(option, CoapOption::Unknown(_)));
+        }
+        let numbers: Vec<u16> = CoapOption::known_variants()
+            .iter()
+            .map(CoapOption::as_u16)
+            .collect();
+        assert!(numbers.windows(2).all(|pair| pair[0] < pair[1]));
+        for number in 0..=u16::MAX {
+            let option = CoapOption::from(number);
+            assert_eq!(number, u16::from(option));
+            assert_eq!(
+                numbers.contains(&number),
+                !matches!(option, CoapOption::Unknown(_))
+            );
+        }
+
+        let mut packet = Packet::new();
+        for option in CoapOption::known_variants() {
+            packet.add_option(*option, vec![]);
+        }
+        let typed: Vec<CoapOption> =
+            packet.typed_options().map(|(option, _)| option).collect();
+        assert_eq!(CoapOption::known_variants(), &typed[..]);
+
+        for format in ContentFormat::known_variants() {
+            assert_eq!(
+                Ok(*format),
+                ContentFormat::try_from(usize::from(format.as_u16()))
+            );
+            assert_eq!(
+                Some(*format),
+                ContentFormat::from_u16(format.as_u16())
+            );
+        }
+        assert_eq!(None, ContentFormat::from_u16(1));
+        assert_eq!(30, ContentFormat::known_variants().len());
+    }
+
+    #[test]
+    fn path_and_queries() {
+        let mut packet = Packet::new();
+        assert_eq!("/", packet.get_uri_path());
+        assert_eq!(None, packet.get_queries());
+
+        packet.set_uri_path("/sensors//temp/").unwrap();
+        assert_eq!(
+            Some(&LinkedList::from([b"sensors".to_vec(), b"temp".to_vec()])),
+            packet.get_option(CoapOption::UriPath)
+        );
+        assert_eq!("/sensors/temp", packet.get_uri_path());
+        packet.set_uri_path("a%2Fb").unwrap();
+        assert_eq!(
+            Some(&LinkedList::from([b"a%2Fb".to_vec()])),
+            packet.get_option(CoapOption::UriPath)
+        );
+        assert_eq!("/a%2Fb", packet.get_uri_path());
+
+        // Segments are at most 255 bytes long
+        let long = "x".repeat(256);
+        assert_eq!(
+            Err(MessageError::InvalidOptionValue {
+                option: CoapOption::UriPath,
+                length: 256
+            }),
+            packet.set_uri_path(&format!("/a/{}", long))
+        );
+        assert_eq!("/a%2Fb", packet.get_uri_path());
+        packet.set_uri_path(&long[1..]).unwrap();
+        assert_eq!(255, packet.get_uri_path().len() - 1);
+        packet.set_uri_path("/").unwrap();
+        assert_eq!(None, packet.get_option(CoapOption::UriPath));
+        assert_eq!("/", packet.get_uri_path());
+        packet.set_uri_path("a%2Fb").unwrap();
+
+        // Invalid UTF-8 doesn't panic
+        packet.add_option(CoapOption::UriPath, vec![0xFF, b'x']);
+        assert_eq!("/a%2Fb/\u{FFFD}x", packet.get_uri_path());
+        packet.add_option(CoapOption::UriQuery, b"unit=C".to_vec());
+        packet.add_option(CoapOption::UriQuery, vec![0xC3]);
+        assert_eq!(
+            Some(vec!["unit=C".to_string(), "\u{FFFD}".to_string()]),
+            packet.get_queries()
+        );
+    }
+
+    #[test]
+    fn validate_options() {
+        let mut packet = Packet::new();
+        for option in CoapOption::known_variants() {
+            let range = option.value_length_range().unwrap();
+            packet.add_option(*option, vec![0; *range.start()]);
+            packet.add_option(*option, vec![0; *range.end()]);
+        }
+        packet.add_option(CoapOption::Unknown(2049), vec![0; 2000]);
+        assert_eq!(Ok(()), packet.validate_options());
+
+        for (option, length) in [
+            (CoapOption::UriPort, 200),
+            (CoapOption::ETag, 20),
+            (CoapOption::ETag, 0),
+            (CoapOption::IfNoneMatch, 1),
+            (CoapOption::Block1, 4),
+            (CoapOption::Block2, 4),
+            (CoapOption::Oscore, 256),
+        ] {
+            let mut invalid = packet.clone();
+            invalid.add_option(option, vec![0; length]);
+            assert_eq!(
+                Err(MessageError::InvalidOptionValue { option, length }),
+                invalid.validate_options()
+            );
+        }
+    }
+
+    #[test]
+    fn etag() {
+        let mut packet = Packet::new();
+        assert_eq!(None, packet.get_etag());
+        assert!(packet.get_etags().is_empty());
+        assert!(!packet.etag_matches(b"v1"));
+
+        let invalid = |length| {
+            Err(MessageError::InvalidOptionValue {
+                option: CoapOption::ETag,
+                length,
+            })
+        };
+        for length in [0, 9] {
+            assert_eq!(invalid(length), packet.set_etag(&vec![0; length]));
+            assert_eq!(invalid(length), packet.add_etag(&vec![0; length]));
+        }
+        assert_eq!(None, packet.get_option(CoapOption::ETag));
+
+        packet.add_etag(b"v1").unwrap();
+        packet.add_etag(&[0xFF; 8]).unwrap();
+        assert_eq!(Some(&b"v1"[..]), packet.get_etag());
+        assert_eq!(vec![&b"v1"[..], &[0xFF; 8][..]], packet.get_etags());
+        assert!(packet.etag_matches(&[0xFF; 8]));
+        assert!(!packet.etag_matches(b"v"));
+
+        packet.set_etag(b"v2").unwrap();
+        assert_eq!(vec![&b"v2"[..]], packet.get_etags());
+        assert!(!packet.etag_matches(b"v1"));
+    }
+
+    #[test]
+    fn max_age() {
+        let mut packet = Packet::new();
+        assert_eq!(None, packet.get_max_age());
+        assert_eq!(60, packet.max_age_or_default());
+
+        for (seconds, value) in [
+            (0, vec![]),
+            (60, vec![60]),
+            (0x1_0000, vec![1, 0, 0]),
+            (u32::MAX, vec![0xFF; 4]),
+        ] {
+            packet.set_max_age(seconds);
+            assert_eq!(
+                Some(&LinkedList::from([value])),
+                packet.get_option(CoapOption::MaxAge)
+            );
+            let bytes = packet.to_bytes().unwrap();
+            let decoded = Packet::from_bytes(&bytes).unwrap();
+            assert_eq!(Some(seconds), decoded.get_max_age());
+            assert_eq!(seconds, decoded.max_age_or_default());
+        }
+
+        // Padded values are accepted, longer ones ignored
+        packet.set_option(CoapOption::MaxAge, LinkedList::from([vec![0, 30]]));
+        assert_eq!(Some(30), packet.get_max_age());
+        packet.set_option(CoapOption::MaxAge, LinkedList::from([vec![1; 5]]));
+        assert_eq!(None, packet.get_max_age());
+        assert_eq!(60, packet.max_age_or_default());
+        assert_eq!(
+            Err(MessageError::InvalidOptionValue {
+                option: CoapOption::MaxAge,
+                length: 5
+            }),
+            packet.validate_options()
+        );
+    }
+
+    #[test]
+    fn uri_port() {
+        let mut packet = Packet::new();
+        assert_eq!(None, packet.get_uri_port());
+
+        packet.set_uri_port(5684);
         assert_eq!(
-            packet.header.get_type(),
-            header::MessageType::Acknowledgement
+            Some(&vec![0x16, 0x34]),
+            packet.get_first_option(CoapOption::UriPort)
         );
-        assert_eq!(packet.header.get_token_length(), 4);
+        assert_eq!(Some(Ok(5684)), packet.get_uri_port());
+
+        packet.set_uri_port(0);
         assert_eq!(
-            packet.header.code,
-            header::MessageClass::Response(header::ResponseType::Content)
+            Some(&vec![]),
+            packet.get_first_option(CoapOption::UriPort)
         );
-        assert_eq!(packet.header.message_id, 5117);
-        assert_eq!(*packet.get_token(), vec![0xD0, 0xE2, 0x4D, 0xAC]);
-        assert_eq!(packet.payload, "Hello".as_bytes().to_vec());
+        assert_eq!(Some(Ok(0)), packet.get_uri_port());
+
+        packet.clear_option(CoapOption::UriPort);
+        packet.add_option(CoapOption::UriPort, vec![0x01, 0x00, 0x00]);
+        assert!(packet.get_uri_port().unwrap().is_err());
     }
 
     #[test]
-    fn test_encode_packet_with_options() {
+    fn filter_options() {
         let mut packet = Packet::new();
-        packet.header.set_version(1);
-        packet.header.set_type(header::MessageType::Confirmable);
-        packet.header.code =
-            header::MessageClass::Request(header::RequestType::Get);
-        packet.header.message_id = 33950;
-        packet.set_token(vec![0x51, 0x55, 0x77, 0xE8]);
-        packet.add_option(CoapOption::UriPath, b"Hi".to_vec());
-        packet.add_option(CoapOption::UriPath, b"Test".to_vec());
-        packet.add_option(CoapOption::UriQuery, b"a=1".to_vec());
+        packet.header.message_id = 3;
+        packet.set_token(vec![1, 2]);
+        packet.add_option(CoapOption::UriPath, b"temp".to_vec());
+        packet.add_option(CoapOption::ETag, vec![0xAA]);
+        packet.add_option(CoapOption::Unknown(2049), vec![0xBB]);
+        packet.payload = b"body".to_vec();
+
+        let filtered = packet.filter_options(|option| !option.is_critical());
+        assert_eq!(3, filtered.header.message_id);
+        assert_eq!([1, 2], filtered.get_token());
+        assert_eq!(b"body", &filtered.payload[..]);
         assert_eq!(
-            packet.to_bytes().unwrap(),
-            vec![
-                0x44, 0x01, 0x84, 0x9e, 0x51, 0x55, 0x77, 0xe8, 0xb2, 0x48,
-                0x69, 0x04, 0x54, 0x65, 0x73, 0x74, 0x43, 0x61, 0x3d, 0x31
-            ]
+            vec![u16::from(CoapOption::ETag)],
+            filtered.options().map(|(&n, _)| n).collect::<Vec<_>>()
         );
+
+        // The original is left untouched
+        assert_eq!(3, packet.options().count());
     }
 
     #[test]
-    fn test_encode_packet_with_payload() {
+    fn fetch_cache_key() {
+        let mut fetch = Packet::new();
+        fetch.header.code = MessageClass::Request(RequestType::Fetch);
+        fetch.set_token(vec![1]);
+        fetch.add_option(CoapOption::UriPath, b"query".to_vec());
+        fetch.set_content_format(ContentFormat::ApplicationCBOR);
+        fetch.add_option_as(
+            CoapOption::Accept,
+            OptionValueU16(usize::from(ContentFormat::ApplicationJSON) as u16),
+        );
+        fetch.payload = vec![0xA1, 0x61, 0x6B, 0x01];
+
+        let mut identical = fetch.clone();
+        identical.set_token(vec![2]);
+        identical.header.message_id = 2;
+        assert_eq!(fetch.cache_key(), identical.cache_key());
+        assert!(fetch.is_coalescable_with(&identical));
+
+        let mut other_body = fetch.clone();
+        other_body.payload = vec![0xA1, 0x61, 0x6B, 0x02];
+        assert_ne!(fetch.cache_key(), other_body.cache_key());
+        assert!(!fetch.is_coalescable_with(&other_body));
+
+        let mut other_accept = fetch.clone();
+        other_accept.clear_option(CoapOption::Accept);
+        other_accept.add_option_as(
+            CoapOption::Accept,
+            OptionValueU16(usize::from(ContentFormat::ApplicationCBOR) as u16),
+        );
+        assert_ne!(fetch.cache_key(), other_accept.cache_key());
+
+        // GET requests don't have a body that is part of the key
+        let mut get = fetch.clone();
+        get.header.code = MessageClass::Request(RequestType::Get);
+        let mut other_get = other_body.clone();
+        other_get.header.code = MessageClass::Request(RequestType::Get);
+        assert_eq!(get.cache_key(), other_get.cache_key());
+        assert_ne!(get.cache_key(), fetch.cache_key());
+    }
+
+    #[test]
+    fn requires_ack_and_expects_response() {
         let mut packet = Packet::new();
-        packet.header.set_version(1);
-        packet.header.set_type(header::MessageType::Acknowledgement);
-        packet.header.code =
-            header::MessageClass::Response(header::ResponseType::Content);
-        packet.header.message_id = 5117;
-        packet.set_token(vec![0xD0, 0xE2, 0x4D, 0xAC]);
-        packet.payload = "Hello".as_bytes().to_vec();
+        packet.header.set_type(MessageType::Confirmable);
+        packet.header.code = MessageClass::Request(RequestType::Get);
+        assert!(packet.requires_ack());
+        assert!(packet.expects_response());
+
+        packet.header.set_type(MessageType::NonConfirmable);
+        assert!(!packet.requires_ack());
+        assert!(packet.expects_response());
+
+        packet.header.set_type(MessageType::Confirmable);
+        packet.header.code = MessageClass::Response(ResponseType::Content);
+        assert!(packet.requires_ack());
+        assert!(!packet.expects_response());
+
+        packet.header.set_type(MessageType::Acknowledgement);
+        packet.header.code = MessageClass::Empty;
+        assert!(!packet.requires_ack());
+        assert!(!packet.expects_response());
+    }
+
+    #[test]
+    fn observe_only_on_observable_methods() {
+        let mut get = Packet::new();
+        get.header.code = MessageClass::Request(RequestType::Get);
+        assert_eq!(Ok(()), get.set_observe_register());
+        assert_eq!(Some(Ok(Observe::Register)), get.get_observe());
+        assert_eq!(Ok(()), get.semantic_validate());
+
+        let mut fetch = get.clone();
+        fetch.header.code = MessageClass::Request(RequestType::Fetch);
+        assert_eq!(Ok(()), fetch.semantic_validate());
+
+        let mut put = get.clone();
+        put.header.code = MessageClass::Request(RequestType::Put);
         assert_eq!(
-            packet.to_bytes().unwrap(),
-            vec![
-                0x64, 0x45, 0x13, 0xFD, 0xD0, 0xE2, 0x4D, 0xAC, 0xFF, 0x48,
-                0x65, 0x6C, 0x6C, 0x6F
-            ]
+            Err(ValidationError::ObserveOnUnobservableMethod(
+                RequestType::Put
+            )),
+            put.semantic_validate()
+        );
+
+        let mut post = Packet::new();
+        post.header.code = MessageClass::Request(RequestType::Post);
+        assert_eq!(Ok(()), post.semantic_validate());
+        assert_eq!(
+            Err(ValidationError::ObserveOnUnobservableMethod(
+                RequestType::Post
+            )),
+            post.set_observe_register()
         );
+        assert_eq!(None, post.get_observe());
+
+        let mut notification = Packet::new();
+        notification.header.code =
+            MessageClass::Response(ResponseType::Content);
+        notification.set_observe(Observe::Sequence(42));
+        assert_eq!(Ok(()), notification.semantic_validate());
     }
 
     #[test]
-    fn test_encode_decode_content_format() {
+    fn experimental_options() {
+        assert!(CoapOption::Unknown(65001).is_experimental());
+        assert!(!CoapOption::Unknown(64999).is_experimental());
+        assert!(!CoapOption::NoResponse.is_experimental());
+        assert!(CoapOption::is_reserved(128));
+        assert!(!CoapOption::is_reserved(129));
+
+        assert_eq!("Experimental", CoapOption::Unknown(65001).name());
+        assert_eq!("Reserved", CoapOption::Unknown(0).name());
+        assert_eq!("Unknown", CoapOption::Unknown(2049).name());
+        assert_eq!("Uri-Path", CoapOption::UriPath.name());
+
         let mut packet = Packet::new();
-        packet.set_content_format(ContentFormat::TextPlain);
+        packet.add_option(CoapOption::UriPath, b"test".to_vec());
+        assert_eq!(Ok(vec![]), packet.validate(ValidationMode::Strict));
+
+        packet.add_option(CoapOption::Unknown(65001), b"staging".to_vec());
         assert_eq!(
-            ContentFormat::TextPlain,
-            packet.get_content_format().unwrap()
-        )
+            Ok(vec![ValidationError::ExperimentalOption(65001)]),
+            packet.validate(ValidationMode::Lenient)
+        );
+        assert_eq!(Ok(()), packet.semantic_validate());
+        assert_eq!(
+            Err(ValidationError::ExperimentalOption(65001)),
+            packet.validate(ValidationMode::Strict)
+        );
+
+        let mut reserved = Packet::new();
+        reserved.add_option(CoapOption::Unknown(140), vec![]);
+        assert_eq!(
+            Err(ValidationError::ReservedOption(140)),
+            reserved.validate(ValidationMode::Strict)
+        );
     }
 
     #[test]
-    fn test_encode_decode_content_format_without_msb() {
+    fn token_from_allocator() {
+        let mut next_token = 0u32;
+        let mut alloc = |len: usize| {
+            next_token += 1;
+            next_token.to_be_bytes()[(4 - len)..].to_vec()
+        };
+
+        let mut first = Packet::new();
+        first.with_token_from(2, &mut alloc).unwrap();
+        let mut second = Packet::new();
+        second.with_token_from(2, &mut alloc).unwrap();
+        assert_eq!([0, 1], first.get_token());
+        assert_eq!([0, 2], second.get_token());
+        assert_eq!(2, second.header.get_token_length());
+
+        assert_eq!(
+            Err(MessageError::InvalidTokenLength),
+            first.with_token_from(9, |_| unreachable!())
+        );
+        assert_eq!(
+            Err(MessageError::InvalidTokenLength),
+            first.with_token_from(4, |_| vec![0; 9])
+        );
+        assert_eq!([0, 1], first.get_token());
+    }
+
+    #[test]
+    fn payload_text() {
         let mut packet = Packet::new();
+        packet.payload = b"plain".to_vec();
+        assert_eq!(Ok("plain"), packet.payload_text());
+        assert_eq!(
+            Err(PayloadTextError::WrongContentFormat(None)),
+            packet.payload_text_with(false)
+        );
+
         packet.set_content_format(ContentFormat::ApplicationJSON);
+        packet.set_payload_text("gr\u{fc}\u{df}e");
         assert_eq!(
-            ContentFormat::ApplicationJSON,
-            packet.get_content_format().unwrap()
-        )
+            Some(ContentFormat::TextPlain),
+            packet.get_content_format()
+        );
+        assert_eq!(
+            1,
+            packet.get_option(CoapOption::ContentFormat).unwrap().len()
+        );
+        assert_eq!(Ok("gr\u{fc}\u{df}e"), packet.payload_text_with(false));
+
+        // "grün" in Latin-1
+        packet.payload = b"gr\xfcn".to_vec();
+        assert_eq!(
+            Err(PayloadTextError::InvalidUtf8 { offset: 2 }),
+            packet.payload_text()
+        );
+
+        packet.clear_option(CoapOption::ContentFormat);
+        packet.set_content_format(ContentFormat::ApplicationCBOR);
+        assert_eq!(
+            Err(PayloadTextError::WrongContentFormat(Some(60))),
+            packet.payload_text()
+        );
     }
 
     #[test]
-    fn test_encode_decode_content_format_with_msb() {
+    fn diagnostic_payload() {
         let mut packet = Packet::new();
-        packet.set_content_format(ContentFormat::ApplicationSensmlXML);
+        packet.header.code = MessageClass::Response(ResponseType::BadRequest);
+        packet.set_diagnostic_payload("Missing Uri-Query");
         assert_eq!(
-            ContentFormat::ApplicationSensmlXML,
-            packet.get_content_format().unwrap()
-        )
+            Some(ContentFormat::TextPlain),
+            packet.get_content_format()
+        );
+        assert_eq!(Ok("Missing Uri-Query"), packet.payload_text());
+
+        // Truncated in the middle of the last "ü"
+        let msg = "\u{fc}".repeat(Packet::MAX_DIAGNOSTIC_LENGTH);
+        packet.set_diagnostic_payload(&msg);
+        assert_eq!(Packet::MAX_DIAGNOSTIC_LENGTH, packet.payload.len());
+        assert!(packet.payload_text().is_ok());
+
+        let msg = format!("a{}", msg);
+        packet.set_diagnostic_payload(&msg);
+        assert_eq!(Packet::MAX_DIAGNOSTIC_LENGTH - 1, packet.payload.len());
+        assert!(packet.payload_text().is_ok());
     }
 
     #[test]
-    fn test_decode_empty_content_format() {
-        let packet = Packet::new();
-        assert!(packet.get_content_format().is_none());
+    fn retransmit_copy() {
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Confirmable);
+        packet.header.message_id = 0xBEEF;
+        packet.set_token(vec![0x01, 0x02, 0x03]);
+        packet.add_option(CoapOption::UriPath, b"b".to_vec());
+        packet.add_option(CoapOption::UriPath, b"a".to_vec());
+        packet.add_option(CoapOption::ETag, vec![]);
+        packet.add_option(CoapOption::Accept, vec![0x32]);
+        packet.clear_option(CoapOption::Accept);
+        packet.payload = b"payload".to_vec();
+        packet.trailing_bytes = vec![0xF0];
+
+        let copy = packet.retransmit_copy();
+        assert_eq!(packet, copy);
+        assert_eq!(packet.to_bytes(), copy.to_bytes());
+        assert_eq!(packet.clone().to_bytes(), packet.to_bytes());
+
+        let config = EncodeConfig {
+            value_order: Some(|_, a, b| a.cmp(b)),
+            lenient: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            packet.to_bytes_with_config(&config),
+            copy.to_bytes_with_config(&config)
+        );
     }
 
     #[test]
-    fn option() {
-        for i in 0..512 {
-            match CoapOption::try_from(i) {
-                Ok(o) => assert_eq!(i, o.into()),
-                _ => (),
-            }
+    fn proxy_audit() {
+        let mut packet = Packet::new();
+        packet.header.code = MessageClass::Request(RequestType::Get);
+        // Safe to forward and elective
+        packet.add_option(CoapOption::Unknown(65000), vec![0x01]);
+        // Critical and recognized
+        packet.add_option(CoapOption::UriPath, b"a".to_vec());
+        packet.add_option(CoapOption::UriPath, b"b".to_vec());
+        // Critical and not recognized
+        packet.add_option(CoapOption::Unknown(65001), vec![]);
+        // Empty options are not present
+        packet.add_option(CoapOption::Accept, vec![]);
+        packet.clear_option(CoapOption::Accept);
+
+        let known = |number| CoapOption::from(number) == CoapOption::UriPath;
+        assert_eq!(
+            ProxyAudit {
+                forward: vec![(65000, 1)],
+                process: vec![(11, 2)],
+                reject: vec![(65001, 1)],
+                drop: vec![],
+            },
+            packet.proxy_audit(&known)
+        );
+
+        // Unsafe but elective options can't be forwarded unrecognized, they
+        // are dropped instead
+        assert!(CoapOption::Unknown(65002).is_unsafe_to_forward());
+        assert!(!CoapOption::Unknown(65002).is_critical());
+        let mut packet = Packet::new();
+        packet.add_option(CoapOption::Unknown(65002), vec![]);
+        let audit = packet.proxy_audit(&|_| false);
+        assert_eq!(vec![(65002, 1)], audit.drop);
+        assert!(audit.reject.is_empty());
+        assert_eq!(vec![(65002, 1)], packet.proxy_audit(&|_| true).process);
+    }
+
+    #[test]
+    fn observe_registration() {
+        let mut packet = Packet::new();
+        packet.set_observe_registration(true);
+        assert_eq!(
+            Some(&LinkedList::from([vec![]])),
+            packet.get_option(CoapOption::Observe)
+        );
+        assert_eq!(Some(Ok(Observe::Register)), packet.get_observe());
+
+        packet.set_observe_registration(false);
+        assert_eq!(
+            Some(&LinkedList::from([vec![1]])),
+            packet.get_option(CoapOption::Observe)
+        );
+        assert_eq!(Some(Ok(Observe::Deregister)), packet.get_observe());
+    }
+
+    #[test]
+    fn observe_notification() {
+        let mut packet = Packet::new();
+        packet.header.code = MessageClass::Response(ResponseType::Content);
+        assert!(!packet.is_observe_notification());
+
+        packet.set_observe(Observe::Sequence(0));
+        assert!(packet.is_observe_notification());
+        packet.set_observe(Observe::Sequence(0xFF_FFFF));
+        assert!(packet.is_observe_notification());
+
+        packet.clear_option(CoapOption::Observe);
+        packet.add_option(CoapOption::Observe, vec![0x01; 4]);
+        assert!(!packet.is_observe_notification());
+
+        packet.set_observe(Observe::Sequence(1));
+        packet.header.code = MessageClass::Response(ResponseType::Valid);
+        assert!(!packet.is_observe_notification());
+
+        packet.header.code = MessageClass::Request(RequestType::Get);
+        packet.set_observe(Observe::Register);
+        assert!(!packet.is_observe_notification());
+    }
+
+    #[test]
+    fn debug_string() {
+        let request = Packet::from_bytes(&[
+            0x44, 0x01, 0x84, 0x9e, 0x51, 0x55, 0x77, 0xe8, 0xb2, 0x48, 0x69,
+            0x04, 0x54, 0x65, 0x73, 0x74, 0x43, 0x61, 0x3d, 0x31,
+        ])
+        .unwrap();
+        assert_eq!(
+            "type: Confirmable\n\
+             code: 0.01\n\
+             message_id: 33950\n\
+             token: 515577e8\n\
+             option 11 Uri-Path: 4869\n\
+             option 11 Uri-Path: 54657374\n\
+             option 15 Uri-Query: 613d31\n\
+             payload:\n",
+            request.to_debug_string()
+        );
+
+        let response = Packet::from_bytes(&[
+            0x64, 0x45, 0x5D, 0x1F, 0x00, 0x00, 0x39, 0x74, 0xFF, 0x48, 0x65,
+            0x6C, 0x6C, 0x6F, 0x20, 0x57, 0x6F, 0x72, 0x6C, 0x64, 0x21,
+        ])
+        .unwrap();
+        assert_eq!(
+            "type: Acknowledgement\n\
+             code: 2.05\n\
+             message_id: 23839\n\
+             token: 00003974\n\
+             payload: 48656c6c6f20576f726c6421\n",
+            response.to_debug_string()
+        );
+
+        let mut packet = Packet::new();
+        packet.add_option(CoapOption::IfNoneMatch, vec![]);
+        packet.add_option(CoapOption::Unknown(65001), vec![0xAB]);
+        packet.trailing_bytes = vec![0x0F];
+        assert_eq!(
+            "type: Confirmable\n\
+             code: 0.01\n\
+             message_id: 0\n\
+             token:\n\
+             option 5 If-None-Match:\n\
+             option 65001 Experimental: ab\n\
+             payload:\n\
+             trailing_bytes: 0f\n",
+            packet.to_debug_string()
+        );
+    }
+
+    #[test]
+    fn uri_path_normalized() {
+        let mut packet = Packet::new();
+        assert_eq!(b"/", &packet.uri_path_normalized()[..]);
+
+        packet.add_option(CoapOption::UriPath, vec![]);
+        assert_eq!(b"/", &packet.uri_path_normalized()[..]);
+
+        packet.clear_option(CoapOption::UriPath);
+        for segment in ["sensors", "", "t%65mp", "100%", ""] {
+            packet
+                .add_option(CoapOption::UriPath, segment.as_bytes().to_vec());
         }
+        packet.add_option(CoapOption::UriPath, vec![0xFF, b'/', b'4']);
+        assert_eq!(
+            b"/sensors//t%2565mp/100%25//\xFF%2F4",
+            &packet.uri_path_normalized()[..]
+        );
+
+        // Neither an encoded nor a literal slash collides with a separator
+        let mut split = Packet::new();
+        split.add_option(CoapOption::UriPath, b"a".to_vec());
+        split.add_option(CoapOption::UriPath, b"b".to_vec());
+        let mut joined = Packet::new();
+        joined.add_option(CoapOption::UriPath, b"a/b".to_vec());
+        let mut encoded = Packet::new();
+        encoded.add_option(CoapOption::UriPath, b"a%2Fb".to_vec());
+        assert_eq!(b"/a/b", &split.uri_path_normalized()[..]);
+        assert_eq!(b"/a%2Fb", &joined.uri_path_normalized()[..]);
+        assert_eq!(b"/a%252Fb", &encoded.uri_path_normalized()[..]);
+
+        // An encoded dot-segment isn't turned into one
+        let mut dots = Packet::new();
+        dots.add_option(CoapOption::UriPath, b"%2E%2E".to_vec());
+        assert_eq!(b"/%252E%252E", &dots.uri_path_normalized()[..]);
+    }
+
+    #[test]
+    fn keepalive() {
+        let ping = Packet::from_bytes(&[0x40, 0x00, 0x12, 0x34]).unwrap();
+        assert!(ping.is_keepalive());
+
+        // The reset answering it isn't a ping itself
+        let pong = Packet::from_bytes(&[0x70, 0x00, 0x12, 0x34]).unwrap();
+        assert!(!pong.is_keepalive());
+
+        let mut request = Packet::new();
+        request.header.set_type(MessageType::Confirmable);
+        request.header.code = MessageClass::Request(RequestType::Get);
+        assert!(!request.is_keepalive());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_payload() {
+        let mut packet = Packet::new();
+        packet.set_content_format(ContentFormat::TextPlain);
+        packet.set_json_payload(&[1, 2, 3]).unwrap();
+        assert_eq!(b"[1,2,3]", &packet.payload[..]);
+        assert_eq!(
+            Some(ContentFormat::ApplicationJSON),
+            packet.get_content_format()
+        );
+        assert_eq!(
+            1,
+            packet.get_option(CoapOption::ContentFormat).unwrap().len()
+        );
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_payload() {
+        let mut packet = Packet::new();
+        packet.set_cbor_payload(&[1, 2, 3]).unwrap();
+        assert_eq!([0x83, 0x01, 0x02, 0x03], packet.payload[..]);
+        assert_eq!(
+            Some(ContentFormat::ApplicationCBOR),
+            packet.get_content_format()
+        );
+    }
+
+    #[cfg(all(feature = "json", feature = "cbor"))]
+    #[test]
+    fn registered_format_payload() {
+        use crate::content_format::{
+            ContentFormatEntry, ContentFormatRegistry, PayloadEncoding,
+        };
+
+        let formats = [ContentFormatEntry::new(
+            65001,
+            "application/vnd.example+cbor",
+            PayloadEncoding::Cbor,
+        )];
+        let registry = ContentFormatRegistry::new(&formats);
+
+        let mut packet = Packet::new();
+        packet
+            .set_cbor_payload_as(&[1, 2, 3], 65001, &registry)
+            .unwrap();
+        assert_eq!([0x83, 0x01, 0x02, 0x03], packet.payload[..]);
+        assert_eq!(
+            Some(Ok(OptionValueU16(65001))),
+            packet.get_first_option_as::<OptionValueU16>(
+                CoapOption::ContentFormat
+            )
+        );
+
+        assert!(packet.set_json_payload_as(&[1], 65001, &registry).is_err());
+        assert!(packet.set_cbor_payload_as(&[1], 65002, &registry).is_err());
+        assert!(packet.set_cbor_payload_as(&[1], 50, &registry).is_err());
+        assert_eq!([0x83, 0x01, 0x02, 0x03], packet.payload[..]);
     }
 
     #[test]
     fn content_format() {
         for i in 0..512 {
             match ContentFormat::try_from(i) {
-                Ok(o) => assert_eq!(i, o.into()),
+                Ok(o) => assert_eq!(i, usize::from(o)),
                 _ => (),
             }
         }
     }
 
+    #[test]
+    fn content_format_raw() {
+        let mut packet = Packet::new();
+        assert_eq!(None, packet.get_content_format_raw());
+
+        // Deflated application/json, and an experimental one
+        for number in [11050, 65001] {
+            packet.set_content_format_raw(number);
+            assert_eq!(None, packet.get_content_format());
+            let bytes = packet.to_bytes().unwrap();
+            let decoded = Packet::from_bytes(&bytes).unwrap();
+            assert_eq!(Some(number), decoded.get_content_format_raw());
+            assert_eq!(bytes, decoded.to_bytes().unwrap());
+        }
+
+        packet.clear_option(CoapOption::ContentFormat);
+        packet.set_content_format(ContentFormat::ApplicationJSON);
+        assert_eq!(Some(50), packet.get_content_format_raw());
+        packet.set_content_format_raw(60);
+        assert_eq!(
+            Some(ContentFormat::ApplicationCBOR),
+            packet.get_content_format()
+        );
+    }
+
+    #[test]
+    fn content_format_from_untrusted_bytes() {
+        for (value, expected) in [
+            (&[][..], Some(ContentFormat::TextPlain)),
+            (&[50], Some(ContentFormat::ApplicationJSON)),
+            (&[0x01, 0x36], Some(ContentFormat::ApplicationSenmlXML)),
+            (&[0x00, 0x00, 50], None),
+        ] {
+            // Content-Format (12), then Accept (17)
+            let bytes = RawPacketBuilder::new()
+                .option(12, value)
+                .option(5, value)
+                .build();
+            let packet = Packet::from_bytes(&bytes).unwrap();
+            assert_eq!(expected, packet.get_content_format());
+            assert_eq!(expected, packet.get_accept());
+        }
+    }
+
+    #[test]
+    fn uint_encoding() {
+        for (value, encoded) in [
+            (0, &[][..]),
+            (50, &[0x32]),
+            (255, &[0xFF]),
+            (256, &[0x01, 0x00]),
+            (0xFF_FFFF, &[0xFF, 0xFF, 0xFF]),
+            (1 << 56, &[1, 0, 0, 0, 0, 0, 0, 0]),
+            (u64::MAX, &[0xFF; 8]),
+        ] {
+            assert_eq!(encoded, &encode_uint(value)[..]);
+            assert_eq!(value, decode_uint(encoded));
+        }
+        assert_eq!(0x3C, decode_uint(&[0x00, 0x00, 0x3C]));
+
+        let mut packet = Packet::new();
+        packet.header.code = MessageClass::Request(RequestType::Get);
+        packet.set_content_format(ContentFormat::TextPlain);
+        packet.set_accept(ContentFormat::ApplicationJSON);
+        packet.set_max_age(0);
+        packet.set_observe(Observe::Register);
+        assert_eq!(
+            vec![
+                (CoapOption::Observe, vec![]),
+                (CoapOption::ContentFormat, vec![]),
+                (CoapOption::MaxAge, vec![]),
+                (CoapOption::Accept, vec![0x32]),
+            ],
+            packet
+                .typed_options()
+                .map(|(option, values)| (
+                    option,
+                    values.front().unwrap().clone()
+                ))
+                .collect::<Vec<_>>()
+        );
+        packet.clear_option(CoapOption::ContentFormat);
+        packet.set_content_format(ContentFormat::ApplicationCBOR);
+        assert_eq!(
+            Some(&LinkedList::from([vec![60]])),
+            packet.get_option(CoapOption::ContentFormat)
+        );
+    }
+
+    #[test]
+    fn content_format_and_accept() {
+        let mut packet = Packet::new();
+        assert_eq!(None, packet.get_content_format());
+        assert_eq!(None, packet.get_accept());
+
+        packet.set_accept(ContentFormat::TextPlain);
+        assert_eq!(
+            Some(&LinkedList::from([vec![]])),
+            packet.get_option(CoapOption::Accept)
+        );
+        packet.set_accept(ContentFormat::ApplicationSenmlXML);
+        assert_eq!(
+            Some(&LinkedList::from([vec![0x01, 0x36]])),
+            packet.get_option(CoapOption::Accept)
+        );
+        let decoded = Packet::from_bytes(&packet.to_bytes().unwrap()).unwrap();
+        assert_eq!(
+            Some(ContentFormat::ApplicationSenmlXML),
+            decoded.get_accept()
+        );
+
+        // Zero, one and two byte encodings, including padded ones
+        for option in [CoapOption::ContentFormat, CoapOption::Accept] {
+            for (value, expected) in [
+                (vec![], Some(ContentFormat::TextPlain)),
+                (vec![0x00], Some(ContentFormat::TextPlain)),
+                (vec![50], Some(ContentFormat::ApplicationJSON)),
+                (vec![0x00, 50], Some(ContentFormat::ApplicationJSON)),
+                (vec![0x01, 0x36], Some(ContentFormat::ApplicationSenmlXML)),
+                (vec![0x00, 0x00, 50], None),
+                (vec![0xFF, 0xFF], None),
+            ] {
+                packet.set_option(option, LinkedList::from([value]));
+                let actual = match option {
+                    CoapOption::Accept => packet.get_accept(),
+                    _ => packet.get_content_format(),
+                };
+                assert_eq!(expected, actual);
+            }
+        }
+    }
+
     #[test]
     fn observe_option() {
         for i in 0..8 {
             match ObserveOption::try_from(i) {
-                Ok(o) => assert_eq!(i, o.into()),
+                Ok(o) => assert_eq!(i, usize::from(o)),
                 _ => (),
             }
         }
@@ -775,7 +5062,7 @@ mod test {
         p.add_option(CoapOption::UriPath, vec![1]);
         p.add_option(CoapOption::ETag, vec![2]);
         p.clear_option(CoapOption::ETag);
-        assert_eq!(3, p.options().len());
+        assert_eq!(2, p.options().len());
 
         let bytes = p.to_bytes().unwrap();
         let mut pp = Packet::from_bytes(&bytes).unwrap();
@@ -818,10 +5105,57 @@ mod test {
     }
 
     #[test]
+    #[allow(deprecated)]
     fn observe() {
         let mut p = Packet::new();
         assert_eq!(None, p.get_observe_value());
         p.set_observe_value(0);
         assert_eq!(Some(Ok(0)), p.get_observe_value());
+        p.set_observe_value(0x0100_0005);
+        assert_eq!(Some(Ok(5)), p.get_observe_value());
+    }
+
+    #[test]
+    fn observe_enum() {
+        assert_eq!(Vec::<u8>::new(), Observe::Register.encode());
+        assert_eq!(vec![1], Observe::Deregister.encode());
+        assert_eq!(vec![0x01, 0x00], Observe::Sequence(256).encode());
+        assert_eq!(
+            vec![0xFF, 0xFF, 0xFF],
+            Observe::Sequence(0x0100_0000 - 1).encode()
+        );
+        assert_eq!(Vec::<u8>::new(), Observe::Sequence(0x0100_0000).encode());
+
+        assert_eq!(Ok(Observe::Register), Observe::decode(&[], true));
+        assert_eq!(Ok(Observe::Register), Observe::decode(&[0], true));
+        assert_eq!(Ok(Observe::Deregister), Observe::decode(&[1], true));
+        assert_eq!(Err(InvalidObserve), Observe::decode(&[2], true));
+        assert_eq!(Ok(Observe::Sequence(0)), Observe::decode(&[], false));
+        assert_eq!(Ok(Observe::Sequence(1)), Observe::decode(&[1], false));
+        assert_eq!(
+            Ok(Observe::Sequence(0x010203)),
+            Observe::decode(&[1, 2, 3], false)
+        );
+        assert_eq!(Err(InvalidObserve), Observe::decode(&[1, 2, 3, 4], false));
+
+        let mut request = Packet::new();
+        assert_eq!(None, request.get_observe());
+        for observe in [Observe::Register, Observe::Deregister] {
+            request.set_observe(observe);
+            assert_eq!(Some(Ok(observe)), request.get_observe());
+        }
+        assert_eq!(1, request.get_option(CoapOption::Observe).unwrap().len());
+
+        let mut response = Packet::new();
+        response.header.code = MessageClass::Response(ResponseType::Content);
+        for sequence in [0, 1, 0xFF_FFFF] {
+            response.set_observe(Observe::Sequence(sequence));
+            let decoded =
+                Packet::from_bytes(&response.to_bytes().unwrap()).unwrap();
+            assert_eq!(
+                Some(Ok(Observe::Sequence(sequence))),
+                decoded.get_observe()
+            );
+        }
     }
 }
@@ -0,0 +1,176 @@
+//! A message parsed in place, borrowing everything from the buffer.
+
+use core::iter::FusedIterator;
+
+use crate::{
+    error::MessageError,
+    header::Header,
+    packet::{decode_header, OptionWalker, Packet, ProtocolLimits},
+};
+
+/// A message that is validated like [`Packet::from_bytes`] does, but whose
+/// token, option values and payload are slices of the buffer, so parsing
+/// doesn't allocate.
+///
+/// Unlike [`LazyPacket`](crate::LazyPacket), which decodes options as they
+/// are looked up, the whole message is checked up front, so iterating the
+/// options can't fail.
+///
+/// Servers that see the same option values over and over, e.g. gateways
+/// routing on Uri-Path segments, and need to keep messages beyond the
+/// buffer can share the values between messages with
+/// [`PacketRef::to_packet_with`].
+///
+/// # Examples
+///
+/// ```
+/// use coap_lite::{CoapOption, Packet, PacketRef};
+///
+/// let mut packet = Packet::new();
+/// packet.add_option(CoapOption::UriPath, b"sensors".to_vec());
+/// packet.add_option(CoapOption::UriPath, b"temp".to_vec());
+/// let bytes = packet.to_bytes().unwrap();
+///
+/// // Route on the first Uri-Path segment
+/// let message = PacketRef::parse(&bytes).unwrap();
+/// let segment = message
+///     .options()
+///     .find(|&(number, _)| number == u16::from(CoapOption::UriPath))
+///     .map(|(_, value)| value);
+/// assert_eq!(Some(&b"sensors"[..]), segment);
+/// ```
+#[derive(Debug, Clone)]
+pub struct PacketRef<'a> {
+    header: Header,
+    token: &'a [u8],
+    options: &'a [u8],
+    payload: &'a [u8],
+}
+
+impl<'a> PacketRef<'a> {
+    /// Parses a message, failing like [`Packet::from_bytes`] does.
+    pub fn parse(buf: &'a [u8]) -> Result<PacketRef<'a>, MessageError> {
+        let (header, token) =
+            decode_header(buf, ProtocolLimits::RFC.max_token_length)?;
+        let options_start = token.end;
+
+        let mut options = OptionWalker::new(buf, options_start, 0);
+        for option in options.by_ref() {
+            option?;
+        }
+        let payload = options.payload()?;
+
+        Ok(PacketRef {
+            header,
+            token: &buf[token],
+            options: &buf[options_start..options.offset()],
+            payload: &buf[payload],
+        })
+    }
+
+    /// Returns the header.
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Returns the token.
+    pub fn token(&self) -> &'a [u8] {
+        self.token
+    }
+
+    /// Returns an iterator over the option numbers and values, in the order
+    /// they're encoded in.
+    pub fn options(&self) -> PacketRefOptions<'a> {
+        PacketRefOptions {
+            walker: OptionWalker::new(self.options, 0, 0),
+        }
+    }
+
+    /// Returns the payload, which is empty if there is none.
+    pub fn payload(&self) -> &'a [u8] {
+        self.payload
+    }
+
+    /// Copies the message into a [`Packet`].
+    pub fn to_packet(&self) -> Packet {
+        let mut packet = Packet::new();
+        packet.header = self.header.clone();
+        packet.set_token(self.token.to_vec());
+        for (number, value) in self.options() {
+            packet.add_option_raw(number, value.to_vec());
+        }
+        packet.payload = self.payload.to_vec();
+        packet
+    }
+}
+
+/// The options of a [`PacketRef`], see [`PacketRef::options`].
+#[derive(Debug, Clone)]
+pub struct PacketRefOptions<'a> {
+    walker: OptionWalker<'a>,
+}
+
+impl<'a> Iterator for PacketRefOptions<'a> {
+    type Item = (u16, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // The options were validated when parsing
+        let option = self.walker.next()?.ok()?;
+        Some((option.number, option.value))
+    }
+}
+
+impl FusedIterator for PacketRefOptions<'_> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::RawPacketBuilder;
+    use crate::{CoapOption, MessageClass, RequestType};
+    use alloc::vec::Vec;
+
+    #[test]
+    fn agrees_with_packet() {
+        let mut packet = Packet::new();
+        packet.header.code = MessageClass::Request(RequestType::Post);
+        packet.set_token(vec![1, 2, 3, 4]);
+        packet.add_option(CoapOption::UriHost, b"example.org".to_vec());
+        packet.add_option(CoapOption::UriPath, b"a".to_vec());
+        packet.add_option(CoapOption::UriPath, b"b".to_vec());
+        packet.add_option(CoapOption::Unknown(2048), vec![0; 300]);
+        packet.payload = b"{}".to_vec();
+
+        for packet in [Packet::new(), packet] {
+            let bytes = packet.to_bytes().unwrap();
+            let message = PacketRef::parse(&bytes).unwrap();
+            assert_eq!(packet.header, *message.header());
+            assert_eq!(packet.get_token(), message.token());
+            assert_eq!(&packet.payload[..], message.payload());
+            let options: Vec<(u16, &[u8])> = packet
+                .options()
+                .flat_map(|(&number, values)| {
+                    values.iter().map(move |value| (number, &value[..]))
+                })
+                .collect();
+            assert_eq!(options, message.options().collect::<Vec<_>>());
+            assert_eq!(packet, message.to_packet());
+        }
+    }
+
+    #[test]
+    fn rejects_what_packet_rejects() {
+        let uri_path = RawPacketBuilder::new().option(11, b"a");
+        let cases = [
+            uri_path.clone().option_header(15, 0).build(),
+            uri_path.clone().option_header(1, 3).bytes(b"b").build(),
+            uri_path.payload(&[]).build(),
+            RawPacketBuilder::new().token_length(9).build(),
+        ];
+        for bytes in cases {
+            assert_eq!(
+                Packet::from_bytes(&bytes).unwrap_err(),
+                PacketRef::parse(&bytes).unwrap_err()
+            );
+        }
+    }
+}
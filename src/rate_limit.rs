@@ -0,0 +1,417 @@
+//! Per-endpoint request rate limiting and rejecting requests while
+//! overloaded.
+//!
+//! Like the transmission helpers this performs no I/O and doesn't read a
+//! clock, so it behaves deterministically given the timestamps passed in.
+
+use alloc::collections::BTreeMap;
+
+use crate::{response::CoapResponse, Packet, ResponseType};
+
+/// The configuration for [`RateLimiter`].
+#[derive(Debug, Clone)]
+pub struct RateLimiterConfig {
+    /// Number of requests per second an endpoint may send in the long run.
+    pub rate: u32,
+
+    /// Number of requests an endpoint may send in a burst, after having been
+    /// quiet for long enough.
+    pub burst: u32,
+
+    /// Number of endpoints to keep track of.  When it is exceeded, the least
+    /// recently seen endpoint is forgotten and starts out with a full burst
+    /// the next time it is seen.
+    pub max_endpoints: usize,
+}
+
+impl Default for RateLimiterConfig {
+    fn default() -> Self {
+        Self {
+            rate: 10,
+            burst: 20,
+            max_endpoints: 64,
+        }
+    }
+}
+
+/// The outcome of [`RateLimiter::allow`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decision {
+    /// The request may be processed.
+    Allow,
+    /// The request should be rejected, the endpoint may try again after the
+    /// given number of seconds.
+    Reject(u32),
+}
+
+#[derive(Debug, Clone)]
+struct Bucket {
+    // Available requests in thousandths, which makes refilling at `rate`
+    // requests per second an addition of `rate` per millisecond
+    tokens: u64,
+    last_refill_ms: u64,
+    last_used: u64,
+}
+
+/// Limits the rate of requests per endpoint using a token bucket for each of
+/// them.
+#[derive(Debug, Clone)]
+pub struct RateLimiter<Endpoint: Ord + Clone> {
+    config: RateLimiterConfig,
+    buckets: BTreeMap<Endpoint, Bucket>,
+    uses: u64,
+}
+
+impl<Endpoint: Ord + Clone> RateLimiter<Endpoint> {
+    /// Creates a new rate limiter.
+    pub fn new(config: RateLimiterConfig) -> Self {
+        Self {
+            config,
+            buckets: BTreeMap::new(),
+            uses: 0,
+        }
+    }
+
+    /// Returns the number of endpoints currently tracked.
+    pub fn len(&self) -> usize {
+        self.buckets.len()
+    }
+
+    /// Returns whether no endpoints are currently tracked.
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    /// Decides whether a request from `endpoint` received at `now_ms`
+    /// milliseconds may be processed.
+    pub fn allow(&mut self, endpoint: &Endpoint, now_ms: u64) -> Decision {
+        let capacity = u64::from(self.config.burst) * 1000;
+        let rate = u64::from(self.config.rate);
+
+        if !self.buckets.contains_key(endpoint) {
+            self.evict_if_full();
+            self.buckets.insert(
+                endpoint.clone(),
+                Bucket {
+                    tokens: capacity,
+                    last_refill_ms: now_ms,
+                    last_used: 0,
+                },
+            );
+        }
+
+        self.uses += 1;
+        let bucket = self.buckets.get_mut(endpoint).unwrap();
+        bucket.last_used = self.uses;
+
+        let elapsed_ms = now_ms.saturating_sub(bucket.last_refill_ms);
+        bucket.tokens = capacity.min(
+            bucket
+                .tokens
+                .saturating_add(elapsed_ms.saturating_mul(rate)),
+        );
+        bucket.last_refill_ms = bucket.last_refill_ms.max(now_ms);
+
+        if bucket.tokens >= 1000 {
+            bucket.tokens -= 1000;
+            return Decision::Allow;
+        }
+
+        let retry_after_secs = match rate {
+            0 => u32::MAX,
+            _ => {
+                let missing_ms = (1000 - bucket.tokens).div_ceil(rate);
+                u32::try_from(missing_ms.div_ceil(1000))
+                    .unwrap_or(u32::MAX)
+                    .max(1)
+            }
+        };
+        Decision::Reject(retry_after_secs)
+    }
+
+    fn evict_if_full(&mut self) {
+        if self.buckets.len() < self.config.max_endpoints {
+            return;
+        }
+        let least_recently_used = self
+            .buckets
+            .iter()
+            .min_by_key(|(_, bucket)| bucket.last_used)
+            .map(|(endpoint, _)| endpoint.clone());
+        if let Some(endpoint) = least_recently_used {
+            self.buckets.remove(&endpoint);
+        }
+    }
+}
+
+/// Creates the 4.29 (Too Many Requests) response to a rejected request, with
+/// the Max-Age option telling the client how long to wait (RFC 8516).
+///
+/// Returns `None` if the request can't be responded to, see
+/// [`CoapResponse::new`].
+pub fn too_many_requests(
+    request: &Packet,
+    retry_after_secs: u32,
+) -> Option<CoapResponse> {
+    let mut response = CoapResponse::new(request)?;
+    response.set_status(ResponseType::TooManyRequests);
+    response.message.payload.clear();
+    response.message.set_max_age(retry_after_secs);
+    Some(response)
+}
+
+/// How to reject a request, see [`OverloadGuard::maybe_reject`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rejection {
+    /// Send the message, which is the 5.03 (Service Unavailable) response,
+    /// or an empty acknowledgement if the request is confirmable and the
+    /// response is suppressed by its No-Response option.
+    Respond(Packet),
+    /// Drop the request without responding.
+    Drop,
+}
+
+/// Rejects all requests with 5.03 (Service Unavailable) for a period, while
+/// the server is busy with e.g. an OTA update.
+///
+/// The server loop calls [`OverloadGuard::maybe_reject`] for each message
+/// before handling it, so that all requests in the period are answered
+/// consistently, with Max-Age counting down to the end of it.
+#[derive(Debug, Clone, Default)]
+pub struct OverloadGuard {
+    until_ms: Option<u64>,
+}
+
+impl OverloadGuard {
+    /// Creates a guard that isn't active.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rejects requests from `now_ms` on for `duration_ms` milliseconds.  An
+    /// activation doesn't shorten a period that is already active.
+    pub fn activate(&mut self, now_ms: u64, duration_ms: u64) {
+        let until_ms = now_ms.saturating_add(duration_ms);
+        self.until_ms = Some(
+            self.until_ms
+                .map_or(until_ms, |current| current.max(until_ms)),
+        );
+    }
+
+    /// Ends the period early.
+    pub fn deactivate(&mut self) {
+        self.until_ms = None;
+    }
+
+    /// Returns whether requests are rejected at `now_ms`.
+    pub fn is_active(&self, now_ms: u64) -> bool {
+        matches!(self.until_ms, Some(until_ms) if now_ms < until_ms)
+    }
+
+    /// Returns how to reject `request` if it is received at `now_ms` during
+    /// the period, or `None` if it should be handled normally.  Messages
+    /// other than requests are never rejected.
+    pub fn maybe_reject(
+        &self,
+        request: &Packet,
+        now_ms: u64,
+    ) -> Option<Rejection> {
+        let until_ms = self.until_ms.filter(|_| self.is_active(now_ms))?;
+        if !request.expects_response() {
+            return None;
+        }
+
+        let status = ResponseType::ServiceUnavailable;
+        if request.suppresses_response(status) {
+            return Some(match CoapResponse::new_empty_ack(request) {
+                Some(ack) => Rejection::Respond(ack),
+                None => Rejection::Drop,
+            });
+        }
+
+        let retry_after_secs =
+            u32::try_from((until_ms - now_ms).div_ceil(1000))
+                .unwrap_or(u32::MAX);
+        Some(
+            match CoapResponse::service_unavailable(request, retry_after_secs)
+            {
+                Some(response) => Rejection::Respond(response.message),
+                None => Rejection::Drop,
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        option_value::OptionValueU32, CoapOption, MessageType, RequestType,
+    };
+
+    fn new_limiter(
+        rate: u32,
+        burst: u32,
+        max_endpoints: usize,
+    ) -> RateLimiter<u8> {
+        RateLimiter::new(RateLimiterConfig {
+            rate,
+            burst,
+            max_endpoints,
+        })
+    }
+
+    #[test]
+    fn burst() {
+        let mut limiter = new_limiter(1, 3, 4);
+        for _ in 0..3 {
+            assert_eq!(Decision::Allow, limiter.allow(&1, 0));
+        }
+        assert_eq!(Decision::Reject(1), limiter.allow(&1, 0));
+        assert_eq!(Decision::Reject(1), limiter.allow(&1, 500));
+        assert_eq!(Decision::Allow, limiter.allow(&1, 1000));
+
+        // Other endpoints have their own bucket
+        assert_eq!(Decision::Allow, limiter.allow(&2, 1000));
+    }
+
+    #[test]
+    fn steady_state() {
+        let mut limiter = new_limiter(10, 1, 4);
+        let mut allowed = 0;
+        // Attempting a request every 20ms for 10s
+        for now_ms in (0..10_000).step_by(20) {
+            if limiter.allow(&1, now_ms) == Decision::Allow {
+                allowed += 1;
+            }
+        }
+        assert_eq!(100, allowed);
+
+        let mut slow = new_limiter(1, 1, 4);
+        assert_eq!(Decision::Allow, slow.allow(&1, 0));
+        assert_eq!(Decision::Reject(1), slow.allow(&1, 1));
+
+        let mut slower = RateLimiter::new(RateLimiterConfig {
+            rate: 0,
+            burst: 1,
+            max_endpoints: 1,
+        });
+        assert_eq!(Decision::Allow, slower.allow(&1, 0));
+        assert_eq!(Decision::Reject(u32::MAX), slower.allow(&1, 1_000_000));
+    }
+
+    #[test]
+    fn eviction() {
+        let mut limiter = new_limiter(1, 1, 2);
+        assert_eq!(Decision::Allow, limiter.allow(&1, 0));
+        assert_eq!(Decision::Allow, limiter.allow(&2, 0));
+        assert_eq!(Decision::Reject(1), limiter.allow(&1, 0));
+        assert_eq!(2, limiter.len());
+
+        // Endpoint 2 was used least recently, so it gets evicted
+        assert_eq!(Decision::Allow, limiter.allow(&3, 0));
+        assert_eq!(2, limiter.len());
+        assert_eq!(Decision::Reject(1), limiter.allow(&1, 0));
+        assert_eq!(Decision::Allow, limiter.allow(&2, 0));
+        assert_eq!(2, limiter.len());
+    }
+
+    #[test]
+    fn rejection_response() {
+        let mut request = Packet::new();
+        request.header.set_type(MessageType::Confirmable);
+        request.header.code = crate::MessageClass::Request(RequestType::Post);
+        request.header.message_id = 42;
+        request.payload = b"data".to_vec();
+
+        let response = too_many_requests(&request, 30).unwrap();
+        assert_eq!(&ResponseType::TooManyRequests, response.get_status());
+        assert_eq!(42, response.message.header.message_id);
+        assert!(response.message.payload.is_empty());
+        assert_eq!(
+            Some(Ok(OptionValueU32(30))),
+            response
+                .message
+                .get_first_option_as::<OptionValueU32>(CoapOption::MaxAge)
+        );
+    }
+
+    fn new_request(message_type: MessageType) -> Packet {
+        let mut request = Packet::new();
+        request.header.set_type(message_type);
+        request.header.code = crate::MessageClass::Request(RequestType::Put);
+        request.header.message_id = 7;
+        request
+    }
+
+    #[test]
+    fn overload_window() {
+        let request = new_request(MessageType::Confirmable);
+        let mut guard = OverloadGuard::new();
+        assert_eq!(None, guard.maybe_reject(&request, 0));
+
+        guard.activate(1000, 2500);
+        for (now_ms, retry_after_secs) in [(1000, 3), (2499, 2), (3499, 1)] {
+            let Some(Rejection::Respond(response)) =
+                guard.maybe_reject(&request, now_ms)
+            else {
+                panic!("not rejected at {}", now_ms);
+            };
+            assert_eq!(
+                crate::MessageClass::Response(
+                    ResponseType::ServiceUnavailable
+                ),
+                response.header.code
+            );
+            assert_eq!(7, response.header.message_id);
+            assert_eq!(
+                Some(Ok(OptionValueU32(retry_after_secs))),
+                response
+                    .get_first_option_as::<OptionValueU32>(CoapOption::MaxAge)
+            );
+        }
+        assert_eq!(None, guard.maybe_reject(&request, 3500));
+
+        // A shorter activation doesn't cut the period short
+        guard.activate(0, 10_000);
+        guard.activate(1000, 10);
+        assert!(guard.is_active(9999));
+        guard.deactivate();
+        assert!(!guard.is_active(1000));
+
+        // Only requests are rejected
+        guard.activate(0, 1000);
+        let ack = CoapResponse::new_empty_ack(&request).unwrap();
+        assert_eq!(None, guard.maybe_reject(&ack, 0));
+    }
+
+    #[test]
+    fn overload_no_response() {
+        let mut guard = OverloadGuard::new();
+        guard.activate(0, 1000);
+
+        // Only suppressing 2.xx responses doesn't affect the 5.03
+        let mut request = new_request(MessageType::NonConfirmable);
+        request.add_option_as(
+            CoapOption::NoResponse,
+            crate::option_value::OptionValueU8(0x02),
+        );
+        assert!(matches!(
+            guard.maybe_reject(&request, 0),
+            Some(Rejection::Respond(_))
+        ));
+
+        request.set_option(CoapOption::NoResponse, [vec![0x10]].into());
+        assert_eq!(Some(Rejection::Drop), guard.maybe_reject(&request, 0));
+
+        // A confirmable request still needs to be acknowledged
+        request.header.set_type(MessageType::Confirmable);
+        let Some(Rejection::Respond(ack)) = guard.maybe_reject(&request, 0)
+        else {
+            panic!("not acknowledged");
+        };
+        assert_eq!(MessageType::Acknowledgement, ack.header.get_type());
+        assert_eq!(crate::MessageClass::Empty, ack.header.code);
+        assert_eq!(7, ack.header.message_id);
+    }
+}
@@ -2,23 +2,58 @@ use alloc::{
     string::{String, ToString},
     vec::Vec,
 };
-use core::convert::TryFrom;
 
 use crate::{
     error::{HandlingError, IncompatibleOptionValueFormat, InvalidObserve},
     header::{MessageClass, RequestType as Method},
     option_value::OptionValueString,
-    packet::{CoapOption, ObserveOption, Packet},
+    packet::{CoapOption, Observe, ObserveOption, Packet},
     response::CoapResponse,
-    ContentFormat,
+    uri::{CoapUri, Scheme},
+    ContentFormat, Extensions, ResponseType,
 };
 
+/// How a request was protected in transit, as reported by the transport
+/// that received it.  No security is implemented by this crate.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum SecurityContext {
+    /// Received without any protection.
+    #[default]
+    Insecure,
+    /// Received over DTLS, with the identity the peer authenticated with if
+    /// any, e.g. the PSK identity or a certificate fingerprint.
+    Dtls { peer_identity: Option<Vec<u8>> },
+    /// Protected by OSCORE (RFC 8613) at the message level.
+    Oscore,
+}
+
+impl SecurityContext {
+    /// Returns whether the request was protected.
+    pub fn is_protected(&self) -> bool {
+        *self != SecurityContext::Insecure
+    }
+
+    /// Returns the URI scheme of the transport, which is `coaps` for DTLS.
+    /// OSCORE messages are sent over plain CoAP, so it's `coap` for them.
+    pub fn scheme(&self) -> Scheme {
+        match self {
+            SecurityContext::Dtls { .. } => Scheme::Coaps,
+            _ => Scheme::Coap,
+        }
+    }
+}
+
 /// The CoAP request.
+///
+/// It has private fields, so it is built with [`CoapRequest::new`] or
+/// [`CoapRequest::from_packet`] rather than a struct literal.
 #[derive(Clone, Debug)]
 pub struct CoapRequest<Endpoint> {
     pub message: Packet,
     pub response: Option<CoapResponse>,
     pub source: Option<Endpoint>,
+    extensions: Extensions,
+    security: SecurityContext,
 }
 
 impl<Endpoint> CoapRequest<Endpoint> {
@@ -36,9 +71,33 @@ impl<Endpoint> CoapRequest<Endpoint> {
             response: CoapResponse::new(&packet),
             message: packet,
             source: Some(source),
+            extensions: Extensions::new(),
+            security: SecurityContext::Insecure,
         }
     }
 
+    /// Returns the data attached by the transport or middleware, which is
+    /// not sent.
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// Returns the data attached by the transport or middleware for
+    /// modification.
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    /// Returns how the request was protected.
+    pub fn security(&self) -> &SecurityContext {
+        &self.security
+    }
+
+    /// Sets how the request was protected, to be called by the transport.
+    pub fn set_security(&mut self, security: SecurityContext) {
+        self.security = security;
+    }
+
     /// Applies the given error to the request and returns true if that was
     /// successful.
     pub fn apply_from_error(&mut self, error: HandlingError) -> bool {
@@ -54,6 +113,30 @@ impl<Endpoint> CoapRequest<Endpoint> {
         false
     }
 
+    /// Reconstructs the URI of the request, given the host it was received
+    /// on.  The scheme, and with it the default port, depend on the
+    /// security context.
+    pub fn request_uri(&self, destination: &str) -> CoapUri {
+        CoapUri::from_packet(
+            &self.message,
+            self.security.scheme(),
+            destination,
+        )
+    }
+
+    /// Fails with 4.01 (Unauthorized) unless the request was protected, for
+    /// resources that must not be accessed otherwise.
+    pub fn require_protection(&self) -> Result<(), HandlingError> {
+        if self.security.is_protected() {
+            Ok(())
+        } else {
+            Err(HandlingError::with_code(
+                ResponseType::Unauthorized,
+                "Protected transport required",
+            ))
+        }
+    }
+
     /// Sets the method.
     pub fn set_method(&mut self, method: Method) {
         self.message.header.code = MessageClass::Request(method);
@@ -74,6 +157,9 @@ impl<Endpoint> CoapRequest<Endpoint> {
     }
 
     /// Sets the path.
+    ///
+    /// Only a leading empty segment is skipped, unlike with
+    /// [`Packet::set_uri_path`], which drops all of them.
     pub fn set_path(&mut self, path: &str) {
         self.message.clear_option(CoapOption::UriPath);
 
@@ -88,7 +174,8 @@ impl<Endpoint> CoapRequest<Endpoint> {
         }
     }
 
-    /// Returns the path.
+    /// Returns the path, without a leading `/` unlike
+    /// [`Packet::get_uri_path`].
     pub fn get_path(&self) -> String {
         match self.message.get_option(CoapOption::UriPath) {
             Some(options) => {
@@ -124,24 +211,72 @@ impl<Endpoint> CoapRequest<Endpoint> {
             )
     }
 
+    /// Adds a `name=value` parameter as a Uri-Query option.
+    ///
+    /// Option values aren't percent-encoded, so the parameter is added as
+    /// is.  Unlike in HTML forms, `+` is never a space in CoAP.
+    pub fn add_uri_query(&mut self, name: &str, value: &str) {
+        let mut parameter = Vec::with_capacity(name.len() + value.len() + 1);
+        parameter.extend_from_slice(name.as_bytes());
+        parameter.push(b'=');
+        parameter.extend_from_slice(value.as_bytes());
+        self.message.add_option(CoapOption::UriQuery, parameter);
+    }
+
+    /// Returns the Uri-Query options as name-value pairs, split at the first
+    /// `=`.  A parameter without `=` has an empty value, and parameters that
+    /// aren't valid UTF-8 are skipped.
+    ///
+    /// The values are returned literally: `+` is not decoded to a space, as
+    /// it would be in application/x-www-form-urlencoded, and there's no
+    /// percent-encoding to decode.  Percent-encoded characters of a URI are
+    /// decoded when the URI is split into options, see
+    /// [`CoapUri::parse`](crate::CoapUri::parse).
+    pub fn get_uri_query_pairs(&self) -> Vec<(String, String)> {
+        self.message
+            .get_option(CoapOption::UriQuery)
+            .into_iter()
+            .flatten()
+            .filter_map(|parameter| core::str::from_utf8(parameter).ok())
+            .map(|parameter| {
+                let (name, value) =
+                    parameter.split_once('=').unwrap_or((parameter, ""));
+                (name.to_string(), value.to_string())
+            })
+            .collect()
+    }
+
+    /// Returns the Uri-Query options as strings, see
+    /// [`Packet::get_queries`].  Without any the list is empty.
+    pub fn get_queries(&self) -> Vec<String> {
+        self.message.get_queries().unwrap_or_default()
+    }
+
+    /// Returns the payload.
+    pub fn get_payload(&self) -> &[u8] {
+        &self.message.payload
+    }
+
     /// Returns the flag in the Observe option or InvalidObserve if the flag
     /// was provided but not understood.
     pub fn get_observe_flag(
         &self,
     ) -> Option<Result<ObserveOption, InvalidObserve>> {
-        self.message.get_observe_value().map(|observe| {
-            observe
-                .map(|value| usize::try_from(value).unwrap())
-                .map_or(Err(InvalidObserve), |value| {
-                    ObserveOption::try_from(value)
-                })
-        })
+        self.message
+            .get_first_option(CoapOption::Observe)
+            .map(|value| match Observe::decode(value, true)? {
+                Observe::Register => Ok(ObserveOption::Register),
+                Observe::Deregister => Ok(ObserveOption::Deregister),
+                Observe::Sequence(_) => Err(InvalidObserve),
+            })
     }
 
     /// Sets the flag in the Observe option.
     pub fn set_observe_flag(&mut self, flag: ObserveOption) {
-        let value = u32::try_from(usize::from(flag)).unwrap();
-        self.message.set_observe_value(value);
+        self.message.set_observe(match flag {
+            ObserveOption::Register => Observe::Register,
+            ObserveOption::Deregister => Observe::Deregister,
+        });
     }
 }
 
@@ -151,6 +286,8 @@ impl<Endpoint> Default for CoapRequest<Endpoint> {
             response: None,
             message: Packet::new(),
             source: None,
+            extensions: Extensions::new(),
+            security: SecurityContext::Insecure,
         }
     }
 }
@@ -299,7 +436,7 @@ mod test {
     fn test_unknown_observe_flag() {
         let mut request: CoapRequest<Endpoint> = CoapRequest::new();
 
-        request.message.set_observe_value(32);
+        request.message.add_option(CoapOption::Observe, vec![32]);
         let expected = Some(Err(InvalidObserve));
         let actual = request.get_observe_flag();
         assert_eq!(actual, expected);
@@ -316,4 +453,120 @@ mod test {
         let actual = request.get_observe_flag();
         assert_eq!(actual, expected);
     }
+
+    #[test]
+    fn test_extensions() {
+        #[derive(Clone, Debug, PartialEq)]
+        struct ReceivedAt(u64);
+        #[derive(Clone, Debug, PartialEq)]
+        struct Identity(&'static str);
+
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Confirmable);
+        packet.set_token(vec![0x01]);
+        let source = || Endpoint(String::from("127.0.0.1"));
+        let plain = CoapRequest::from_packet(packet.clone(), source());
+
+        let mut request = CoapRequest::from_packet(packet, source());
+        request.extensions_mut().insert(ReceivedAt(1000));
+        request.extensions_mut().insert(Identity("sensor-1"));
+        let response = request.response.as_mut().unwrap();
+        response.extensions_mut().insert(Identity("sensor-1"));
+
+        assert_eq!(Some(&ReceivedAt(1000)), request.extensions().get());
+        assert_eq!(Some(&Identity("sensor-1")), request.extensions().get());
+        let response = request.response.as_ref().unwrap();
+        assert_eq!(Some(&Identity("sensor-1")), response.extensions().get());
+        assert_eq!(None, response.extensions().get::<ReceivedAt>());
+
+        assert_eq!(plain.message, request.message);
+        assert_eq!(
+            plain.response.unwrap().message.to_bytes(),
+            response.message.to_bytes()
+        );
+    }
+
+    #[test]
+    fn test_security_context() {
+        let mut request: CoapRequest<Endpoint> = CoapRequest::new();
+        request.set_method(Method::Put);
+        request.set_path("admin");
+
+        let uri = request.request_uri("192.0.2.1");
+        assert_eq!(Scheme::Coap, uri.scheme());
+        assert_eq!(5683, uri.port());
+        assert_eq!("coap://192.0.2.1/admin", uri.to_string());
+        assert_eq!(
+            Some(ResponseType::Unauthorized),
+            request.require_protection().unwrap_err().code
+        );
+
+        request.set_security(SecurityContext::Dtls {
+            peer_identity: Some(b"client-1".to_vec()),
+        });
+        assert!(request.security().is_protected());
+        let uri = request.request_uri("192.0.2.1");
+        assert_eq!(Scheme::Coaps, uri.scheme());
+        assert_eq!(5684, uri.port());
+        assert_eq!("coaps://192.0.2.1/admin", uri.to_string());
+        assert!(request.require_protection().is_ok());
+
+        // An explicit port that is the default of the other scheme is kept
+        request.message.set_uri_port(5683);
+        assert_eq!(
+            "coaps://192.0.2.1:5683/admin",
+            request.request_uri("192.0.2.1").to_string()
+        );
+
+        request.set_security(SecurityContext::Oscore);
+        assert_eq!(Scheme::Coap, request.request_uri("192.0.2.1").scheme());
+        assert!(request.require_protection().is_ok());
+    }
+
+    #[test]
+    fn test_payload() {
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Confirmable);
+        packet.payload = b"22.5".to_vec();
+        let request = CoapRequest::from_packet(packet, Endpoint("a".into()));
+        assert_eq!(b"22.5", request.get_payload());
+        assert!(request.get_queries().is_empty());
+    }
+
+    #[test]
+    fn test_uri_query_pairs() {
+        let uri =
+            CoapUri::parse("coap://192.0.2.1/?a=1+2&b=%20x%2B&c=d=e&flag")
+                .unwrap();
+        let mut request: CoapRequest<Endpoint> = CoapRequest::new();
+        request.message.set_uri(&uri);
+        assert_eq!(
+            vec![
+                ("a".to_string(), "1+2".to_string()),
+                ("b".to_string(), " x+".to_string()),
+                ("c".to_string(), "d=e".to_string()),
+                ("flag".to_string(), "".to_string()),
+            ],
+            request.get_uri_query_pairs()
+        );
+
+        let mut request: CoapRequest<Endpoint> = CoapRequest::new();
+        request.add_uri_query("q", "a+b c");
+        request.add_uri_query("expr", "x=1&y");
+        assert_eq!(
+            vec![
+                ("q".to_string(), "a+b c".to_string()),
+                ("expr".to_string(), "x=1&y".to_string()),
+            ],
+            request.get_uri_query_pairs()
+        );
+        assert_eq!(
+            vec!["q=a+b c".to_string(), "expr=x=1&y".to_string()],
+            request.get_queries()
+        );
+        assert_eq!(
+            "coap://192.0.2.1/?q=a+b%20c&expr=x=1%26y",
+            request.request_uri("192.0.2.1").to_string()
+        );
+    }
 }
@@ -1,16 +1,50 @@
+use alloc::vec::Vec;
+
 use crate::{
+    error::{MessageError, ValidationError},
     header::{MessageClass, MessageType, ResponseType as Status},
     packet::Packet,
+    Extensions,
 };
 
 /// The CoAP response.
+///
+/// It has private fields, so it is built with [`CoapResponse::new`] or
+/// [`CoapResponse::from_packet`] rather than a struct literal.
 #[derive(Clone, Debug)]
 pub struct CoapResponse {
     pub message: Packet,
+    extensions: Extensions,
 }
 
 impl CoapResponse {
+    /// Wraps a received response message, e.g. on the client side.
+    pub fn from_packet(packet: Packet) -> CoapResponse {
+        CoapResponse {
+            message: packet,
+            extensions: Extensions::new(),
+        }
+    }
+
+    /// Returns the data attached by the application or middleware, which is
+    /// not sent.
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// Returns the data attached by the application or middleware for
+    /// modification.
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
     /// Creates a new response.
+    ///
+    /// A confirmable request gets a piggybacked response in the
+    /// acknowledgement, which reuses the request's message ID.  A
+    /// non-confirmable request gets a non-confirmable response, for which the
+    /// message ID is copied as well but should be replaced by a fresh one
+    /// before sending, see [`Packet::new_non_response`].
     pub fn new(request: &Packet) -> Option<CoapResponse> {
         let mut packet = Packet::new();
 
@@ -27,7 +61,171 @@ impl CoapResponse {
 
         packet.payload = request.payload.clone();
 
-        Some(CoapResponse { message: packet })
+        Some(CoapResponse::from_packet(packet))
+    }
+
+    /// Creates a separate response, which is sent in its own confirmable
+    /// message after the request has been acknowledged with
+    /// [`CoapResponse::new_empty_ack`].
+    pub fn new_separate(request: &Packet, message_id: u16) -> CoapResponse {
+        let mut packet = Packet::new();
+
+        packet.header.set_version(1);
+        packet.header.set_type(MessageType::Confirmable);
+        packet.header.code = MessageClass::Response(Status::Content);
+        packet.header.message_id = message_id;
+        packet.set_token(request.get_token().to_vec());
+
+        CoapResponse::from_packet(packet)
+    }
+
+    /// Creates the 5.03 (Service Unavailable) response for a server that is
+    /// temporarily busy, e.g. writing flash, with the Max-Age option telling
+    /// the client when to retry (RFC 7252 section 5.9.3.4).
+    ///
+    /// Returns `None` if the request can't be responded to, see
+    /// [`CoapResponse::new`].
+    pub fn service_unavailable(
+        request: &Packet,
+        retry_after_secs: u32,
+    ) -> Option<CoapResponse> {
+        let mut response = CoapResponse::new(request)?;
+        response.set_status(Status::ServiceUnavailable);
+        response.message.payload.clear();
+        response.message.set_max_age(retry_after_secs);
+        Some(response)
+    }
+
+    /// Creates the empty acknowledgement for a confirmable message, which
+    /// is used when the actual response can't be piggybacked.
+    pub fn new_empty_ack(message: &Packet) -> Option<Packet> {
+        if message.header.get_type() != MessageType::Confirmable {
+            return None;
+        }
+
+        let mut packet = Packet::new();
+        packet.header.set_version(1);
+        packet.header.set_type(MessageType::Acknowledgement);
+        packet.header.code = MessageClass::Empty;
+        packet.header.message_id = message.header.message_id;
+
+        Some(packet)
+    }
+
+    /// Checks that the type and message ID of the response fit `request`:
+    /// a response that reuses the message ID of a confirmable request is
+    /// piggybacked and has to be an acknowledgement, and only confirmable
+    /// requests can be acknowledged.  Separate responses, which have a
+    /// message ID of their own, may be confirmable or non-confirmable.
+    pub fn validate_message_type(
+        &self,
+        request: &Packet,
+    ) -> Result<(), ValidationError> {
+        let actual = self.message.header.get_type();
+        let expected = match (request.header.get_type(), actual) {
+            (MessageType::NonConfirmable, MessageType::Acknowledgement) => {
+                MessageType::NonConfirmable
+            }
+            (MessageType::Confirmable, MessageType::Acknowledgement) => {
+                if self.message.header.message_id != request.header.message_id
+                {
+                    return Err(ValidationError::MessageIdMismatch {
+                        expected: request.header.message_id,
+                        actual: self.message.header.message_id,
+                    });
+                }
+                actual
+            }
+            (MessageType::Confirmable, _)
+                if self.message.header.message_id
+                    == request.header.message_id =>
+            {
+                MessageType::Acknowledgement
+            }
+            _ => actual,
+        };
+        if expected != actual {
+            return Err(ValidationError::ResponseTypeMismatch {
+                expected,
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks [`CoapResponse::validate_message_type`] before sending.  A
+    /// violation is a bug, so it's only asserted in debug builds, unless the
+    /// `strict-invariants` feature is enabled, which makes it an error in
+    /// all builds, e.g. for test environments.
+    pub fn check_message_type(
+        &self,
+        request: &Packet,
+    ) -> Result<(), ValidationError> {
+        let result = self.validate_message_type(request);
+        if cfg!(feature = "strict-invariants") {
+            return result;
+        }
+        debug_assert_eq!(Ok(()), result, "invalid response type");
+        Ok(())
+    }
+
+    /// Encodes the response to `request` like [`Packet::to_bytes`], after
+    /// checking its type with [`CoapResponse::check_message_type`].  That
+    /// is only an error with the `strict-invariants` feature, which fails
+    /// with [`MessageError::InvalidResponse`].
+    pub fn to_bytes_for(
+        &self,
+        request: &Packet,
+    ) -> Result<Vec<u8>, MessageError> {
+        self.check_message_type(request)
+            .map_err(MessageError::InvalidResponse)?;
+        self.message.to_bytes()
+    }
+
+    /// Fixes the type and message ID of the response if they don't fit
+    /// `request`, see [`CoapResponse::validate_message_type`]: an
+    /// acknowledgement of a non-confirmable request becomes non-confirmable,
+    /// and a piggybacked response to a confirmable request becomes an
+    /// acknowledgement echoing its message ID.
+    pub fn repair_message_type(&mut self, request: &Packet) {
+        match self.validate_message_type(request) {
+            Err(ValidationError::ResponseTypeMismatch {
+                expected, ..
+            }) => self.message.header.set_type(expected),
+            Err(ValidationError::MessageIdMismatch { expected, .. }) => {
+                self.message.header.message_id = expected
+            }
+            _ => {}
+        }
+    }
+
+    /// Turns the response into a 2.03 (Valid) response carrying `etag`, the
+    /// tag of the current representation, if `request` is a GET or FETCH
+    /// request listing that tag (RFC 7252 section 5.10.6.2 and RFC 8132
+    /// section 2.3.1).  The payload is dropped, as the client already has
+    /// the representation.  For FETCH, the tag is that of the result of the
+    /// query in the request body.
+    ///
+    /// Returns whether the response was changed, failing with
+    /// [`MessageError::InvalidOptionValue`] unless `etag` is 1 to 8 bytes
+    /// long.
+    pub fn set_valid_if_matching(
+        &mut self,
+        request: &Packet,
+        etag: &[u8],
+    ) -> Result<bool, MessageError> {
+        let is_safe = matches!(
+            request.header.code,
+            MessageClass::Request(method) if method.is_safe()
+        );
+        self.message.set_etag(etag)?;
+        if !is_safe || !request.etag_matches(etag) {
+            return Ok(false);
+        }
+
+        self.set_status(Status::Valid);
+        self.message.payload.clear();
+        Ok(true)
     }
 
     /// Sets the status.
@@ -43,6 +241,7 @@ impl CoapResponse {
             MessageClass::Response(Status::Valid) => &Status::Valid,
             MessageClass::Response(Status::Changed) => &Status::Changed,
             MessageClass::Response(Status::Content) => &Status::Content,
+            MessageClass::Response(Status::Continue) => &Status::Continue,
 
             MessageClass::Response(Status::BadRequest) => &Status::BadRequest,
             MessageClass::Response(Status::Unauthorized) => {
@@ -57,6 +256,7 @@ impl CoapResponse {
             MessageClass::Response(Status::NotAcceptable) => {
                 &Status::NotAcceptable
             }
+            MessageClass::Response(Status::Conflict) => &Status::Conflict,
             MessageClass::Response(Status::PreconditionFailed) => {
                 &Status::PreconditionFailed
             }
@@ -66,6 +266,15 @@ impl CoapResponse {
             MessageClass::Response(Status::UnsupportedContentFormat) => {
                 &Status::UnsupportedContentFormat
             }
+            MessageClass::Response(Status::RequestEntityIncomplete) => {
+                &Status::RequestEntityIncomplete
+            }
+            MessageClass::Response(Status::UnprocessableEntity) => {
+                &Status::UnprocessableEntity
+            }
+            MessageClass::Response(Status::TooManyRequests) => {
+                &Status::TooManyRequests
+            }
 
             MessageClass::Response(Status::InternalServerError) => {
                 &Status::InternalServerError
@@ -83,6 +292,9 @@ impl CoapResponse {
             MessageClass::Response(Status::ProxyingNotSupported) => {
                 &Status::ProxyingNotSupported
             }
+            MessageClass::Response(Status::HopLimitReached) => {
+                &Status::HopLimitReached
+            }
             _ => &Status::UnKnown,
         }
     }
@@ -91,6 +303,7 @@ impl CoapResponse {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::CoapOption;
 
     #[test]
     fn test_new_response_valid() {
@@ -112,4 +325,245 @@ mod test {
         packet.header.set_type(MessageType::Acknowledgement);
         assert!(CoapResponse::new(&packet).is_none());
     }
+
+    #[test]
+    fn test_con_and_non_response() {
+        let mut request = Packet::new();
+        request.header.set_type(MessageType::Confirmable);
+        request.header.message_id = 17;
+        request.set_token(vec![0xAB]);
+
+        let response = CoapResponse::new(&request).unwrap();
+        assert_eq!(
+            MessageType::Acknowledgement,
+            response.message.header.get_type()
+        );
+        assert_eq!(17, response.message.header.message_id);
+        assert_eq!([0xAB], response.message.get_token());
+
+        request.header.set_type(MessageType::NonConfirmable);
+        let response = CoapResponse::new(&request).unwrap();
+        assert_eq!(
+            MessageType::NonConfirmable,
+            response.message.header.get_type()
+        );
+
+        let response = request.new_non_response(Status::Changed, 18);
+        assert_eq!(MessageType::NonConfirmable, response.header.get_type());
+        assert_eq!(
+            MessageClass::Response(Status::Changed),
+            response.header.code
+        );
+        assert_eq!(18, response.header.message_id);
+        assert_eq!([0xAB], response.get_token());
+    }
+
+    #[test]
+    fn test_get_status() {
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Confirmable);
+        let mut response = CoapResponse::new(&packet).unwrap();
+
+        for status in [
+            Status::Content,
+            Status::Continue,
+            Status::Conflict,
+            Status::RequestEntityIncomplete,
+            Status::UnprocessableEntity,
+            Status::TooManyRequests,
+            Status::HopLimitReached,
+        ] {
+            response.set_status(status);
+            assert_eq!(&status, response.get_status());
+        }
+    }
+
+    #[test]
+    fn test_separate_response() {
+        let mut request = Packet::new();
+        request.header.set_type(MessageType::Confirmable);
+        request.header.message_id = 17;
+        request.set_token(vec![0xAB]);
+
+        let ack = CoapResponse::new_empty_ack(&request).unwrap();
+        assert_eq!(MessageType::Acknowledgement, ack.header.get_type());
+        assert_eq!(MessageClass::Empty, ack.header.code);
+        assert_eq!(17, ack.header.message_id);
+        assert!(ack.get_token().is_empty());
+
+        let response = CoapResponse::new_separate(&request, 18);
+        assert_eq!(
+            MessageType::Confirmable,
+            response.message.header.get_type()
+        );
+        assert_eq!(18, response.message.header.message_id);
+        assert_eq!([0xAB], response.message.get_token());
+
+        request.header.set_type(MessageType::NonConfirmable);
+        assert!(CoapResponse::new_empty_ack(&request).is_none());
+    }
+
+    #[test]
+    fn test_repair_message_type() {
+        let mut request = Packet::new();
+        request.header.set_type(MessageType::NonConfirmable);
+        request.header.message_id = 10;
+
+        // An acknowledgement of a non-confirmable request
+        let mut response = CoapResponse::new(&request).unwrap();
+        response
+            .message
+            .header
+            .set_type(MessageType::Acknowledgement);
+        assert_eq!(
+            Err(ValidationError::ResponseTypeMismatch {
+                expected: MessageType::NonConfirmable,
+                actual: MessageType::Acknowledgement,
+            }),
+            response.validate_message_type(&request)
+        );
+        response.repair_message_type(&request);
+        assert_eq!(
+            MessageType::NonConfirmable,
+            response.message.header.get_type()
+        );
+        assert_eq!(Ok(()), response.validate_message_type(&request));
+
+        // A non-confirmable piggybacked response to a confirmable request
+        request.header.set_type(MessageType::Confirmable);
+        assert_eq!(
+            Err(ValidationError::ResponseTypeMismatch {
+                expected: MessageType::Acknowledgement,
+                actual: MessageType::NonConfirmable,
+            }),
+            response.validate_message_type(&request)
+        );
+        response.repair_message_type(&request);
+        assert_eq!(
+            MessageType::Acknowledgement,
+            response.message.header.get_type()
+        );
+        assert_eq!(10, response.message.header.message_id);
+
+        // An acknowledgement with the wrong message ID
+        response.message.header.message_id = 11;
+        response.repair_message_type(&request);
+        assert_eq!(10, response.message.header.message_id);
+        assert_eq!(Ok(()), response.check_message_type(&request));
+
+        // Separate responses are fine either way
+        let mut response = CoapResponse::new_separate(&request, 20);
+        assert_eq!(Ok(()), response.validate_message_type(&request));
+        response
+            .message
+            .header
+            .set_type(MessageType::NonConfirmable);
+        assert_eq!(Ok(()), response.validate_message_type(&request));
+    }
+
+    #[test]
+    fn test_service_unavailable() {
+        let mut request = Packet::new();
+        request.header.set_type(MessageType::Confirmable);
+        request.payload = b"data".to_vec();
+        let response =
+            CoapResponse::service_unavailable(&request, 30).unwrap();
+        assert_eq!(&Status::ServiceUnavailable, response.get_status());
+        assert_eq!(Some(30), response.message.get_max_age());
+        assert_eq!(
+            1,
+            response
+                .message
+                .get_option(CoapOption::MaxAge)
+                .unwrap()
+                .len()
+        );
+        assert!(response.message.payload.is_empty());
+    }
+
+    #[test]
+    fn test_set_valid_if_matching() {
+        use crate::{ContentFormat, RequestType};
+
+        let mut fetch = Packet::new();
+        fetch.header.set_type(MessageType::Confirmable);
+        fetch.header.code = MessageClass::Request(RequestType::Fetch);
+        fetch.set_content_format(ContentFormat::ApplicationCBOR);
+        fetch.set_accept(ContentFormat::ApplicationJSON);
+        fetch.payload = vec![0xA1, 0x61, 0x6B, 0x01];
+        fetch.add_etag(b"v1").unwrap();
+
+        let mut response = CoapResponse::new(&fetch).unwrap();
+        response.message.payload = b"{\"k\":1}".to_vec();
+        assert_eq!(Ok(false), response.set_valid_if_matching(&fetch, b"v2"));
+        assert_eq!(&Status::Content, response.get_status());
+        assert_eq!(Some(&b"v2"[..]), response.message.get_etag());
+
+        assert_eq!(Ok(true), response.set_valid_if_matching(&fetch, b"v1"));
+        assert_eq!(&Status::Valid, response.get_status());
+        assert_eq!(Some(&b"v1"[..]), response.message.get_etag());
+        assert!(response.message.payload.is_empty());
+
+        // GET requests are validated just the same, but not unsafe ones
+        let mut get = fetch.clone();
+        get.header.code = MessageClass::Request(RequestType::Get);
+        let mut response = CoapResponse::new(&get).unwrap();
+        assert_eq!(Ok(true), response.set_valid_if_matching(&get, b"v1"));
+
+        let mut post = fetch.clone();
+        post.header.code = MessageClass::Request(RequestType::Post);
+        let mut response = CoapResponse::new(&post).unwrap();
+        assert_eq!(Ok(false), response.set_valid_if_matching(&post, b"v1"));
+
+        assert_eq!(
+            Err(MessageError::InvalidOptionValue {
+                option: CoapOption::ETag,
+                length: 9,
+            }),
+            response.set_valid_if_matching(&fetch, &[0; 9])
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "strict-invariants")]
+    fn test_strict_invariants() {
+        let mut request = Packet::new();
+        request.header.set_type(MessageType::NonConfirmable);
+        let mut response = CoapResponse::new(&request).unwrap();
+        response
+            .message
+            .header
+            .set_type(MessageType::Acknowledgement);
+        assert!(response.check_message_type(&request).is_err());
+        assert_eq!(
+            Err(MessageError::InvalidResponse(
+                ValidationError::ResponseTypeMismatch {
+                    expected: MessageType::NonConfirmable,
+                    actual: MessageType::Acknowledgement,
+                }
+            )),
+            response.to_bytes_for(&request)
+        );
+    }
+
+    #[test]
+    #[cfg(all(debug_assertions, not(feature = "strict-invariants")))]
+    #[should_panic(expected = "invalid response type")]
+    fn test_debug_invariants() {
+        let mut request = Packet::new();
+        request.header.set_type(MessageType::Confirmable);
+        let response = CoapResponse::new_separate(&request, 0);
+        let _ = response.to_bytes_for(&request);
+    }
+
+    #[test]
+    fn test_to_bytes_for() {
+        let mut request = Packet::new();
+        request.header.set_type(MessageType::Confirmable);
+        let response = CoapResponse::new(&request).unwrap();
+        assert_eq!(
+            response.message.to_bytes(),
+            response.to_bytes_for(&request)
+        );
+    }
 }
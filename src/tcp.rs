@@ -0,0 +1,328 @@
+//! The message framing of CoAP over TCP (RFC 8323).
+//!
+//! A TCP message has no type and no message ID, the header consists of a
+//! length nibble, the token length and the code.  The options and payload are
+//! encoded just like they are over UDP.
+
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+use crate::{
+    error::MessageError,
+    header::MessageClass,
+    packet::{
+        decode_options_and_payload, DecodeConfig, EncodeConfig, Packet,
+        ProtocolLimits,
+    },
+};
+
+impl Packet {
+    /// Decodes a single message in the TCP framing of RFC 8323.
+    ///
+    /// The buffer must contain exactly one message.  As the framing doesn't
+    /// carry them, the type and message ID of the header are left at their
+    /// defaults.
+    pub fn from_bytes_tcp(buf: &[u8]) -> Result<Packet, MessageError> {
+        let truncated = |expected| MessageError::TruncatedMessage {
+            expected,
+            actual: buf.len(),
+        };
+
+        let first = *buf.first().ok_or_else(|| truncated(1))?;
+        let token_length = first & 0xF;
+        if usize::from(token_length) > ProtocolLimits::RFC.max_token_length {
+            return Err(MessageError::ReservedTokenLength(token_length));
+        }
+
+        let code_index = 1 + extended_length_size(first >> 4);
+        let options_start = code_index + 1 + usize::from(token_length);
+        if options_start > buf.len() {
+            return Err(truncated(options_start));
+        }
+
+        let length = body_length(first >> 4, &buf[1..code_index]);
+        let end = options_start as u64 + length;
+        if end > buf.len() as u64 {
+            return Err(truncated(usize::try_from(end).unwrap_or(usize::MAX)));
+        }
+        if end < buf.len() as u64 {
+            return Err(MessageError::InvalidPacketLength);
+        }
+
+        let body = decode_options_and_payload(
+            buf,
+            options_start,
+            &DecodeConfig::default(),
+        )?;
+
+        let mut packet = Packet::new();
+        packet.header.code = MessageClass::from(buf[code_index]);
+        packet.set_token(buf[(code_index + 1)..options_start].to_vec());
+        packet.options = body.options;
+        packet.payload = body.payload;
+        Ok(packet)
+    }
+
+    /// Returns a vector of bytes representing the Packet in the TCP framing
+    /// of RFC 8323.
+    ///
+    /// The type and message ID of the header are not encoded.  Apart from
+    /// that the same normalizations as for [`Packet::to_bytes`] apply, but
+    /// there is no limit on the message size.
+    pub fn to_bytes_tcp(&self) -> Result<Vec<u8>, MessageError> {
+        if self.get_token().len() > ProtocolLimits::RFC.max_token_length {
+            return Err(MessageError::InvalidTokenLength);
+        }
+
+        // An option too long to be encoded makes for a body long enough to
+        // need the 4-byte extended length
+        let options_start = 1 + 4 + 1 + self.get_token().len();
+        let mut body =
+            self.options_to_bytes(&EncodeConfig::default(), options_start)?;
+        if self.header.code != MessageClass::Empty && !self.payload.is_empty()
+        {
+            body.push(0xFF);
+            body.extend_from_slice(&self.payload);
+        }
+
+        let length = body.len();
+        let mut buf = Vec::with_capacity(
+            1 + 4 + 1 + self.get_token().len() + body.len(),
+        );
+        let token_length = self.get_token().len() as u8;
+        if length < 13 {
+            buf.push((length as u8) << 4 | token_length);
+        } else if length < 269 {
+            buf.push(13 << 4 | token_length);
+            buf.push((length - 13) as u8);
+        } else if length < 65805 {
+            buf.push(14 << 4 | token_length);
+            buf.extend_from_slice(&((length - 269) as u16).to_be_bytes());
+        } else {
+            let extended = u32::try_from(length - 65805)
+                .map_err(|_| MessageError::InvalidPacketLength)?;
+            buf.push(15 << 4 | token_length);
+            buf.extend_from_slice(&extended.to_be_bytes());
+        }
+        buf.push(self.header.code.into());
+        buf.extend_from_slice(self.get_token());
+        buf.extend_from_slice(&body);
+        Ok(buf)
+    }
+
+    /// Reads a single message in the TCP framing of RFC 8323 from a stream,
+    /// e.g. a `TcpStream`, blocking until it is complete.
+    ///
+    /// The length announced by a peer can be up to 4 GB, so messages longer
+    /// than `max_message_size` bytes, framing included, are rejected before
+    /// anything beyond their header is read.  A server would announce that
+    /// limit in the Max-Message-Size option of its CSM message (RFC 8323
+    /// section 5.3.1), which defaults to 1152 bytes.
+    ///
+    /// Fails with `UnexpectedEof` if the stream ends before the message does
+    /// and with `InvalidData` if the message is too long or can't be
+    /// decoded.  After a message was rejected as too long, the stream is
+    /// positioned in the middle of it and has to be closed.
+    #[cfg(feature = "std")]
+    pub fn read_from<R: std::io::Read>(
+        reader: &mut R,
+        max_message_size: usize,
+    ) -> std::io::Result<Packet> {
+        use std::io::{Error, ErrorKind, Read};
+
+        let mut first = [0; 1];
+        reader.read_exact(&mut first)?;
+
+        let mut frame = vec![0; 1 + extended_length_size(first[0] >> 4)];
+        frame[0] = first[0];
+        reader.read_exact(&mut frame[1..])?;
+
+        // The code, the token and the options and payload
+        let remaining = 1
+            + u64::from(first[0] & 0xF)
+            + body_length(first[0] >> 4, &frame[1..]);
+        if frame.len() as u64 + remaining > max_message_size as u64 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                MessageError::InvalidPacketLength,
+            ));
+        }
+        let read = reader.by_ref().take(remaining).read_to_end(&mut frame)?;
+        if (read as u64) < remaining {
+            return Err(Error::from(ErrorKind::UnexpectedEof));
+        }
+
+        Packet::from_bytes_tcp(&frame)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+}
+
+/// Returns the number of bytes of the extended length following the first
+/// byte of a message.
+fn extended_length_size(length_nibble: u8) -> usize {
+    match length_nibble {
+        13 => 1,
+        14 => 2,
+        15 => 4,
+        _ => 0,
+    }
+}
+
+/// Returns the length of the options and payload of a message.
+fn body_length(length_nibble: u8, extended: &[u8]) -> u64 {
+    match (length_nibble, extended) {
+        (13, &[a]) => u64::from(a) + 13,
+        (14, &[a, b]) => u64::from(u16::from_be_bytes([a, b])) + 269,
+        (15, &[a, b, c, d]) => {
+            u64::from(u32::from_be_bytes([a, b, c, d])) + 65805
+        }
+        _ => u64::from(length_nibble),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{CoapOption, RequestType, ResponseType};
+
+    fn get_request() -> Packet {
+        let mut packet = Packet::new();
+        packet.header.code = MessageClass::Request(RequestType::Get);
+        packet.set_token(vec![0x01]);
+        packet.add_option(CoapOption::UriPath, b"a".to_vec());
+        packet
+    }
+
+    #[test]
+    fn encode_decode() {
+        let packet = get_request();
+        let bytes = packet.to_bytes_tcp().unwrap();
+        assert_eq!(vec![0x21, 0x01, 0x01, 0xB1, 0x61], bytes);
+        assert_eq!(packet, Packet::from_bytes_tcp(&bytes).unwrap());
+
+        for (payload_length, header) in [
+            (12, vec![0xD0, 0x00]),
+            (300, vec![0xE0, 0x00, 0x20]),
+            (70000, vec![0xF0, 0x00, 0x00, 0x10, 0x64]),
+        ] {
+            let mut packet = Packet::new();
+            packet.header.code = MessageClass::Response(ResponseType::Content);
+            packet.payload = vec![0x42; payload_length];
+            let bytes = packet.to_bytes_tcp().unwrap();
+            assert_eq!(&header[..], &bytes[..header.len()]);
+            assert_eq!(header.len() + 2 + payload_length, bytes.len());
+            assert_eq!(packet, Packet::from_bytes_tcp(&bytes).unwrap());
+        }
+
+        let mut packet = get_request();
+        packet.add_option(CoapOption::UriQuery, vec![0; 70000]);
+        assert_eq!(
+            Err(MessageError::InvalidOptionLength { offset: 9 }),
+            packet.to_bytes_tcp()
+        );
+    }
+
+    #[test]
+    fn decode_errors() {
+        assert_eq!(
+            Err(MessageError::TruncatedMessage {
+                expected: 1,
+                actual: 0
+            }),
+            Packet::from_bytes_tcp(&[])
+        );
+        assert_eq!(
+            Err(MessageError::ReservedTokenLength(9)),
+            Packet::from_bytes_tcp(&[0x09, 0x01])
+        );
+        assert_eq!(
+            Err(MessageError::TruncatedMessage {
+                expected: 5,
+                actual: 4
+            }),
+            Packet::from_bytes_tcp(&[0x21, 0x01, 0x01, 0xB1])
+        );
+        assert_eq!(
+            Err(MessageError::InvalidPacketLength),
+            Packet::from_bytes_tcp(&[0x21, 0x01, 0x01, 0xB1, 0x61, 0x00])
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn read_from() {
+        use std::io::{Cursor, ErrorKind, Read};
+
+        // Hands out the data one byte at a time
+        struct Trickle<'a>(&'a [u8]);
+
+        impl Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                match (self.0.split_first(), buf.first_mut()) {
+                    (Some((&byte, rest)), Some(first)) => {
+                        *first = byte;
+                        self.0 = rest;
+                        Ok(1)
+                    }
+                    _ => Ok(0),
+                }
+            }
+        }
+
+        let first = get_request();
+        let mut second = Packet::new();
+        second.header.code = MessageClass::Response(ResponseType::Content);
+        second.payload = vec![0x42; 300];
+
+        let mut stream = first.to_bytes_tcp().unwrap();
+        stream.extend(second.to_bytes_tcp().unwrap());
+
+        let mut cursor = Cursor::new(&stream);
+        assert_eq!(first, Packet::read_from(&mut cursor, 1152).unwrap());
+        assert_eq!(second, Packet::read_from(&mut cursor, 1152).unwrap());
+        assert_eq!(
+            ErrorKind::UnexpectedEof,
+            Packet::read_from(&mut cursor, 1152).unwrap_err().kind()
+        );
+
+        let mut trickle = Trickle(&stream);
+        assert_eq!(first, Packet::read_from(&mut trickle, 1152).unwrap());
+        assert_eq!(second, Packet::read_from(&mut trickle, 1152).unwrap());
+
+        let mut truncated = Trickle(&stream[..stream.len() - 1]);
+        assert_eq!(first, Packet::read_from(&mut truncated, 1152).unwrap());
+        assert_eq!(
+            ErrorKind::UnexpectedEof,
+            Packet::read_from(&mut truncated, 1152).unwrap_err().kind()
+        );
+
+        let mut invalid = Cursor::new([0x09, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            ErrorKind::InvalidData,
+            Packet::read_from(&mut invalid, 1152).unwrap_err().kind()
+        );
+
+        // A peer announcing a 4 GB message is rejected before it is read
+        let mut huge = Cursor::new([0xF0, 0xFF, 0xFF, 0xFF, 0xFF, 0x45]);
+        assert_eq!(
+            ErrorKind::InvalidData,
+            Packet::read_from(&mut huge, 1152).unwrap_err().kind()
+        );
+        assert_eq!(5, huge.position());
+
+        // The limit includes the framing
+        let bytes = second.to_bytes_tcp().unwrap();
+        let mut exact = Cursor::new(&bytes);
+        assert_eq!(
+            second,
+            Packet::read_from(&mut exact, bytes.len()).unwrap()
+        );
+        let mut exact = Cursor::new(&bytes);
+        assert_eq!(
+            ErrorKind::InvalidData,
+            Packet::read_from(&mut exact, bytes.len() - 1)
+                .unwrap_err()
+                .kind()
+        );
+    }
+}
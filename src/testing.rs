@@ -0,0 +1,192 @@
+//! Crafting malformed messages for negative tests.
+//!
+//! **This module is for tests only.**  It's only available with the
+//! `testing` feature, which is meant for `[dev-dependencies]`: nothing here
+//! checks that the bytes form a valid message, and what it builds is
+//! expected to be rejected.  Use [`Packet`](crate::Packet) to build messages
+//! to send.
+
+use alloc::vec::Vec;
+
+use crate::option_header;
+
+/// The largest option length that can be encoded.
+const MAX_OPTION_LENGTH: usize = 0xFFFF + 269;
+
+/// Assembles the bytes of a message piece by piece, without any validation.
+///
+/// The header starts out as a confirmable GET with message ID 1 and no
+/// token.  Options, payload and arbitrary bytes are appended in the order of
+/// the calls.
+///
+/// ```
+/// use coap_lite::testing::RawPacketBuilder;
+///
+/// // A token length of 12, which is reserved
+/// let bytes = RawPacketBuilder::new().token_length(12).build();
+/// assert_eq!(vec![0x4C, 0x01, 0x00, 0x01], bytes);
+/// ```
+#[derive(Debug, Clone)]
+pub struct RawPacketBuilder {
+    version: u8,
+    message_type: u8,
+    token_length: Option<u8>,
+    code: u8,
+    message_id: u16,
+    token: Vec<u8>,
+    body: Vec<u8>,
+}
+
+impl Default for RawPacketBuilder {
+    fn default() -> Self {
+        RawPacketBuilder {
+            version: 1,
+            message_type: 0,
+            token_length: None,
+            code: 0x01,
+            message_id: 1,
+            token: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+}
+
+impl RawPacketBuilder {
+    /// Creates a builder for a confirmable GET with message ID 1.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the version, of which the lower 2 bits are used.
+    pub fn version(mut self, version: u8) -> Self {
+        self.version = version & 0x03;
+        self
+    }
+
+    /// Sets the type, of which the lower 2 bits are used.
+    pub fn message_type(mut self, message_type: u8) -> Self {
+        self.message_type = message_type & 0x03;
+        self
+    }
+
+    /// Sets the token length nibble, of which the lower 4 bits are used,
+    /// regardless of the token actually present.  Without it, the length of
+    /// the token is used.
+    pub fn token_length(mut self, token_length: u8) -> Self {
+        self.token_length = Some(token_length & 0x0F);
+        self
+    }
+
+    /// Sets the code byte.
+    pub fn code(mut self, code: u8) -> Self {
+        self.code = code;
+        self
+    }
+
+    /// Sets the message ID.
+    pub fn message_id(mut self, message_id: u16) -> Self {
+        self.message_id = message_id;
+        self
+    }
+
+    /// Sets the token bytes following the header.
+    pub fn token(mut self, token: &[u8]) -> Self {
+        self.token = token.to_vec();
+        self
+    }
+
+    /// Appends a well-formed option with the given delta to the previous
+    /// one, which may still be invalid, e.g. by exceeding option number
+    /// 65535.
+    ///
+    /// A value longer than 65804 bytes, the largest length that can be
+    /// encoded, gets a header announcing 65804 bytes, so that the rest of
+    /// the value is read as whatever follows the option.
+    pub fn option(mut self, delta: u16, value: &[u8]) -> Self {
+        let mut header = [0; option_header::MAX_LEN];
+        let announced = value.len().min(MAX_OPTION_LENGTH);
+        let len = option_header::encode(delta, announced, &mut header)
+            .expect("Length must be encodable");
+        self.body.extend_from_slice(&header[..len]);
+        self.body.extend_from_slice(value);
+        self
+    }
+
+    /// Appends the first byte of an option header, made of the delta and
+    /// length nibbles, of which the lower 4 bits are used.  Any extended
+    /// delta, extended length and value have to follow with
+    /// [`bytes`](Self::bytes).
+    pub fn option_header(mut self, delta: u8, length: u8) -> Self {
+        self.body.push((delta & 0x0F) << 4 | (length & 0x0F));
+        self
+    }
+
+    /// Appends the payload marker followed by `payload`, which may be empty.
+    pub fn payload(mut self, payload: &[u8]) -> Self {
+        self.body.push(0xFF);
+        self.body.extend_from_slice(payload);
+        self
+    }
+
+    /// Appends arbitrary bytes.
+    pub fn bytes(mut self, bytes: &[u8]) -> Self {
+        self.body.extend_from_slice(bytes);
+        self
+    }
+
+    /// Returns the bytes of the message.
+    pub fn build(&self) -> Vec<u8> {
+        let token_length =
+            self.token_length.unwrap_or((self.token.len() & 0x0F) as u8);
+        let mut bytes =
+            Vec::with_capacity(4 + self.token.len() + self.body.len());
+        bytes.push(self.version << 6 | self.message_type << 4 | token_length);
+        bytes.push(self.code);
+        bytes.extend_from_slice(&self.message_id.to_be_bytes());
+        bytes.extend_from_slice(&self.token);
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn build() {
+        let bytes = RawPacketBuilder::new()
+            .message_type(2)
+            .code(0x45)
+            .message_id(0x1234)
+            .token(&[0xAB])
+            .option(11, b"a")
+            .option(300, &[])
+            .payload(b"hi")
+            .build();
+        assert_eq!(
+            vec![
+                0x61, 0x45, 0x12, 0x34, 0xAB, 0xB1, b'a', 0xE0, 0x00, 0x1F,
+                0xFF, b'h', b'i'
+            ],
+            bytes
+        );
+
+        let bytes = RawPacketBuilder::new()
+            .version(3)
+            .token(&[1, 2])
+            .token_length(15)
+            .option_header(15, 1)
+            .bytes(&[0x00])
+            .build();
+        assert_eq!(vec![0xCF, 0x01, 0x00, 0x01, 1, 2, 0xF1, 0x00], bytes);
+    }
+
+    #[test]
+    fn option_too_long() {
+        let value = vec![0; MAX_OPTION_LENGTH + 1];
+        let bytes = RawPacketBuilder::new().option(1, &value).build();
+        assert_eq!([0x1E, 0xFF, 0xFF], bytes[4..7]);
+        assert_eq!(7 + value.len(), bytes.len());
+    }
+}
@@ -0,0 +1,1170 @@
+//! Message transmission helpers (RFC 7252 section 4).
+//!
+//! Nothing in here performs any I/O or reads a clock, the caller is expected
+//! to feed in the relevant observations and act on the returned values.
+
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use crate::{
+    error::{InvalidTransmissionParameter, MessageError},
+    header::{MessageClass, MessageType},
+    packet::Packet,
+    response::CoapResponse,
+};
+
+/// Default initial retransmission timeout (RFC 7252 section 4.8).
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Maximum number of retransmissions of a confirmable message (RFC 7252
+/// section 4.8).
+pub const MAX_RETRANSMIT: u8 = 4;
+
+/// Time from the first transmission of a confirmable message until its
+/// message ID can safely be reused, which also bounds how long a separate
+/// response is waited for (RFC 7252 section 4.8.2).
+pub const EXCHANGE_LIFETIME: Duration = Duration::from_secs(247);
+
+/// Default factor by which the initial timeout of a confirmable message is
+/// randomized, in thousandths, i.e. 1.5 (RFC 7252 section 4.8).
+pub const ACK_RANDOM_FACTOR_MILLI: u32 = 1500;
+
+/// Maximum time a datagram is expected to take from the start of its
+/// transmission to the completion of its reception (RFC 7252 section 4.8.2).
+pub const MAX_LATENCY: Duration = Duration::from_secs(100);
+
+/// Lower bound applied to a computed retransmission timeout (RFC 6298
+/// section 2.4).
+pub const MIN_RTO: Duration = Duration::from_secs(1);
+
+/// Upper bound applied to a computed retransmission timeout (RFC 6298
+/// section 2.5).
+pub const MAX_RTO: Duration = Duration::from_secs(60);
+
+/// Estimates the round-trip time to a peer and derives the retransmission
+/// timeout from it, using the smoothing algorithm of RFC 6298.
+///
+/// This is the adaptive alternative to always starting from the fixed
+/// [`ACK_TIMEOUT`].  Following Karn's algorithm, samples should only be taken
+/// from exchanges that were not retransmitted, since the acknowledgement of a
+/// retransmitted message can't be attributed to a specific transmission.
+///
+/// All arithmetic is done in integer milliseconds, the `Duration` based
+/// methods are conveniences on top of [`RttEstimator::update_ms`] and
+/// [`RttEstimator::rto_ms`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RttEstimator {
+    // Smoothed round-trip time and round-trip time variation, both in
+    // milliseconds, once the first sample has been taken
+    state: Option<(u32, u32)>,
+}
+
+impl RttEstimator {
+    /// Creates a new estimator without any samples.
+    pub fn new() -> RttEstimator {
+        Default::default()
+    }
+
+    /// Feeds a new round-trip time measurement into the estimator.
+    pub fn update(&mut self, sample: Duration) {
+        let sample_ms = u32::try_from(sample.as_millis()).unwrap_or(u32::MAX);
+        self.update_ms(sample_ms);
+    }
+
+    /// Feeds a new round-trip time measurement in milliseconds into the
+    /// estimator.
+    pub fn update_ms(&mut self, sample_ms: u32) {
+        self.state = Some(match self.state {
+            None => (sample_ms, sample_ms / 2),
+            Some((srtt, rttvar)) => {
+                // RTTVAR <- (1 - 1/4) * RTTVAR + 1/4 * |SRTT - R'|
+                // SRTT <- (1 - 1/8) * SRTT + 1/8 * R'
+                let deviation = u64::from(srtt.abs_diff(sample_ms));
+                let rttvar = (3 * u64::from(rttvar) + deviation) / 4;
+                let srtt = (7 * u64::from(srtt) + u64::from(sample_ms)) / 8;
+                (srtt as u32, rttvar as u32)
+            }
+        });
+    }
+
+    /// Returns the smoothed round-trip time, if any sample has been taken.
+    pub fn srtt(&self) -> Option<Duration> {
+        self.state
+            .map(|(srtt, _)| Duration::from_millis(u64::from(srtt)))
+    }
+
+    /// Returns the current retransmission timeout.
+    pub fn rto(&self) -> Duration {
+        Duration::from_millis(u64::from(self.rto_ms()))
+    }
+
+    /// Returns the current retransmission timeout in milliseconds.
+    ///
+    /// Before the first sample this is [`ACK_TIMEOUT`], afterwards it is
+    /// `SRTT + 4 * RTTVAR` clamped to [`MIN_RTO`] and [`MAX_RTO`].
+    pub fn rto_ms(&self) -> u32 {
+        match self.state {
+            None => ACK_TIMEOUT.as_millis() as u32,
+            Some((srtt, rttvar)) => {
+                // The clock granularity is a millisecond, so it never
+                // dominates the variance term
+                let rto = u64::from(srtt) + 4 * u64::from(rttvar).max(1);
+                rto.clamp(
+                    MIN_RTO.as_millis() as u64,
+                    MAX_RTO.as_millis() as u64,
+                ) as u32
+            }
+        }
+    }
+}
+
+/// The transmission parameters of RFC 7252 section 4.8 and the time values
+/// derived from them in section 4.8.2.
+///
+/// The defaults are those of the RFC, for which the derived values match the
+/// constants of this module, e.g. [`EXCHANGE_LIFETIME`].  The setters reject
+/// values the RFC doesn't allow.  Like [`RttEstimator`], everything is
+/// computed in integer milliseconds, ACK_RANDOM_FACTOR is given in
+/// thousandths.
+///
+/// # Examples
+///
+/// ```
+/// use core::time::Duration;
+/// use coap_lite::TransmissionParameters;
+///
+/// let mut parameters = TransmissionParameters::default();
+/// parameters.set_ack_timeout(Duration::from_secs(1)).unwrap();
+/// parameters.set_max_retransmit(2);
+/// // 1s * (2^2 - 1) * 1.5
+/// assert_eq!(Duration::from_millis(4500), parameters.max_transmit_span());
+///
+/// assert!(parameters.set_ack_random_factor_milli(900).is_err());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransmissionParameters {
+    ack_timeout_ms: u64,
+    ack_random_factor_milli: u32,
+    max_retransmit: u8,
+}
+
+impl Default for TransmissionParameters {
+    fn default() -> Self {
+        TransmissionParameters {
+            ack_timeout_ms: ACK_TIMEOUT.as_millis() as u64,
+            ack_random_factor_milli: ACK_RANDOM_FACTOR_MILLI,
+            max_retransmit: MAX_RETRANSMIT,
+        }
+    }
+}
+
+impl TransmissionParameters {
+    /// Returns ACK_TIMEOUT, the minimum initial retransmission timeout.
+    pub fn ack_timeout(&self) -> Duration {
+        Duration::from_millis(self.ack_timeout_ms)
+    }
+
+    /// Sets ACK_TIMEOUT, failing if it is shorter than a millisecond.
+    pub fn set_ack_timeout(
+        &mut self,
+        ack_timeout: Duration,
+    ) -> Result<(), InvalidTransmissionParameter> {
+        let ms = u64::try_from(ack_timeout.as_millis()).unwrap_or(u64::MAX);
+        if ms == 0 {
+            return Err(InvalidTransmissionParameter);
+        }
+        self.ack_timeout_ms = ms;
+        Ok(())
+    }
+
+    /// Returns ACK_RANDOM_FACTOR in thousandths.
+    pub fn ack_random_factor_milli(&self) -> u32 {
+        self.ack_random_factor_milli
+    }
+
+    /// Sets ACK_RANDOM_FACTOR in thousandths, failing if it is below 1.0
+    /// (RFC 7252 section 4.8.1).
+    pub fn set_ack_random_factor_milli(
+        &mut self,
+        factor: u32,
+    ) -> Result<(), InvalidTransmissionParameter> {
+        if factor < 1000 {
+            return Err(InvalidTransmissionParameter);
+        }
+        self.ack_random_factor_milli = factor;
+        Ok(())
+    }
+
+    /// Returns MAX_RETRANSMIT.
+    pub fn max_retransmit(&self) -> u8 {
+        self.max_retransmit
+    }
+
+    /// Sets MAX_RETRANSMIT, the number of retransmissions after which a
+    /// confirmable message is given up on.
+    pub fn set_max_retransmit(&mut self, max_retransmit: u8) {
+        self.max_retransmit = max_retransmit;
+    }
+
+    /// Returns the initial timeout of a confirmable message, a point in the
+    /// range from ACK_TIMEOUT to ACK_TIMEOUT * ACK_RANDOM_FACTOR picked by
+    /// `random`, which should be uniformly distributed: 0 picks the lower and
+    /// `u16::MAX` the upper bound.
+    pub fn initial_timeout(&self, random: u16) -> Duration {
+        let timeout = u128::from(self.ack_timeout_ms);
+        let spread = timeout
+            * u128::from(self.ack_random_factor_milli - 1000)
+            * u128::from(random)
+            / (1000 * u128::from(u16::MAX));
+        from_millis_saturating(timeout + spread)
+    }
+
+    /// Returns the timeout following `previous`, which doubles on every
+    /// retransmission.
+    pub fn next_timeout(&self, previous: Duration) -> Duration {
+        previous.saturating_mul(2)
+    }
+
+    /// Returns MAX_TRANSMIT_SPAN, the maximum time from the first to the
+    /// last transmission of a confirmable message.
+    pub fn max_transmit_span(&self) -> Duration {
+        self.scaled_backoff(u32::from(self.max_retransmit))
+    }
+
+    /// Returns MAX_TRANSMIT_WAIT, the maximum time from the first
+    /// transmission of a confirmable message until it is given up on.
+    pub fn max_transmit_wait(&self) -> Duration {
+        self.scaled_backoff(u32::from(self.max_retransmit) + 1)
+    }
+
+    /// Returns MAX_RTT, the maximum round-trip time, which is
+    /// `2 * MAX_LATENCY + PROCESSING_DELAY` with PROCESSING_DELAY being
+    /// ACK_TIMEOUT.
+    pub fn max_rtt(&self) -> Duration {
+        (MAX_LATENCY * 2).saturating_add(self.ack_timeout())
+    }
+
+    /// Returns EXCHANGE_LIFETIME, the time from the first transmission of a
+    /// confirmable message until its message ID can safely be reused.
+    pub fn exchange_lifetime(&self) -> Duration {
+        self.max_transmit_span().saturating_add(self.max_rtt())
+    }
+
+    /// Returns NON_LIFETIME, the time from the first transmission of a
+    /// non-confirmable message until its message ID can safely be reused.
+    pub fn non_lifetime(&self) -> Duration {
+        self.max_transmit_span().saturating_add(MAX_LATENCY)
+    }
+
+    // ACK_TIMEOUT * (2^exponent - 1) * ACK_RANDOM_FACTOR
+    fn scaled_backoff(&self, exponent: u32) -> Duration {
+        let factor = 2u128.checked_pow(exponent).unwrap_or(u128::MAX) - 1;
+        let ms = u128::from(self.ack_timeout_ms)
+            .saturating_mul(factor)
+            .saturating_mul(u128::from(self.ack_random_factor_milli))
+            / 1000;
+        from_millis_saturating(ms)
+    }
+}
+
+fn from_millis_saturating(ms: u128) -> Duration {
+    Duration::from_millis(u64::try_from(ms).unwrap_or(u64::MAX))
+}
+
+/// Whether a confirmable message is to be retransmitted, see
+/// [`RetransmissionState::poll`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retransmission {
+    /// Nothing is due before the given point in time.
+    Wait(Duration),
+    /// The message is to be retransmitted now.
+    Retransmit,
+    /// The message wasn't acknowledged in time and is to be given up on.
+    GiveUp,
+}
+
+/// Tracks the retransmissions of a single confirmable message according to
+/// [`TransmissionParameters`].
+///
+/// Like [`ClientTransaction`], it doesn't read a clock, the current time is
+/// passed as a `Duration` since an arbitrary, fixed point in time.  The
+/// caller polls it no later than the instant returned in
+/// [`Retransmission::Wait`] and drops it once the message is acknowledged.
+///
+/// # Examples
+///
+/// ```
+/// use core::time::Duration;
+/// use coap_lite::{
+///     transmission::Retransmission, RetransmissionState,
+///     TransmissionParameters,
+/// };
+///
+/// let parameters = TransmissionParameters::default();
+/// // Sending the message for the first time
+/// let mut state = RetransmissionState::new(&parameters, 0, Duration::ZERO);
+///
+/// let mut now = Duration::ZERO;
+/// let mut retransmissions = 0;
+/// loop {
+///     match state.poll(now) {
+///         Retransmission::Wait(until) => now = until,
+///         Retransmission::Retransmit => retransmissions += 1,
+///         Retransmission::GiveUp => break,
+///     }
+/// }
+/// assert_eq!(4, retransmissions);
+/// // 2 + 4 + 8 + 16 + 32 seconds
+/// assert_eq!(Duration::from_secs(62), now);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetransmissionState {
+    parameters: TransmissionParameters,
+    timeout: Duration,
+    next_transmission: Duration,
+    retransmissions: u8,
+}
+
+impl RetransmissionState {
+    /// Starts tracking a message first sent at `now`, with the initial
+    /// timeout picked by `random`, see
+    /// [`TransmissionParameters::initial_timeout`].
+    pub fn new(
+        parameters: &TransmissionParameters,
+        random: u16,
+        now: Duration,
+    ) -> RetransmissionState {
+        let timeout = parameters.initial_timeout(random);
+        Self::with_initial_timeout(parameters, timeout, now)
+    }
+
+    /// Starts tracking a message first sent at `now`, with the given initial
+    /// timeout, e.g. the one of an [`RttEstimator`] rather than one derived
+    /// from ACK_TIMEOUT.
+    pub fn with_initial_timeout(
+        parameters: &TransmissionParameters,
+        timeout: Duration,
+        now: Duration,
+    ) -> RetransmissionState {
+        RetransmissionState {
+            parameters: *parameters,
+            timeout,
+            next_transmission: now.saturating_add(timeout),
+            retransmissions: 0,
+        }
+    }
+
+    /// Returns the number of retransmissions so far.
+    pub fn retransmissions(&self) -> u8 {
+        self.retransmissions
+    }
+
+    /// Returns the point in time at which the message is to be retransmitted
+    /// or given up on next.
+    pub fn next_transmission(&self) -> Duration {
+        self.next_transmission
+    }
+
+    /// Returns what is to be done about the message at `now`.  Once
+    /// [`Retransmission::GiveUp`] is returned, it is returned from then on.
+    pub fn poll(&mut self, now: Duration) -> Retransmission {
+        if now < self.next_transmission {
+            return Retransmission::Wait(self.next_transmission);
+        }
+        if self.retransmissions >= self.parameters.max_retransmit {
+            return Retransmission::GiveUp;
+        }
+
+        self.retransmissions += 1;
+        self.timeout = self.parameters.next_timeout(self.timeout);
+        self.next_transmission = now.saturating_add(self.timeout);
+        Retransmission::Retransmit
+    }
+}
+
+/// What a datagram passed to [`ClientTransaction::handle_datagram`] meant for
+/// the outstanding requests.
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// A confirmable request was acknowledged, its response will follow
+    /// separately.
+    Ack,
+    /// The response to a request.
+    Response(CoapResponse),
+    /// A confirmable request was rejected by the peer.
+    Rst,
+    /// The datagram didn't match any outstanding request, or was a
+    /// duplicate of a separate response that was already reported.
+    Ignored,
+}
+
+// A confirmable request that hasn't been acknowledged yet.
+#[derive(Debug, Clone)]
+struct Pending<Endpoint> {
+    message_id: u16,
+    token: Vec<u8>,
+    endpoint: Endpoint,
+    bytes: Vec<u8>,
+    sent_at: Duration,
+    retransmission: RetransmissionState,
+}
+
+// A request for which only the response is still outstanding.
+#[derive(Debug, Clone)]
+struct Awaiting<Endpoint> {
+    token: Vec<u8>,
+    endpoint: Endpoint,
+    expires_at: Duration,
+}
+
+/// The client side of CoAP message exchanges: assigns message IDs and
+/// tokens, retransmits confirmable requests, matches acknowledgements and
+/// responses and acknowledges confirmable responses.
+///
+/// It performs no I/O and doesn't read a clock, every method takes the
+/// current time as a `Duration` since an arbitrary, fixed point in time.  The
+/// caller sends the datagrams it is handed, feeds the ones it receives into
+/// [`ClientTransaction::handle_datagram`] and calls
+/// [`ClientTransaction::poll`] no later than
+/// [`ClientTransaction::next_timeout`].
+///
+/// Retransmissions start from the timeout of an [`RttEstimator`] and follow
+/// the default [`TransmissionParameters`] from there, see
+/// [`RetransmissionState`].  Once a request is given up on, its token is reported
+/// by [`ClientTransaction::take_timeouts`].
+#[derive(Debug, Clone)]
+pub struct ClientTransaction<Endpoint> {
+    next_message_id: u16,
+    next_token: u32,
+    rtt: RttEstimator,
+    pending: Vec<Pending<Endpoint>>,
+    awaiting: Vec<Awaiting<Endpoint>>,
+    outgoing: Vec<(Vec<u8>, Endpoint)>,
+    timeouts: Vec<Vec<u8>>,
+    // Confirmable responses that were matched and acknowledged
+    answered: DedupCache<Endpoint>,
+}
+
+impl<Endpoint: Clone + PartialEq> ClientTransaction<Endpoint> {
+    /// Creates a new client, which should be seeded with random initial
+    /// values for the message ID and token sequences.
+    pub fn new(
+        message_id_seed: u16,
+        token_seed: u32,
+    ) -> ClientTransaction<Endpoint> {
+        ClientTransaction {
+            next_message_id: message_id_seed,
+            next_token: token_seed,
+            rtt: RttEstimator::new(),
+            pending: Vec::new(),
+            awaiting: Vec::new(),
+            outgoing: Vec::new(),
+            timeouts: Vec::new(),
+            answered: DedupCache::new(),
+        }
+    }
+
+    /// Returns the round-trip time estimator used for retransmissions.
+    pub fn rtt(&self) -> &RttEstimator {
+        &self.rtt
+    }
+
+    /// Starts a request, assigning it a new message ID and token, and
+    /// returns the datagram to send along with its destination.
+    ///
+    /// Only confirmable requests are retransmitted, the response to a
+    /// non-confirmable one is matched all the same.
+    pub fn start(
+        &mut self,
+        mut request: Packet,
+        endpoint: Endpoint,
+        now: Duration,
+    ) -> Result<(Vec<u8>, Endpoint), MessageError> {
+        let message_id = self.next_message_id;
+        let token = self.next_token.to_be_bytes().to_vec();
+        request.header.message_id = message_id;
+        request.set_token(token.clone());
+        let bytes = request.to_bytes()?;
+
+        self.next_message_id = self.next_message_id.wrapping_add(1);
+        self.next_token = self.next_token.wrapping_add(1);
+
+        if request.requires_ack() {
+            let retransmission = RetransmissionState::with_initial_timeout(
+                &TransmissionParameters::default(),
+                self.rtt.rto(),
+                now,
+            );
+            self.pending.push(Pending {
+                message_id,
+                token,
+                endpoint: endpoint.clone(),
+                bytes: bytes.clone(),
+                sent_at: now,
+                retransmission,
+            });
+        } else {
+            self.awaiting.push(Awaiting {
+                token,
+                endpoint: endpoint.clone(),
+                expires_at: now.saturating_add(EXCHANGE_LIFETIME),
+            });
+        }
+
+        Ok((bytes, endpoint))
+    }
+
+    /// Processes a datagram received from `from`.
+    pub fn handle_datagram(
+        &mut self,
+        bytes: &[u8],
+        from: Endpoint,
+        now: Duration,
+    ) -> Event {
+        let packet = match Packet::from_bytes(bytes) {
+            Ok(packet) => packet,
+            Err(_) => return Event::Ignored,
+        };
+
+        match packet.header.get_type() {
+            MessageType::Acknowledgement | MessageType::Reset => {
+                self.handle_ack_or_reset(packet, from, now)
+            }
+            _ => self.handle_response(packet, from, now),
+        }
+    }
+
+    /// Returns the datagrams that are due now: acknowledgements and resets
+    /// for received messages as well as retransmissions.
+    pub fn poll(&mut self, now: Duration) -> Vec<(Vec<u8>, Endpoint)> {
+        let mut datagrams = core::mem::take(&mut self.outgoing);
+
+        let timeouts = &mut self.timeouts;
+        self.pending.retain_mut(|pending| {
+            match pending.retransmission.poll(now) {
+                Retransmission::Wait(_) => true,
+                Retransmission::Retransmit => {
+                    datagrams.push((
+                        pending.bytes.clone(),
+                        pending.endpoint.clone(),
+                    ));
+                    true
+                }
+                Retransmission::GiveUp => {
+                    timeouts.push(pending.token.clone());
+                    false
+                }
+            }
+        });
+        self.awaiting.retain(|awaiting| {
+            if awaiting.expires_at > now {
+                return true;
+            }
+            timeouts.push(awaiting.token.clone());
+            false
+        });
+
+        datagrams
+    }
+
+    /// Returns the point in time at which [`ClientTransaction::poll`] needs
+    /// to be called next, if there are outstanding requests or datagrams.
+    pub fn next_timeout(&self) -> Option<Duration> {
+        if !self.outgoing.is_empty() {
+            return Some(Duration::ZERO);
+        }
+        self.pending
+            .iter()
+            .map(|pending| pending.retransmission.next_transmission())
+            .chain(self.awaiting.iter().map(|awaiting| awaiting.expires_at))
+            .min()
+    }
+
+    /// Returns the tokens of the requests that were given up on since the
+    /// last call.
+    pub fn take_timeouts(&mut self) -> Vec<Vec<u8>> {
+        core::mem::take(&mut self.timeouts)
+    }
+
+    fn handle_ack_or_reset(
+        &mut self,
+        packet: Packet,
+        from: Endpoint,
+        now: Duration,
+    ) -> Event {
+        let position = self.pending.iter().position(|pending| {
+            pending.message_id == packet.header.message_id
+                && pending.endpoint == from
+        });
+        let position = match position {
+            Some(position) => position,
+            None => return Event::Ignored,
+        };
+
+        // A piggybacked response with another token isn't the one to this
+        // request, which keeps being retransmitted until the proper response
+        // arrives or it is given up on
+        let matches = match packet.header.code {
+            _ if packet.header.get_type() == MessageType::Reset => true,
+            MessageClass::Empty => true,
+            MessageClass::Response(_) => {
+                packet.get_token() == self.pending[position].token
+            }
+            _ => false,
+        };
+        if !matches {
+            return Event::Ignored;
+        }
+
+        let pending = self.pending.remove(position);
+        if packet.header.get_type() == MessageType::Reset {
+            return Event::Rst;
+        }
+
+        // Karn's algorithm, the acknowledgement of a retransmitted message
+        // can't be attributed to a specific transmission
+        if pending.retransmission.retransmissions() == 0 {
+            self.rtt.update(now.saturating_sub(pending.sent_at));
+        }
+
+        match packet.header.code {
+            MessageClass::Empty => {
+                self.awaiting.push(Awaiting {
+                    token: pending.token,
+                    endpoint: pending.endpoint,
+                    expires_at: now.saturating_add(EXCHANGE_LIFETIME),
+                });
+                Event::Ack
+            }
+            _ => Event::Response(CoapResponse::from_packet(packet)),
+        }
+    }
+
+    fn handle_response(
+        &mut self,
+        packet: Packet,
+        from: Endpoint,
+        now: Duration,
+    ) -> Event {
+        if !matches!(packet.header.code, MessageClass::Response(_)) {
+            return Event::Ignored;
+        }
+
+        // A retransmission of a response we acknowledged, as the
+        // acknowledgement got lost, is acknowledged again rather than being
+        // rejected, which would make the server abort (RFC 7252 section 4.5)
+        let message_id = packet.header.message_id;
+        if packet.requires_ack()
+            && self.answered.contains(&from, message_id, now)
+        {
+            let reply = empty_reply(message_id, true);
+            self.outgoing.extend(reply.map(|bytes| (bytes, from)));
+            return Event::Ignored;
+        }
+
+        let token = packet.get_token();
+        let awaiting = self.awaiting.iter().position(|awaiting| {
+            awaiting.token == token && awaiting.endpoint == from
+        });
+        // The separate response may overtake the acknowledgement
+        let pending = self.pending.iter().position(|pending| {
+            pending.token == token && pending.endpoint == from
+        });
+        let matched = match (awaiting, pending) {
+            (Some(position), _) => {
+                self.awaiting.remove(position);
+                true
+            }
+            (None, Some(position)) => {
+                self.pending.remove(position);
+                true
+            }
+            (None, None) => false,
+        };
+
+        if packet.requires_ack() {
+            if matched {
+                self.answered.remember(&from, message_id, now);
+            }
+            let reply = empty_reply(message_id, matched);
+            self.outgoing.extend(reply.map(|bytes| (bytes, from)));
+        }
+
+        if matched {
+            Event::Response(CoapResponse::from_packet(packet))
+        } else {
+            Event::Ignored
+        }
+    }
+}
+
+// Encodes an empty acknowledgement or reset of a message
+fn empty_reply(message_id: u16, ack: bool) -> Option<Vec<u8>> {
+    let mut reply = Packet::new();
+    reply.header.set_version(1);
+    reply.header.set_type(if ack {
+        MessageType::Acknowledgement
+    } else {
+        MessageType::Reset
+    });
+    reply.header.code = MessageClass::Empty;
+    reply.header.message_id = message_id;
+    reply.to_bytes().ok()
+}
+
+/// Detects duplicates of confirmable and non-confirmable messages by their
+/// message ID and sender (RFC 7252 section 4.5).
+///
+/// A message ID is remembered for [`EXCHANGE_LIFETIME`] by default, after
+/// which the sender may reuse it.
+#[derive(Debug, Clone)]
+pub struct DedupCache<Endpoint> {
+    seen: Vec<(Endpoint, u16, Duration)>,
+    lifetime: Duration,
+}
+
+impl<Endpoint: Clone + PartialEq> DedupCache<Endpoint> {
+    /// Creates a cache remembering message IDs for [`EXCHANGE_LIFETIME`].
+    pub fn new() -> DedupCache<Endpoint> {
+        Self::with_lifetime(EXCHANGE_LIFETIME)
+    }
+
+    /// Creates a cache remembering message IDs for `lifetime`.
+    pub fn with_lifetime(lifetime: Duration) -> DedupCache<Endpoint> {
+        DedupCache {
+            seen: Vec::new(),
+            lifetime,
+        }
+    }
+
+    /// Returns whether a message with `message_id` was already received
+    /// from `from`, remembering it otherwise.
+    pub fn is_duplicate(
+        &mut self,
+        from: &Endpoint,
+        message_id: u16,
+        now: Duration,
+    ) -> bool {
+        let seen = self.contains(from, message_id, now);
+        if !seen {
+            self.remember(from, message_id, now);
+        }
+        seen
+    }
+
+    /// Returns whether a message with `message_id` was already received
+    /// from `from`, without remembering it otherwise.
+    pub fn contains(
+        &self,
+        from: &Endpoint,
+        message_id: u16,
+        now: Duration,
+    ) -> bool {
+        self.seen.iter().any(|(endpoint, id, expires_at)| {
+            *id == message_id && endpoint == from && *expires_at > now
+        })
+    }
+
+    // Remembers a message ID that isn't known yet
+    pub(crate) fn remember(
+        &mut self,
+        from: &Endpoint,
+        message_id: u16,
+        now: Duration,
+    ) {
+        self.purge_expired(now);
+        self.seen.push((
+            from.clone(),
+            message_id,
+            now.saturating_add(self.lifetime),
+        ));
+    }
+
+    /// Forgets the message IDs that expired, returning how many there were.
+    pub fn purge_expired(&mut self, now: Duration) -> usize {
+        let before = self.seen.len();
+        self.seen.retain(|(_, _, expires_at)| *expires_at > now);
+        before - self.seen.len()
+    }
+}
+
+impl<Endpoint: Clone + PartialEq> Default for DedupCache<Endpoint> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn initial_rto() {
+        let estimator = RttEstimator::new();
+        assert_eq!(None, estimator.srtt());
+        assert_eq!(ACK_TIMEOUT, estimator.rto());
+    }
+
+    #[test]
+    fn first_sample() {
+        let mut estimator = RttEstimator::new();
+        estimator.update(Duration::from_millis(400));
+        assert_eq!(Some(Duration::from_millis(400)), estimator.srtt());
+        // 400 + 4 * 200
+        assert_eq!(1200, estimator.rto_ms());
+    }
+
+    #[test]
+    fn smoothing() {
+        let mut estimator = RttEstimator::new();
+        estimator.update_ms(800);
+        estimator.update_ms(400);
+        // RTTVAR = (3 * 400 + 400) / 4 = 400, SRTT = (7 * 800 + 400) / 8 = 750
+        assert_eq!(Some(Duration::from_millis(750)), estimator.srtt());
+        assert_eq!(2350, estimator.rto_ms());
+
+        for _ in 0..100 {
+            estimator.update_ms(400);
+        }
+        assert_eq!(Some(Duration::from_millis(400)), estimator.srtt());
+        assert_eq!(MIN_RTO, estimator.rto());
+    }
+
+    #[test]
+    fn bounds() {
+        let mut estimator = RttEstimator::new();
+        estimator.update_ms(10);
+        assert_eq!(MIN_RTO, estimator.rto());
+
+        estimator.update(Duration::from_secs(u64::MAX));
+        assert_eq!(MAX_RTO, estimator.rto());
+    }
+
+    #[test]
+    fn default_parameters() {
+        let parameters = TransmissionParameters::default();
+        assert_eq!(ACK_TIMEOUT, parameters.ack_timeout());
+        assert_eq!(1500, parameters.ack_random_factor_milli());
+        assert_eq!(MAX_RETRANSMIT, parameters.max_retransmit());
+
+        // The values of RFC 7252 section 4.8.2
+        assert_eq!(Duration::from_secs(45), parameters.max_transmit_span());
+        assert_eq!(Duration::from_secs(93), parameters.max_transmit_wait());
+        assert_eq!(Duration::from_secs(202), parameters.max_rtt());
+        assert_eq!(EXCHANGE_LIFETIME, parameters.exchange_lifetime());
+        assert_eq!(Duration::from_secs(145), parameters.non_lifetime());
+    }
+
+    #[test]
+    fn parameter_validation() {
+        let mut parameters = TransmissionParameters::default();
+        assert_eq!(
+            Err(InvalidTransmissionParameter),
+            parameters.set_ack_random_factor_milli(999)
+        );
+        assert_eq!(
+            Err(InvalidTransmissionParameter),
+            parameters.set_ack_timeout(Duration::from_micros(999))
+        );
+        assert_eq!(TransmissionParameters::default(), parameters);
+
+        parameters.set_ack_random_factor_milli(1000).unwrap();
+        assert_eq!(ACK_TIMEOUT, parameters.initial_timeout(u16::MAX));
+        assert_eq!(Duration::from_secs(30), parameters.max_transmit_span());
+
+        // The derived values saturate instead of overflowing
+        parameters.set_ack_timeout(Duration::MAX).unwrap();
+        parameters.set_max_retransmit(u8::MAX);
+        let max = Duration::from_millis(u64::MAX);
+        assert_eq!(max, parameters.max_transmit_wait());
+        assert!(parameters.exchange_lifetime() > max);
+    }
+
+    #[test]
+    fn timeouts() {
+        let parameters = TransmissionParameters::default();
+        assert_eq!(ACK_TIMEOUT, parameters.initial_timeout(0));
+        assert_eq!(
+            Duration::from_secs(3),
+            parameters.initial_timeout(u16::MAX)
+        );
+        let middle = parameters.initial_timeout(u16::MAX / 2);
+        assert_eq!(Duration::from_millis(2499), middle);
+        assert_eq!(
+            Duration::from_millis(4998),
+            parameters.next_timeout(middle)
+        );
+        assert_eq!(Duration::MAX, parameters.next_timeout(Duration::MAX));
+    }
+
+    #[test]
+    fn retransmission_state() {
+        let mut parameters = TransmissionParameters::default();
+        parameters.set_max_retransmit(2);
+        let start = Duration::from_secs(10);
+        let mut state = RetransmissionState::new(&parameters, u16::MAX, start);
+        let first = start + Duration::from_secs(3);
+        assert_eq!(Retransmission::Wait(first), state.poll(start));
+
+        // Polled late, the next timeout counts from the retransmission
+        let now = first + Duration::from_secs(1);
+        assert_eq!(Retransmission::Retransmit, state.poll(now));
+        assert_eq!(1, state.retransmissions());
+        let second = now + Duration::from_secs(6);
+        assert_eq!(Retransmission::Wait(second), state.poll(now));
+
+        assert_eq!(Retransmission::Retransmit, state.poll(second));
+        let end = second + Duration::from_secs(12);
+        assert_eq!(Retransmission::Wait(end), state.poll(second));
+        assert_eq!(Retransmission::GiveUp, state.poll(end));
+        assert_eq!(Retransmission::GiveUp, state.poll(end));
+        assert_eq!(2, state.retransmissions());
+
+        // A given initial timeout, close to the end of time
+        let timeout = Duration::from_secs(5);
+        let late = Duration::MAX - timeout;
+        let mut state = RetransmissionState::with_initial_timeout(
+            &parameters,
+            timeout,
+            late,
+        );
+        assert_eq!(Duration::MAX, state.next_transmission());
+        assert_eq!(Retransmission::Retransmit, state.poll(Duration::MAX));
+        assert_eq!(Duration::MAX, state.next_transmission());
+    }
+
+    fn get_request(message_type: MessageType) -> Packet {
+        let mut request = Packet::new();
+        request.header.set_type(message_type);
+        request.header.code = MessageClass::Request(crate::RequestType::Get);
+        request.add_option(crate::CoapOption::UriPath, b"temp".to_vec());
+        request
+    }
+
+    // Answers a request the way a server would, with a piggybacked response
+    fn serve(bytes: &[u8]) -> Vec<u8> {
+        let request = Packet::from_bytes(bytes).unwrap();
+        let mut response = CoapResponse::new(&request).unwrap();
+        response.message.payload = b"22".to_vec();
+        response.message.to_bytes().unwrap()
+    }
+
+    #[test]
+    fn con_get_with_loss() {
+        let mut client = ClientTransaction::new(0x1000, 7);
+        let start = Duration::from_secs(100);
+        let (bytes, endpoint) = client
+            .start(get_request(MessageType::Confirmable), 'S', start)
+            .unwrap();
+        assert_eq!('S', endpoint);
+        // The first transmission is lost
+        drop(bytes);
+
+        assert!(client.poll(start + Duration::from_secs(1)).is_empty());
+        assert_eq!(Some(start + ACK_TIMEOUT), client.next_timeout());
+
+        let now = start + ACK_TIMEOUT;
+        let retransmissions = client.poll(now);
+        assert_eq!(1, retransmissions.len());
+        let (bytes, endpoint) = &retransmissions[0];
+        assert_eq!('S', *endpoint);
+
+        let reply = serve(bytes);
+        let event = client.handle_datagram(&reply, 'S', now);
+        match event {
+            Event::Response(response) => {
+                assert_eq!(0x1000, response.message.header.message_id);
+                assert_eq!([0, 0, 0, 7], response.message.get_token());
+                assert_eq!(b"22", &response.message.payload[..]);
+            }
+            other => panic!("Unexpected event {:?}", other),
+        }
+
+        // No sample was taken from the retransmitted exchange
+        assert_eq!(None, client.rtt().srtt());
+        assert_eq!(None, client.next_timeout());
+        assert!(client.poll(now + Duration::from_secs(600)).is_empty());
+        assert!(client.take_timeouts().is_empty());
+
+        // A duplicate of the response doesn't match anything anymore
+        let event = client.handle_datagram(&reply, 'S', now);
+        assert!(matches!(event, Event::Ignored));
+    }
+
+    #[test]
+    fn separate_response() {
+        let mut client = ClientTransaction::new(1, 1);
+        let (bytes, _) = client
+            .start(get_request(MessageType::Confirmable), 'S', Duration::ZERO)
+            .unwrap();
+        let request = Packet::from_bytes(&bytes).unwrap();
+
+        let now = Duration::from_millis(300);
+        let ack = CoapResponse::new_empty_ack(&request).unwrap();
+        let event = client.handle_datagram(&ack.to_bytes().unwrap(), 'S', now);
+        assert!(matches!(event, Event::Ack));
+        assert_eq!(Some(Duration::from_millis(300)), client.rtt().srtt());
+        assert!(client.poll(now + Duration::from_secs(60)).is_empty());
+
+        // A response from another endpoint is rejected
+        let response = CoapResponse::new_separate(&request, 0x5000);
+        let bytes = response.message.to_bytes().unwrap();
+        let event = client.handle_datagram(&bytes, 'X', now);
+        assert!(matches!(event, Event::Ignored));
+        let replies = client.poll(now);
+        assert_eq!(vec![(vec![0x70, 0x00, 0x50, 0x00], 'X')], replies);
+
+        let event = client.handle_datagram(&bytes, 'S', now);
+        assert!(matches!(event, Event::Response(_)));
+        let replies = client.poll(now);
+        assert_eq!(vec![(vec![0x60, 0x00, 0x50, 0x00], 'S')], replies);
+        assert_eq!(None, client.next_timeout());
+    }
+
+    #[test]
+    fn separate_response_lost_ack() {
+        let mut client = ClientTransaction::new(1, 1);
+        let (bytes, _) = client
+            .start(get_request(MessageType::Confirmable), 'S', Duration::ZERO)
+            .unwrap();
+        let request = Packet::from_bytes(&bytes).unwrap();
+        let ack = CoapResponse::new_empty_ack(&request).unwrap();
+        let now = Duration::from_millis(300);
+        client.handle_datagram(&ack.to_bytes().unwrap(), 'S', now);
+
+        let response = CoapResponse::new_separate(&request, 0x5000);
+        let bytes = response.message.to_bytes().unwrap();
+        let event = client.handle_datagram(&bytes, 'S', now);
+        assert!(matches!(event, Event::Response(_)));
+        let ack = vec![0x60, 0x00, 0x50, 0x00];
+        assert_eq!(vec![(ack.clone(), 'S')], client.poll(now));
+
+        // The acknowledgement is lost and the server retransmits, which is
+        // acknowledged again but not reported twice
+        let now = now + ACK_TIMEOUT;
+        let event = client.handle_datagram(&bytes, 'S', now);
+        assert!(matches!(event, Event::Ignored));
+        assert_eq!(vec![(ack, 'S')], client.poll(now));
+
+        // The message ID may be reused once the exchange lifetime is over
+        let now = now + EXCHANGE_LIFETIME;
+        let event = client.handle_datagram(&bytes, 'S', now);
+        assert!(matches!(event, Event::Ignored));
+        assert_eq!(
+            vec![(vec![0x70, 0x00, 0x50, 0x00], 'S')],
+            client.poll(now)
+        );
+    }
+
+    #[test]
+    fn mismatched_piggybacked_token() {
+        let mut client = ClientTransaction::new(1, 1);
+        let (bytes, _) = client
+            .start(get_request(MessageType::Confirmable), 'S', Duration::ZERO)
+            .unwrap();
+        let mut request = Packet::from_bytes(&bytes).unwrap();
+        request.set_token(vec![0x99]);
+        let reply = serve(&request.to_bytes().unwrap());
+
+        let now = Duration::from_millis(100);
+        let event = client.handle_datagram(&reply, 'S', now);
+        assert!(matches!(event, Event::Ignored));
+        assert_eq!(None, client.rtt().srtt());
+
+        // The request is still retransmitted and eventually given up on
+        assert_eq!(Some(ACK_TIMEOUT), client.next_timeout());
+        assert_eq!(vec![(bytes, 'S')], client.poll(ACK_TIMEOUT));
+        while let Some(timeout) = client.next_timeout() {
+            client.poll(timeout);
+        }
+        assert_eq!(vec![vec![0, 0, 0, 1]], client.take_timeouts());
+    }
+
+    #[test]
+    fn reset_and_timeout() {
+        let mut client = ClientTransaction::new(1, 1);
+        let (bytes, _) = client
+            .start(get_request(MessageType::Confirmable), 'S', Duration::ZERO)
+            .unwrap();
+        let mut reset = Packet::from_bytes(&bytes).unwrap();
+        reset.header.set_type(MessageType::Reset);
+        reset.header.code = MessageClass::Empty;
+        reset.set_token(vec![]);
+        reset.clear_option(crate::CoapOption::UriPath);
+        let event = client.handle_datagram(
+            &reset.to_bytes().unwrap(),
+            'S',
+            Duration::ZERO,
+        );
+        assert!(matches!(event, Event::Rst));
+        assert_eq!(None, client.next_timeout());
+
+        client
+            .start(get_request(MessageType::Confirmable), 'S', Duration::ZERO)
+            .unwrap();
+        let mut now = Duration::ZERO;
+        let mut transmissions = 0;
+        while let Some(timeout) = client.next_timeout() {
+            now = timeout;
+            transmissions += client.poll(now).len();
+        }
+        assert_eq!(usize::from(MAX_RETRANSMIT), transmissions);
+        // 2 + 4 + 8 + 16 + 32 seconds
+        assert_eq!(Duration::from_secs(62), now);
+        assert_eq!(vec![vec![0, 0, 0, 2]], client.take_timeouts());
+    }
+
+    #[test]
+    fn non_request() {
+        let mut client = ClientTransaction::new(1, 1);
+        let (bytes, _) = client
+            .start(
+                get_request(MessageType::NonConfirmable),
+                'S',
+                Duration::ZERO,
+            )
+            .unwrap();
+        assert!(client.poll(Duration::from_secs(10)).is_empty());
+
+        let request = Packet::from_bytes(&bytes).unwrap();
+        let response =
+            request.new_non_response(crate::ResponseType::Content, 9);
+        let event = client.handle_datagram(
+            &response.to_bytes().unwrap(),
+            'S',
+            Duration::from_secs(1),
+        );
+        assert!(matches!(event, Event::Response(_)));
+        assert!(client.poll(Duration::from_secs(1)).is_empty());
+        assert_eq!(None, client.next_timeout());
+    }
+
+    #[test]
+    fn dedup_cache() {
+        let mut cache = DedupCache::new();
+        let now = Duration::from_secs(10);
+        assert!(!cache.is_duplicate(&"a", 1, now));
+        assert!(cache.is_duplicate(&"a", 1, now + ACK_TIMEOUT));
+        assert!(!cache.is_duplicate(&"b", 1, now));
+        assert!(!cache.is_duplicate(&"a", 2, now));
+        assert!(cache.contains(&"a", 2, now));
+        assert!(!cache.contains(&"a", 3, now));
+        assert!(!cache.is_duplicate(&"a", 3, now));
+
+        // The message ID may be reused once the exchange lifetime is over
+        assert_eq!(0, cache.purge_expired(now + Duration::from_secs(100)));
+        assert!(!cache.is_duplicate(&"a", 1, now + EXCHANGE_LIFETIME));
+    }
+
+    #[test]
+    fn dedup_cache_saturates() {
+        let mut cache = DedupCache::with_lifetime(Duration::MAX);
+        let now = Duration::from_secs(10);
+        assert!(!cache.is_duplicate(&"a", 1, now));
+        assert!(cache.is_duplicate(&"a", 1, Duration::MAX / 2));
+
+        let mut cache = DedupCache::new();
+        assert!(!cache.is_duplicate(&"a", 1, Duration::MAX - ACK_TIMEOUT));
+        assert!(cache.contains(&"a", 1, Duration::MAX - ACK_TIMEOUT));
+    }
+}
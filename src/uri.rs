@@ -0,0 +1,519 @@
+//! Decomposing CoAP URIs into request options and reconstructing them (RFC
+//! 7252 sections 6.4 and 6.5), for all the schemes of RFC 7252 and RFC 8323.
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{fmt, str::FromStr};
+
+use crate::{
+    consts::{DEFAULT_PORT, DEFAULT_PORT_SECURE},
+    error::InvalidUri,
+    packet::hex_value,
+    CoapOption, Packet,
+};
+
+/// The path of the WebSocket endpoint of a CoAP server (RFC 8323 section
+/// 8.4).
+const WELL_KNOWN_COAP: &str = "/.well-known/coap";
+
+/// The transport a CoAP URI scheme refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    WebSockets,
+}
+
+/// The registered CoAP URI schemes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheme {
+    /// `coap`, over UDP.
+    Coap,
+    /// `coaps`, over DTLS.
+    Coaps,
+    /// `coap+tcp`, over TCP.
+    CoapTcp,
+    /// `coaps+tcp`, over TLS.
+    CoapsTcp,
+    /// `coap+ws`, over WebSockets.
+    CoapWs,
+    /// `coaps+ws`, over secure WebSockets.
+    CoapsWs,
+}
+
+impl Scheme {
+    /// Returns the scheme as it appears in a URI.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scheme::Coap => "coap",
+            Scheme::Coaps => "coaps",
+            Scheme::CoapTcp => "coap+tcp",
+            Scheme::CoapsTcp => "coaps+tcp",
+            Scheme::CoapWs => "coap+ws",
+            Scheme::CoapsWs => "coaps+ws",
+        }
+    }
+
+    /// Returns the port used if a URI doesn't have one: 5683 and 5684 for
+    /// CoAP over UDP and TCP, the HTTP ports 80 and 443 for WebSockets.
+    pub fn default_port(&self) -> u16 {
+        match self {
+            Scheme::Coap | Scheme::CoapTcp => DEFAULT_PORT,
+            Scheme::Coaps | Scheme::CoapsTcp => DEFAULT_PORT_SECURE,
+            Scheme::CoapWs => 80,
+            Scheme::CoapsWs => 443,
+        }
+    }
+
+    /// Returns the transport the scheme refers to, e.g. to choose a
+    /// connector for a request.
+    pub fn transport(&self) -> Transport {
+        match self {
+            Scheme::Coap | Scheme::Coaps => Transport::Udp,
+            Scheme::CoapTcp | Scheme::CoapsTcp => Transport::Tcp,
+            Scheme::CoapWs | Scheme::CoapsWs => Transport::WebSockets,
+        }
+    }
+
+    /// Returns whether the transport is secured by (D)TLS.
+    pub fn is_secure(&self) -> bool {
+        matches!(self, Scheme::Coaps | Scheme::CoapsTcp | Scheme::CoapsWs)
+    }
+}
+
+// Ignores ASCII case
+impl FromStr for Scheme {
+    type Err = InvalidUri;
+
+    fn from_str(scheme: &str) -> Result<Scheme, InvalidUri> {
+        [
+            Scheme::Coap,
+            Scheme::Coaps,
+            Scheme::CoapTcp,
+            Scheme::CoapsTcp,
+            Scheme::CoapWs,
+            Scheme::CoapsWs,
+        ]
+        .into_iter()
+        .find(|candidate| candidate.as_str().eq_ignore_ascii_case(scheme))
+        .ok_or(InvalidUri)
+    }
+}
+
+impl fmt::Display for Scheme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A CoAP URI, decomposed into the parts that are carried in the options of
+/// a request.
+///
+/// ```
+/// use coap_lite::{uri::Scheme, CoapUri};
+///
+/// let uri = CoapUri::parse("coaps+ws://example.org/.well-known/coap/temp")
+///     .unwrap();
+/// assert_eq!(Scheme::CoapsWs, uri.scheme());
+/// assert_eq!(443, uri.port());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoapUri {
+    scheme: Scheme,
+    host: String,
+    port: u16,
+    endpoint: String,
+    path: Vec<Vec<u8>>,
+    query: Vec<Vec<u8>>,
+}
+
+impl CoapUri {
+    /// Parses an absolute URI.
+    ///
+    /// The host is converted to lowercase and the path segments and query
+    /// parameters are percent-decoded.  URIs of the WebSocket schemes have
+    /// to contain the path of the WebSocket endpoint, which ends in
+    /// `/.well-known/coap`; only the path after it is the path of the
+    /// resource.  Fragments and user information are not allowed.
+    pub fn parse(uri: &str) -> Result<CoapUri, InvalidUri> {
+        let (scheme, rest) = uri.split_once("://").ok_or(InvalidUri)?;
+        let scheme: Scheme = scheme.parse()?;
+        if rest.contains('#') {
+            return Err(InvalidUri);
+        }
+
+        let authority_end = rest.find(['/', '?']).unwrap_or(rest.len());
+        let (authority, rest) = rest.split_at(authority_end);
+        let (path, query) = match rest.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (rest, None),
+        };
+
+        if authority.contains('@') {
+            return Err(InvalidUri);
+        }
+        let host_end = match authority.strip_prefix('[') {
+            Some(literal) => literal.find(']').ok_or(InvalidUri)? + 2,
+            None => authority.find(':').unwrap_or(authority.len()),
+        };
+        let (host, port) = authority.split_at(host_end);
+        if host.is_empty() {
+            return Err(InvalidUri);
+        }
+        let port = match port {
+            "" | ":" => scheme.default_port(),
+            _ => port
+                .strip_prefix(':')
+                .and_then(|port| port.parse().ok())
+                .ok_or(InvalidUri)?,
+        };
+
+        let (endpoint, path) = match scheme.transport() {
+            Transport::WebSockets => split_endpoint(path)?,
+            _ => ("", path),
+        };
+        let path = match path {
+            "" | "/" => Vec::new(),
+            _ => path[1..]
+                .split('/')
+                .map(percent_decode)
+                .collect::<Result<_, _>>()?,
+        };
+        let query = match query {
+            None | Some("") => Vec::new(),
+            Some(query) => query
+                .split('&')
+                .map(percent_decode)
+                .collect::<Result<_, _>>()?,
+        };
+
+        Ok(CoapUri {
+            scheme,
+            host: host.to_ascii_lowercase(),
+            port,
+            endpoint: endpoint.to_string(),
+            path,
+            query,
+        })
+    }
+
+    /// Reconstructs the URI a request was sent to, given the scheme and the
+    /// host of the destination, which is used if the request has no
+    /// Uri-Host option.  WebSocket endpoints are assumed to be at
+    /// `/.well-known/coap`.
+    pub fn from_packet(
+        packet: &Packet,
+        scheme: Scheme,
+        destination: &str,
+    ) -> CoapUri {
+        let host = match packet.get_first_option(CoapOption::UriHost) {
+            Some(host) => String::from_utf8_lossy(host).to_ascii_lowercase(),
+            None => destination.to_ascii_lowercase(),
+        };
+        let port = match packet.get_uri_port() {
+            Some(Ok(port)) => port,
+            _ => scheme.default_port(),
+        };
+        let endpoint = match scheme.transport() {
+            Transport::WebSockets => WELL_KNOWN_COAP,
+            _ => "",
+        };
+        let values = |option| {
+            packet
+                .get_option(option)
+                .map(|values| values.iter().cloned().collect())
+                .unwrap_or_default()
+        };
+
+        CoapUri {
+            scheme,
+            host,
+            port,
+            endpoint: endpoint.to_string(),
+            path: values(CoapOption::UriPath),
+            query: values(CoapOption::UriQuery),
+        }
+    }
+
+    /// Returns the scheme.
+    pub fn scheme(&self) -> Scheme {
+        self.scheme
+    }
+
+    /// Returns the host, IPv6 addresses being enclosed in brackets.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Returns the port, which is the default port of the scheme if the URI
+    /// doesn't have one.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Returns the path of the WebSocket endpoint, or an empty string for
+    /// schemes other than WebSockets.
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /// Returns the decoded segments of the path of the resource.
+    pub fn path(&self) -> &[Vec<u8>] {
+        &self.path
+    }
+
+    /// Returns the decoded query parameters.
+    pub fn query(&self) -> &[Vec<u8>] {
+        &self.query
+    }
+
+    /// Returns whether the host is an IP address rather than a name.
+    fn has_ip_literal(&self) -> bool {
+        self.host.starts_with('[')
+            || self.host.split('.').count() == 4
+                && self.host.split('.').all(|part| part.parse::<u8>().is_ok())
+    }
+}
+
+impl fmt::Display for CoapUri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}://{}", self.scheme, self.host)?;
+        if self.port != self.scheme.default_port() {
+            write!(f, ":{}", self.port)?;
+        }
+        f.write_str(&self.endpoint)?;
+        if self.path.is_empty() && self.endpoint.is_empty() {
+            f.write_str("/")?;
+        }
+        for segment in &self.path {
+            f.write_str("/")?;
+            percent_encode(f, segment)?;
+        }
+        for (i, parameter) in self.query.iter().enumerate() {
+            f.write_str(if i == 0 { "?" } else { "&" })?;
+            percent_encode(f, parameter)?;
+        }
+        Ok(())
+    }
+}
+
+impl Packet {
+    /// Sets the Uri-Host, Uri-Port, Uri-Path and Uri-Query options of a
+    /// request to `uri`, replacing any existing values.
+    ///
+    /// As the destination of the request is expected to be the host of the
+    /// URI, Uri-Host is omitted for IP addresses and Uri-Port for the
+    /// default port of the scheme.  The WebSocket endpoint is not part of
+    /// the options, it is only needed to connect.
+    pub fn set_uri(&mut self, uri: &CoapUri) {
+        for option in [
+            CoapOption::UriHost,
+            CoapOption::UriPort,
+            CoapOption::UriPath,
+            CoapOption::UriQuery,
+        ] {
+            self.clear_option(option);
+        }
+        if !uri.has_ip_literal() {
+            self.add_option(CoapOption::UriHost, uri.host.as_bytes().to_vec());
+        }
+        if uri.port != uri.scheme.default_port() {
+            self.set_uri_port(uri.port);
+        }
+        for segment in &uri.path {
+            self.add_option(CoapOption::UriPath, segment.clone());
+        }
+        for parameter in &uri.query {
+            self.add_option(CoapOption::UriQuery, parameter.clone());
+        }
+    }
+}
+
+/// Splits the path of a WebSocket URI after the endpoint path.
+fn split_endpoint(path: &str) -> Result<(&str, &str), InvalidUri> {
+    let mut start = 0;
+    while let Some(position) = path[start..].find(WELL_KNOWN_COAP) {
+        let end = start + position + WELL_KNOWN_COAP.len();
+        if matches!(path.as_bytes().get(end), None | Some(b'/')) {
+            return Ok(path.split_at(end));
+        }
+        start = end;
+    }
+    Err(InvalidUri)
+}
+
+fn percent_decode(component: &str) -> Result<Vec<u8>, InvalidUri> {
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let high = bytes.get(i + 1).copied().and_then(hex_value);
+            let low = bytes.get(i + 2).copied().and_then(hex_value);
+            let (high, low) = high.zip(low).ok_or(InvalidUri)?;
+            decoded.push(high << 4 | low);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Ok(decoded)
+}
+
+fn percent_encode(f: &mut fmt::Formatter, component: &[u8]) -> fmt::Result {
+    for &byte in component {
+        // Unreserved characters, sub-delims apart from "&", ":" and "@"
+        if byte.is_ascii_alphanumeric() || b"-._~!$'()*+,;=:@".contains(&byte)
+        {
+            write!(f, "{}", char::from(byte))?;
+        } else {
+            write!(f, "%{:02X}", byte)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for (uri, scheme, port, transport) in [
+            (
+                "coap://example.org/temp",
+                Scheme::Coap,
+                5683,
+                Transport::Udp,
+            ),
+            (
+                "coaps://example.org/temp",
+                Scheme::Coaps,
+                5684,
+                Transport::Udp,
+            ),
+            (
+                "coap+tcp://example.org/temp",
+                Scheme::CoapTcp,
+                5683,
+                Transport::Tcp,
+            ),
+            (
+                "coaps+tcp://example.org:5685/temp",
+                Scheme::CoapsTcp,
+                5685,
+                Transport::Tcp,
+            ),
+            (
+                "coap+ws://example.org/.well-known/coap/temp",
+                Scheme::CoapWs,
+                80,
+                Transport::WebSockets,
+            ),
+            (
+                "coaps+ws://example.org/.well-known/coap/temp",
+                Scheme::CoapsWs,
+                443,
+                Transport::WebSockets,
+            ),
+        ] {
+            let parsed = CoapUri::parse(uri).unwrap();
+            assert_eq!(scheme, parsed.scheme());
+            assert_eq!(port, parsed.port());
+            assert_eq!(transport, parsed.scheme().transport());
+            assert_eq!([b"temp".to_vec()], parsed.path());
+            assert_eq!(uri, parsed.to_string());
+
+            let mut packet = Packet::new();
+            packet.set_uri(&parsed);
+            assert_eq!(
+                parsed,
+                CoapUri::from_packet(&packet, scheme, "192.0.2.1")
+            );
+        }
+    }
+
+    #[test]
+    fn default_port_omitted() {
+        let uri = CoapUri::parse("COAP://Example.ORG:5683").unwrap();
+        assert_eq!("coap://example.org/", uri.to_string());
+
+        let mut packet = Packet::new();
+        packet.set_uri(&uri);
+        assert_eq!(None, packet.get_uri_port());
+        assert_eq!(
+            Some(&b"example.org".to_vec()),
+            packet.get_first_option(CoapOption::UriHost)
+        );
+        assert_eq!(None, packet.get_option(CoapOption::UriPath));
+
+        let uri =
+            CoapUri::parse("coaps+ws://[2001:db8::1]:5684/").unwrap_err();
+        assert_eq!(InvalidUri, uri);
+
+        let uri =
+            CoapUri::parse("coap+ws://[2001:db8::1]:8080/.well-known/coap")
+                .unwrap();
+        assert_eq!("[2001:db8::1]", uri.host());
+        assert_eq!(8080, uri.port());
+        assert!(uri.path().is_empty());
+        assert_eq!(
+            "coap+ws://[2001:db8::1]:8080/.well-known/coap",
+            uri.to_string()
+        );
+
+        let mut packet = Packet::new();
+        packet.set_uri(&uri);
+        assert_eq!(None, packet.get_option(CoapOption::UriHost));
+        assert_eq!(Some(Ok(8080)), packet.get_uri_port());
+    }
+
+    #[test]
+    fn ws_endpoint_prefix() {
+        let uri = CoapUri::parse(
+            "coaps+ws://example.org/gateway/.well-known/coap/sensors/temp\
+             ?unit=C",
+        )
+        .unwrap();
+        assert_eq!("/gateway/.well-known/coap", uri.endpoint());
+        assert_eq!([b"sensors".to_vec(), b"temp".to_vec()], uri.path());
+        assert_eq!([b"unit=C".to_vec()], uri.query());
+        assert_eq!(
+            "coaps+ws://example.org/gateway/.well-known/coap/sensors/temp\
+             ?unit=C",
+            uri.to_string()
+        );
+
+        // The endpoint path is required, and must be a whole segment
+        assert!(CoapUri::parse("coap+ws://example.org/temp").is_err());
+        assert!(
+            CoapUri::parse("coap+ws://example.org/.well-known/coapx").is_err()
+        );
+    }
+
+    #[test]
+    fn percent_encoding() {
+        let uri =
+            CoapUri::parse("coap://example.org/a%20b/%C3%BC?q=%26&x").unwrap();
+        assert_eq!(
+            [b"a b".to_vec(), "\u{fc}".as_bytes().to_vec()],
+            uri.path()
+        );
+        assert_eq!([b"q=&".to_vec(), b"x".to_vec()], uri.query());
+        assert_eq!("coap://example.org/a%20b/%C3%BC?q=%26&x", uri.to_string());
+
+        for invalid in [
+            "coap://example.org/%zz",
+            "http://example.org/",
+            "coap://example.org/#fragment",
+            "coap://user@example.org/",
+            "coap://:5683/",
+            "coap://example.org:port/",
+        ] {
+            assert_eq!(Err(InvalidUri), CoapUri::parse(invalid));
+        }
+    }
+}
@@ -0,0 +1,265 @@
+//! Differential test of the message decoder against a reference decoder that
+//! is written independently of it, straight from RFC 7252 section 3.  The
+//! reference favours obviousness over efficiency.
+//!
+//! Both are fed generated buffers, which are built like messages but with
+//! random field values, including the reserved ones, and random truncation.
+
+use coap_lite::{MessageType, Packet};
+
+/// A message as decoded by the reference decoder.
+#[derive(Debug, PartialEq)]
+struct Message {
+    first_byte: u8,
+    code: u8,
+    message_id: u16,
+    token: Vec<u8>,
+    options: Vec<(u32, Vec<u8>)>,
+    payload: Vec<u8>,
+}
+
+/// Reads the bytes of a buffer front to back.
+struct Reader<'a> {
+    rest: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> Option<Vec<u8>> {
+        if self.rest.len() < n {
+            return None;
+        }
+        let (taken, rest) = self.rest.split_at(n);
+        self.rest = rest;
+        Some(taken.to_vec())
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    /// Reads the extended value announced by an option nibble.
+    fn extended(&mut self, nibble: u8) -> Option<u32> {
+        match nibble {
+            0..=12 => Some(u32::from(nibble)),
+            13 => Some(u32::from(self.byte()?) + 13),
+            14 => {
+                let bytes = self.take(2)?;
+                Some(u32::from(bytes[0]) * 256 + u32::from(bytes[1]) + 269)
+            }
+            _ => None,
+        }
+    }
+}
+
+fn reference_decode(buf: &[u8]) -> Option<Message> {
+    let mut reader = Reader { rest: buf };
+    let first_byte = reader.byte()?;
+    let code = reader.byte()?;
+    let id = reader.take(2)?;
+    let message_id = u16::from(id[0]) * 256 + u16::from(id[1]);
+
+    let token_length = first_byte & 0x0F;
+    if token_length > 8 {
+        return None;
+    }
+    let token = reader.take(usize::from(token_length))?;
+
+    let mut options = Vec::new();
+    let mut number = 0;
+    let mut payload = Vec::new();
+    while let Some(byte) = reader.byte() {
+        if byte == 0xFF {
+            if reader.rest.is_empty() {
+                return None;
+            }
+            payload = reader.rest.to_vec();
+            break;
+        }
+        number += reader.extended(byte >> 4)?;
+        let length = reader.extended(byte & 0x0F)?;
+        if number > u32::from(u16::MAX) {
+            return None;
+        }
+        options.push((number, reader.take(length as usize)?));
+    }
+
+    Some(Message {
+        first_byte,
+        code,
+        message_id,
+        token,
+        options,
+        payload,
+    })
+}
+
+/// Converts a decoded packet to the structure of the reference decoder.
+fn to_message(packet: &Packet) -> Message {
+    let message_type = match packet.header.get_type() {
+        MessageType::Confirmable => 0,
+        MessageType::NonConfirmable => 1,
+        MessageType::Acknowledgement => 2,
+        MessageType::Reset => 3,
+    };
+    let first_byte = packet.header.get_version() << 6
+        | message_type << 4
+        | packet.header.get_token_length();
+    Message {
+        first_byte,
+        code: packet.header.code.to_byte(),
+        message_id: packet.header.message_id,
+        token: packet.get_token().to_vec(),
+        options: packet
+            .options()
+            .flat_map(|(&number, values)| {
+                values
+                    .iter()
+                    .map(move |value| (u32::from(number), value.clone()))
+            })
+            .collect(),
+        payload: packet.payload.clone(),
+    }
+}
+
+/// A xorshift generator, so that failures are reproducible.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn byte(&mut self) -> u8 {
+        self.next() as u8
+    }
+
+    fn bytes(&mut self, n: usize) -> Vec<u8> {
+        (0..n).map(|_| self.byte()).collect()
+    }
+
+    /// Returns a nibble that's usually a plain value, but often one of the
+    /// special ones.
+    fn nibble(&mut self) -> u8 {
+        match self.below(8) {
+            0 => 13,
+            1 => 14,
+            2 if self.below(4) == 0 => 15,
+            3 => 0,
+            _ => self.below(13) as u8,
+        }
+    }
+}
+
+fn generate(rng: &mut Rng) -> Vec<u8> {
+    let token_length = match rng.below(10) {
+        0 => rng.below(16) as u8,
+        _ => rng.below(9) as u8,
+    };
+    let mut buf = vec![
+        0x40 | (rng.byte() & 0x30) | token_length,
+        rng.byte(),
+        rng.byte(),
+        rng.byte(),
+    ];
+    let token = rng.bytes(usize::from(token_length));
+    buf.extend(token);
+
+    for _ in 0..rng.below(6) {
+        let delta = rng.nibble();
+        let length = rng.nibble();
+        buf.push(delta << 4 | length);
+        for nibble in [delta, length] {
+            match nibble {
+                13 => buf.push(rng.byte()),
+                14 => {
+                    // Keep the extended values mostly small
+                    buf.push(rng.below(2) as u8);
+                    buf.push(rng.byte());
+                }
+                _ => {}
+            }
+        }
+        let value_length = match length {
+            13 | 14 => rng.below(300) as usize,
+            _ => usize::from(length),
+        };
+        buf.extend(rng.bytes(value_length));
+    }
+
+    match rng.below(4) {
+        0 => buf.push(0xFF),
+        1 => {
+            buf.push(0xFF);
+            let payload_length = rng.below(20) as usize + 1;
+            buf.extend(rng.bytes(payload_length));
+        }
+        _ => {}
+    }
+
+    if rng.below(5) == 0 {
+        let length = rng.below(buf.len() as u64 + 1) as usize;
+        buf.truncate(length);
+    }
+    buf
+}
+
+#[test]
+fn decoders_agree() {
+    let mut rng = Rng(0x2545_F491_4F6C_DD1D);
+    let mut accepted = 0;
+    for _ in 0..50_000 {
+        let buf = generate(&mut rng);
+        let expected = reference_decode(&buf);
+        let actual = Packet::from_bytes(&buf);
+        assert_eq!(
+            expected,
+            actual.as_ref().ok().map(to_message),
+            "decoding {:02x?}",
+            buf
+        );
+
+        if let Ok(packet) = actual {
+            accepted += 1;
+            // Apart from the payload of empty messages the bytes encode to
+            // themselves, see `Packet::to_bytes`
+            if packet.header.code.to_byte() != 0 && buf.len() <= 1280 {
+                assert_eq!(buf, packet.to_bytes().unwrap(), "encoding");
+            }
+        }
+    }
+    // Make sure both outcomes are covered well
+    assert!(accepted > 10_000, "only {} accepted", accepted);
+    assert!(accepted < 40_000, "only {} rejected", 50_000 - accepted);
+}
+
+#[test]
+fn edge_cases() {
+    for buf in [
+        // Zero-length option values with 1 and 2 byte extended deltas
+        &[0x40, 0x01, 0x00, 0x00, 0xD0, 0x00, 0xE0, 0x00, 0x00][..],
+        // Option number 65535 exactly, and beyond it
+        &[0x40, 0x01, 0x00, 0x00, 0xE0, 0xFE, 0xF2],
+        &[0x40, 0x01, 0x00, 0x00, 0xE0, 0xFE, 0xF2, 0x10],
+        // 2 byte extended length
+        &[0x40, 0x01, 0x00, 0x00, 0x1E, 0x00, 0x00],
+        // Reserved nibbles
+        &[0x40, 0x01, 0x00, 0x00, 0xF0],
+        &[0x40, 0x01, 0x00, 0x00, 0x0F],
+        // Payload marker without payload
+        &[0x40, 0x01, 0x00, 0x00, 0xFF],
+    ] {
+        assert_eq!(
+            reference_decode(buf),
+            Packet::from_bytes(buf).as_ref().ok().map(to_message),
+            "decoding {:02x?}",
+            buf
+        );
+    }
+}
@@ -0,0 +1,355 @@
+//! End-to-end exchanges between a client and a server that are built from the
+//! request/response, observe and block handling helpers, run over an
+//! in-memory transport.  Every datagram is checked against its wire format.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use coap_lite::{
+    block_handler::BlockValue, error::HandlingError, BlockHandler,
+    BlockHandlerConfig, CoapOption, CoapRequest, CoapResponse, MessageClass,
+    MessageType, Observe, ObserveOption, Packet, RequestType as Method,
+    ResponseType, Subject,
+};
+
+type Endpoint = String;
+
+const CLIENT: &str = "client";
+const SERVER: &str = "server";
+
+struct Datagram {
+    from: Endpoint,
+    to: Endpoint,
+    bytes: Vec<u8>,
+}
+
+/// Delivers datagrams in order without loss.
+#[derive(Default)]
+struct Network {
+    in_flight: VecDeque<Datagram>,
+}
+
+impl Network {
+    /// Encodes and sends a packet, returning the bytes that went on the wire.
+    fn send(&mut self, from: &str, to: &str, packet: &Packet) -> Vec<u8> {
+        let bytes = packet.to_bytes().unwrap();
+        self.in_flight.push_back(Datagram {
+            from: from.to_string(),
+            to: to.to_string(),
+            bytes: bytes.clone(),
+        });
+        bytes
+    }
+
+    /// Receives the next datagram, which must be addressed to `to`.
+    fn receive(&mut self, to: &str) -> (Packet, Endpoint) {
+        let datagram = self.in_flight.pop_front().expect("Nothing in flight");
+        assert_eq!(to, datagram.to);
+        (Packet::from_bytes(&datagram.bytes).unwrap(), datagram.from)
+    }
+}
+
+fn new_request(
+    method: Method,
+    path: &str,
+    message_id: u16,
+    token: u8,
+) -> CoapRequest<Endpoint> {
+    let mut request = CoapRequest::new();
+    request.message.header.set_type(MessageType::Confirmable);
+    request.message.header.message_id = message_id;
+    request.message.set_token(vec![token]);
+    request.set_method(method);
+    request.set_path(path);
+    request
+}
+
+fn empty_ack(message_id: u16) -> Packet {
+    let mut packet = Packet::new();
+    packet.header.set_type(MessageType::Confirmable);
+    packet.header.message_id = message_id;
+    CoapResponse::new_empty_ack(&packet).unwrap()
+}
+
+#[test]
+fn get_with_piggybacked_response() {
+    let mut network = Network::default();
+
+    let get = new_request(Method::Get, "/temp", 0x0001, 0x01);
+    assert_eq!(
+        network.send(CLIENT, SERVER, &get.message),
+        [0x41, 0x01, 0x00, 0x01, 0x01, 0xB4, 0x74, 0x65, 0x6D, 0x70,]
+    );
+
+    let (packet, source) = network.receive(SERVER);
+    let request = CoapRequest::from_packet(packet, source);
+    assert_eq!(&Method::Get, request.get_method());
+    assert_eq!("temp", request.get_path());
+
+    let mut response = request.response.unwrap();
+    response.message.payload = b"22.5 C".to_vec();
+    assert_eq!(
+        network.send(SERVER, CLIENT, &response.message),
+        [
+            0x61, 0x45, 0x00, 0x01, 0x01, 0xFF, 0x32, 0x32, 0x2E, 0x35, 0x20,
+            0x43,
+        ]
+    );
+
+    let (packet, _) = network.receive(CLIENT);
+    assert_eq!(MessageType::Acknowledgement, packet.header.get_type());
+    assert_eq!(0x0001, packet.header.message_id);
+    assert_eq!([0x01], packet.get_token());
+    let response = CoapResponse::from_packet(packet);
+    assert_eq!(&ResponseType::Content, response.get_status());
+    assert_eq!(b"22.5 C", &response.message.payload[..]);
+}
+
+#[test]
+fn post_with_separate_response() {
+    let mut network = Network::default();
+
+    let mut post = new_request(Method::Post, "/jobs", 0x0002, 0x02);
+    post.message.payload = b"run".to_vec();
+    assert_eq!(
+        network.send(CLIENT, SERVER, &post.message),
+        [
+            0x41, 0x02, 0x00, 0x02, 0x02, 0xB4, 0x6A, 0x6F, 0x62, 0x73, 0xFF,
+            0x72, 0x75, 0x6E,
+        ]
+    );
+
+    // The server can't answer right away, so it only acknowledges
+    let (request, _) = network.receive(SERVER);
+    let ack = CoapResponse::new_empty_ack(&request).unwrap();
+    assert_eq!(network.send(SERVER, CLIENT, &ack), [0x60, 0x00, 0x00, 0x02]);
+
+    let (packet, _) = network.receive(CLIENT);
+    assert_eq!(MessageType::Acknowledgement, packet.header.get_type());
+    assert_eq!(MessageClass::Empty, packet.header.code);
+    assert_eq!(0x0002, packet.header.message_id);
+
+    // Later on the response follows in its own confirmable message
+    let mut response = CoapResponse::new_separate(&request, 0x8001);
+    response.set_status(ResponseType::Created);
+    assert_eq!(
+        network.send(SERVER, CLIENT, &response.message),
+        [0x41, 0x41, 0x80, 0x01, 0x02]
+    );
+
+    let (packet, _) = network.receive(CLIENT);
+    assert_eq!(MessageType::Confirmable, packet.header.get_type());
+    assert_eq!([0x02], packet.get_token());
+    let ack = CoapResponse::new_empty_ack(&packet).unwrap();
+    let response = CoapResponse::from_packet(packet);
+    assert_eq!(&ResponseType::Created, response.get_status());
+    assert_eq!(network.send(CLIENT, SERVER, &ack), [0x60, 0x00, 0x80, 0x01]);
+
+    let (packet, _) = network.receive(SERVER);
+    assert_eq!(0x8001, packet.header.message_id);
+}
+
+#[test]
+fn observe_register_notify_cancel() {
+    let mut network = Network::default();
+    let mut subject: Subject<Endpoint> = Subject::default();
+
+    // Registration
+    let mut register = new_request(Method::Get, "/temp", 0x0003, 0x03);
+    register.set_observe_flag(ObserveOption::Register);
+    assert_eq!(
+        network.send(CLIENT, SERVER, &register.message),
+        [0x41, 0x01, 0x00, 0x03, 0x03, 0x60, 0x54, 0x74, 0x65, 0x6D, 0x70,]
+    );
+
+    let (packet, source) = network.receive(SERVER);
+    let request = CoapRequest::from_packet(packet, source);
+    assert_eq!(
+        Some(Ok(ObserveOption::Register)),
+        request.get_observe_flag()
+    );
+    subject.register(&request);
+    let sequence = subject.get_resource("temp").unwrap().sequence;
+
+    let mut response = request.response.unwrap();
+    response.message.set_observe(Observe::Sequence(sequence));
+    response.message.payload = b"22".to_vec();
+    assert_eq!(
+        network.send(SERVER, CLIENT, &response.message),
+        [0x61, 0x45, 0x00, 0x03, 0x03, 0x60, 0xFF, 0x32, 0x32]
+    );
+    let (packet, _) = network.receive(CLIENT);
+    assert_eq!(Some(Ok(Observe::Sequence(0))), packet.get_observe());
+
+    // Notification
+    subject.resource_changed("temp", 0x8002);
+    let sequence = subject.get_resource("temp").unwrap().sequence;
+    let observer = &subject.get_resource_observers("temp").unwrap()[0];
+    let notification = coap_lite::create_notification(
+        0x8002,
+        observer.token.clone(),
+        sequence,
+        b"23".to_vec(),
+    );
+    assert_eq!(
+        network.send(SERVER, CLIENT, &notification),
+        [0x41, 0x45, 0x80, 0x02, 0x03, 0x61, 0x01, 0xFF, 0x32, 0x33]
+    );
+
+    let (packet, _) = network.receive(CLIENT);
+    assert_eq!(Some(Ok(Observe::Sequence(1))), packet.get_observe());
+    assert_eq!(b"23", &packet.payload[..]);
+    assert_eq!(
+        network.send(CLIENT, SERVER, &empty_ack(packet.header.message_id)),
+        [0x60, 0x00, 0x80, 0x02]
+    );
+
+    let (packet, source) = network.receive(SERVER);
+    subject.acknowledge(&CoapRequest::from_packet(packet, source));
+
+    // Cancellation
+    let mut cancel = new_request(Method::Get, "/temp", 0x0004, 0x03);
+    cancel.set_observe_flag(ObserveOption::Deregister);
+    assert_eq!(
+        network.send(CLIENT, SERVER, &cancel.message),
+        [
+            0x41, 0x01, 0x00, 0x04, 0x03, 0x61, 0x01, 0x54, 0x74, 0x65, 0x6D,
+            0x70,
+        ]
+    );
+
+    let (packet, source) = network.receive(SERVER);
+    let request = CoapRequest::from_packet(packet, source);
+    assert_eq!(
+        Some(Ok(ObserveOption::Deregister)),
+        request.get_observe_flag()
+    );
+    subject.deregister(&request);
+    assert!(subject.get_resource_observers("temp").unwrap().is_empty());
+
+    let mut response = request.response.unwrap();
+    response.message.payload = b"23".to_vec();
+    assert_eq!(
+        network.send(SERVER, CLIENT, &response.message),
+        [0x61, 0x45, 0x00, 0x04, 0x03, 0xFF, 0x32, 0x33]
+    );
+    let (packet, _) = network.receive(CLIENT);
+    assert_eq!(None, packet.get_observe());
+}
+
+#[test]
+fn block2_download() {
+    let mut network = Network::default();
+    let mut handler = BlockHandler::new(BlockHandlerConfig {
+        max_total_message_size: 48,
+        cache_expiry_duration: Duration::from_secs(60),
+    });
+    let body: Vec<u8> = (0..40).collect();
+
+    let mut received: Vec<u8> = Vec::new();
+    let mut block2: Option<BlockValue> = None;
+    let fixtures: [&[u8]; 6] = [
+        &[0x41, 0x01, 0x00, 0x10, 0x05, 0xB3, 0x62, 0x69, 0x67],
+        &[
+            0x61, 0x45, 0x00, 0x10, 0x05, 0xD1, 0x0A, 0x08, 0x51, 0x28, 0xFF,
+            0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A,
+            0x0B, 0x0C, 0x0D, 0x0E, 0x0F,
+        ],
+        &[
+            0x41, 0x01, 0x00, 0x11, 0x05, 0xB3, 0x62, 0x69, 0x67, 0xC1, 0x10,
+        ],
+        &[
+            0x61, 0x45, 0x00, 0x11, 0x05, 0xD1, 0x0A, 0x18, 0xFF, 0x10, 0x11,
+            0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1A, 0x1B, 0x1C,
+            0x1D, 0x1E, 0x1F,
+        ],
+        &[
+            0x41, 0x01, 0x00, 0x12, 0x05, 0xB3, 0x62, 0x69, 0x67, 0xC1, 0x20,
+        ],
+        &[
+            0x61, 0x45, 0x00, 0x12, 0x05, 0xD1, 0x0A, 0x20, 0xFF, 0x20, 0x21,
+            0x22, 0x23, 0x24, 0x25, 0x26, 0x27,
+        ],
+    ];
+
+    for (i, message_id) in (0x0010..0x0013).enumerate() {
+        let mut get = new_request(Method::Get, "/big", message_id, 0x05);
+        if let Some(block2) = block2.take() {
+            get.message.add_option_as(CoapOption::Block2, block2);
+        }
+        assert_eq!(
+            network.send(CLIENT, SERVER, &get.message),
+            fixtures[2 * i]
+        );
+
+        let (packet, source) = network.receive(SERVER);
+        let mut request = CoapRequest::from_packet(packet, source);
+        if !handler.intercept_request(&mut request).unwrap() {
+            request.response.as_mut().unwrap().message.payload = body.clone();
+            handler.intercept_response(&mut request).unwrap();
+        }
+        assert_eq!(
+            network.send(SERVER, CLIENT, &request.response.unwrap().message),
+            fixtures[2 * i + 1]
+        );
+
+        let (packet, _) = network.receive(CLIENT);
+        received.extend(&packet.payload);
+        let block = packet
+            .get_first_option_as::<BlockValue>(CoapOption::Block2)
+            .unwrap()
+            .unwrap();
+        if block.more {
+            block2 = Some(
+                BlockValue::new(block.num as usize + 1, false, block.size())
+                    .unwrap(),
+            );
+        }
+    }
+
+    assert!(block2.is_none());
+    assert_eq!(body, received);
+}
+
+#[test]
+fn unknown_critical_option_rejected() {
+    let mut network = Network::default();
+
+    let mut get = new_request(Method::Get, "/temp", 0x0020, 0x06);
+    get.message
+        .add_option(CoapOption::Unknown(2049), b"x".to_vec());
+    assert_eq!(
+        network.send(CLIENT, SERVER, &get.message),
+        [
+            0x41, 0x01, 0x00, 0x20, 0x06, 0xB4, 0x74, 0x65, 0x6D, 0x70, 0xE1,
+            0x06, 0xE9, 0x78,
+        ]
+    );
+
+    let (packet, source) = network.receive(SERVER);
+    let mut request = CoapRequest::from_packet(packet, source);
+    let unknown_critical = request
+        .message
+        .options()
+        .map(|(&number, _)| CoapOption::from(number))
+        .find(|option| {
+            option.is_critical() && matches!(option, CoapOption::Unknown(_))
+        });
+    let option = unknown_critical.expect("Must detect the critical option");
+    assert!(request.apply_from_error(HandlingError::bad_option(format!(
+        "Unknown critical option {}",
+        u16::from(option)
+    ))));
+
+    let response = request.response.unwrap();
+    assert_eq!(&ResponseType::BadOption, response.get_status());
+    assert_eq!(
+        network.send(SERVER, CLIENT, &response.message),
+        [
+            0x61, 0x82, 0x00, 0x20, 0x06, 0xC0, 0xFF, 0x55, 0x6E, 0x6B, 0x6E,
+            0x6F, 0x77, 0x6E, 0x20, 0x63, 0x72, 0x69, 0x74, 0x69, 0x63, 0x61,
+            0x6C, 0x20, 0x6F, 0x70, 0x74, 0x69, 0x6F, 0x6E, 0x20, 0x32, 0x30,
+            0x34, 0x39,
+        ]
+    );
+}
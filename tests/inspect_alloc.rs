@@ -0,0 +1,51 @@
+//! Packet::inspect and Packet::serialize_envelope_into don't allocate,
+//! checked with a counting allocator.  The allocator is global, so this file
+//! holds a single test.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use coap_lite::{CoapOption, Packet};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+#[test]
+fn inspect_and_envelope_do_not_allocate() {
+    let mut packet = Packet::new();
+    packet.set_token(vec![1, 2, 3, 4]);
+    packet.add_option(CoapOption::UriPath, b"sensors".to_vec());
+    packet.add_option(CoapOption::UriPath, b"temp".to_vec());
+    packet.add_option(CoapOption::Unknown(2048), vec![0; 300]);
+    packet.payload = vec![0x42; 64];
+    let bytes = packet.to_bytes().unwrap();
+    let mut buf = [0; 1280];
+
+    let before = ALLOCATIONS.load(Ordering::SeqCst);
+    let stats = Packet::inspect(&bytes).unwrap();
+    let envelope = packet.serialize_envelope_into(64, &mut buf).unwrap();
+    let too_small = packet.serialize_envelope_into(64, &mut buf[..16]);
+    let after = ALLOCATIONS.load(Ordering::SeqCst);
+
+    assert_eq!(before, after);
+    assert_eq!(3, stats.option_count);
+    assert_eq!(64, stats.payload_length);
+    assert_eq!(bytes.len() - 64, envelope.len);
+    assert_eq!(bytes[..envelope.len], buf[..envelope.len]);
+    assert!(too_small.is_err());
+}
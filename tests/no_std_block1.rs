@@ -0,0 +1,66 @@
+//! Block1 reassembly into a fixed buffer, without the standard library or
+//! allocations.
+
+#![no_std]
+
+use coap_lite::block_handler::{
+    Block1AssemblerFixed, Block1Outcome, BlockValue,
+};
+use coap_lite::ResponseType;
+
+fn block(num: usize, more: bool) -> BlockValue {
+    BlockValue::new(num, more, 16).unwrap()
+}
+
+#[test]
+fn transfer_fits() {
+    let mut body = [0u8; 40];
+    for (i, byte) in body.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+
+    let mut assembler = Block1AssemblerFixed::<48>::new_fixed();
+    assert_eq!(
+        Block1Outcome::Continue {
+            block1: block(0, true)
+        },
+        assembler.push(&block(0, true), &body[..16], Some(40))
+    );
+    assert_eq!(
+        Block1Outcome::Continue {
+            block1: block(1, true)
+        },
+        assembler.push(&block(1, true), &body[16..32], None)
+    );
+    assert_eq!(
+        Block1Outcome::Complete {
+            block1: block(2, false)
+        },
+        assembler.push(&block(2, false), &body[32..], None)
+    );
+    assert_eq!(&body[..], assembler.body());
+}
+
+#[test]
+fn transfer_exceeds_capacity() {
+    let mut assembler = Block1AssemblerFixed::<24>::new_fixed();
+    assert_eq!(
+        Block1Outcome::Continue {
+            block1: block(0, true)
+        },
+        assembler.push(&block(0, true), &[1; 16], None)
+    );
+    let outcome = assembler.push(&block(1, true), &[2; 16], None);
+    assert_eq!(Block1Outcome::TooLarge, outcome);
+    assert_eq!(
+        Some(ResponseType::RequestEntityTooLarge),
+        outcome.response_type()
+    );
+    assert!(assembler.body().is_empty());
+
+    // The transfer is abandoned, further blocks are out of sequence
+    assert_eq!(
+        Block1Outcome::Incomplete,
+        assembler.push(&block(2, false), &[3; 4], None)
+    );
+}